@@ -0,0 +1,365 @@
+//! On-device rollups: min / avg / max per minute, 15 minutes, ... in `sensor_rollups`
+//!
+//! With `agg_res` set (e.g. `1m,15m`), flushed on-board readings are folded
+//! into fixed buckets per resolution. A bucket is complete once a reading of a
+//! later bucket arrives; it then becomes a row of the `sensor_rollups` table,
+//! written with the next forwarding round like the alert transitions:
+//!
+//! | Column | Content |
+//! | ------ | ------- |
+//! | `timestamp` | Start of the bucket |
+//! | `table_name` | Table the readings were flushed for |
+//! | `resolution` | The resolution as configured, e.g. `15m` |
+//! | `bucket_ms` | Bucket length |
+//! | `samples` | Readings in the bucket |
+//! | `<metric>_min`, `_avg`, `_max` | Per metric, NaN if it was never set |
+//!
+//! Unset (NaN) values and warming-up rows are left out. `raw_upload` decides
+//! what happens to the raw rows: `all` uploads them as before, `off` uploads
+//! only the rollups and a resolution such as `5m` keeps the first reading of
+//! every interval. Open buckets and queued rows are saved across deep sleep.
+
+use std::collections::VecDeque;
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::config::DeviceConfig;
+use crate::lake::{Cell, Column, ColumnType, LakeBackend, RecordBatch, S3Target};
+use crate::sensors::SensorReading;
+
+const ROLLUPS_TABLE: &str = "sensor_rollups";
+// Completed buckets kept for the lake; oldest dropped beyond
+const MAX_QUEUED: usize = 256;
+
+macro_rules! rollup_columns {
+    ($($metric:literal),*) => {
+        [
+            Column {
+                name: "timestamp",
+                kind: ColumnType::Long,
+            },
+            Column {
+                name: "table_name",
+                kind: ColumnType::Text,
+            },
+            Column {
+                name: "resolution",
+                kind: ColumnType::Text,
+            },
+            Column {
+                name: "bucket_ms",
+                kind: ColumnType::Long,
+            },
+            Column {
+                name: "samples",
+                kind: ColumnType::Long,
+            },
+            $(
+                Column {
+                    name: concat!($metric, "_min"),
+                    kind: ColumnType::Float,
+                },
+                Column {
+                    name: concat!($metric, "_avg"),
+                    kind: ColumnType::Float,
+                },
+                Column {
+                    name: concat!($metric, "_max"),
+                    kind: ColumnType::Float,
+                },
+            )*
+        ]
+    };
+}
+
+// In `METRIC_NAMES` order
+const ROLLUP_COLUMNS: [Column; 5 + 9 * 3] = rollup_columns!(
+    "temperature",
+    "humidity",
+    "pressure",
+    "pm1_0",
+    "pm2_5",
+    "pm10",
+    "gas_resistance",
+    "light",
+    "noise"
+);
+
+/// What happens to raw rows once they are rolled up
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RawUpload {
+    All,
+    Off,
+    /// At most one row per interval of this many milliseconds
+    Every(i64),
+}
+
+impl RawUpload {
+    fn parse(spec: &str) -> Result<Self> {
+        match spec.trim() {
+            "" | "all" => Ok(Self::All),
+            "off" => Ok(Self::Off),
+            interval => Ok(Self::Every(parse_resolution(interval)?)),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Bucket {
+    table: String,
+    start: i64,
+    samples: u32,
+    mins: [f32; 9],
+    maxs: [f32; 9],
+    sums: [f64; 9],
+    counts: [u32; 9],
+}
+
+impl Bucket {
+    fn new(table: &str, start: i64) -> Self {
+        Self {
+            table: table.to_string(),
+            start,
+            samples: 0,
+            mins: [0.0; 9],
+            maxs: [0.0; 9],
+            sums: [0.0; 9],
+            counts: [0; 9],
+        }
+    }
+
+    fn add(&mut self, reading: &SensorReading) {
+        self.samples += 1;
+        for (i, value) in reading.metrics().into_iter().enumerate() {
+            if !value.is_finite() {
+                continue;
+            }
+            // The first value seeds min and max, so they never hold infinities
+            if self.counts[i] == 0 {
+                self.mins[i] = value;
+                self.maxs[i] = value;
+            } else {
+                self.mins[i] = self.mins[i].min(value);
+                self.maxs[i] = self.maxs[i].max(value);
+            }
+            self.sums[i] += f64::from(value);
+            self.counts[i] += 1;
+        }
+    }
+}
+
+/// A completed bucket waiting for the lake
+#[derive(Clone, Serialize, Deserialize)]
+struct Completed {
+    resolution: String,
+    bucket_ms: i64,
+    bucket: Bucket,
+}
+
+struct Resolution {
+    // As configured ("15m")
+    label: String,
+    bucket_ms: i64,
+    bucket: Option<Bucket>,
+}
+
+/// What survives deep sleep: open buckets by resolution, queued rows, the raw cursor
+#[derive(Serialize, Deserialize)]
+pub struct RollupState {
+    buckets: Vec<(String, Bucket)>,
+    unwritten: VecDeque<Completed>,
+    next_raw_ms: i64,
+}
+
+pub struct Aggregator {
+    resolutions: Vec<Resolution>,
+    raw: RawUpload,
+    unwritten: VecDeque<Completed>,
+    // First timestamp a thinned raw row is kept from
+    next_raw_ms: i64,
+}
+
+impl Aggregator {
+    /// Rollups per `agg_res`; with no resolution set, raw rows are uploaded as before
+    pub fn from_config(config: &DeviceConfig) -> Result<Self> {
+        let mut resolutions = Vec::new();
+        for spec in config.agg_resolutions.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            resolutions.push(Resolution {
+                label: spec.to_string(),
+                bucket_ms: parse_resolution(spec)?,
+                bucket: None,
+            });
+        }
+        let mut raw = RawUpload::parse(&config.raw_upload)?;
+        if resolutions.is_empty() && raw != RawUpload::All {
+            warn!("raw_upload needs rollups (agg_res) in place of raw rows, uploading all");
+            raw = RawUpload::All;
+        }
+
+        Ok(Self {
+            resolutions,
+            raw,
+            unwritten: VecDeque::new(),
+            next_raw_ms: i64::MIN,
+        })
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.resolutions.is_empty()
+    }
+
+    /// Resolutions and raw policy, for logging
+    pub fn describe(&self) -> String {
+        let labels: Vec<&str> = self.resolutions.iter().map(|r| r.label.as_str()).collect();
+        let raw = match self.raw {
+            RawUpload::All => "all raw rows".to_string(),
+            RawUpload::Off => "no raw rows".to_string(),
+            RawUpload::Every(ms) => format!("a raw row every {}s", ms / 1000),
+        };
+        format!("rollups {}, {}", labels.join(", "), raw)
+    }
+
+    /// Fold `readings` of `table` into the open buckets, queuing the completed ones
+    pub fn record(&mut self, table: &str, readings: &[SensorReading]) {
+        for resolution in &mut self.resolutions {
+            for reading in readings.iter().filter(|r| !r.warming_up) {
+                let start = reading.timestamp - reading.timestamp.rem_euclid(resolution.bucket_ms);
+                match &mut resolution.bucket {
+                    Some(bucket) if bucket.start == start && bucket.table == table => {
+                        bucket.add(reading)
+                    }
+                    bucket => {
+                        if let Some(done) = bucket.take() {
+                            if self.unwritten.len() == MAX_QUEUED {
+                                self.unwritten.pop_front();
+                            }
+                            self.unwritten.push_back(Completed {
+                                resolution: resolution.label.clone(),
+                                bucket_ms: resolution.bucket_ms,
+                                bucket: done,
+                            });
+                        }
+                        let mut next = Bucket::new(table, start);
+                        next.add(reading);
+                        *bucket = Some(next);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The raw rows to upload out of `readings`, per `raw_upload`
+    pub fn raw(&mut self, readings: Vec<SensorReading>) -> Vec<SensorReading> {
+        match self.raw {
+            RawUpload::All => readings,
+            RawUpload::Off => Vec::new(),
+            RawUpload::Every(interval_ms) => {
+                let mut kept = Vec::new();
+                for reading in readings {
+                    // A clock jump backwards restarts the interval
+                    let start = reading.timestamp - reading.timestamp.rem_euclid(interval_ms);
+                    let jumped_back = start + interval_ms < self.next_raw_ms;
+                    if reading.timestamp >= self.next_raw_ms || jumped_back {
+                        self.next_raw_ms = start + interval_ms;
+                        kept.push(reading);
+                    }
+                }
+                kept
+            }
+        }
+    }
+
+    /// True if completed buckets are waiting for the lake
+    pub fn has_unwritten(&self) -> bool {
+        !self.unwritten.is_empty()
+    }
+
+    /// Write completed buckets to `sensor_rollups`; they stay queued on failure
+    pub fn write(&mut self, lake: &mut dyn LakeBackend, target: &S3Target) {
+        let batch = RecordBatch {
+            columns: &ROLLUP_COLUMNS,
+            rows: self.unwritten.iter().map(row).collect(),
+        };
+        let written = lake
+            .append_records(target, ROLLUPS_TABLE, &batch)
+            .and_then(|_| lake.commit());
+        match written {
+            Ok(()) => {
+                info!("  Wrote {} rollup rows", self.unwritten.len());
+                self.unwritten.clear();
+            }
+            Err(e) => warn!("  Failed to write rollup rows: {:?}", e),
+        }
+    }
+
+    /// Take open buckets and queued rows for the next boot, `None` without rollups
+    pub fn take_state(&mut self) -> Option<RollupState> {
+        if !self.is_active() {
+            return None;
+        }
+        Some(RollupState {
+            buckets: self
+                .resolutions
+                .iter_mut()
+                .filter_map(|r| Some((r.label.clone(), r.bucket.take()?)))
+                .collect(),
+            unwritten: std::mem::take(&mut self.unwritten),
+            next_raw_ms: self.next_raw_ms,
+        })
+    }
+
+    /// Take over what `take_state` took before the last boot. Buckets of
+    /// resolutions that are no longer configured are dropped.
+    pub fn restore_state(&mut self, saved: RollupState) {
+        for (label, bucket) in saved.buckets {
+            if let Some(resolution) = self.resolutions.iter_mut().find(|r| r.label == label) {
+                resolution.bucket = Some(bucket);
+            }
+        }
+        self.unwritten = saved.unwritten;
+        self.next_raw_ms = saved.next_raw_ms;
+    }
+}
+
+/// One `sensor_rollups` row, in `ROLLUP_COLUMNS` order
+fn row(completed: &Completed) -> Vec<Cell> {
+    let bucket = &completed.bucket;
+    let mut cells = vec![
+        Cell::Long(bucket.start),
+        Cell::Text(bucket.table.clone()),
+        Cell::Text(completed.resolution.clone()),
+        Cell::Long(completed.bucket_ms),
+        Cell::Long(i64::from(bucket.samples)),
+    ];
+    for (i, &count) in bucket.counts.iter().enumerate() {
+        if count == 0 {
+            cells.extend([Cell::Float(f32::NAN), Cell::Float(f32::NAN), Cell::Float(f32::NAN)]);
+            continue;
+        }
+        let average = (bucket.sums[i] / f64::from(count)) as f32;
+        cells.extend([
+            Cell::Float(bucket.mins[i]),
+            Cell::Float(average),
+            Cell::Float(bucket.maxs[i]),
+        ]);
+    }
+    cells
+}
+
+/// `<n>s`, `<n>m` or `<n>h` in milliseconds
+pub fn parse_resolution(spec: &str) -> Result<i64> {
+    let unit = spec.chars().last().unwrap_or(' ');
+    let number = &spec[..spec.len() - unit.len_utf8()];
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        _ => bail!("invalid rollup resolution '{}', expected e.g. 1m or 1h", spec),
+    };
+    match number.parse::<i64>() {
+        Ok(n) if n > 0 => Ok(n * seconds * 1000),
+        _ => bail!("invalid rollup resolution '{}', expected e.g. 1m or 1h", spec),
+    }
+}
@@ -0,0 +1,408 @@
+//! Stateful threshold alerts with hysteresis and minimum duration
+//!
+//! Rules come from the `alerts` NVS key, comma-separated:
+//!
+//! ```text
+//! pm2_5>35:25@300,temperature<2:4@600
+//! ```
+//!
+//! `<metric><op><raise>[:<clear>][@<seconds>]` raises an alert once the metric
+//! has been above (`>`) or below (`<`) `raise` for `seconds`, and clears it once
+//! it has been back on the other side of `clear` for as long. Without a clear
+//! threshold the raise threshold is used, without seconds the first sample
+//! decides. Unset (NaN) values and warming-up rows leave the state as it is.
+//!
+//! Every raise and clear is written as a row of the `alerts` lake table with
+//! the next forwarding round, and POSTed to the `alert_url` webhook (encoded
+//! per `alert_enc`, see `payload.rs`) as soon as WiFi is up.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::clock::clock;
+use crate::identity::DeviceIdentity;
+use crate::lake::{Cell, Column, ColumnType, LakeBackend, RecordBatch, S3Target};
+use crate::payload::{self, PeerEncoding};
+use crate::sensors::{SensorReading, METRIC_NAMES};
+
+const ALERTS_TABLE: &str = "alerts";
+// Transitions kept for the lake and the webhook each; oldest dropped beyond
+const MAX_QUEUED: usize = 64;
+const WEBHOOK_RETRY: Duration = Duration::from_secs(60);
+
+const ALERT_COLUMNS: [Column; 7] = [
+    Column {
+        name: "timestamp",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "metric",
+        kind: ColumnType::Text,
+    },
+    Column {
+        name: "state",
+        kind: ColumnType::Text,
+    },
+    Column {
+        name: "value",
+        kind: ColumnType::Float,
+    },
+    Column {
+        name: "threshold",
+        kind: ColumnType::Float,
+    },
+    Column {
+        name: "raised_at",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "rule",
+        kind: ColumnType::Text,
+    },
+];
+
+struct Rule {
+    spec: String,
+    metric: usize,
+    above: bool,
+    raise: f32,
+    clear: f32,
+    hold_ms: i64,
+}
+
+impl Rule {
+    fn parse(spec: &str) -> Result<Self> {
+        let (op_at, above) = match (spec.find('>'), spec.find('<')) {
+            (Some(i), None) => (i, true),
+            (None, Some(i)) => (i, false),
+            _ => bail!("alert rule '{}' needs exactly one of > or <", spec),
+        };
+        let name = spec[..op_at].trim();
+        let metric = METRIC_NAMES
+            .iter()
+            .position(|m| *m == name)
+            .ok_or_else(|| anyhow!("unknown alert metric '{}'", name))?;
+
+        let rest = &spec[op_at + 1..];
+        let (thresholds, hold) = match rest.split_once('@') {
+            Some((thresholds, secs)) => (thresholds, secs.trim().parse::<u32>()?),
+            None => (rest, 0),
+        };
+        let (raise, clear) = match thresholds.split_once(':') {
+            Some((raise, clear)) => (raise.trim().parse::<f32>()?, clear.trim().parse::<f32>()?),
+            None => {
+                let raise = thresholds.trim().parse::<f32>()?;
+                (raise, raise)
+            }
+        };
+        if (above && clear > raise) || (!above && clear < raise) {
+            bail!("alert rule '{}' clears on the wrong side of its threshold", spec);
+        }
+
+        Ok(Self {
+            spec: spec.to_string(),
+            metric,
+            above,
+            raise,
+            clear,
+            hold_ms: i64::from(hold) * 1000,
+        })
+    }
+
+    fn breached(&self, value: f32) -> bool {
+        if self.above {
+            value > self.raise
+        } else {
+            value < self.raise
+        }
+    }
+
+    fn recovered(&self, value: f32) -> bool {
+        if self.above {
+            value <= self.clear
+        } else {
+            value >= self.clear
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+enum State {
+    Normal,
+    /// Breached since, not for long enough yet
+    Pending { since: i64 },
+    Raised { raised_at: i64 },
+    /// Recovered since, not for long enough yet
+    Clearing { raised_at: i64, since: i64 },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Transition {
+    timestamp: i64,
+    metric: String,
+    raised: bool,
+    value: f32,
+    threshold: f32,
+    raised_at: i64,
+    rule: String,
+}
+
+/// A raise or clear, as `update` reports it
+pub struct AlertChange {
+    pub metric: String,
+    pub raised: bool,
+    pub value: f32,
+}
+
+/// What survives deep sleep: states by rule spec, plus the queues
+#[derive(Serialize, Deserialize)]
+pub struct AlertState {
+    states: Vec<(String, State)>,
+    unwritten: VecDeque<Transition>,
+    unsent: VecDeque<Transition>,
+}
+
+pub struct Alerts {
+    rules: Vec<Rule>,
+    states: Vec<State>,
+    webhook: Option<(String, PeerEncoding)>,
+    unwritten: VecDeque<Transition>,
+    unsent: VecDeque<Transition>,
+    retry_at: Duration,
+}
+
+impl Alerts {
+    /// Parse the `alerts` rules; an empty `webhook_url` disables notifications
+    pub fn parse(spec: &str, webhook_url: &str, encoding: &str) -> Result<Self> {
+        let rules = spec
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(Rule::parse)
+            .collect::<Result<Vec<_>>>()?;
+        let encoding = PeerEncoding::from_name(encoding)
+            .ok_or_else(|| anyhow!("unknown alert encoding '{}'", encoding))?;
+
+        Ok(Self {
+            states: vec![State::Normal; rules.len()],
+            rules,
+            webhook: (!webhook_url.is_empty()).then(|| (webhook_url.to_string(), encoding)),
+            unwritten: VecDeque::new(),
+            unsent: VecDeque::new(),
+            retry_at: Duration::ZERO,
+        })
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// Configured rules, for logging
+    pub fn describe(&self) -> String {
+        let rules: Vec<&str> = self.rules.iter().map(|r| r.spec.as_str()).collect();
+        rules.join(", ")
+    }
+
+    /// Advance every rule's state machine with `reading`, returning the alerts
+    /// it raised or cleared
+    pub fn update(&mut self, reading: &SensorReading) -> Vec<AlertChange> {
+        if reading.warming_up {
+            return Vec::new();
+        }
+        let now = reading.timestamp;
+        let metrics = reading.metrics();
+        let mut transitions = Vec::new();
+
+        for (rule, state) in self.rules.iter().zip(self.states.iter_mut()) {
+            let value = metrics[rule.metric];
+            if !value.is_finite() {
+                continue;
+            }
+
+            let next = match *state {
+                State::Normal if rule.breached(value) => State::Pending { since: now },
+                State::Pending { .. } if !rule.breached(value) => State::Normal,
+                State::Raised { raised_at } if rule.recovered(value) => State::Clearing {
+                    raised_at,
+                    since: now,
+                },
+                State::Clearing { raised_at, .. } if !rule.recovered(value) => {
+                    State::Raised { raised_at }
+                }
+                other => other,
+            };
+
+            // Held long enough? A clock jump backwards restarts the hold.
+            *state = match next {
+                State::Pending { since } if now < since => State::Pending { since: now },
+                State::Pending { since } if now - since >= rule.hold_ms => {
+                    transitions.push(transition(rule, true, value, now, now));
+                    State::Raised { raised_at: now }
+                }
+                State::Clearing { raised_at, since } if now < since => State::Clearing {
+                    raised_at,
+                    since: now,
+                },
+                State::Clearing { raised_at, since } if now - since >= rule.hold_ms => {
+                    transitions.push(transition(rule, false, value, now, raised_at));
+                    State::Normal
+                }
+                other => other,
+            };
+        }
+
+        let mut changes = Vec::new();
+        for transition in transitions {
+            changes.push(AlertChange {
+                metric: transition.metric.clone(),
+                raised: transition.raised,
+                value: transition.value,
+            });
+            for queue in [&mut self.unwritten, &mut self.unsent] {
+                if queue.len() == MAX_QUEUED {
+                    queue.pop_front();
+                }
+                queue.push_back(transition.clone());
+            }
+        }
+        changes
+    }
+
+    /// True if transitions are waiting for the lake
+    pub fn has_unwritten(&self) -> bool {
+        !self.unwritten.is_empty()
+    }
+
+    /// Write queued transitions to the `alerts` table; they stay queued on failure
+    pub fn write(&mut self, lake: &mut dyn LakeBackend, target: &S3Target) {
+        let batch = RecordBatch {
+            columns: &ALERT_COLUMNS,
+            rows: self.unwritten.iter().map(row).collect(),
+        };
+        let written = lake
+            .append_records(target, ALERTS_TABLE, &batch)
+            .and_then(|_| lake.commit());
+        match written {
+            Ok(()) => {
+                info!("  Wrote {} alert transitions", self.unwritten.len());
+                self.unwritten.clear();
+            }
+            Err(e) => warn!("  Failed to write alert transitions: {:?}", e),
+        }
+    }
+
+    /// POST queued transitions to the webhook, oldest first, stopping at the first failure
+    pub fn notify(&mut self, identity: &DeviceIdentity) {
+        let Some((url, encoding)) = self.webhook.as_mut() else {
+            self.unsent.clear();
+            return;
+        };
+        if self.unsent.is_empty() || clock().monotonic() < self.retry_at {
+            return;
+        }
+
+        while let Some(transition) = self.unsent.front() {
+            if let Err(e) = post(url, encoding, identity, transition) {
+                warn!("Alert webhook failed, retrying in {:?}: {:?}", WEBHOOK_RETRY, e);
+                self.retry_at = clock().monotonic() + WEBHOOK_RETRY;
+                return;
+            }
+            self.unsent.pop_front();
+        }
+    }
+
+    /// Take states and queues for the next boot, `None` without rules
+    pub fn take_state(&mut self) -> Option<AlertState> {
+        if !self.is_active() {
+            return None;
+        }
+        Some(AlertState {
+            states: self
+                .rules
+                .iter()
+                .zip(&self.states)
+                .map(|(rule, state)| (rule.spec.clone(), *state))
+                .collect(),
+            unwritten: std::mem::take(&mut self.unwritten),
+            unsent: std::mem::take(&mut self.unsent),
+        })
+    }
+
+    /// Take over what `take_state` took before the last boot. States of
+    /// rules that changed in the meantime start over.
+    pub fn restore_state(&mut self, saved: AlertState) {
+        for (rule, state) in self.rules.iter().zip(self.states.iter_mut()) {
+            let found = saved.states.iter().find(|(spec, _)| *spec == rule.spec);
+            if let Some((_, found)) = found {
+                *state = *found;
+            }
+        }
+        self.unwritten = saved.unwritten;
+        self.unsent = saved.unsent;
+    }
+}
+
+fn transition(rule: &Rule, raised: bool, value: f32, now: i64, raised_at: i64) -> Transition {
+    let metric = METRIC_NAMES[rule.metric];
+    if raised {
+        warn!("Alert raised: {} = {} ({})", metric, value, rule.spec);
+    } else {
+        info!("Alert cleared: {} = {} ({})", metric, value, rule.spec);
+    }
+
+    Transition {
+        timestamp: now,
+        metric: metric.to_string(),
+        raised,
+        value,
+        threshold: if raised { rule.raise } else { rule.clear },
+        raised_at,
+        rule: rule.spec.clone(),
+    }
+}
+
+fn state_name(raised: bool) -> &'static str {
+    if raised {
+        "raised"
+    } else {
+        "cleared"
+    }
+}
+
+/// One `alerts` table row, in `ALERT_COLUMNS` order
+fn row(transition: &Transition) -> Vec<Cell> {
+    vec![
+        Cell::Long(transition.timestamp),
+        Cell::Text(transition.metric.clone()),
+        Cell::Text(state_name(transition.raised).to_string()),
+        Cell::Float(transition.value),
+        Cell::Float(transition.threshold),
+        Cell::Long(transition.raised_at),
+        Cell::Text(transition.rule.clone()),
+    ]
+}
+
+fn post(
+    url: &str,
+    encoding: &mut PeerEncoding,
+    identity: &DeviceIdentity,
+    transition: &Transition,
+) -> Result<()> {
+    let document = json!({
+        "device_id": identity.device_id,
+        "location": identity.location,
+        "state": state_name(transition.raised),
+        "metric": transition.metric,
+        "value": transition.value,
+        "threshold": transition.threshold,
+        "timestamp": transition.timestamp,
+        "raised_at": transition.raised_at,
+        "rule": transition.rule,
+    });
+    payload::post(url, &document, encoding)
+}
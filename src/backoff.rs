@@ -0,0 +1,128 @@
+//! Upload circuit breaker, persisted in NVS so reboots respect the cooldown
+//!
+//! A forwarding round that uploads nothing because S3 is unreachable (a
+//! transient failure that outlasted `with_retry`) opens the breaker: no
+//! round is attempted until its cooldown has passed. The cooldown doubles
+//! with every failed round, from `backoff_s` up to `backoff_max_s`, and is
+//! jittered so a fleet coming back from a regional outage doesn't return in
+//! lockstep. The round after the cooldown is the probe: any upload closes
+//! the breaker, another outage opens it for longer.
+//!
+//! The failed round count and the end of the cooldown (Unix epoch ms) are
+//! kept in the `backoff` NVS namespace. A device that crashes or deep
+//! sleeps during an outage therefore picks up the same cooldown after the
+//! reboot instead of hammering the endpoint right away.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use rand_core::{OsRng, RngCore};
+
+use crate::clock::clock;
+use crate::config::DeviceConfig;
+use crate::events;
+
+const NAMESPACE: &str = "backoff";
+
+const KEY_FAILURES: &str = "failures";
+const KEY_UNTIL: &str = "until_ms";
+
+pub struct UploadBackoff {
+    nvs: EspNvs<NvsDefault>,
+    base: Duration,
+    max: Duration,
+    // Consecutive rounds lost to an outage
+    failures: u32,
+    // End of the cooldown, 0 while closed
+    until_ms: i64,
+}
+
+impl UploadBackoff {
+    /// The breaker as the last boot left it
+    pub fn load(partition: EspDefaultNvsPartition, config: &DeviceConfig) -> Result<Self> {
+        let nvs: EspNvs<NvsDefault> = EspNvs::new(partition, NAMESPACE, true)?;
+        let failures = nvs.get_u32(KEY_FAILURES)?.unwrap_or(0);
+        let until_ms = nvs.get_i64(KEY_UNTIL)?.unwrap_or(0);
+        if failures > 0 {
+            info!(
+                "Upload backoff carried over: {} failed round(s), cooling down until {}",
+                failures, until_ms
+            );
+        }
+
+        let base = Duration::from_secs(config.backoff_secs.max(1).into());
+        Ok(Self {
+            nvs,
+            base,
+            max: Duration::from_secs(config.backoff_max_secs.into()).max(base),
+            failures,
+            until_ms,
+        })
+    }
+
+    /// True unless a cooldown is running; only meaningful once time is trusted
+    pub fn allows(&mut self) -> bool {
+        if self.until_ms == 0 {
+            return true;
+        }
+        let now_ms = clock().now_millis();
+        // A cooldown saved under a wrong clock can't outlast the longest one
+        self.until_ms = self.until_ms.min(now_ms + self.max.as_millis() as i64);
+        now_ms >= self.until_ms
+    }
+
+    /// A round uploaded nothing because S3 was unreachable
+    pub fn round_failed(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+        let cooldown = self.cooldown();
+        self.until_ms = clock().now_millis() + cooldown.as_millis() as i64;
+        warn!(
+            "S3 unreachable ({} failed round(s)), next upload attempt in {}s",
+            self.failures,
+            cooldown.as_secs()
+        );
+        if self.failures == 1 {
+            let detail = format!("S3 unreachable, backing off up to {}s", self.max.as_secs());
+            events::report(events::UPLOAD_BACKOFF, "", detail);
+        }
+        self.save();
+    }
+
+    /// A round got through to S3
+    pub fn round_succeeded(&mut self) {
+        if self.failures == 0 {
+            return;
+        }
+        info!("S3 reachable again after {} failed round(s)", self.failures);
+        self.failures = 0;
+        self.until_ms = 0;
+        self.save();
+    }
+
+    /// Failed rounds and the end of the cooldown, `None` while closed
+    pub fn status(&self) -> (u32, Option<i64>) {
+        (self.failures, (self.until_ms > 0).then_some(self.until_ms))
+    }
+
+    /// Exponential in the failed rounds, jittered over its upper half like `RetryPolicy`
+    fn cooldown(&self) -> Duration {
+        let backoff = self
+            .base
+            .saturating_mul(1 << (self.failures - 1).min(16))
+            .min(self.max);
+        let jitter = (OsRng.next_u32() as f64 / u32::MAX as f64) * 0.5 + 0.5;
+        backoff.mul_f64(jitter)
+    }
+
+    fn save(&self) {
+        let saved = self
+            .nvs
+            .set_u32(KEY_FAILURES, self.failures)
+            .and_then(|_| self.nvs.set_i64(KEY_UNTIL, self.until_ms));
+        if let Err(e) = saved {
+            warn!("Failed to save the upload backoff, a reboot will forget it: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,778 @@
+//! `lakectl`: inspect a node's DuckLake from a laptop
+//!
+//! The catalog of the `ducklake` backend lives on the device's flash, so the
+//! host reads it over the HTTP endpoints (`--features http` on the device):
+//! `GET /export` for the lake settings, `GET /catalog` for the catalog file.
+//! `/catalog` and `/maintain` need the device's `ctl_token`, taken from the
+//! `LAKECTL_TOKEN` variable. `pull` saves both to a file, which every other
+//! command accepts instead of a device (`--from`), e.g. to inspect a node
+//! that has since gone to sleep.
+//!
+//! ```text
+//! lakectl --device 192.168.1.50 snapshots
+//! lakectl --device 192.168.1.50 pull --out lake.json
+//! lakectl --from lake.json verify [--table esp32s3]
+//! lakectl --device 192.168.1.50 compact
+//! lakectl --from lake.json simulate --devices 50 --batches 4
+//! ```
+//!
+//! `verify` downloads every live data file and checks its Parquet footer
+//! and size against the catalog entry, signing with the usual
+//! `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`
+//! variables (unsigned without them). `simulate` has that many virtual
+//! devices upload data files of synthetic readings to the node's table at
+//! once, to check a fleet sharing the prefix never overwrites a file.
+//! Host-only: build with `--features host` for the host target.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::process::ExitCode;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::Bytes;
+use parquet::basic::{Compression, Encoding};
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, FloatType, Int32Type, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+use serde::Deserialize;
+use serde_json::{json, Value as Json};
+
+const USAGE: &str = "\
+usage: lakectl (--device <host[:port]> | --from <file>) <command>
+
+commands:
+  snapshots              list snapshots with the files and rows they added
+  verify [--table <t>]   check live data files in S3 against the catalog
+  pull --out <file>      save the lake settings and catalog for --from
+  compact                run lake maintenance on the device now (--device only)
+  simulate [--devices <n>] [--batches <n>] [--rows <n>]
+                         upload data files of that many virtual devices at once";
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+const PRESIGN_EXPIRY: Duration = Duration::from_secs(300);
+// Merged files stay under 256 KB, this only bounds a wrong catalog entry
+const MAX_OBJECT_LEN: u64 = 64 * 1024 * 1024;
+
+// `simulate` defaults: a small fleet, each device flushing a few batches
+const SIM_DEVICES: usize = 10;
+const SIM_BATCHES: usize = 4;
+// `ROWS_PER_FILE` of the firmware, 5 s apart like its `SAMPLE_INTERVAL`
+const SIM_ROWS: usize = 178;
+const SIM_INTERVAL_MS: i64 = 5000;
+const SIM_MAX_DEVICES: usize = 1000;
+
+// ============================================================================
+// DEVICE EXPORT
+// ============================================================================
+
+/// `GET /export` of the device
+#[derive(Deserialize)]
+struct Export {
+    device_id: String,
+    firmware_version: String,
+    lake_backend: String,
+    table: String,
+    // Missing from `pull` files of older firmware
+    #[serde(default)]
+    data_path: String,
+    s3: S3Settings,
+}
+
+#[derive(Deserialize)]
+struct S3Settings {
+    #[serde(default)]
+    bucket: String,
+    region: String,
+    endpoint: String,
+    url_style: String,
+    use_ssl: bool,
+}
+
+/// The parts of the device's `catalog.json` the commands read
+#[derive(Deserialize)]
+struct Envelope {
+    catalog: CatalogState,
+}
+
+#[derive(Deserialize)]
+struct CatalogState {
+    next_snapshot_id: u64,
+    files: Vec<DataFile>,
+    #[serde(default)]
+    snapshots: Vec<Snapshot>,
+    #[serde(default)]
+    retired: Vec<RetiredFile>,
+    #[serde(default)]
+    maintained_ms: i64,
+}
+
+#[derive(Deserialize)]
+struct DataFile {
+    snapshot_id: u64,
+    table: String,
+    bucket: String,
+    key: String,
+    rows: usize,
+    bytes: usize,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    snapshot_id: u64,
+    committed_ms: i64,
+}
+
+#[derive(Deserialize)]
+struct RetiredFile {
+    file: DataFile,
+    retired_in: u64,
+}
+
+/// Export and catalog as fetched, the format `pull` saves
+struct Lake {
+    export: Json,
+    catalog: Json,
+}
+
+impl Lake {
+    fn fetch(device: &str) -> Result<Self> {
+        let export = get_json(&format!("{}/export", device))?;
+        let catalog = get_json(&format!("{}/catalog", device))?;
+        Ok(Self { export, catalog })
+    }
+
+    fn load(path: &str) -> Result<Self> {
+        let saved: Json = serde_json::from_slice(
+            &std::fs::read(path).with_context(|| format!("reading {}", path))?,
+        )?;
+        match (saved.get("export"), saved.get("catalog")) {
+            (Some(export), Some(catalog)) => Ok(Self {
+                export: export.clone(),
+                catalog: catalog.clone(),
+            }),
+            _ => bail!("{} is not a `lakectl pull` file", path),
+        }
+    }
+
+    fn parse(&self) -> Result<(Export, CatalogState)> {
+        let export: Export =
+            serde_json::from_value(self.export.clone()).context("parsing the device export")?;
+        let envelope: Envelope =
+            serde_json::from_value(self.catalog.clone()).context("parsing the catalog")?;
+        Ok((export, envelope.catalog))
+    }
+}
+
+// ============================================================================
+// COMMANDS
+// ============================================================================
+
+fn snapshots(export: &Export, catalog: &CatalogState) {
+    println!(
+        "Device {} (firmware {}), {} backend, table '{}', next snapshot {}",
+        export.device_id,
+        export.firmware_version,
+        export.lake_backend,
+        export.table,
+        catalog.next_snapshot_id
+    );
+    if catalog.maintained_ms > 0 {
+        println!("Last maintenance {}", utc(catalog.maintained_ms));
+    }
+
+    // What each snapshot added, counting files a merge has retired since
+    let mut added: BTreeMap<u64, (usize, usize)> = BTreeMap::new();
+    let mut replaced: BTreeMap<u64, usize> = BTreeMap::new();
+    let retired = catalog.retired.iter().map(|r| &r.file);
+    for file in catalog.files.iter().chain(retired) {
+        let entry = added.entry(file.snapshot_id).or_default();
+        entry.0 += 1;
+        entry.1 += file.rows;
+    }
+    for retired in &catalog.retired {
+        *replaced.entry(retired.retired_in).or_default() += 1;
+    }
+
+    println!();
+    println!(
+        "{:>8}  {:<19}  {:>6}  {:>9}  {:>8}",
+        "snapshot", "committed", "files", "rows", "replaced"
+    );
+    for snapshot in &catalog.snapshots {
+        let (files, rows) = added.get(&snapshot.snapshot_id).copied().unwrap_or_default();
+        println!(
+            "{:>8}  {:<19}  {:>6}  {:>9}  {:>8}",
+            snapshot.snapshot_id,
+            utc(snapshot.committed_ms),
+            files,
+            rows,
+            replaced.get(&snapshot.snapshot_id).copied().unwrap_or_default()
+        );
+    }
+    let rows: usize = catalog.files.iter().map(|f| f.rows).sum();
+    println!();
+    println!(
+        "{} snapshots, {} live data files ({} rows), {} retired files awaiting deletion",
+        catalog.snapshots.len(),
+        catalog.files.len(),
+        rows,
+        catalog.retired.len()
+    );
+}
+
+/// Check each live data file against its catalog entry; false on any mismatch
+fn verify(export: &Export, catalog: &CatalogState, table: Option<&str>) -> Result<bool> {
+    let credentials = env_credentials();
+    if credentials.is_none() {
+        eprintln!("AWS_ACCESS_KEY_ID / AWS_SECRET_ACCESS_KEY not set, sending unsigned requests");
+    }
+
+    let files: Vec<&DataFile> = catalog
+        .files
+        .iter()
+        .filter(|f| table.is_none_or(|t| f.table == t))
+        .collect();
+    println!("Verifying {} data files...", files.len());
+
+    let mut buckets: BTreeMap<&str, Bucket> = BTreeMap::new();
+    let mut failed = 0;
+    for file in &files {
+        if !buckets.contains_key(file.bucket.as_str()) {
+            buckets.insert(&file.bucket, s3_bucket(&file.bucket, &export.s3)?);
+        }
+        let bucket = &buckets[file.bucket.as_str()];
+        let outcome = check_file(bucket, credentials.as_ref(), file);
+        let label = format!("s3://{}/{}", file.bucket, file.key);
+        match outcome {
+            Ok(()) => println!("  ok        {} ({} rows)", label, file.rows),
+            Err(e) => {
+                failed += 1;
+                println!("  MISMATCH  {}: {:#}", label, e);
+            }
+        }
+    }
+
+    println!();
+    if failed == 0 {
+        println!("All {} data files match the catalog", files.len());
+    } else {
+        println!("{} of {} data files don't match the catalog", failed, files.len());
+    }
+    Ok(failed == 0)
+}
+
+fn check_file(bucket: &Bucket, credentials: Option<&Credentials>, file: &DataFile) -> Result<()> {
+    let url = bucket.get_object(credentials, &file.key).sign(PRESIGN_EXPIRY);
+    let response = match agent().get(url.as_str()).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(404, _)) => bail!("object is missing"),
+        Err(ureq::Error::Status(status, response)) => {
+            bail!("S3 answered {}: {}", status, response.into_string().unwrap_or_default())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut data = Vec::with_capacity(file.bytes);
+    response.into_reader().take(MAX_OBJECT_LEN).read_to_end(&mut data)?;
+
+    if data.len() != file.bytes {
+        bail!("{} bytes, the catalog says {}", data.len(), file.bytes);
+    }
+    let reader = SerializedFileReader::new(Bytes::from(data)).context("reading the footer")?;
+    let rows = reader.metadata().file_metadata().num_rows();
+    if rows != file.rows as i64 {
+        bail!("{} rows, the catalog says {}", rows, file.rows);
+    }
+    Ok(())
+}
+
+fn pull(lake: &Lake, out: &str) -> Result<()> {
+    let saved = json!({ "export": lake.export, "catalog": lake.catalog });
+    std::fs::write(out, serde_json::to_vec_pretty(&saved)?)
+        .with_context(|| format!("writing {}", out))?;
+    println!("Saved the lake settings and catalog to {}", out);
+    Ok(())
+}
+
+fn compact(device: &str) -> Result<()> {
+    let url = format!("{}/maintain", device);
+    match authorized(agent().post(&url)).call() {
+        Ok(_) => {
+            println!("Maintenance scheduled, the device runs it with its next forwarding round");
+            Ok(())
+        }
+        Err(ureq::Error::Status(status, response)) => {
+            bail!("device answered {}: {}", status, response.into_string().unwrap_or_default())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// ============================================================================
+// FLEET SIMULATION
+// ============================================================================
+
+/// How one virtual device's uploads went
+#[derive(Default)]
+struct SimOutcome {
+    uploaded: usize,
+    rows: usize,
+    bytes: usize,
+    // Keys another device had written first (HTTP 412)
+    collisions: Vec<String>,
+    failures: Vec<String>,
+    latencies: Vec<Duration>,
+}
+
+/// Have `devices` virtual devices upload `batches` data files of `rows`
+/// readings each to the node's table, all at once; false on any collision
+/// or failed upload
+///
+/// Every device is named `<device_id>-sim<n>` and samples on the same
+/// clock, so their files start at the same timestamps and only the device
+/// ID in the key tells them apart, as on a fleet flushing in lockstep.
+/// Uploads are conditional (`If-None-Match: *`), so an object that already
+/// exists is reported rather than overwritten.
+fn simulate(export: &Export, devices: usize, batches: usize, rows: usize) -> Result<bool> {
+    if export.s3.bucket.is_empty() || export.data_path.is_empty() {
+        bail!("the export has no bucket or data path, pull it again from newer firmware");
+    }
+    if !(1..=SIM_MAX_DEVICES).contains(&devices) || batches == 0 || rows == 0 {
+        bail!("simulate needs 1 to {} devices, and at least 1 batch and row", SIM_MAX_DEVICES);
+    }
+    let credentials = env_credentials();
+    if credentials.is_none() {
+        eprintln!("AWS_ACCESS_KEY_ID / AWS_SECRET_ACCESS_KEY not set, sending unsigned requests");
+    }
+    let bucket = s3_bucket(&export.s3.bucket, &export.s3)?;
+
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+    // Aligned on the sampling interval like a synchronized fleet
+    let start_ms = now_ms - now_ms.rem_euclid(SIM_INTERVAL_MS);
+    println!(
+        "Simulating {} devices x {} batches x {} rows into s3://{}/{}/{}/ ...",
+        devices, batches, rows, export.s3.bucket, export.data_path, export.table
+    );
+
+    let barrier = Barrier::new(devices);
+    let started = Instant::now();
+    let outcomes: Vec<SimOutcome> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..devices)
+            .map(|n| {
+                let (barrier, bucket) = (&barrier, &bucket);
+                let device_id = format!("{}-sim{:03}", export.device_id, n);
+                let credentials = credentials.as_ref();
+                scope.spawn(move || {
+                    let mut outcome = SimOutcome::default();
+                    // Encode first, so the uploads contend for real
+                    let files: Vec<(String, Result<Vec<u8>>)> = (0..batches)
+                        .map(|batch| {
+                            let first_ms = start_ms + (batch * rows) as i64 * SIM_INTERVAL_MS;
+                            let key = format!(
+                                "{}/{}/sensor_data_{}_{}.parquet",
+                                export.data_path, export.table, device_id, first_ms
+                            );
+                            let data = simulated_parquet(
+                                &device_id,
+                                &export.firmware_version,
+                                n,
+                                first_ms,
+                                rows,
+                            );
+                            (key, data)
+                        })
+                        .collect();
+                    barrier.wait();
+                    for (key, data) in files {
+                        let data = match data {
+                            Ok(data) => data,
+                            Err(e) => {
+                                outcome.failures.push(format!("{}: encoding: {:#}", key, e));
+                                continue;
+                            }
+                        };
+                        let upload_start = Instant::now();
+                        match put_new_object(bucket, credentials, &key, &data) {
+                            Ok(true) => {
+                                outcome.latencies.push(upload_start.elapsed());
+                                outcome.uploaded += 1;
+                                outcome.rows += rows;
+                                outcome.bytes += data.len();
+                            }
+                            Ok(false) => outcome.collisions.push(key),
+                            Err(e) => outcome.failures.push(format!("{}: {:#}", key, e)),
+                        }
+                    }
+                    outcome
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or_default())
+            .collect()
+    });
+    let elapsed = started.elapsed();
+
+    let uploaded: usize = outcomes.iter().map(|o| o.uploaded).sum();
+    let total_rows: usize = outcomes.iter().map(|o| o.rows).sum();
+    let bytes: usize = outcomes.iter().map(|o| o.bytes).sum();
+    let mut latencies: Vec<Duration> =
+        outcomes.iter().flat_map(|o| o.latencies.iter().copied()).collect();
+    latencies.sort();
+    for outcome in &outcomes {
+        for key in &outcome.collisions {
+            println!("  COLLISION  s3://{}/{} already existed", export.s3.bucket, key);
+        }
+        for failure in &outcome.failures {
+            println!("  FAILED     {}", failure);
+        }
+    }
+    let collisions: usize = outcomes.iter().map(|o| o.collisions.len()).sum();
+    let failures: usize = outcomes.iter().map(|o| o.failures.len()).sum();
+
+    println!();
+    println!(
+        "{} of {} files uploaded ({} rows, {:.1} KB) in {:.1}s, {:.1} files/s",
+        uploaded,
+        devices * batches,
+        total_rows,
+        bytes as f64 / 1024.0,
+        elapsed.as_secs_f64(),
+        uploaded as f64 / elapsed.as_secs_f64().max(0.001)
+    );
+    if let (Some(first), Some(last)) = (latencies.first(), latencies.last()) {
+        let p95 = latencies[(latencies.len() * 95 / 100).min(latencies.len() - 1)];
+        println!(
+            "Upload latency: min {} ms, median {} ms, p95 {} ms, max {} ms",
+            first.as_millis(),
+            latencies[latencies.len() / 2].as_millis(),
+            p95.as_millis(),
+            last.as_millis()
+        );
+    }
+    println!("{} key collisions, {} failed uploads", collisions, failures);
+    if uploaded > 0 {
+        println!(
+            "The simulated files are not in any catalog; delete them with \
+             `aws s3 rm s3://{}/{}/{}/ --recursive --exclude '*' --include '*-sim*'`",
+            export.s3.bucket, export.data_path, export.table
+        );
+    }
+    Ok(collisions == 0 && failures == 0)
+}
+
+/// `PUT` an object unless it exists; false if it did (HTTP 412)
+fn put_new_object(
+    bucket: &Bucket,
+    credentials: Option<&Credentials>,
+    key: &str,
+    data: &[u8],
+) -> Result<bool> {
+    let url = bucket.put_object(credentials, key).sign(PRESIGN_EXPIRY);
+    match agent().put(url.as_str()).set("If-None-Match", "*").send_bytes(data) {
+        Ok(_) => Ok(true),
+        Err(ureq::Error::Status(412, _)) => Ok(false),
+        Err(ureq::Error::Status(status, response)) => {
+            bail!("S3 answered {}: {}", status, response.into_string().unwrap_or_default())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A data file in the firmware's `sensor_data` schema, with readings that
+/// drift slowly per device
+fn simulated_parquet(
+    device_id: &str,
+    firmware_version: &str,
+    n: usize,
+    first_ms: i64,
+    rows: usize,
+) -> Result<Vec<u8>> {
+    // As in the firmware's `create_sensor_parquet`
+    let message_type = "
+        message sensor_data {
+            required int64 timestamp;
+            required float temperature;
+            required float humidity;
+            required float pressure;
+            required float pm1_0;
+            required float pm2_5;
+            required float pm10;
+            required float gas_resistance;
+            required float light;
+            required float noise;
+            required int32 sample_interval_ms;
+            required boolean warming_up;
+            required binary device_id (UTF8);
+            required binary firmware_version (UTF8);
+            required binary location (UTF8);
+            optional int64 ingested_at;
+            optional binary source (UTF8);
+            optional int64 pipeline_version;
+        }
+    ";
+    let schema = Arc::new(parse_message_type(message_type)?);
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_encoding(Encoding::PLAIN)
+        .build();
+    let mut buffer = Vec::new();
+    let mut writer = SerializedFileWriter::new(&mut buffer, schema, Arc::new(props))?;
+    let mut row_group = writer.next_row_group()?;
+
+    let timestamps: Vec<i64> = (0..rows).map(|i| first_ms + i as i64 * SIM_INTERVAL_MS).collect();
+    let wave = |i: usize, period: f32| ((i + n * 7) as f32 / period).sin();
+    let metrics: [Vec<f32>; 9] = [
+        (0..rows).map(|i| 21.0 + n as f32 % 5.0 + wave(i, 40.0)).collect(),
+        (0..rows).map(|i| 45.0 + 5.0 * wave(i, 60.0)).collect(),
+        (0..rows).map(|i| 1013.0 + wave(i, 200.0)).collect(),
+        (0..rows).map(|i| 5.0 + 2.0 * wave(i, 15.0).abs()).collect(),
+        (0..rows).map(|i| 8.0 + 3.0 * wave(i, 15.0).abs()).collect(),
+        (0..rows).map(|i| 12.0 + 4.0 * wave(i, 15.0).abs()).collect(),
+        (0..rows).map(|i| 50_000.0 + 5000.0 * wave(i, 90.0)).collect(),
+        (0..rows).map(|i| 300.0 + 100.0 * wave(i, 120.0)).collect(),
+        (0..rows).map(|i| 40.0 + 10.0 * wave(i, 10.0).abs()).collect(),
+    ];
+
+    let mut column = row_group.next_column()?.context("timestamp column")?;
+    column.typed::<Int64Type>().write_batch(&timestamps, None, None)?;
+    column.close()?;
+    for values in &metrics {
+        let mut column = row_group.next_column()?.context("metric column")?;
+        column.typed::<FloatType>().write_batch(values, None, None)?;
+        column.close()?;
+    }
+    let mut column = row_group.next_column()?.context("sample_interval_ms column")?;
+    column.typed::<Int32Type>().write_batch(&vec![SIM_INTERVAL_MS as i32; rows], None, None)?;
+    column.close()?;
+    let mut column = row_group.next_column()?.context("warming_up column")?;
+    column.typed::<BoolType>().write_batch(&vec![false; rows], None, None)?;
+    column.close()?;
+    for value in [device_id, firmware_version, "simulated"] {
+        let mut column = row_group.next_column()?.context("identity column")?;
+        let values = vec![ByteArray::from(value); rows];
+        column.typed::<ByteArrayType>().write_batch(&values, None, None)?;
+        column.close()?;
+    }
+    // Provenance, present on every row
+    let present = vec![1i16; rows];
+    let mut column = row_group.next_column()?.context("ingested_at column")?;
+    column.typed::<Int64Type>().write_batch(&timestamps, Some(&present), None)?;
+    column.close()?;
+    let mut column = row_group.next_column()?.context("source column")?;
+    let sources = vec![ByteArray::from("simulated"); rows];
+    column.typed::<ByteArrayType>().write_batch(&sources, Some(&present), None)?;
+    column.close()?;
+    let mut column = row_group.next_column()?.context("pipeline_version column")?;
+    column.typed::<Int64Type>().write_batch(&vec![0i64; rows], Some(&present), None)?;
+    column.close()?;
+
+    row_group.close()?;
+    writer.close()?;
+    Ok(buffer)
+}
+
+// ============================================================================
+// HELPERS
+// ============================================================================
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new().timeout(HTTP_TIMEOUT).build()
+}
+
+/// `request` with the `ctl_token` the device's control endpoints need
+fn authorized(request: ureq::Request) -> ureq::Request {
+    match std::env::var("LAKECTL_TOKEN") {
+        Ok(token) if !token.is_empty() => {
+            request.set("Authorization", &format!("Bearer {}", token))
+        }
+        _ => request,
+    }
+}
+
+fn get_json(url: &str) -> Result<Json> {
+    match authorized(agent().get(url)).call() {
+        Ok(response) => Ok(serde_json::from_reader(response.into_reader())?),
+        Err(ureq::Error::Status(404, _)) => {
+            bail!("{} not found, is the device built with `--features http`?", url)
+        }
+        Err(ureq::Error::Status(401 | 403, _)) => {
+            bail!("{} refused, set LAKECTL_TOKEN to the device's ctl_token", url)
+        }
+        Err(ureq::Error::Status(status, response)) => {
+            bail!("{} answered {}: {}", url, status, response.into_string().unwrap_or_default())
+        }
+        Err(e) => Err(anyhow!("{}: {}", url, e)),
+    }
+}
+
+/// Same addressing as the device's `profiles::s3_bucket`
+fn s3_bucket(name: &str, s3: &S3Settings) -> Result<Bucket> {
+    let endpoint = s3.endpoint.trim().trim_end_matches('/');
+    let url = match endpoint.split_once("://") {
+        _ if endpoint.is_empty() => format!("https://s3.{}.amazonaws.com", s3.region),
+        Some(_) => endpoint.to_string(),
+        None => format!("{}://{}", if s3.use_ssl { "https" } else { "http" }, endpoint),
+    };
+    let style = match s3.url_style.trim().to_ascii_lowercase().as_str() {
+        "path" => UrlStyle::Path,
+        _ => UrlStyle::VirtualHost,
+    };
+    Ok(Bucket::new(url.parse()?, style, name.to_string(), s3.region.clone())?)
+}
+
+fn env_credentials() -> Option<Credentials> {
+    let key = std::env::var("AWS_ACCESS_KEY_ID").ok()?;
+    let secret = std::env::var("AWS_SECRET_ACCESS_KEY").ok()?;
+    Some(match std::env::var("AWS_SESSION_TOKEN") {
+        Ok(token) => Credentials::new_with_token(key, secret, token),
+        Err(_) => Credentials::new(key, secret),
+    })
+}
+
+/// A positive number given to `flag`
+fn count(flag: &str, value: &str) -> Result<usize> {
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!("{} needs a positive number, got '{}'", flag, value),
+    }
+}
+
+/// `http://host[:port]` of `--device`, which may omit the scheme
+fn device_url(device: &str) -> String {
+    let device = device.trim_end_matches('/');
+    if device.contains("://") {
+        device.to_string()
+    } else {
+        format!("http://{}", device)
+    }
+}
+
+/// `YYYY-MM-DD HH:MM:SS` of Unix epoch milliseconds, in UTC
+fn utc(epoch_millis: i64) -> String {
+    let days = epoch_millis.div_euclid(86_400_000);
+    let secs = epoch_millis.rem_euclid(86_400_000) / 1000;
+    // Proleptic Gregorian date, as in the firmware's `clock.rs`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+// ============================================================================
+// ENTRY POINT
+// ============================================================================
+
+fn main() -> ExitCode {
+    match run(std::env::args().skip(1).collect()) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("lakectl: {:#}", e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Parse the arguments and run the command; false if verification failed
+fn run(args: Vec<String>) -> Result<bool> {
+    let mut device = None;
+    let mut from = None;
+    let mut table = None;
+    let mut out = None;
+    let mut devices = SIM_DEVICES;
+    let mut batches = SIM_BATCHES;
+    let mut rows = SIM_ROWS;
+    let mut command = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value\n\n{}", arg, USAGE));
+        match arg.as_str() {
+            "--device" => device = Some(device_url(&value()?)),
+            "--from" => from = Some(value()?),
+            "--table" => table = Some(value()?),
+            "--out" => out = Some(value()?),
+            "--devices" => devices = count(&arg, &value()?)?,
+            "--batches" => batches = count(&arg, &value()?)?,
+            "--rows" => rows = count(&arg, &value()?)?,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(true);
+            }
+            _ if command.is_none() && !arg.starts_with('-') => command = Some(arg.clone()),
+            _ => bail!("unexpected argument '{}'\n\n{}", arg, USAGE),
+        }
+    }
+    let Some(command) = command else {
+        bail!("no command given\n\n{}", USAGE);
+    };
+
+    if command == "compact" {
+        let Some(device) = device else {
+            bail!("compact needs --device");
+        };
+        compact(&device)?;
+        return Ok(true);
+    }
+
+    // Only needs the export, so it also works against backends without a local catalog
+    if command == "simulate" {
+        let export = match (&device, &from) {
+            (Some(device), None) => get_json(&format!("{}/export", device))?,
+            (None, Some(path)) => Lake::load(path)?.export,
+            _ => bail!("give either --device or --from\n\n{}", USAGE),
+        };
+        let export: Export =
+            serde_json::from_value(export).context("parsing the device export")?;
+        return simulate(&export, devices, batches, rows);
+    }
+
+    let lake = match (&device, &from) {
+        (Some(device), None) => Lake::fetch(device)?,
+        (None, Some(path)) => Lake::load(path)?,
+        _ => bail!("give either --device or --from\n\n{}", USAGE),
+    };
+    match command.as_str() {
+        "pull" => {
+            let Some(out) = out else {
+                bail!("pull needs --out");
+            };
+            pull(&lake, &out)?;
+            Ok(true)
+        }
+        "snapshots" => {
+            let (export, catalog) = lake.parse()?;
+            snapshots(&export, &catalog);
+            Ok(true)
+        }
+        "verify" => {
+            let (export, catalog) = lake.parse()?;
+            if export.lake_backend != "ducklake" {
+                eprintln!("Note: the device runs the '{}' backend", export.lake_backend);
+            }
+            verify(&export, &catalog, table.as_deref())
+        }
+        other => bail!("unknown command '{}'\n\n{}", other, USAGE),
+    }
+}
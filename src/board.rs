@@ -0,0 +1,166 @@
+//! Target boards: pin assignment, core affinity and defaults per chip
+//!
+//! One crate builds for both boards, selected with a Cargo feature that has to
+//! match the `--target` (see `.cargo/config.toml` for the `build-s3` /
+//! `build-c6` aliases):
+//!
+//! | Feature | Board | Target | Notes |
+//! | ------- | ----- | ------ | ----- |
+//! | `esp32s3` (default) | ESP32-S3 DevKitC | `xtensa-esp32s3-espidf` | dual core, octal PSRAM |
+//! | `esp32c6` | ESP32-C6 DevKitC | `riscv32imac-esp-espidf` | single core, WiFi 6, 802.15.4 |
+//!
+//! Chip-specific ESP-IDF settings (PSRAM, main task affinity, the 802.15.4
+//! radio) live in `sdkconfig.defaults.<chip>`, which ESP-IDF applies on top of
+//! `sdkconfig.defaults`.
+
+use esp_idf_svc::hal::cpu::Core;
+use esp_idf_svc::hal::gpio::Pins;
+
+#[cfg(all(feature = "esp32s3", feature = "esp32c6"))]
+compile_error!("features `esp32s3` and `esp32c6` select the target board, enable only one");
+#[cfg(not(any(feature = "esp32s3", feature = "esp32c6")))]
+compile_error!("select the target board with feature `esp32s3` or `esp32c6`");
+
+// ============================================================================
+// ESP32-S3
+// ============================================================================
+
+#[cfg(feature = "esp32s3")]
+mod pins {
+    use esp_idf_svc::hal::gpio::{
+        Gpio10, Gpio11, Gpio12, Gpio13, Gpio17, Gpio18, Gpio43, Gpio44, Gpio8, Gpio9,
+    };
+
+    pub type I2cSda = Gpio8;
+    pub type I2cScl = Gpio9;
+    pub type SensorTx = Gpio17;
+    pub type SensorRx = Gpio18;
+    pub type SdSclk = Gpio12;
+    pub type SdMosi = Gpio11;
+    pub type SdMiso = Gpio13;
+    pub type SdCs = Gpio10;
+    /// UART0 on the DevKitC's USB-UART bridge
+    pub type ConsoleTx = Gpio43;
+    pub type ConsoleRx = Gpio44;
+}
+
+#[cfg(feature = "esp32s3")]
+pub const CHIP: &str = "esp32s3";
+/// PSRAM holds a deep offline buffer (~300 KB of readings)
+#[cfg(feature = "esp32s3")]
+pub const MAX_BUFFERED_ROWS: usize = crate::ROWS_PER_FILE * 40;
+/// Buffered rows combined into one data file when a backlog is replayed
+#[cfg(feature = "esp32s3")]
+pub const BULK_INSERT_ROWS: usize = crate::ROWS_PER_FILE * 4;
+#[cfg(feature = "esp32s3")]
+pub const DEFAULT_MIN_FREE_HEAP: u32 = 64 * 1024;
+/// The sampler task preempts the rest of the pipeline on its own core
+#[cfg(feature = "esp32s3")]
+pub const SAMPLER_CORE: Option<Core> = Some(Core::Core1);
+
+// ============================================================================
+// ESP32-C6
+// ============================================================================
+
+#[cfg(feature = "esp32c6")]
+mod pins {
+    use esp_idf_svc::hal::gpio::{
+        Gpio16, Gpio17, Gpio18, Gpio19, Gpio2, Gpio20, Gpio21, Gpio3, Gpio6, Gpio7,
+    };
+
+    pub type I2cSda = Gpio6;
+    pub type I2cScl = Gpio7;
+    pub type SensorTx = Gpio2;
+    pub type SensorRx = Gpio3;
+    pub type SdSclk = Gpio21;
+    pub type SdMosi = Gpio19;
+    pub type SdMiso = Gpio20;
+    pub type SdCs = Gpio18;
+    /// UART0 on the DevKitC's USB-UART bridge
+    pub type ConsoleTx = Gpio16;
+    pub type ConsoleRx = Gpio17;
+}
+
+#[cfg(feature = "esp32c6")]
+pub const CHIP: &str = "esp32c6";
+/// No PSRAM: the offline buffer shares 512 KB of SRAM (~75 KB of readings)
+#[cfg(feature = "esp32c6")]
+pub const MAX_BUFFERED_ROWS: usize = crate::ROWS_PER_FILE * 10;
+#[cfg(feature = "esp32c6")]
+pub const BULK_INSERT_ROWS: usize = crate::ROWS_PER_FILE * 2;
+#[cfg(feature = "esp32c6")]
+pub const DEFAULT_MIN_FREE_HEAP: u32 = 48 * 1024;
+#[cfg(feature = "esp32c6")]
+pub const SAMPLER_CORE: Option<Core> = None;
+
+pub use pins::*;
+
+/// The pins the drivers use, split off the chip's `Pins`
+#[allow(dead_code)] // Only the pins of enabled features are used
+pub struct BoardPins {
+    pub sda: I2cSda,
+    pub scl: I2cScl,
+    pub sensor_tx: SensorTx,
+    pub sensor_rx: SensorRx,
+    pub sd_sclk: SdSclk,
+    pub sd_mosi: SdMosi,
+    pub sd_miso: SdMiso,
+    pub sd_cs: SdCs,
+    pub console_tx: ConsoleTx,
+    pub console_rx: ConsoleRx,
+}
+
+impl BoardPins {
+    #[cfg(feature = "esp32s3")]
+    pub fn take(pins: Pins) -> Self {
+        Self {
+            sda: pins.gpio8,
+            scl: pins.gpio9,
+            sensor_tx: pins.gpio17,
+            sensor_rx: pins.gpio18,
+            sd_sclk: pins.gpio12,
+            sd_mosi: pins.gpio11,
+            sd_miso: pins.gpio13,
+            sd_cs: pins.gpio10,
+            console_tx: pins.gpio43,
+            console_rx: pins.gpio44,
+        }
+    }
+
+    #[cfg(feature = "esp32c6")]
+    pub fn take(pins: Pins) -> Self {
+        Self {
+            sda: pins.gpio6,
+            scl: pins.gpio7,
+            sensor_tx: pins.gpio2,
+            sensor_rx: pins.gpio3,
+            sd_sclk: pins.gpio21,
+            sd_mosi: pins.gpio19,
+            sd_miso: pins.gpio20,
+            sd_cs: pins.gpio18,
+            console_tx: pins.gpio16,
+            console_rx: pins.gpio17,
+        }
+    }
+}
+
+/// Keep background threads (WiFi watch, MQTT, console, lake attach) off the
+/// ingestion loop's core
+///
+/// On the S3 the main task runs on core 1 (`sdkconfig.defaults.esp32s3`), so
+/// Parquet encoding doesn't compete with the WiFi stack; threads spawned from
+/// it afterwards go to core 0 with WiFi. The C6 has a single core.
+pub fn configure_threads() -> anyhow::Result<()> {
+    #[cfg(feature = "esp32s3")]
+    {
+        use esp_idf_svc::hal::task::thread::ThreadSpawnConfiguration;
+
+        ThreadSpawnConfiguration {
+            pin_to_core: Some(Core::Core0),
+            ..Default::default()
+        }
+        .set()?;
+        log::info!("Background threads pinned to core 0, ingestion on core 1");
+    }
+    Ok(())
+}
@@ -0,0 +1,160 @@
+//! One `device_boots` row per boot, describing what the unit runs
+//!
+//! The row lists what this build and boot can do: the sensor drivers that
+//! came up, the Cargo features compiled in, the lake backend and its
+//! catalog, plus why the chip reset. It is written with the first
+//! forwarding round after boot, so fleet operators can query exactly what
+//! every deployed unit is running. Its timestamp is the boot time, derived
+//! from the uptime once the wall clock is set.
+
+use log::{info, warn};
+
+use crate::clock::clock;
+use crate::config::DeviceConfig;
+use crate::lake::{Cell, Column, ColumnType, LakeBackend, RecordBatch, S3Target, SCHEMA_VERSION};
+use crate::power;
+
+const BOOTS_TABLE: &str = "device_boots";
+
+const BOOT_COLUMNS: [Column; 9] = [
+    Column {
+        name: "timestamp",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "reset_reason",
+        kind: ColumnType::Text,
+    },
+    Column {
+        name: "sensors",
+        kind: ColumnType::Text,
+    },
+    Column {
+        name: "features",
+        kind: ColumnType::Text,
+    },
+    Column {
+        name: "lake_backend",
+        kind: ColumnType::Text,
+    },
+    Column {
+        name: "catalog",
+        kind: ColumnType::Text,
+    },
+    Column {
+        name: "partition_by",
+        kind: ColumnType::Text,
+    },
+    Column {
+        name: "schema_version",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "sd_card",
+        kind: ColumnType::Boolean,
+    },
+];
+
+// Cargo features reported in `features`
+const FEATURES: [(&str, bool); 17] = [
+    ("esp32s3", cfg!(feature = "esp32s3")),
+    ("esp32c6", cfg!(feature = "esp32c6")),
+    ("bme280", cfg!(feature = "bme280")),
+    ("bme680", cfg!(feature = "bme680")),
+    ("pms5003", cfg!(feature = "pms5003")),
+    ("timer-sampling", cfg!(feature = "timer-sampling")),
+    ("sdcard", cfg!(feature = "sdcard")),
+    ("console", cfg!(feature = "console")),
+    ("http", cfg!(feature = "http")),
+    ("mqtt", cfg!(feature = "mqtt")),
+    ("fallback", cfg!(feature = "fallback")),
+    ("iceberg", cfg!(feature = "iceberg")),
+    ("parquet-fallback", cfg!(feature = "parquet-fallback")),
+    ("sts", cfg!(feature = "sts")),
+    ("gzip", cfg!(feature = "gzip")),
+    ("cbor", cfg!(feature = "cbor")),
+    ("ota", cfg!(feature = "ota")),
+];
+
+pub struct BootRecord {
+    reset_reason: &'static str,
+    sensors: String,
+    features: String,
+    lake_backend: &'static str,
+    catalog: &'static str,
+    partition_by: String,
+    sd_card: bool,
+    written: bool,
+}
+
+impl BootRecord {
+    /// Describe this boot; `sensors` are the names of the drivers that came up
+    pub fn new(
+        sensors: &[&str],
+        lake: &dyn LakeBackend,
+        config: &DeviceConfig,
+        sd_card: bool,
+    ) -> Self {
+        let features: Vec<&str> = FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name)
+            .collect();
+        let catalog = match lake.name() {
+            "ducklake" => "flash",
+            "iceberg" => "rest",
+            _ => "none",
+        };
+
+        Self {
+            reset_reason: power::reset_reason(),
+            sensors: sensors.join(","),
+            features: features.join(","),
+            lake_backend: lake.name(),
+            catalog,
+            partition_by: config.partition_by.clone(),
+            sd_card,
+            written: false,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "reset {}, sensors [{}], features [{}], {} backend ({} catalog)",
+            self.reset_reason, self.sensors, self.features, self.lake_backend, self.catalog
+        )
+    }
+
+    pub fn is_pending(&self) -> bool {
+        !self.written
+    }
+
+    /// Write the row to the `device_boots` table; retried next round on failure
+    pub fn write(&mut self, lake: &mut dyn LakeBackend, target: &S3Target) {
+        let booted_ms = clock().now_millis() - clock().monotonic().as_millis() as i64;
+        let batch = RecordBatch {
+            columns: &BOOT_COLUMNS,
+            rows: vec![vec![
+                Cell::Long(booted_ms),
+                Cell::Text(self.reset_reason.to_string()),
+                Cell::Text(self.sensors.clone()),
+                Cell::Text(self.features.clone()),
+                Cell::Text(self.lake_backend.to_string()),
+                Cell::Text(self.catalog.to_string()),
+                Cell::Text(self.partition_by.clone()),
+                Cell::Long(SCHEMA_VERSION.into()),
+                Cell::Boolean(self.sd_card),
+            ]],
+        };
+        let written = lake
+            .append_records(target, BOOTS_TABLE, &batch)
+            .and_then(|_| lake.commit());
+        match written {
+            Ok(()) => {
+                info!("  Wrote boot record");
+                self.written = true;
+            }
+            Err(e) => warn!("  Failed to write boot record: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Default spool format: a versioned CBOR sequence
+//!
+//! The file is the `DBSC` magic followed by CBOR items (RFC 8742): the spool
+//! version, then one `[index, table, [row, ...]]` array per batch. Rows are
+//! positional arrays, so they carry no column names:
+//!
+//! | Version | Row fields |
+//! | ------- | ---------- |
+//! | 1 | `timestamp, [9 metrics], sample_interval_ms, warming_up, ingested_at, pipeline_version` |
+//!
+//! A new column is appended to the row and bumps `SPOOL_VERSION`. Rows
+//! written by older firmware are shorter and their missing fields take the
+//! `SensorReading::empty` defaults; fields of a newer version are skipped.
+//! The source isn't kept: restored rows are replayed.
+
+use std::fmt;
+
+use anyhow::{anyhow, bail, Result};
+use log::warn;
+use serde::de::{self, IgnoredAny, SeqAccess, Visitor};
+use serde::ser::SerializeTuple;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{BufferedBatch, SpoolFormat};
+use crate::reading::{SensorReading, Source};
+
+const MAGIC: &[u8; 4] = b"DBSC";
+const SPOOL_VERSION: u32 = 1;
+const ROW_FIELDS: usize = 6;
+
+pub struct CborSpool;
+
+impl SpoolFormat for CborSpool {
+    fn name(&self) -> &'static str {
+        "CBOR"
+    }
+
+    fn detects(&self, data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    fn encode(&self, batches: &[&BufferedBatch], out: &mut Vec<u8>) -> Result<()> {
+        out.extend_from_slice(MAGIC);
+        ciborium::into_writer(&SPOOL_VERSION, &mut *out)?;
+        for batch in batches {
+            let rows: Vec<RowOut> = batch.readings.iter().map(RowOut).collect();
+            ciborium::into_writer(&(batch.index as u64, batch.table.as_str(), rows), &mut *out)?;
+        }
+        Ok(())
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<BufferedBatch>> {
+        let mut items = data
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| anyhow!("not a CBOR spool file"))?;
+        let version: u32 = ciborium::from_reader(&mut items)?;
+        if version == 0 {
+            bail!("invalid spool version 0");
+        }
+        if version > SPOOL_VERSION {
+            warn!(
+                "Spool version {} is newer than {}, dropping the columns it added",
+                version, SPOOL_VERSION
+            );
+        }
+
+        let mut batches = Vec::new();
+        while !items.is_empty() {
+            let (index, table, rows): (u64, String, Vec<RowIn>) =
+                ciborium::from_reader(&mut items)?;
+            batches.push(BufferedBatch {
+                index: index as usize,
+                table,
+                readings: rows.into_iter().map(|row| row.0).collect(),
+            });
+        }
+        Ok(batches)
+    }
+}
+
+struct RowOut<'a>(&'a SensorReading);
+
+impl Serialize for RowOut<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let r = self.0;
+        let mut row = serializer.serialize_tuple(ROW_FIELDS)?;
+        row.serialize_element(&r.timestamp)?;
+        row.serialize_element(&r.metrics())?;
+        row.serialize_element(&r.sample_interval_ms)?;
+        row.serialize_element(&r.warming_up)?;
+        row.serialize_element(&r.ingested_at)?;
+        row.serialize_element(&r.pipeline_version)?;
+        row.end()
+    }
+}
+
+struct RowIn(SensorReading);
+
+impl<'de> Deserialize<'de> for RowIn {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(RowVisitor)
+    }
+}
+
+struct RowVisitor;
+
+impl<'de> Visitor<'de> for RowVisitor {
+    type Value = RowIn;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a spool row array")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<RowIn, A::Error> {
+        let timestamp = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let mut reading = SensorReading::empty(timestamp);
+        reading.source = Source::Replay;
+
+        // Fields a shorter row lacks keep their defaults
+        if let Some(values) = seq.next_element::<Vec<f32>>()? {
+            let mut metrics = reading.metrics();
+            for (metric, value) in metrics.iter_mut().zip(values) {
+                *metric = value;
+            }
+            reading.set_metrics(metrics);
+        }
+        if let Some(interval) = seq.next_element()? {
+            reading.sample_interval_ms = interval;
+        }
+        if let Some(warming_up) = seq.next_element()? {
+            reading.warming_up = warming_up;
+        }
+        if let Some(ingested_at) = seq.next_element()? {
+            reading.ingested_at = ingested_at;
+        }
+        if let Some(version) = seq.next_element()? {
+            reading.pipeline_version = version;
+        }
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(RowIn(reading))
+    }
+}
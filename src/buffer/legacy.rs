@@ -0,0 +1,148 @@
+//! `DBF` spool files of earlier firmware
+//!
+//! A little-endian binary format: a format tag, then per batch the index,
+//! table name and fixed-size rows. Three generations are read: `DBF3`
+//! (61-byte rows), `DBF2` (49-byte rows, no provenance) and untagged files
+//! (48-byte rows, no warm-up flag either). Writing produces `DBF3`, for
+//! tooling that still expects it.
+
+use anyhow::{bail, Result};
+
+use super::{BufferedBatch, SpoolFormat};
+use crate::reading::{SensorReading, Source};
+
+// Timestamp, 9 metrics, sample interval, warm-up flag, ingested_at, pipeline version.
+// The source isn't kept: restored rows are replayed.
+const ROW_LEN: usize = 8 + 9 * 4 + 4 + 1 + 8 + 4;
+// Leads the file; untagged files start with the batch count instead
+const FORMAT_TAG: u32 = 0x3346_4244; // "DBF3"
+const FORMAT_TAG_V2: u32 = 0x3246_4244; // "DBF2", no provenance
+
+pub struct DbfSpool;
+
+impl SpoolFormat for DbfSpool {
+    fn name(&self) -> &'static str {
+        "DBF3"
+    }
+
+    /// Untagged files have no magic, so this accepts anything: try it last
+    fn detects(&self, _data: &[u8]) -> bool {
+        true
+    }
+
+    fn encode(&self, batches: &[&BufferedBatch], out: &mut Vec<u8>) -> Result<()> {
+        let rows: usize = batches.iter().map(|b| b.readings.len()).sum();
+        out.reserve(rows * ROW_LEN + 64);
+        out.extend_from_slice(&FORMAT_TAG.to_le_bytes());
+        out.extend_from_slice(&(batches.len() as u32).to_le_bytes());
+
+        for batch in batches {
+            out.extend_from_slice(&(batch.index as u64).to_le_bytes());
+            out.extend_from_slice(&(batch.table.len() as u16).to_le_bytes());
+            out.extend_from_slice(batch.table.as_bytes());
+            out.extend_from_slice(&(batch.readings.len() as u32).to_le_bytes());
+            for r in &batch.readings {
+                out.extend_from_slice(&r.timestamp.to_le_bytes());
+                for value in r.metrics() {
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+                out.extend_from_slice(&r.sample_interval_ms.to_le_bytes());
+                out.push(r.warming_up as u8);
+                out.extend_from_slice(&r.ingested_at.to_le_bytes());
+                out.extend_from_slice(&r.pipeline_version.to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<BufferedBatch>> {
+        let mut reader = Reader { data, pos: 0 };
+        let tag = reader.u32()?;
+        let (tagged, provenance) = match tag {
+            FORMAT_TAG => (true, true),
+            FORMAT_TAG_V2 => (true, false),
+            _ => (false, false),
+        };
+        if !tagged {
+            reader.pos = 0;
+        }
+        let count = reader.u32()?;
+        let mut batches = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let index = reader.u64()? as usize;
+            let table_len = reader.u16()? as usize;
+            let table = String::from_utf8(reader.take(table_len)?.to_vec())?;
+            let rows = reader.u32()? as usize;
+
+            let mut readings = Vec::with_capacity(rows);
+            for _ in 0..rows {
+                let mut reading = SensorReading::empty(reader.i64()?);
+                let mut metrics = [0f32; 9];
+                for m in metrics.iter_mut() {
+                    *m = reader.f32()?;
+                }
+                reading.set_metrics(metrics);
+                reading.sample_interval_ms = reader.u32()?;
+                if tagged {
+                    reading.warming_up = reader.take(1)?[0] != 0;
+                }
+                if provenance {
+                    reading.ingested_at = reader.i64()?;
+                    reading.pipeline_version = reader.u32()?;
+                }
+                reading.source = Source::Replay;
+                readings.push(reading);
+            }
+
+            batches.push(BufferedBatch {
+                index,
+                table,
+                readings,
+            });
+        }
+        Ok(batches)
+    }
+}
+
+/// Cursor over a persisted buffer file
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.pos + len > self.data.len() {
+            bail!("persisted buffer is truncated");
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut out = [0u8; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+}
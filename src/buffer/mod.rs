@@ -0,0 +1,486 @@
+//! Offline buffering with store-and-forward replay
+//!
+//! Every flushed batch is queued here and uploaded oldest-first whenever
+//! connectivity is available; batches that can't be uploaded (no WiFi,
+//! failed PUT) stay queued until it returns. The queue is bounded by
+//! total row count; when full, the oldest batches are evicted so a long
+//! outage can't exhaust the heap.
+//!
+//! With a spool directory (`open_spool`) every queued batch is also written
+//! to flash as a file of its own, deleted again once the batch is uploaded or
+//! evicted, so a crash or power loss loses nothing that was flushed. The
+//! spool is loaded again on the next boot. Batches the spool couldn't take
+//! are persisted to a single file before deep sleep (`persist`) and loaded
+//! again on wake (`restore`), as are the files of firmware without a spool.
+//!
+//! The on-flash encoding is a `SpoolFormat`: a versioned, compact CBOR
+//! sequence by default (see `cbor.rs`), while files of earlier firmware
+//! (`DBF`, see `legacy.rs`) are still restored. Devices with an existing
+//! spool format of their own plug it in with `with_format`. Restored rows
+//! are backfilled data and get `replay` as their source.
+
+mod cbor;
+mod legacy;
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+
+use crate::reading::SensorReading;
+
+pub use cbor::CborSpool;
+pub use legacy::DbfSpool;
+
+/// On-flash encoding of the queued batches
+pub trait SpoolFormat: Send {
+    fn name(&self) -> &'static str;
+
+    /// True if `data`, a whole persisted file, is in this format
+    fn detects(&self, data: &[u8]) -> bool;
+
+    fn encode(&self, batches: &[&BufferedBatch], out: &mut Vec<u8>) -> Result<()>;
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<BufferedBatch>>;
+}
+
+pub struct BufferedBatch {
+    pub index: usize,
+    pub table: String,
+    pub readings: Vec<SensorReading>,
+}
+
+pub struct OfflineBuffer {
+    batches: VecDeque<BufferedBatch>,
+    // Spool file of each batch, in step with `batches`; `None` if it has none
+    spooled: VecDeque<Option<PathBuf>>,
+    spool: Option<Spool>,
+    // The first one writes, restored files are detected in order
+    formats: Vec<Box<dyn SpoolFormat>>,
+    max_rows: usize,
+    buffered_rows: usize,
+    evicted_rows: usize,
+}
+
+impl OfflineBuffer {
+    pub fn new(max_rows: usize) -> Self {
+        Self {
+            batches: VecDeque::new(),
+            spooled: VecDeque::new(),
+            spool: None,
+            formats: vec![Box::new(CborSpool), Box::new(DbfSpool)],
+            max_rows,
+            buffered_rows: 0,
+            evicted_rows: 0,
+        }
+    }
+
+    /// Persist in `format`, which also gets the first try at restored files
+    pub fn with_format(mut self, format: Box<dyn SpoolFormat>) -> Self {
+        self.formats.insert(0, format);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.batches.len()
+    }
+
+    /// Write every batch queued from now on to a file of its own in `dir`,
+    /// queueing the batches spooled there before. Returns how many there were.
+    pub fn open_spool(&mut self, dir: &Path) -> Result<usize> {
+        fs::create_dir_all(dir)?;
+        let mut files: Vec<(u64, PathBuf)> = fs::read_dir(dir)?
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let seq = path.file_stem()?.to_str()?.parse().ok()?;
+                Some((seq, path))
+            })
+            .collect();
+        files.sort_unstable_by_key(|(seq, _)| *seq);
+        self.spool = Some(Spool {
+            dir: dir.to_path_buf(),
+            next_seq: files.last().map_or(0, |(seq, _)| seq + 1),
+        });
+
+        let mut count = 0;
+        for (_, path) in files {
+            match self.load(&path) {
+                Ok(mut batches) if batches.len() == 1 => {
+                    if let Some(batch) = batches.pop() {
+                        self.enqueue(batch, Some(path));
+                        count += 1;
+                    }
+                }
+                // Not written by the spool: queued (and spooled) afresh
+                Ok(batches) => {
+                    count += batches.len();
+                    for batch in batches {
+                        self.push(&batch.table, batch.index, batch.readings);
+                    }
+                    remove_spooled(&path);
+                }
+                Err(e) => {
+                    warn!("Spooled batch {} is unreadable, dropping it: {:?}", path.display(), e);
+                    remove_spooled(&path);
+                }
+            }
+        }
+        if count > 0 {
+            info!("Loaded {} spooled batches from {}", count, dir.display());
+        }
+        Ok(count)
+    }
+
+    /// Queue a batch for `table`, evicting the oldest batches if the row budget is exceeded
+    pub fn push(&mut self, table: &str, index: usize, readings: Vec<SensorReading>) {
+        let batch = BufferedBatch {
+            index,
+            table: table.to_string(),
+            readings,
+        };
+        let spooled = self.spool_batch(&batch);
+        self.enqueue(batch, spooled);
+        info!(
+            "  Buffered {} batch {} ({} batches / {} rows queued)",
+            table,
+            index,
+            self.batches.len(),
+            self.buffered_rows
+        );
+    }
+
+    /// True if some queued batches are only in RAM, for `persist`
+    pub fn has_unspooled(&self) -> bool {
+        self.spooled.iter().any(Option::is_none)
+    }
+
+    /// Write the queued batches the spool doesn't hold to `path`, replacing
+    /// any previous file
+    pub fn persist(&self, path: &Path) -> Result<()> {
+        let batches: Vec<&BufferedBatch> = self
+            .batches
+            .iter()
+            .zip(&self.spooled)
+            .filter(|(_, spooled)| spooled.is_none())
+            .map(|(batch, _)| batch)
+            .collect();
+        let rows: usize = batches.iter().map(|batch| batch.readings.len()).sum();
+        let format = &self.formats[0];
+        let mut out = Vec::new();
+        format.encode(&batches, &mut out)?;
+
+        fs::write(path, &out)?;
+        info!(
+            "Persisted {} buffered batches ({} rows, {} bytes {}) to {}",
+            batches.len(),
+            rows,
+            out.len(),
+            format.name(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Queue the batches persisted at `path` and delete the file.
+    ///
+    /// Returns the number of batches restored (0 if there is no file).
+    pub fn restore(&mut self, path: &Path) -> Result<usize> {
+        if !path.exists() {
+            return Ok(0);
+        }
+
+        let batches = self.load(path);
+        // Delete first: a corrupt file must not be retried on every boot
+        fs::remove_file(path)?;
+        let batches = batches?;
+        let count = batches.len();
+        for batch in batches {
+            self.push(&batch.table, batch.index, batch.readings);
+        }
+
+        info!("Restored {} buffered batches from {}", count, path.display());
+        Ok(count)
+    }
+
+    /// Replay queued batches oldest-first through `upload`.
+    ///
+    /// Consecutive batches of the same table go to `upload` together, up to
+    /// `max_rows` rows, so a backlog is written as a few larger files (a
+    /// batch over `max_rows` still goes alone). Stops at the first failure
+    /// so the remaining batches keep their order for the next attempt.
+    /// Returns the number of batches replayed.
+    pub fn replay<F>(&mut self, max_rows: usize, mut upload: F) -> usize
+    where
+        F: FnMut(&[BufferedBatch]) -> Result<()>,
+    {
+        let mut replayed = 0;
+
+        while !self.batches.is_empty() {
+            let batches = self.batches.make_contiguous();
+            let mut count = 1;
+            let mut rows = batches[0].readings.len();
+            for batch in &batches[1..] {
+                if batch.table != batches[0].table || rows + batch.readings.len() > max_rows {
+                    break;
+                }
+                rows += batch.readings.len();
+                count += 1;
+            }
+            if let Err(e) = upload(&batches[..count]) {
+                warn!(
+                    "Replay of batches {}..={} failed, will retry later: {:?}",
+                    batches[0].index,
+                    batches[count - 1].index,
+                    e
+                );
+                break;
+            }
+
+            for _ in 0..count {
+                if let Some(batch) = self.pop_front() {
+                    self.buffered_rows -= batch.readings.len();
+                }
+            }
+            replayed += count;
+        }
+
+        if replayed > 0 {
+            info!(
+                "Replayed {} buffered batches ({} still queued)",
+                replayed,
+                self.batches.len()
+            );
+        }
+
+        replayed
+    }
+
+    fn enqueue(&mut self, batch: BufferedBatch, spooled: Option<PathBuf>) {
+        while !self.batches.is_empty() && self.buffered_rows + batch.readings.len() > self.max_rows
+        {
+            if let Some(oldest) = self.pop_front() {
+                self.buffered_rows -= oldest.readings.len();
+                self.evicted_rows += oldest.readings.len();
+                warn!(
+                    "Offline buffer full, evicted batch {} ({} rows, {} evicted in total)",
+                    oldest.index,
+                    oldest.readings.len(),
+                    self.evicted_rows
+                );
+            }
+        }
+
+        self.buffered_rows += batch.readings.len();
+        self.batches.push_back(batch);
+        self.spooled.push_back(spooled);
+    }
+
+    /// The oldest batch, its spool file deleted
+    fn pop_front(&mut self) -> Option<BufferedBatch> {
+        if let Some(path) = self.spooled.pop_front().flatten() {
+            remove_spooled(&path);
+        }
+        self.batches.pop_front()
+    }
+
+    /// Write `batch` to a new spool file, `None` without a spool or if that fails
+    fn spool_batch(&mut self, batch: &BufferedBatch) -> Option<PathBuf> {
+        let spool = self.spool.as_mut()?;
+        let path = spool.dir.join(format!("{:010}.bin", spool.next_seq));
+        spool.next_seq += 1;
+        match write_batch(self.formats[0].as_ref(), batch, &path) {
+            Ok(()) => Some(path),
+            Err(e) => {
+                warn!("Failed to spool batch {}, keeping it in RAM: {:?}", batch.index, e);
+                None
+            }
+        }
+    }
+
+    /// The batches of a spool file, in whichever known format it is
+    fn load(&self, path: &Path) -> Result<Vec<BufferedBatch>> {
+        let data = fs::read(path)?;
+        let Some(format) = self.formats.iter().find(|f| f.detects(&data)) else {
+            bail!("persisted buffer is in no known spool format");
+        };
+        format.decode(&data)
+    }
+}
+
+struct Spool {
+    dir: PathBuf,
+    next_seq: u64,
+}
+
+fn write_batch(format: &dyn SpoolFormat, batch: &BufferedBatch, path: &Path) -> Result<()> {
+    let mut out = Vec::new();
+    format.encode(&[batch], &mut out)?;
+    fs::write(path, &out)?;
+    Ok(())
+}
+
+fn remove_spooled(path: &Path) {
+    // A leftover file is loaded again and uploaded twice, but nothing is lost
+    if let Err(e) = fs::remove_file(path) {
+        warn!("Failed to delete spooled batch {}: {:?}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::bail;
+
+    use super::*;
+    use crate::reading::Source;
+
+    /// A directory of its own for each test, empty
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("buffer-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn readings(first: i64, count: usize) -> Vec<SensorReading> {
+        (0..count)
+            .map(|i| {
+                let mut reading = SensorReading::empty(first + i as i64 * 5_000);
+                reading.temperature = 20.0 + i as f32;
+                reading.sample_interval_ms = 5_000;
+                reading.pipeline_version = 1;
+                reading
+            })
+            .collect()
+    }
+
+    /// Index, table and row timestamps of each queued batch
+    fn queued(buffer: &OfflineBuffer) -> Vec<(usize, String, Vec<i64>)> {
+        buffer
+            .batches
+            .iter()
+            .map(|batch| {
+                let timestamps = batch.readings.iter().map(|r| r.timestamp).collect();
+                (batch.index, batch.table.clone(), timestamps)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn spool_round_trip() -> Result<()> {
+        let dir = scratch("spool");
+        let mut buffer = OfflineBuffer::new(100);
+        assert_eq!(buffer.open_spool(&dir)?, 0);
+        buffer.push("esp32s3", 0, readings(1_000, 3));
+        buffer.push("esp32s3", 1, readings(20_000, 2));
+        buffer.push("lan", 2, readings(40_000, 1));
+        assert!(!buffer.has_unspooled());
+        assert_eq!(fs::read_dir(&dir)?.count(), 3);
+        let before = queued(&buffer);
+        drop(buffer);
+
+        // The next boot
+        let mut buffer = OfflineBuffer::new(100);
+        assert_eq!(buffer.open_spool(&dir)?, 3);
+        assert_eq!(queued(&buffer), before);
+        let first = &buffer.batches[0].readings[1];
+        assert_eq!(first.temperature, 21.0);
+        assert_eq!(first.sample_interval_ms, 5_000);
+        assert_eq!(first.pipeline_version, 1);
+        assert_eq!(first.source, Source::Replay);
+        assert!(first.humidity.is_nan());
+
+        // Uploaded batches leave the spool
+        assert_eq!(buffer.replay(usize::MAX, |_| Ok(())), 3);
+        assert_eq!(fs::read_dir(&dir)?.count(), 0);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn persist_and_restore() -> Result<()> {
+        let dir = scratch("persist");
+        fs::create_dir_all(&dir)?;
+        let cbor = dir.join("buffer.bin");
+        let dbf = dir.join("legacy.bin");
+
+        let mut buffer = OfflineBuffer::new(100);
+        buffer.push("esp32s3", 4, readings(1_000, 2));
+        buffer.push("esp32s3", 5, readings(9_000, 3));
+        assert!(buffer.has_unspooled());
+        buffer.persist(&cbor)?;
+        let before = queued(&buffer);
+
+        // Earlier firmware wrote DBF files, which are still restored
+        let mut legacy = OfflineBuffer::new(100).with_format(Box::new(DbfSpool));
+        legacy.push("esp32s3", 6, readings(30_000, 1));
+        legacy.persist(&dbf)?;
+
+        let mut restored = OfflineBuffer::new(100);
+        assert_eq!(restored.restore(&cbor)?, 2);
+        assert_eq!(restored.restore(&dbf)?, 1);
+        assert!(!cbor.exists() && !dbf.exists());
+        let mut expected = before;
+        expected.push((6, "esp32s3".to_string(), vec![30_000]));
+        assert_eq!(queued(&restored), expected);
+        assert_eq!(restored.restore(&cbor)?, 0);
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn replay_groups_runs_of_a_table() {
+        let mut buffer = OfflineBuffer::new(100);
+        buffer.push("esp32s3", 0, readings(0, 2));
+        buffer.push("esp32s3", 1, readings(0, 2));
+        buffer.push("esp32s3", 2, readings(0, 1));
+        buffer.push("lan", 3, readings(0, 2));
+        buffer.push("esp32s3", 4, readings(0, 6));
+        buffer.push("esp32s3", 5, readings(0, 1));
+
+        let mut groups = Vec::new();
+        let replayed = buffer.replay(4, |batches| {
+            groups.push(batches.iter().map(|b| b.index).collect::<Vec<_>>());
+            Ok(())
+        });
+        assert_eq!(replayed, 6);
+        // Up to 4 rows per group, and a batch over that goes alone
+        assert_eq!(groups, [vec![0, 1], vec![2], vec![3], vec![4], vec![5]]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn replay_stops_at_a_failure() {
+        let mut buffer = OfflineBuffer::new(100);
+        buffer.push("esp32s3", 0, readings(0, 2));
+        buffer.push("lan", 1, readings(0, 2));
+        buffer.push("esp32s3", 2, readings(0, 2));
+
+        let mut calls = 0;
+        let replayed = buffer.replay(10, |batches| {
+            calls += 1;
+            match batches[0].table.as_str() {
+                "lan" => bail!("503 Slow Down"),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!((replayed, calls), (1, 2));
+        let left: Vec<usize> = queued(&buffer).into_iter().map(|(index, ..)| index).collect();
+        assert_eq!(left, [1, 2]);
+    }
+
+    #[test]
+    fn evicts_the_oldest_batches() {
+        let mut buffer = OfflineBuffer::new(5);
+        buffer.push("esp32s3", 0, readings(0, 2));
+        buffer.push("esp32s3", 1, readings(0, 2));
+        buffer.push("esp32s3", 2, readings(0, 3));
+        let left: Vec<usize> = queued(&buffer).into_iter().map(|(index, ..)| index).collect();
+        assert_eq!(left, [1, 2]);
+        assert_eq!(buffer.evicted_rows, 2);
+    }
+}
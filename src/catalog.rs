@@ -0,0 +1,483 @@
+//! Lake catalog persisted on flash storage
+//!
+//! Every uploaded data file is recorded with its table, object key, row count
+//! and timestamp range. Files committed together share one snapshot ID, and
+//! snapshot IDs increase monotonically. Tables are registered before their
+//! first data file, with their partition fields (if any) and, for sensor
+//! tables, the schema version (see `lake/migrations.rs`). The
+//! catalog lives in `/storage/lake/` and survives reboots: on boot the
+//! existing catalog is re-attached instead of starting a new one.
+//!
+//! Files replaced by a merge are retired, not forgotten: snapshots older than
+//! the merge still reference them, so they may only be deleted from S3 once
+//! those snapshots have expired.
+//!
+//! Crash recovery: the catalog is written to `catalog.tmp` and synced, the
+//! previous version is kept as `catalog.bak`, and only then is the new one
+//! renamed into place. Every copy carries a CRC-32 of its contents and a
+//! generation counted up by every save; on boot the newest copy that verifies
+//! wins, so a power loss at any point leaves a usable catalog.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::clock;
+
+const CATALOG_DIR: &str = "lake";
+const CATALOG_FILE: &str = "catalog.json";
+const TEMP_FILE: &str = "catalog.tmp";
+const BACKUP_FILE: &str = "catalog.bak";
+#[cfg(feature = "http")]
+const EXPORT_FILE: &str = "catalog.export";
+
+// Oldest entries are dropped beyond this, bounding the file on a small partition
+const MAX_DATA_FILES: usize = 4096;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DataFile {
+    pub snapshot_id: u64,
+    pub table: String,
+    pub bucket: String,
+    pub key: String,
+    pub rows: usize,
+    pub bytes: usize,
+    pub min_timestamp: i64,
+    pub max_timestamp: i64,
+    /// Hive partition path below the table (`year=2026/month=03/`), empty if unpartitioned
+    #[serde(default)]
+    pub partition: String,
+    /// Rows of an auxiliary table rather than sensor readings
+    #[serde(default)]
+    pub records: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub snapshot_id: u64,
+    /// Unix epoch milliseconds
+    pub committed_ms: i64,
+}
+
+/// A data file replaced by a merge in `retired_in`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RetiredFile {
+    pub file: DataFile,
+    pub retired_in: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct CatalogState {
+    // Counted up by every save, so the newest copy wins even between commits
+    #[serde(default)]
+    generation: u64,
+    next_snapshot_id: u64,
+    #[serde(default)] // Catalogs written before tables were registered
+    tables: Vec<String>,
+    // Partition fields per table, absent = unpartitioned
+    #[serde(default)]
+    partitioned_by: BTreeMap<String, String>,
+    // Schema version per sensor table, absent = registered before versioning
+    #[serde(default)]
+    schema_versions: BTreeMap<String, u32>,
+    files: Vec<DataFile>,
+    // Catalogs written before maintenance start without history
+    #[serde(default)]
+    snapshots: Vec<Snapshot>,
+    #[serde(default)]
+    retired: Vec<RetiredFile>,
+    #[serde(default)]
+    maintained_ms: i64,
+}
+
+/// On-flash envelope: the state plus a checksum of its serialized form
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    checksum: u32,
+    catalog: CatalogState,
+}
+
+#[derive(Serialize)]
+struct EnvelopeRef<'a> {
+    checksum: u32,
+    catalog: &'a CatalogState,
+}
+
+pub struct Catalog {
+    dir: PathBuf,
+    state: CatalogState,
+}
+
+impl Catalog {
+    /// Attach the catalog under `root`, creating it if there is none
+    pub fn open(root: &str) -> Result<Self> {
+        let dir = Path::new(root).join(CATALOG_DIR);
+        fs::create_dir_all(&dir)?;
+
+        // On a tie (catalogs written before generations) the last one wins
+        let newest = [BACKUP_FILE, TEMP_FILE, CATALOG_FILE]
+            .iter()
+            .filter_map(|name| match load(&dir.join(name)) {
+                Ok(state) => state,
+                Err(e) => {
+                    warn!("Catalog copy '{}' is corrupt, ignoring: {:?}", name, e);
+                    None
+                }
+            })
+            .max_by_key(|state| (state.generation, state.next_snapshot_id));
+
+        let state = match newest {
+            Some(state) => {
+                info!(
+                    "Attached existing lake catalog ({} data files, next snapshot {})",
+                    state.files.len(),
+                    state.next_snapshot_id
+                );
+                state
+            }
+            None => {
+                info!("No lake catalog found, creating a new one");
+                CatalogState {
+                    next_snapshot_id: 1,
+                    ..CatalogState::default()
+                }
+            }
+        };
+
+        let mut catalog = Self { dir, state };
+        catalog.save()?;
+        Ok(catalog)
+    }
+
+    pub fn files(&self) -> &[DataFile] {
+        &self.state.files
+    }
+
+    pub fn tables(&self) -> &[String] {
+        &self.state.tables
+    }
+
+    /// Partition fields of `table`, empty if unpartitioned
+    pub fn partitioned_by(&self, table: &str) -> &str {
+        self.state.partitioned_by.get(table).map_or("", String::as_str)
+    }
+
+    /// Schema version of the sensor table `table`, if recorded
+    pub fn schema_version(&self, table: &str) -> Option<u32> {
+        self.state.schema_versions.get(table).copied()
+    }
+
+    /// Files replaced by merges, still referenced by unexpired snapshots
+    pub fn retired(&self) -> &[RetiredFile] {
+        &self.state.retired
+    }
+
+    /// When maintenance last ran, Unix epoch milliseconds (0 = never)
+    pub fn maintained_ms(&self) -> i64 {
+        self.state.maintained_ms
+    }
+
+    /// Register `table`, returning false if it already exists
+    pub fn create_table(&mut self, table: &str) -> Result<bool> {
+        if self.state.tables.iter().any(|t| t == table) {
+            return Ok(false);
+        }
+
+        self.state.tables.push(table.to_string());
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Record that `table` has been migrated to schema `version`
+    pub fn set_schema_version(&mut self, table: &str, version: u32) -> Result<()> {
+        self.state.schema_versions.insert(table.to_string(), version);
+        self.save()
+    }
+
+    /// Set the partition fields of `table` (`""` = unpartitioned) for files
+    /// written from now on, returning false if they are unchanged
+    pub fn set_partitioning(&mut self, table: &str, fields: &str) -> Result<bool> {
+        if self.partitioned_by(table) == fields {
+            return Ok(false);
+        }
+
+        if fields.is_empty() {
+            self.state.partitioned_by.remove(table);
+        } else {
+            self.state
+                .partitioned_by
+                .insert(table.to_string(), fields.to_string());
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Record uploaded data files as one new snapshot, returning its ID
+    pub fn commit(&mut self, files: Vec<DataFile>) -> Result<u64> {
+        let snapshot_id = self.new_snapshot();
+        self.state.files.extend(files.into_iter().map(|mut file| {
+            file.snapshot_id = snapshot_id;
+            file
+        }));
+
+        if self.state.files.len() > MAX_DATA_FILES {
+            let excess = self.state.files.len() - MAX_DATA_FILES;
+            self.state.files.drain(..excess);
+            warn!("Lake catalog full, dropped {} oldest entries", excess);
+        }
+
+        self.save()?;
+        Ok(snapshot_id)
+    }
+
+    /// Replace the data files with keys `replaced` by `merged`, in one new
+    /// snapshot. The replaced files are retired and `merged` takes the place
+    /// of the first of them, so files stay in timestamp order.
+    pub fn replace(&mut self, replaced: &[String], mut merged: DataFile) -> Result<u64> {
+        let snapshot_id = self.new_snapshot();
+        merged.snapshot_id = snapshot_id;
+
+        let position = self
+            .state
+            .files
+            .iter()
+            .position(|f| replaced.contains(&f.key))
+            .unwrap_or(self.state.files.len());
+        let (retired, kept): (Vec<DataFile>, Vec<DataFile>) = std::mem::take(&mut self.state.files)
+            .into_iter()
+            .partition(|f| replaced.contains(&f.key));
+        self.state.files = kept;
+        self.state.files.insert(position.min(self.state.files.len()), merged);
+        self.state.retired.extend(retired.into_iter().map(|file| RetiredFile {
+            file,
+            retired_in: snapshot_id,
+        }));
+
+        self.save()?;
+        Ok(snapshot_id)
+    }
+
+    /// Drop snapshots committed before `cutoff_ms`, always keeping the latest.
+    /// Returns the number of expired snapshots.
+    pub fn expire_snapshots(&mut self, cutoff_ms: i64) -> Result<usize> {
+        let keep_from = self
+            .state
+            .snapshots
+            .iter()
+            .position(|s| s.committed_ms >= cutoff_ms)
+            .unwrap_or(self.state.snapshots.len())
+            .min(self.state.snapshots.len().saturating_sub(1));
+        self.state.snapshots.drain(..keep_from);
+        self.save()?;
+        Ok(keep_from)
+    }
+
+    /// Retired files no remaining snapshot references, safe to delete from S3
+    pub fn unreferenced(&self) -> Vec<DataFile> {
+        let oldest = self
+            .state
+            .snapshots
+            .first()
+            .map_or(self.state.next_snapshot_id, |s| s.snapshot_id);
+        self.state
+            .retired
+            .iter()
+            .filter(|r| r.retired_in <= oldest)
+            .map(|r| r.file.clone())
+            .collect()
+    }
+
+    /// Forget retired files once they are deleted from S3
+    pub fn forget_retired(&mut self, keys: &[String]) -> Result<()> {
+        self.state.retired.retain(|r| !keys.contains(&r.file.key));
+        self.save()
+    }
+
+    /// Record that maintenance ran now
+    pub fn set_maintained(&mut self) -> Result<()> {
+        self.state.maintained_ms = clock().now_millis();
+        self.save()
+    }
+
+    fn new_snapshot(&mut self) -> u64 {
+        let snapshot_id = self.state.next_snapshot_id;
+        self.state.next_snapshot_id += 1;
+        self.state.snapshots.push(Snapshot {
+            snapshot_id,
+            committed_ms: clock().now_millis(),
+        });
+        // Without maintenance nothing expires snapshots
+        if self.state.snapshots.len() > MAX_DATA_FILES {
+            let excess = self.state.snapshots.len() - MAX_DATA_FILES;
+            self.state.snapshots.drain(..excess);
+        }
+        snapshot_id
+    }
+
+    /// Copy of the current catalog file that later commits leave alone,
+    /// for `GET /catalog`
+    #[cfg(feature = "http")]
+    pub fn export(&self) -> Result<PathBuf> {
+        let export = self.dir.join(EXPORT_FILE);
+        fs::copy(self.dir.join(CATALOG_FILE), &export)?;
+        Ok(export)
+    }
+
+    fn save(&mut self) -> Result<()> {
+        self.state.generation += 1;
+        let body = serde_json::to_vec(&self.state)?;
+        let envelope = serde_json::to_vec(&EnvelopeRef {
+            checksum: crc32(&body),
+            catalog: &self.state,
+        })?;
+
+        let (current, temp, backup) = (
+            self.dir.join(CATALOG_FILE),
+            self.dir.join(TEMP_FILE),
+            self.dir.join(BACKUP_FILE),
+        );
+
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(&envelope)?;
+        file.sync_all()?;
+        drop(file);
+
+        // FAT can't rename over an existing file, so rotate step by step
+        if current.exists() {
+            if backup.exists() {
+                fs::remove_file(&backup)?;
+            }
+            fs::rename(&current, &backup)?;
+        }
+        fs::rename(&temp, &current)?;
+
+        Ok(())
+    }
+}
+
+/// Load and verify one catalog copy; `Ok(None)` if it doesn't exist
+fn load(path: &Path) -> Result<Option<CatalogState>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let envelope: Envelope = serde_json::from_slice(&fs::read(path)?)?;
+    if crc32(&serde_json::to_vec(&envelope.catalog)?) != envelope.checksum {
+        bail!("checksum mismatch");
+    }
+
+    Ok(Some(envelope.catalog))
+}
+
+/// CRC-32 (IEEE), bitwise - the catalog is small and written rarely
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A storage root of its own for each test, empty
+    fn scratch(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("catalog-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        root
+    }
+
+    fn data_file(key: &str, rows: usize, min_timestamp: i64) -> DataFile {
+        DataFile {
+            snapshot_id: 0,
+            table: "esp32s3".to_string(),
+            bucket: "lake".to_string(),
+            key: key.to_string(),
+            rows,
+            bytes: rows * 40,
+            min_timestamp,
+            max_timestamp: min_timestamp + 60_000,
+            partition: String::new(),
+            records: false,
+        }
+    }
+
+    #[test]
+    fn reattaches_after_a_reboot() -> Result<()> {
+        let root = scratch("reattach");
+        let mut catalog = Catalog::open(root.to_str().unwrap_or_default())?;
+        assert!(catalog.create_table("esp32s3")?);
+        catalog.set_partitioning("esp32s3", "year,month")?;
+        assert_eq!(catalog.commit(vec![data_file("a.parquet", 10, 0)])?, 1);
+        assert_eq!(catalog.commit(vec![data_file("b.parquet", 20, 60_000)])?, 2);
+        drop(catalog);
+
+        let mut catalog = Catalog::open(root.to_str().unwrap_or_default())?;
+        assert!(!catalog.create_table("esp32s3")?);
+        assert_eq!(catalog.partitioned_by("esp32s3"), "year,month");
+        let keys: Vec<&str> = catalog.files().iter().map(|f| f.key.as_str()).collect();
+        assert_eq!(keys, ["a.parquet", "b.parquet"]);
+        assert_eq!(catalog.commit(Vec::new())?, 3);
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn a_corrupt_catalog_falls_back_to_the_backup() -> Result<()> {
+        let root = scratch("corrupt");
+        let mut catalog = Catalog::open(root.to_str().unwrap_or_default())?;
+        catalog.commit(vec![data_file("a.parquet", 10, 0)])?;
+        catalog.commit(vec![data_file("b.parquet", 20, 60_000)])?;
+        drop(catalog);
+
+        // Torn by a power loss: the previous save is the newest that verifies
+        let current = root.join(CATALOG_DIR).join(CATALOG_FILE);
+        let mut data = fs::read(&current)?;
+        data.truncate(data.len() / 2);
+        fs::write(&current, data)?;
+
+        let catalog = Catalog::open(root.to_str().unwrap_or_default())?;
+        let keys: Vec<&str> = catalog.files().iter().map(|f| f.key.as_str()).collect();
+        assert_eq!(keys, ["a.parquet"]);
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn retired_files_wait_for_their_snapshots() -> Result<()> {
+        let root = scratch("retire");
+        let mut catalog = Catalog::open(root.to_str().unwrap_or_default())?;
+        catalog.commit(vec![data_file("a.parquet", 10, 0)])?;
+        catalog.commit(vec![data_file("b.parquet", 20, 60_000)])?;
+        let replaced = ["a.parquet".to_string(), "b.parquet".to_string()];
+        let merged = catalog.replace(&replaced, data_file("ab.parquet", 30, 0))?;
+        assert_eq!(merged, 3);
+        assert_eq!(catalog.files().len(), 1);
+        assert_eq!(catalog.retired().len(), 2);
+
+        // Snapshots 1 and 2 still reference them
+        assert!(catalog.unreferenced().is_empty());
+        assert_eq!(catalog.expire_snapshots(i64::MAX)?, 2);
+        assert_eq!(catalog.unreferenced().len(), 2);
+        catalog.forget_retired(&replaced)?;
+        assert!(catalog.retired().is_empty());
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
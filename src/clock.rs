@@ -0,0 +1,234 @@
+//! Clock abstraction for timestamps, scheduling and backoff
+//!
+//! Everything that reads the time or waits goes through `clock()`. The
+//! firmware installs the SNTP-driven system time (`timesync::SntpClock`)
+//! first thing at boot; until then, and on the host, it is the plain system
+//! time. Tests use a `ManualClock` that fast-forwards deterministically
+//! instead of sleeping.
+
+#[cfg(test)]
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use log::warn;
+
+pub trait Clock: Send + Sync {
+    /// Wall-clock time in milliseconds since the Unix epoch
+    fn now_millis(&self) -> i64;
+
+    /// Monotonic time since an arbitrary start, for scheduling
+    fn monotonic(&self) -> Duration;
+
+    fn sleep(&self, duration: Duration);
+
+    /// Bring the wall clock in sync with the time source
+    fn synchronize(&self) -> Result<()>;
+
+    /// False while the wall clock can't be relied on, e.g. before the first sync
+    fn is_trusted(&self) -> bool {
+        true
+    }
+
+    fn elapsed_since(&self, start: Duration) -> Duration {
+        self.monotonic().saturating_sub(start)
+    }
+}
+
+static CLOCK: OnceLock<Box<dyn Clock>> = OnceLock::new();
+
+/// The installed clock, the system clock if none was
+pub fn clock() -> &'static dyn Clock {
+    CLOCK.get_or_init(|| Box::new(SystemClock::new())).as_ref()
+}
+
+/// Read the time from `clock` from now on; has to come before the first
+/// `clock()` call, which settles on the system clock otherwise
+pub fn install(clock: Box<dyn Clock>) {
+    if CLOCK.set(clock).is_err() {
+        warn!("A clock is already in use, keeping it");
+    }
+}
+
+// ============================================================================
+// SYSTEM CLOCK
+// ============================================================================
+
+/// The system time as it is, trusted as soon as it is read
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or(0)
+    }
+
+    fn monotonic(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+
+    fn synchronize(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// ============================================================================
+// MANUAL CLOCK (TESTS)
+// ============================================================================
+
+/// Clock that only moves when slept on or advanced
+#[cfg(test)]
+pub struct ManualClock {
+    epoch_millis: i64,
+    elapsed: Mutex<Duration>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new(epoch_millis: i64) -> Self {
+        Self {
+            epoch_millis,
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        if let Ok(mut elapsed) = self.elapsed.lock() {
+            *elapsed += duration;
+        }
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now_millis(&self) -> i64 {
+        self.epoch_millis + self.monotonic().as_millis() as i64
+    }
+
+    fn monotonic(&self) -> Duration {
+        self.elapsed.lock().map(|e| *e).unwrap_or_default()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+
+    fn synchronize(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// ============================================================================
+// CALENDAR (UTC, PROLEPTIC GREGORIAN)
+// ============================================================================
+
+/// `YYYY-MM-DD` of a Unix epoch millisecond timestamp
+pub fn utc_date(epoch_millis: i64) -> String {
+    let (year, month, day) = civil_from_days(epoch_millis.div_euclid(86_400_000));
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Unix epoch milliseconds of `YYYY-MM-DD[(T| )HH:MM[:SS[.fff]]][Z]`, in UTC
+pub fn parse_utc(value: &str) -> Option<i64> {
+    let (date, time) = match value.split_once(['T', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (value, None),
+    };
+    let mut date = date.split('-').map(|p| p.parse::<i64>());
+    let (y, m, d) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+
+    let (mut h, mut min, mut s) = (0, 0, 0.0);
+    if let Some(time) = time {
+        let mut time = time.trim_end_matches('Z').split(':');
+        h = time.next()?.parse().ok()?;
+        min = time.next()?.parse().ok()?;
+        s = time.next().unwrap_or("0").parse().ok()?;
+    }
+
+    let secs = days_from_civil(y, m, d) * 86400 + h * 3600 + min * 60;
+    Some(secs * 1000 + (s * 1000.0) as i64)
+}
+
+/// Proleptic Gregorian date for days since 1970-01-01
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (m + if m > 2 { -3 } else { 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2026-03-01T00:00:00Z
+    const MARCH_1: i64 = 1_772_323_200_000;
+
+    #[test]
+    fn manual_clock_moves_when_slept_on() {
+        let clock = ManualClock::new(MARCH_1);
+        assert_eq!(clock.now_millis(), MARCH_1);
+
+        let start = clock.monotonic();
+        clock.sleep(Duration::from_secs(90));
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(clock.elapsed_since(start), Duration::from_millis(90_500));
+        assert_eq!(clock.now_millis(), MARCH_1 + 90_500);
+        assert!(clock.synchronize().is_ok());
+    }
+
+    #[test]
+    fn calendar_round_trip() {
+        let clock = ManualClock::new(MARCH_1);
+        assert_eq!(utc_date(clock.now_millis()), "2026-03-01");
+        clock.sleep(Duration::from_secs(86_400 - 1));
+        assert_eq!(utc_date(clock.now_millis()), "2026-03-01");
+        clock.sleep(Duration::from_secs(1));
+        assert_eq!(utc_date(clock.now_millis()), "2026-03-02");
+
+        assert_eq!(parse_utc("2026-03-01"), Some(MARCH_1));
+        assert_eq!(parse_utc("2026-03-01T00:01:30.250Z"), Some(MARCH_1 + 90_250));
+        assert_eq!(parse_utc("2026-03-01 12:00"), Some(MARCH_1 + 43_200_000));
+        assert_eq!(parse_utc("yesterday"), None);
+        assert_eq!(utc_date(-1), "1969-12-31");
+    }
+}
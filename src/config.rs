@@ -0,0 +1,888 @@
+//! Runtime device configuration persisted in NVS
+//!
+//! Settings that used to be compile-time constants (WiFi, S3 bucket, table
+//! name) are loaded from the `device_cfg` NVS namespace at boot. On first boot
+//! the namespace is empty: if the compiled-in defaults have been filled in
+//! they are used and written back, otherwise the device has to be provisioned
+//! (see `provisioning.rs`). A device that has WiFi and a fleet server URL but
+//! no S3 credentials fetches the rest by enrolling (see `enrollment.rs`).
+//!
+//! The S3 access / secret key are part of `DeviceConfig` but are stored in the
+//! encrypted `SecretStore`, never in this namespace or in the firmware image.
+//!
+//! Every configuration carries a version and a content hash. An update from
+//! the fleet server (see `config_sync.rs`) is applied on trial: the previous
+//! configuration is kept, and restored if no batch is committed within
+//! `config_trial_mins` under the new one.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::clock;
+use crate::credentials::S3Credentials;
+use crate::secrets::SecretStore;
+
+const NAMESPACE: &str = "device_cfg";
+
+// NVS keys (max 15 characters)
+const KEY_WIFI_SSID: &str = "wifi_ssid";
+const KEY_WIFI_PASSWORD: &str = "wifi_pass";
+const KEY_WIFI_AUTH: &str = "wifi_auth";
+const KEY_WIFI_IDENTITY: &str = "wifi_eap_id";
+const KEY_WIFI_NETWORKS: &str = "wifi_nets";
+const KEY_WIFI_CA: &str = "wifi_ca";
+// Plaintext keys written by older firmware, migrated into the SecretStore
+const LEGACY_KEY_AWS_ACCESS_KEY: &str = "aws_ak";
+const LEGACY_KEY_AWS_SECRET_KEY: &str = "aws_sk";
+const KEY_S3_BUCKET: &str = "s3_bucket";
+const KEY_S3_REGION: &str = "s3_region";
+const KEY_S3_ENDPOINT: &str = "s3_url";
+const KEY_S3_URL_STYLE: &str = "s3_style";
+const KEY_S3_USE_SSL: &str = "s3_ssl";
+const KEY_S3_CA: &str = "s3_ca";
+const KEY_CREDENTIAL_SOURCE: &str = "cred_src";
+const KEY_STS_ROLE_ARN: &str = "sts_role";
+const KEY_CREDENTIAL_URL: &str = "cred_url";
+const KEY_CREDENTIAL_TTL: &str = "cred_ttl_s";
+const KEY_NTP_SERVERS: &str = "ntp_servers";
+const KEY_NTP_SYNC_MINS: &str = "ntp_sync_m";
+const KEY_TIME_TRUST_MINS: &str = "ntp_trust_m";
+const KEY_DATA_PATH: &str = "data_path";
+const KEY_TABLE_NAME: &str = "table";
+const KEY_DEVICE_ID: &str = "device_id";
+const KEY_ID_SOURCE: &str = "id_src";
+const KEY_SECRETS_SOURCE: &str = "sec_src";
+const KEY_LOCATION: &str = "location";
+const KEY_LAKE_BACKEND: &str = "lake";
+const KEY_PARTITION_BY: &str = "partition";
+const KEY_ATTACH_SECS: &str = "attach_s";
+const KEY_ICEBERG_URL: &str = "ice_url";
+const KEY_ICEBERG_WAREHOUSE: &str = "ice_wh";
+const KEY_ICEBERG_NAMESPACE: &str = "ice_ns";
+const KEY_DEADBANDS: &str = "deadbands";
+const KEY_HEARTBEAT_SECS: &str = "heartbeat_s";
+const KEY_WARM_UP: &str = "warmup";
+const KEY_ALERTS: &str = "alerts";
+const KEY_ALERT_URL: &str = "alert_url";
+const KEY_ALERT_ENCODING: &str = "alert_enc";
+const KEY_RAW_DAYS: &str = "raw_days";
+const KEY_ROLLUPS: &str = "rollups";
+const KEY_AGG_RESOLUTIONS: &str = "agg_res";
+const KEY_RAW_UPLOAD: &str = "raw_upload";
+const KEY_FLUSH_ROWS: &str = "flush_rows";
+const KEY_FLUSH_SECS: &str = "flush_secs";
+const KEY_MIN_FREE_HEAP: &str = "min_heap";
+const KEY_SLEEP_SECS: &str = "sleep_s";
+const KEY_BACKOFF_SECS: &str = "backoff_s";
+const KEY_BACKOFF_MAX_SECS: &str = "backoff_max_s";
+const KEY_OTA_URL: &str = "ota_url";
+const KEY_OTA_CHECK_MINS: &str = "ota_check_m";
+const KEY_S3_CHUNK_KB: &str = "s3_chunk_kb";
+const KEY_S3_PART_KB: &str = "s3_part_kb";
+const KEY_S3_MPU_KB: &str = "s3_mpu_kb";
+const KEY_LOG_FORMAT: &str = "log_fmt";
+const KEY_FLAGS_MINS: &str = "flags_m";
+const KEY_SLA_MINS: &str = "sla_m";
+const KEY_SLA_TARGET_MINS: &str = "sla_target_m";
+const KEY_SKETCHES: &str = "sketches";
+const KEY_REPORT: &str = "report";
+const KEY_REPROV_MINS: &str = "reprov_m";
+const KEY_FRESH_MINS: &str = "fresh_m";
+const KEY_NDJSON_FALLBACK: &str = "ndjson_fb";
+const KEY_HEALTH_TABLE: &str = "health_tbl";
+const KEY_ENROLL_URL: &str = "enroll_url";
+const KEY_CLAIM_CODE: &str = "claim_code";
+const KEY_CONFIG_URL: &str = "cfg_url";
+const KEY_MQTT_URL: &str = "mqtt_url";
+const KEY_MAINTENANCE_MINS: &str = "maint_m";
+const KEY_SNAPSHOT_RETENTION: &str = "snap_keep_h";
+const KEY_MQTT_TOPICS: &str = "mqtt_topics";
+const KEY_CONTROL_TOKEN: &str = "ctl_token";
+const KEY_PAUSE_MAX_MINS: &str = "pause_max_m";
+const KEY_CONFIG_TRIAL_MINS: &str = "cfg_trial_m";
+const KEY_TASK_KEY: &str = "task_key";
+const KEY_TASK_MINS: &str = "task_m";
+const KEY_VERSION: &str = "cfg_ver";
+// Trial state of a remote update
+const KEY_PREVIOUS: &str = "cfg_prev";
+const KEY_TRIAL_DEADLINE: &str = "cfg_deadline";
+const KEY_TRIAL_BOOTS: &str = "cfg_boots";
+
+// Boots (not deep sleep wakes) after which a trial counts as failed, e.g. a crash loop
+const MAX_TRIAL_BOOTS: u32 = 3;
+
+// ============================================================================
+// DEFAULTS - REPLACE THESE VALUES, OR LEAVE THEM AND PROVISION VIA SOFTAP
+// ============================================================================
+
+// Defaults still containing this marker are treated as "not configured"
+const PLACEHOLDER_PREFIX: &str = "YOUR_";
+
+// WiFi Configuration
+const DEFAULT_WIFI_SSID: &str = "YOUR_WIFI";
+const DEFAULT_WIFI_PASSWORD: &str = "YOUR_PASSWORD";
+// "open", "wpa2" (or better), "wpa3" or "enterprise" (EAP identity below), see `wifi.rs`
+const DEFAULT_WIFI_AUTH: &str = "wpa2";
+const DEFAULT_WIFI_IDENTITY: &str = "";
+// Fallback networks tried in order, e.g. [{"ssid": "lab", "pass": "...", "auth": "wpa3"}]
+const DEFAULT_WIFI_NETWORKS: &str = "";
+
+// AWS S3 Configuration (access / secret key are provisioned, never compiled in)
+const DEFAULT_S3_BUCKET: &str = "YOUR_BUCKET";
+const DEFAULT_S3_REGION: &str = "us-west-2";
+// S3-compatible store, e.g. "minio.lan:9000" (empty = AWS), "virtual" or "path" addressing
+const DEFAULT_S3_ENDPOINT: &str = "";
+const DEFAULT_S3_URL_STYLE: &str = "virtual";
+// Scheme of an endpoint given without one
+const DEFAULT_S3_USE_SSL: bool = true;
+// "static" (the provisioned keys), "sts" (AssumeRole with them) or "token" (fleet
+// token endpoint), see `sts.rs`; STS sessions last `cred_ttl_s`
+const DEFAULT_CREDENTIAL_SOURCE: &str = "static";
+const DEFAULT_STS_ROLE_ARN: &str = "";
+const DEFAULT_CREDENTIAL_URL: &str = "";
+const DEFAULT_CREDENTIAL_TTL_SECS: u32 = 3600;
+
+// Comma-separated SNTP servers, resynced every `ntp_sync_m` minutes. Time that
+// hasn't been synced for `ntp_trust_m` minutes (0 = never stale) isn't trusted
+const DEFAULT_NTP_SERVERS: &str = "pool.ntp.org";
+const DEFAULT_NTP_SYNC_MINS: u32 = 60;
+const DEFAULT_TIME_TRUST_MINS: u32 = 1440;
+
+// Object layout: s3://<bucket>/<data_path>/<table_name>/...
+const DEFAULT_DATA_PATH: &str = "opensensor-test";
+const DEFAULT_TABLE_NAME: &str = "esp32s3";
+// Written to every row; an empty ID means the station MAC address
+const DEFAULT_DEVICE_ID: &str = "";
+// Providers of the device ID and the secrets, see `identity.rs` and `secrets.rs`
+const DEFAULT_ID_SOURCE: &str = "nvs";
+const DEFAULT_SECRETS_SOURCE: &str = "nvs";
+const DEFAULT_LOCATION: &str = "";
+// "ducklake" (Parquet + lake catalog) or "parquet" (plain files)
+const DEFAULT_LAKE_BACKEND: &str = "ducklake";
+// Hive partition fields of data files, e.g. "year,month,day,device_id" (empty = flat)
+const DEFAULT_PARTITION_BY: &str = "";
+// Give up attaching the lake after this long (see `lake/attach.rs`)
+const DEFAULT_ATTACH_SECS: u32 = 60;
+
+// DuckLake file merging / snapshot expiry (0 = off) and how long snapshots are kept
+const DEFAULT_MAINTENANCE_MINS: u32 = 0;
+const DEFAULT_SNAPSHOT_RETENTION_HOURS: u32 = 168;
+
+// Iceberg REST catalog, for the "iceberg" backend
+const DEFAULT_ICEBERG_URL: &str = "";
+const DEFAULT_ICEBERG_WAREHOUSE: &str = "";
+const DEFAULT_ICEBERG_NAMESPACE: &str = "opensensor";
+
+// Change-of-value suppression, e.g. "temperature=0.2,humidity=1" (empty = record every reading)
+const DEFAULT_DEADBANDS: &str = "";
+// Record a row at least this often even when nothing leaves its deadband
+const DEFAULT_HEARTBEAT_SECS: u32 = 300;
+// Readings taken while a sensor warms up: "flag" or "suppress"
+const DEFAULT_WARM_UP: &str = "flag";
+// Threshold alert rules (none by default), their webhook and its encoding
+const DEFAULT_ALERTS: &str = "";
+const DEFAULT_ALERT_URL: &str = "";
+// `auto` negotiates with the webhook, JSON until it advertises more
+const DEFAULT_ALERT_ENCODING: &str = "auto";
+// Days raw readings are kept on the SD card instead of uploaded (0 = upload raw)
+const DEFAULT_RAW_DAYS: u32 = 0;
+// Aggregates uploaded while raw readings stay on the card
+const DEFAULT_ROLLUPS: &str = "1m,1h";
+// Min / avg / max rollups in `sensor_rollups` (none by default), and which raw rows still go up
+const DEFAULT_AGG_RESOLUTIONS: &str = "";
+const DEFAULT_RAW_UPLOAD: &str = "all";
+
+// Ingestion flush policy: whichever threshold is hit first
+const DEFAULT_FLUSH_ROWS: u32 = 178; // 15 minutes at 5s
+const DEFAULT_FLUSH_SECS: u32 = 900;
+const DEFAULT_MIN_FREE_HEAP: u32 = crate::board::DEFAULT_MIN_FREE_HEAP; // Per board
+
+// Deep sleep between upload windows (0 = stay awake)
+const DEFAULT_SLEEP_SECS: u32 = 0;
+// Cooldown after a forwarding round lost to an S3 outage, doubling up to the max
+const DEFAULT_BACKOFF_SECS: u32 = 30;
+const DEFAULT_BACKOFF_MAX_SECS: u32 = 1800;
+
+// HTTPS manifest of the firmware to run (`ota` feature), checked every few hours
+const DEFAULT_OTA_URL: &str = "";
+const DEFAULT_OTA_CHECK_MINS: u32 = 360;
+
+// S3 upload sizing, see `multipart.rs`: small writes, S3's minimum part size,
+// and multipart from the size maintenance no longer merges (merged files, too)
+const DEFAULT_S3_CHUNK_KB: u32 = 8;
+const DEFAULT_S3_PART_KB: u32 = 5 * 1024;
+const DEFAULT_S3_MPU_KB: u32 = 64;
+
+// Serial log lines: `text` (ESP-IDF style) or `json`, see `logging.rs`
+const DEFAULT_LOG_FORMAT: &str = "text";
+
+// Minutes between fetches of the `feature_flags` table, see `flags.rs`
+const DEFAULT_FLAGS_MINS: u32 = 15;
+
+// Hourly `sla_metrics` rows, rows count as delivered within an hour, see `sla.rs`
+const DEFAULT_SLA_MINS: u32 = 60;
+const DEFAULT_SLA_TARGET_MINS: u32 = 60;
+
+// Per-batch `batch_sketches` rows, see `sketches.rs`
+const DEFAULT_SKETCHES: bool = false;
+// Daily report to S3: `html`, `md` or off (empty), see `report.rs`
+const DEFAULT_REPORT: &str = "";
+
+// Re-enroll after a day of S3 rejecting the keys, see `reprovision.rs`
+const DEFAULT_REPROV_MINS: u32 = 24 * 60;
+
+// Freshness self-check off, see `freshness.rs`
+const DEFAULT_FRESH_MINS: u32 = 0;
+
+// Upload batches the lake rejects as gzip'd NDJSON (`fallback` feature)
+const DEFAULT_NDJSON_FALLBACK: bool = false;
+// A `device_health` row per flush
+const DEFAULT_HEALTH_TABLE: bool = true;
+
+// Fleet enrollment (empty URL = enrollment disabled, provision S3 via the portal)
+const DEFAULT_ENROLL_URL: &str = "";
+const DEFAULT_CLAIM_CODE: &str = "";
+
+// Remote config updates (empty URL = no updates) and how long one stays on trial
+const DEFAULT_CONFIG_URL: &str = "";
+const DEFAULT_CONFIG_TRIAL_MINS: u32 = 30;
+
+// Public key of signed maintenance SQL tasks (empty = no tasks), see `sql_tasks.rs`,
+// and the minutes between fetches of the tasks table
+const DEFAULT_TASK_KEY: &str = "";
+const DEFAULT_TASK_MINS: u32 = 60;
+
+// MQTT ingestion (`mqtt` feature, empty URL = disabled), e.g. "lan/+/air=lan_air,weather/#"
+const DEFAULT_MQTT_URL: &str = "";
+const DEFAULT_MQTT_TOPICS: &str = "";
+
+// Shared secret of pipeline pause / resume over HTTP and MQTT (empty = refused),
+// and how long a pause may last (0 = no limit), see `pause.rs`
+const DEFAULT_CONTROL_TOKEN: &str = "";
+const DEFAULT_PAUSE_MAX_MINS: u32 = 60;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceConfig {
+    /// Bumped by every remote update, 0 for a locally provisioned configuration
+    pub version: u32,
+    pub wifi_ssid: String,
+    pub wifi_password: String,
+    pub wifi_auth: String,
+    pub wifi_identity: String,
+    pub wifi_networks: String,
+    /// PEM CA certificate of the WPA2-Enterprise RADIUS server, kept like `s3_ca`
+    #[serde(skip)]
+    pub wifi_ca: String,
+    #[serde(skip)]
+    pub aws_access_key: String,
+    #[serde(skip)]
+    pub aws_secret_key: String,
+    pub s3_bucket: String,
+    pub s3_region: String,
+    pub s3_endpoint: String,
+    pub s3_url_style: String,
+    pub s3_use_ssl: bool,
+    /// PEM CA certificates of `s3_endpoint`, empty for the bundled roots.
+    /// Too large for remote updates and rollback copies, kept like the keys.
+    #[serde(skip)]
+    pub s3_ca: String,
+    pub credential_source: String,
+    pub sts_role_arn: String,
+    pub credential_url: String,
+    pub credential_ttl_secs: u32,
+    pub ntp_servers: String,
+    pub ntp_sync_mins: u32,
+    pub time_trust_mins: u32,
+    pub data_path: String,
+    pub table_name: String,
+    pub device_id: String,
+    pub id_source: String,
+    pub secrets_source: String,
+    pub location: String,
+    pub lake_backend: String,
+    pub partition_by: String,
+    pub attach_secs: u32,
+    pub maintenance_mins: u32,
+    pub snapshot_retention_hours: u32,
+    pub iceberg_url: String,
+    pub iceberg_warehouse: String,
+    pub iceberg_namespace: String,
+    pub deadbands: String,
+    pub heartbeat_secs: u32,
+    pub warm_up: String,
+    pub alerts: String,
+    pub alert_url: String,
+    pub alert_encoding: String,
+    pub raw_days: u32,
+    pub rollups: String,
+    pub agg_resolutions: String,
+    pub raw_upload: String,
+    pub flush_rows: u32,
+    pub flush_secs: u32,
+    pub min_free_heap: u32,
+    pub sleep_secs: u32,
+    pub backoff_secs: u32,
+    pub backoff_max_secs: u32,
+    pub ota_url: String,
+    pub ota_check_mins: u32,
+    pub s3_chunk_kb: u32,
+    pub s3_part_kb: u32,
+    pub s3_mpu_kb: u32,
+    pub log_format: String,
+    pub flags_mins: u32,
+    pub sla_mins: u32,
+    pub sla_target_mins: u32,
+    pub sketches: bool,
+    pub report: String,
+    pub reprov_mins: u32,
+    pub fresh_mins: u32,
+    pub ndjson_fallback: bool,
+    pub health_table: bool,
+    pub enroll_url: String,
+    pub claim_code: String,
+    pub config_url: String,
+    pub config_trial_mins: u32,
+    pub task_key: String,
+    pub task_mins: u32,
+    pub mqtt_url: String,
+    pub mqtt_topics: String,
+    pub control_token: String,
+    pub pause_max_mins: u32,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            version: 0,
+            wifi_ssid: DEFAULT_WIFI_SSID.to_string(),
+            wifi_password: DEFAULT_WIFI_PASSWORD.to_string(),
+            wifi_auth: DEFAULT_WIFI_AUTH.to_string(),
+            wifi_identity: DEFAULT_WIFI_IDENTITY.to_string(),
+            wifi_networks: DEFAULT_WIFI_NETWORKS.to_string(),
+            wifi_ca: String::new(),
+            aws_access_key: String::new(),
+            aws_secret_key: String::new(),
+            s3_bucket: DEFAULT_S3_BUCKET.to_string(),
+            s3_region: DEFAULT_S3_REGION.to_string(),
+            s3_endpoint: DEFAULT_S3_ENDPOINT.to_string(),
+            s3_url_style: DEFAULT_S3_URL_STYLE.to_string(),
+            s3_use_ssl: DEFAULT_S3_USE_SSL,
+            s3_ca: String::new(),
+            credential_source: DEFAULT_CREDENTIAL_SOURCE.to_string(),
+            sts_role_arn: DEFAULT_STS_ROLE_ARN.to_string(),
+            credential_url: DEFAULT_CREDENTIAL_URL.to_string(),
+            credential_ttl_secs: DEFAULT_CREDENTIAL_TTL_SECS,
+            ntp_servers: DEFAULT_NTP_SERVERS.to_string(),
+            ntp_sync_mins: DEFAULT_NTP_SYNC_MINS,
+            time_trust_mins: DEFAULT_TIME_TRUST_MINS,
+            data_path: DEFAULT_DATA_PATH.to_string(),
+            table_name: DEFAULT_TABLE_NAME.to_string(),
+            device_id: DEFAULT_DEVICE_ID.to_string(),
+            id_source: DEFAULT_ID_SOURCE.to_string(),
+            secrets_source: DEFAULT_SECRETS_SOURCE.to_string(),
+            location: DEFAULT_LOCATION.to_string(),
+            lake_backend: DEFAULT_LAKE_BACKEND.to_string(),
+            partition_by: DEFAULT_PARTITION_BY.to_string(),
+            attach_secs: DEFAULT_ATTACH_SECS,
+            maintenance_mins: DEFAULT_MAINTENANCE_MINS,
+            snapshot_retention_hours: DEFAULT_SNAPSHOT_RETENTION_HOURS,
+            iceberg_url: DEFAULT_ICEBERG_URL.to_string(),
+            iceberg_warehouse: DEFAULT_ICEBERG_WAREHOUSE.to_string(),
+            iceberg_namespace: DEFAULT_ICEBERG_NAMESPACE.to_string(),
+            deadbands: DEFAULT_DEADBANDS.to_string(),
+            heartbeat_secs: DEFAULT_HEARTBEAT_SECS,
+            warm_up: DEFAULT_WARM_UP.to_string(),
+            alerts: DEFAULT_ALERTS.to_string(),
+            alert_url: DEFAULT_ALERT_URL.to_string(),
+            alert_encoding: DEFAULT_ALERT_ENCODING.to_string(),
+            raw_days: DEFAULT_RAW_DAYS,
+            rollups: DEFAULT_ROLLUPS.to_string(),
+            agg_resolutions: DEFAULT_AGG_RESOLUTIONS.to_string(),
+            raw_upload: DEFAULT_RAW_UPLOAD.to_string(),
+            flush_rows: DEFAULT_FLUSH_ROWS,
+            flush_secs: DEFAULT_FLUSH_SECS,
+            min_free_heap: DEFAULT_MIN_FREE_HEAP,
+            sleep_secs: DEFAULT_SLEEP_SECS,
+            backoff_secs: DEFAULT_BACKOFF_SECS,
+            backoff_max_secs: DEFAULT_BACKOFF_MAX_SECS,
+            ota_url: DEFAULT_OTA_URL.to_string(),
+            ota_check_mins: DEFAULT_OTA_CHECK_MINS,
+            s3_chunk_kb: DEFAULT_S3_CHUNK_KB,
+            s3_part_kb: DEFAULT_S3_PART_KB,
+            s3_mpu_kb: DEFAULT_S3_MPU_KB,
+            log_format: DEFAULT_LOG_FORMAT.to_string(),
+            flags_mins: DEFAULT_FLAGS_MINS,
+            sla_mins: DEFAULT_SLA_MINS,
+            sla_target_mins: DEFAULT_SLA_TARGET_MINS,
+            sketches: DEFAULT_SKETCHES,
+            report: DEFAULT_REPORT.to_string(),
+            reprov_mins: DEFAULT_REPROV_MINS,
+            fresh_mins: DEFAULT_FRESH_MINS,
+            ndjson_fallback: DEFAULT_NDJSON_FALLBACK,
+            health_table: DEFAULT_HEALTH_TABLE,
+            enroll_url: DEFAULT_ENROLL_URL.to_string(),
+            claim_code: DEFAULT_CLAIM_CODE.to_string(),
+            config_url: DEFAULT_CONFIG_URL.to_string(),
+            config_trial_mins: DEFAULT_CONFIG_TRIAL_MINS,
+            task_key: DEFAULT_TASK_KEY.to_string(),
+            task_mins: DEFAULT_TASK_MINS,
+            mqtt_url: DEFAULT_MQTT_URL.to_string(),
+            mqtt_topics: DEFAULT_MQTT_TOPICS.to_string(),
+            control_token: DEFAULT_CONTROL_TOKEN.to_string(),
+            pause_max_mins: DEFAULT_PAUSE_MAX_MINS,
+        }
+    }
+}
+
+impl DeviceConfig {
+    /// Credentials from the configuration, used until a rotation has happened
+    pub fn credentials(&self) -> S3Credentials {
+        S3Credentials::new(&self.aws_access_key, &self.aws_secret_key)
+    }
+
+    /// True if WiFi or S3 settings still carry the `YOUR_...` placeholders.
+    /// The bucket doesn't count while it is still to be fetched by enrollment.
+    pub fn is_placeholder(&self) -> bool {
+        self.wifi_ssid.starts_with(PLACEHOLDER_PREFIX)
+            || (self.s3_bucket.starts_with(PLACEHOLDER_PREFIX) && !self.needs_enrollment())
+    }
+
+    /// True if the S3 access / secret key have been provisioned
+    pub fn has_credentials(&self) -> bool {
+        !self.aws_access_key.is_empty() && !self.aws_secret_key.is_empty()
+    }
+
+    /// True if S3 requests can be signed: with provisioned keys, or with
+    /// temporary ones from the token endpoint, which needs none
+    pub fn is_provisioned(&self) -> bool {
+        self.has_credentials() || self.credential_source == "token"
+    }
+
+    /// True if S3 settings have to be fetched from the fleet server first
+    pub fn needs_enrollment(&self) -> bool {
+        !self.is_provisioned() && !self.enroll_url.is_empty()
+    }
+
+    /// Object key prefix of the sensor table
+    pub fn table_path(&self) -> String {
+        self.path_for(&self.table_name)
+    }
+
+    /// Object key prefix of any table in the lake
+    pub fn path_for(&self, table: &str) -> String {
+        format!("{}/{}", self.data_path, table)
+    }
+
+    /// FNV-1a hash of the settings, as hex. Secrets and the version don't count.
+    pub fn hash(&self) -> String {
+        let content = DeviceConfig {
+            version: 0,
+            ..self.clone()
+        };
+        // Serializing a struct of strings and integers can't fail
+        let bytes = serde_json::to_vec(&content).unwrap_or_default();
+        let hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+            (hash ^ u64::from(*b)).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+        format!("{:016x}", hash)
+    }
+}
+
+/// A remote configuration update that hasn't committed a batch yet
+pub struct ConfigTrial {
+    pub version: u32,
+    deadline_ms: i64,
+    duration: Duration,
+    boots: u32,
+}
+
+impl ConfigTrial {
+    /// True once the trial has failed: past its deadline by wall clock or by
+    /// uptime (the clock may never sync under a broken config), or after
+    /// `MAX_TRIAL_BOOTS` reboots
+    pub fn expired(&self) -> bool {
+        clock().now_millis() >= self.deadline_ms
+            || clock().monotonic() >= self.duration
+            || self.boots > MAX_TRIAL_BOOTS
+    }
+}
+
+/// The `sec_src` provider, read before the `SecretStore` it selects is opened
+pub fn secrets_source(partition: EspDefaultNvsPartition) -> Result<String> {
+    let nvs: EspNvs<NvsDefault> = EspNvs::new(partition, NAMESPACE, true)?;
+    Ok(get_str(&nvs, KEY_SECRETS_SOURCE)?.unwrap_or_else(|| DEFAULT_SECRETS_SOURCE.to_string()))
+}
+
+/// A string of any length: the buffer is sized from the stored value (URLs,
+/// SEC1 keys and PEM certificates outgrow a fixed one)
+fn get_str(nvs: &EspNvs<NvsDefault>, key: &str) -> Result<Option<String>> {
+    let Some(len) = nvs.str_len(key)? else {
+        return Ok(None);
+    };
+    let mut buf = vec![0u8; len];
+    Ok(nvs.get_str(key, &mut buf)?.map(str::to_string))
+}
+
+pub struct ConfigStore {
+    nvs: EspNvs<NvsDefault>,
+    secrets: SecretStore,
+}
+
+impl ConfigStore {
+    pub fn new(partition: EspDefaultNvsPartition, secrets: SecretStore) -> Result<Self> {
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        Ok(Self { nvs, secrets })
+    }
+
+    /// Load the configuration.
+    ///
+    /// On first boot, compiled-in defaults are persisted and used if they have
+    /// been filled in; `None` means the device needs provisioning. S3
+    /// credentials always have to be provisioned or obtained by enrollment
+    /// (`DeviceConfig::needs_enrollment`), unless they come from a token
+    /// endpoint.
+    pub fn load(&mut self) -> Result<Option<DeviceConfig>> {
+        self.migrate_legacy_credentials()?;
+        let credentials = self
+            .secrets
+            .s3_credentials()?
+            .unwrap_or_else(|| S3Credentials::new("", ""));
+
+        if !self.nvs.contains(KEY_WIFI_SSID)? {
+            let config = DeviceConfig {
+                aws_access_key: credentials.access_key,
+                aws_secret_key: credentials.secret_key,
+                ..DeviceConfig::default()
+            };
+            let provisioned = config.is_provisioned() || config.needs_enrollment();
+            if config.is_placeholder() || !provisioned {
+                info!("No device configuration in NVS (first boot), provisioning required");
+                return Ok(None);
+            }
+
+            info!("No device configuration in NVS (first boot), writing defaults");
+            self.save(&config)?;
+            return Ok(Some(config));
+        }
+
+        // Missing individual keys (e.g. added by a newer firmware) take their default
+        let defaults = DeviceConfig::default();
+        let config = DeviceConfig {
+            wifi_ssid: self.get_or(KEY_WIFI_SSID, defaults.wifi_ssid),
+            wifi_password: self.get_or(KEY_WIFI_PASSWORD, defaults.wifi_password),
+            wifi_auth: self.get_or(KEY_WIFI_AUTH, defaults.wifi_auth),
+            wifi_identity: self.get_or(KEY_WIFI_IDENTITY, defaults.wifi_identity),
+            wifi_networks: self.get_or(KEY_WIFI_NETWORKS, defaults.wifi_networks),
+            wifi_ca: self.get_or(KEY_WIFI_CA, String::new()),
+            aws_access_key: credentials.access_key,
+            aws_secret_key: credentials.secret_key,
+            s3_bucket: self.get_or(KEY_S3_BUCKET, defaults.s3_bucket),
+            s3_region: self.get_or(KEY_S3_REGION, defaults.s3_region),
+            s3_endpoint: self.get_or(KEY_S3_ENDPOINT, defaults.s3_endpoint),
+            s3_url_style: self.get_or(KEY_S3_URL_STYLE, defaults.s3_url_style),
+            s3_use_ssl: self.get_u32_or(KEY_S3_USE_SSL, defaults.s3_use_ssl.into())? != 0,
+            s3_ca: self.get_or(KEY_S3_CA, String::new()),
+            credential_source: self.get_or(KEY_CREDENTIAL_SOURCE, defaults.credential_source),
+            sts_role_arn: self.get_or(KEY_STS_ROLE_ARN, defaults.sts_role_arn),
+            credential_url: self.get_or(KEY_CREDENTIAL_URL, defaults.credential_url),
+            credential_ttl_secs: self
+                .get_u32_or(KEY_CREDENTIAL_TTL, defaults.credential_ttl_secs)?,
+            ntp_servers: self.get_or(KEY_NTP_SERVERS, defaults.ntp_servers),
+            ntp_sync_mins: self.get_u32_or(KEY_NTP_SYNC_MINS, defaults.ntp_sync_mins)?,
+            time_trust_mins: self.get_u32_or(KEY_TIME_TRUST_MINS, defaults.time_trust_mins)?,
+            data_path: self.get_or(KEY_DATA_PATH, defaults.data_path),
+            table_name: self.get_or(KEY_TABLE_NAME, defaults.table_name),
+            device_id: self.get_or(KEY_DEVICE_ID, defaults.device_id),
+            id_source: self.get_or(KEY_ID_SOURCE, defaults.id_source),
+            secrets_source: self.get_or(KEY_SECRETS_SOURCE, defaults.secrets_source),
+            location: self.get_or(KEY_LOCATION, defaults.location),
+            lake_backend: self.get_or(KEY_LAKE_BACKEND, defaults.lake_backend),
+            partition_by: self.get_or(KEY_PARTITION_BY, defaults.partition_by),
+            attach_secs: self.get_u32_or(KEY_ATTACH_SECS, defaults.attach_secs)?,
+            maintenance_mins: self.get_u32_or(KEY_MAINTENANCE_MINS, defaults.maintenance_mins)?,
+            snapshot_retention_hours: self
+                .get_u32_or(KEY_SNAPSHOT_RETENTION, defaults.snapshot_retention_hours)?,
+            iceberg_url: self.get_or(KEY_ICEBERG_URL, defaults.iceberg_url),
+            iceberg_warehouse: self.get_or(KEY_ICEBERG_WAREHOUSE, defaults.iceberg_warehouse),
+            iceberg_namespace: self.get_or(KEY_ICEBERG_NAMESPACE, defaults.iceberg_namespace),
+            deadbands: self.get_or(KEY_DEADBANDS, defaults.deadbands),
+            heartbeat_secs: self.get_u32_or(KEY_HEARTBEAT_SECS, defaults.heartbeat_secs)?,
+            warm_up: self.get_or(KEY_WARM_UP, defaults.warm_up),
+            alerts: self.get_or(KEY_ALERTS, defaults.alerts),
+            alert_url: self.get_or(KEY_ALERT_URL, defaults.alert_url),
+            alert_encoding: self.get_or(KEY_ALERT_ENCODING, defaults.alert_encoding),
+            raw_days: self.get_u32_or(KEY_RAW_DAYS, defaults.raw_days)?,
+            rollups: self.get_or(KEY_ROLLUPS, defaults.rollups),
+            agg_resolutions: self.get_or(KEY_AGG_RESOLUTIONS, defaults.agg_resolutions),
+            raw_upload: self.get_or(KEY_RAW_UPLOAD, defaults.raw_upload),
+            flush_rows: self.get_u32_or(KEY_FLUSH_ROWS, defaults.flush_rows)?,
+            flush_secs: self.get_u32_or(KEY_FLUSH_SECS, defaults.flush_secs)?,
+            min_free_heap: self.get_u32_or(KEY_MIN_FREE_HEAP, defaults.min_free_heap)?,
+            sleep_secs: self.get_u32_or(KEY_SLEEP_SECS, defaults.sleep_secs)?,
+            backoff_secs: self.get_u32_or(KEY_BACKOFF_SECS, defaults.backoff_secs)?,
+            backoff_max_secs: self.get_u32_or(KEY_BACKOFF_MAX_SECS, defaults.backoff_max_secs)?,
+            ota_url: self.get_or(KEY_OTA_URL, defaults.ota_url),
+            ota_check_mins: self.get_u32_or(KEY_OTA_CHECK_MINS, defaults.ota_check_mins)?,
+            s3_chunk_kb: self.get_u32_or(KEY_S3_CHUNK_KB, defaults.s3_chunk_kb)?,
+            s3_part_kb: self.get_u32_or(KEY_S3_PART_KB, defaults.s3_part_kb)?,
+            s3_mpu_kb: self.get_u32_or(KEY_S3_MPU_KB, defaults.s3_mpu_kb)?,
+            log_format: self.get_or(KEY_LOG_FORMAT, defaults.log_format),
+            flags_mins: self.get_u32_or(KEY_FLAGS_MINS, defaults.flags_mins)?,
+            sla_mins: self.get_u32_or(KEY_SLA_MINS, defaults.sla_mins)?,
+            sla_target_mins: self.get_u32_or(KEY_SLA_TARGET_MINS, defaults.sla_target_mins)?,
+            sketches: self.get_u32_or(KEY_SKETCHES, defaults.sketches.into())? != 0,
+            report: self.get_or(KEY_REPORT, defaults.report),
+            reprov_mins: self.get_u32_or(KEY_REPROV_MINS, defaults.reprov_mins)?,
+            fresh_mins: self.get_u32_or(KEY_FRESH_MINS, defaults.fresh_mins)?,
+            ndjson_fallback: self
+                .get_u32_or(KEY_NDJSON_FALLBACK, defaults.ndjson_fallback.into())?
+                != 0,
+            health_table: self.get_u32_or(KEY_HEALTH_TABLE, defaults.health_table.into())? != 0,
+            enroll_url: self.get_or(KEY_ENROLL_URL, defaults.enroll_url),
+            claim_code: self.get_or(KEY_CLAIM_CODE, defaults.claim_code),
+            config_url: self.get_or(KEY_CONFIG_URL, defaults.config_url),
+            config_trial_mins: self
+                .get_u32_or(KEY_CONFIG_TRIAL_MINS, defaults.config_trial_mins)?,
+            task_key: self.get_or(KEY_TASK_KEY, defaults.task_key),
+            task_mins: self.get_u32_or(KEY_TASK_MINS, defaults.task_mins)?,
+            mqtt_url: self.get_or(KEY_MQTT_URL, defaults.mqtt_url),
+            mqtt_topics: self.get_or(KEY_MQTT_TOPICS, defaults.mqtt_topics),
+            control_token: self.get_or(KEY_CONTROL_TOKEN, defaults.control_token),
+            pause_max_mins: self.get_u32_or(KEY_PAUSE_MAX_MINS, defaults.pause_max_mins)?,
+            version: self.get_u32_or(KEY_VERSION, defaults.version)?,
+        };
+
+        if !config.is_provisioned() && !config.needs_enrollment() {
+            info!("No S3 credentials in encrypted storage, provisioning required");
+            return Ok(None);
+        }
+
+        info!(
+            "Device configuration v{} ({}) loaded from NVS (WiFi '{}', s3://{}/{})",
+            config.version,
+            config.hash(),
+            config.wifi_ssid,
+            config.s3_bucket,
+            config.table_path()
+        );
+        Ok(Some(config))
+    }
+
+    pub fn save(&mut self, config: &DeviceConfig) -> Result<()> {
+        if config.has_credentials() {
+            self.secrets.set_s3_credentials(&config.credentials())?;
+        }
+        self.nvs.set_str(KEY_WIFI_PASSWORD, &config.wifi_password)?;
+        self.nvs.set_str(KEY_WIFI_AUTH, &config.wifi_auth)?;
+        self.nvs.set_str(KEY_WIFI_IDENTITY, &config.wifi_identity)?;
+        self.nvs.set_str(KEY_WIFI_NETWORKS, &config.wifi_networks)?;
+        self.nvs.set_str(KEY_WIFI_CA, &config.wifi_ca)?;
+        self.nvs.set_str(KEY_S3_BUCKET, &config.s3_bucket)?;
+        self.nvs.set_str(KEY_S3_REGION, &config.s3_region)?;
+        self.nvs.set_str(KEY_S3_ENDPOINT, &config.s3_endpoint)?;
+        self.nvs.set_str(KEY_S3_URL_STYLE, &config.s3_url_style)?;
+        self.nvs.set_u32(KEY_S3_USE_SSL, config.s3_use_ssl.into())?;
+        self.nvs.set_str(KEY_S3_CA, &config.s3_ca)?;
+        self.nvs.set_str(KEY_CREDENTIAL_SOURCE, &config.credential_source)?;
+        self.nvs.set_str(KEY_STS_ROLE_ARN, &config.sts_role_arn)?;
+        self.nvs.set_str(KEY_CREDENTIAL_URL, &config.credential_url)?;
+        self.nvs.set_u32(KEY_CREDENTIAL_TTL, config.credential_ttl_secs)?;
+        self.nvs.set_str(KEY_NTP_SERVERS, &config.ntp_servers)?;
+        self.nvs.set_u32(KEY_NTP_SYNC_MINS, config.ntp_sync_mins)?;
+        self.nvs.set_u32(KEY_TIME_TRUST_MINS, config.time_trust_mins)?;
+        self.nvs.set_str(KEY_DATA_PATH, &config.data_path)?;
+        self.nvs.set_str(KEY_TABLE_NAME, &config.table_name)?;
+        self.nvs.set_str(KEY_DEVICE_ID, &config.device_id)?;
+        self.nvs.set_str(KEY_ID_SOURCE, &config.id_source)?;
+        self.nvs.set_str(KEY_SECRETS_SOURCE, &config.secrets_source)?;
+        self.nvs.set_str(KEY_LOCATION, &config.location)?;
+        self.nvs.set_str(KEY_LAKE_BACKEND, &config.lake_backend)?;
+        self.nvs.set_str(KEY_PARTITION_BY, &config.partition_by)?;
+        self.nvs.set_u32(KEY_ATTACH_SECS, config.attach_secs)?;
+        self.nvs.set_u32(KEY_MAINTENANCE_MINS, config.maintenance_mins)?;
+        self.nvs.set_u32(KEY_SNAPSHOT_RETENTION, config.snapshot_retention_hours)?;
+        self.nvs.set_str(KEY_ICEBERG_URL, &config.iceberg_url)?;
+        self.nvs.set_str(KEY_ICEBERG_WAREHOUSE, &config.iceberg_warehouse)?;
+        self.nvs.set_str(KEY_ICEBERG_NAMESPACE, &config.iceberg_namespace)?;
+        self.nvs.set_str(KEY_DEADBANDS, &config.deadbands)?;
+        self.nvs.set_u32(KEY_HEARTBEAT_SECS, config.heartbeat_secs)?;
+        self.nvs.set_str(KEY_WARM_UP, &config.warm_up)?;
+        self.nvs.set_str(KEY_ALERTS, &config.alerts)?;
+        self.nvs.set_str(KEY_ALERT_URL, &config.alert_url)?;
+        self.nvs.set_str(KEY_ALERT_ENCODING, &config.alert_encoding)?;
+        self.nvs.set_u32(KEY_RAW_DAYS, config.raw_days)?;
+        self.nvs.set_str(KEY_ROLLUPS, &config.rollups)?;
+        self.nvs.set_str(KEY_AGG_RESOLUTIONS, &config.agg_resolutions)?;
+        self.nvs.set_str(KEY_RAW_UPLOAD, &config.raw_upload)?;
+        self.nvs.set_u32(KEY_FLUSH_ROWS, config.flush_rows)?;
+        self.nvs.set_u32(KEY_FLUSH_SECS, config.flush_secs)?;
+        self.nvs.set_u32(KEY_MIN_FREE_HEAP, config.min_free_heap)?;
+        self.nvs.set_u32(KEY_SLEEP_SECS, config.sleep_secs)?;
+        self.nvs.set_u32(KEY_BACKOFF_SECS, config.backoff_secs)?;
+        self.nvs.set_u32(KEY_BACKOFF_MAX_SECS, config.backoff_max_secs)?;
+        self.nvs.set_str(KEY_OTA_URL, &config.ota_url)?;
+        self.nvs.set_u32(KEY_OTA_CHECK_MINS, config.ota_check_mins)?;
+        self.nvs.set_u32(KEY_S3_CHUNK_KB, config.s3_chunk_kb)?;
+        self.nvs.set_u32(KEY_S3_PART_KB, config.s3_part_kb)?;
+        self.nvs.set_u32(KEY_S3_MPU_KB, config.s3_mpu_kb)?;
+        self.nvs.set_str(KEY_LOG_FORMAT, &config.log_format)?;
+        self.nvs.set_u32(KEY_FLAGS_MINS, config.flags_mins)?;
+        self.nvs.set_u32(KEY_SLA_MINS, config.sla_mins)?;
+        self.nvs.set_u32(KEY_SLA_TARGET_MINS, config.sla_target_mins)?;
+        self.nvs.set_u32(KEY_SKETCHES, config.sketches.into())?;
+        self.nvs.set_str(KEY_REPORT, &config.report)?;
+        self.nvs.set_u32(KEY_REPROV_MINS, config.reprov_mins)?;
+        self.nvs.set_u32(KEY_FRESH_MINS, config.fresh_mins)?;
+        self.nvs.set_u32(KEY_NDJSON_FALLBACK, config.ndjson_fallback.into())?;
+        self.nvs.set_u32(KEY_HEALTH_TABLE, config.health_table.into())?;
+        self.nvs.set_str(KEY_ENROLL_URL, &config.enroll_url)?;
+        self.nvs.set_str(KEY_CLAIM_CODE, &config.claim_code)?;
+        self.nvs.set_str(KEY_CONFIG_URL, &config.config_url)?;
+        self.nvs.set_u32(KEY_CONFIG_TRIAL_MINS, config.config_trial_mins)?;
+        self.nvs.set_str(KEY_TASK_KEY, &config.task_key)?;
+        self.nvs.set_u32(KEY_TASK_MINS, config.task_mins)?;
+        self.nvs.set_str(KEY_MQTT_URL, &config.mqtt_url)?;
+        self.nvs.set_str(KEY_MQTT_TOPICS, &config.mqtt_topics)?;
+        self.nvs.set_str(KEY_CONTROL_TOKEN, &config.control_token)?;
+        self.nvs.set_u32(KEY_PAUSE_MAX_MINS, config.pause_max_mins)?;
+        self.nvs.set_u32(KEY_VERSION, config.version)?;
+        // Written last: its presence marks the configuration as complete
+        self.nvs.set_str(KEY_WIFI_SSID, &config.wifi_ssid)?;
+        Ok(())
+    }
+
+    /// Save a remote update on trial, keeping `current` to roll back to
+    pub fn apply_update(&mut self, current: &DeviceConfig, updated: &DeviceConfig) -> Result<()> {
+        let trial = i64::from(updated.config_trial_mins) * 60_000;
+        self.nvs.set_blob(KEY_PREVIOUS, &serde_json::to_vec(current)?)?;
+        self.nvs.set_i64(KEY_TRIAL_DEADLINE, clock().now_millis() + trial)?;
+        self.nvs.set_u32(KEY_TRIAL_BOOTS, 0)?;
+        self.save(updated)?;
+        info!(
+            "Configuration v{} saved on trial, v{} kept for rollback",
+            updated.version, current.version
+        );
+        Ok(())
+    }
+
+    /// The trial of the loaded configuration, if it is still on one.
+    /// `count_boot` is false on a deep sleep wake, which is not a failed boot.
+    pub fn update_trial(
+        &mut self,
+        config: &DeviceConfig,
+        count_boot: bool,
+    ) -> Result<Option<ConfigTrial>> {
+        let Some(deadline_ms) = self.nvs.get_i64(KEY_TRIAL_DEADLINE)? else {
+            return Ok(None);
+        };
+
+        let mut boots = self.get_u32_or(KEY_TRIAL_BOOTS, 0)?;
+        if count_boot {
+            boots += 1;
+            self.nvs.set_u32(KEY_TRIAL_BOOTS, boots)?;
+        }
+        Ok(Some(ConfigTrial {
+            version: config.version,
+            deadline_ms,
+            duration: Duration::from_secs(u64::from(config.config_trial_mins) * 60),
+            boots,
+        }))
+    }
+
+    /// The configuration on trial has committed a batch, drop the previous one
+    pub fn confirm_update(&mut self) -> Result<()> {
+        self.nvs.remove(KEY_PREVIOUS)?;
+        self.nvs.remove(KEY_TRIAL_BOOTS)?;
+        // Removed last: its presence marks a trial
+        self.nvs.remove(KEY_TRIAL_DEADLINE)?;
+        Ok(())
+    }
+
+    /// Restore the configuration kept by `apply_update`. False if there is none.
+    pub fn rollback_update(&mut self, current: &DeviceConfig) -> Result<bool> {
+        let previous = match self.get_previous() {
+            Ok(Some(blob)) => serde_json::from_slice::<DeviceConfig>(&blob),
+            Ok(None) => {
+                self.confirm_update()?;
+                return Ok(false);
+            }
+            Err(e) => {
+                warn!("Previous configuration is unreadable, no rollback: {:?}", e);
+                self.confirm_update()?;
+                return Ok(false);
+            }
+        };
+
+        match previous {
+            Ok(previous) => {
+                // Updates never carry secrets or the CA, so the current ones still apply
+                self.save(&DeviceConfig {
+                    aws_access_key: current.aws_access_key.clone(),
+                    aws_secret_key: current.aws_secret_key.clone(),
+                    s3_ca: current.s3_ca.clone(),
+                    wifi_ca: current.wifi_ca.clone(),
+                    ..previous
+                })?;
+                self.confirm_update()?;
+                Ok(true)
+            }
+            Err(e) => {
+                warn!(
+                    "Previous configuration is unreadable, keeping v{}: {:?}",
+                    current.version, e
+                );
+                self.confirm_update()?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// The configuration kept by `apply_update`, sized from NVS
+    fn get_previous(&self) -> Result<Option<Vec<u8>>> {
+        let Some(len) = self.nvs.blob_len(KEY_PREVIOUS)? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0u8; len];
+        Ok(self.nvs.get_blob(KEY_PREVIOUS, &mut buf)?.map(<[u8]>::to_vec))
+    }
+
+    /// Move plaintext S3 credentials left by older firmware into the SecretStore
+    fn migrate_legacy_credentials(&mut self) -> Result<()> {
+        if !self.nvs.contains(LEGACY_KEY_AWS_ACCESS_KEY)? {
+            return Ok(());
+        }
+
+        let access_key = self.get_or(LEGACY_KEY_AWS_ACCESS_KEY, String::new());
+        let secret_key = self.get_or(LEGACY_KEY_AWS_SECRET_KEY, String::new());
+        if !access_key.is_empty() && !secret_key.is_empty() {
+            self.secrets
+                .set_s3_credentials(&S3Credentials::new(&access_key, &secret_key))?;
+        }
+
+        self.nvs.remove(LEGACY_KEY_AWS_ACCESS_KEY)?;
+        self.nvs.remove(LEGACY_KEY_AWS_SECRET_KEY)?;
+        info!("Migrated plaintext S3 credentials to encrypted storage");
+        Ok(())
+    }
+
+    /// An unreadable value takes the default too, so a bad value can't keep
+    /// the device from booting
+    fn get_or(&self, key: &str, default: String) -> String {
+        match get_str(&self.nvs, key) {
+            Ok(value) => value.unwrap_or(default),
+            Err(e) => {
+                warn!("Unreadable '{}' in NVS, using the default: {:?}", key, e);
+                default
+            }
+        }
+    }
+
+    fn get_u32_or(&self, key: &str, default: u32) -> Result<u32> {
+        Ok(self.nvs.get_u32(key)?.unwrap_or(default))
+    }
+}
@@ -0,0 +1,193 @@
+//! Differential configuration sync with the fleet server
+//!
+//! When the device goes online it POSTs its configuration version and hash,
+//! signed with the enrollment device key, to `config_url`:
+//!
+//! ```json
+//! {"device_id": "...", "version": 3, "hash": "...", "timestamp": 0, "signature": "..."}
+//! ```
+//!
+//! The server answers 204 if the device is up to date, or 200 with only the
+//! settings that changed, using the `DeviceConfig` field names:
+//!
+//! ```json
+//! {"version": 4, "changes": {"flush_rows": 60, "sleep_secs": 300}}
+//! ```
+//!
+//! The hash lets the server notice local edits (e.g. through the portal) and
+//! resend everything. An update is applied on trial (see `config.rs`) and the
+//! caller reboots into it, once the pipeline state is saved.
+//!
+//! S3 keys are never part of `changes`. To rotate them the server adds
+//! `"credentials": {"access_key": "...", "secret_key": "..."}`, with the
+//! current version if nothing else changed; they are staged in
+//! `credentials.rs` and only swapped in once a test upload passes with them.
+
+use anyhow::{bail, Result};
+use log::{error, info};
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::Signature;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::clock::clock;
+use crate::config::{ConfigStore, DeviceConfig};
+use crate::credentials::{CredentialStore, S3Credentials};
+use crate::enrollment;
+use crate::secrets::SecretStore;
+
+#[derive(Serialize)]
+struct SyncRequest<'a> {
+    device_id: &'a str,
+    version: u32,
+    hash: String,
+    timestamp: u64,
+    /// DER ECDSA signature over `device_id|version|hash|timestamp`, hex
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct Update {
+    version: u32,
+    #[serde(default)]
+    changes: Map<String, Value>,
+    #[serde(default)]
+    credentials: Option<NewCredentials>,
+}
+
+/// Long-lived S3 keys to rotate to
+#[derive(Deserialize)]
+struct NewCredentials {
+    access_key: String,
+    secret_key: String,
+}
+
+/// What a sync leaves for the caller to do
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncOutcome {
+    /// Nothing changed, or the sync failed
+    Current,
+    /// New S3 credentials are staged, to rotate to
+    CredentialsStaged,
+    /// An update is applied on trial, to reboot into
+    Updated,
+}
+
+/// Check for a configuration update and apply it if there is one.
+/// Sync failures are logged, the device keeps running its current config.
+pub fn run(
+    store: &mut ConfigStore,
+    secrets: &mut SecretStore,
+    credentials: &mut CredentialStore,
+    config: &DeviceConfig,
+    device_id: &str,
+) -> Result<SyncOutcome> {
+    if config.config_url.is_empty() {
+        return Ok(SyncOutcome::Current);
+    }
+
+    let update = match fetch_update(secrets, config, device_id) {
+        Ok(Some(update)) => update,
+        Ok(None) => {
+            info!("Configuration v{} is up to date", config.version);
+            return Ok(SyncOutcome::Current);
+        }
+        Err(e) => {
+            error!("Configuration sync failed: {:?}", e);
+            return Ok(SyncOutcome::Current);
+        }
+    };
+
+    let staged = match &update.credentials {
+        Some(keys) if !keys.access_key.is_empty() && !keys.secret_key.is_empty() => {
+            credentials.stage(&S3Credentials::new(&keys.access_key, &keys.secret_key))?;
+            SyncOutcome::CredentialsStaged
+        }
+        Some(_) => {
+            error!("Fleet server sent empty S3 credentials, ignoring them");
+            SyncOutcome::Current
+        }
+        None => SyncOutcome::Current,
+    };
+    if update.version == config.version && update.changes.is_empty() {
+        return Ok(staged);
+    }
+    let updated = match apply_changes(config, update) {
+        Ok(updated) => updated,
+        Err(e) => {
+            error!("Configuration update rejected: {:?}", e);
+            return Ok(staged);
+        }
+    };
+
+    store.apply_update(config, &updated)?;
+    info!(
+        "Configuration updated to v{} ({}), rebooting...",
+        updated.version,
+        updated.hash()
+    );
+    Ok(SyncOutcome::Updated)
+}
+
+fn fetch_update(
+    secrets: &mut SecretStore,
+    config: &DeviceConfig,
+    device_id: &str,
+) -> Result<Option<Update>> {
+    let key = enrollment::device_key(secrets)?;
+    let hash = config.hash();
+    let timestamp = (clock().now_millis() / 1000) as u64;
+    let message = format!("{}|{}|{}|{}", device_id, config.version, hash, timestamp);
+    let signature: Signature = key.sign(message.as_bytes());
+
+    let request = SyncRequest {
+        device_id,
+        version: config.version,
+        hash,
+        timestamp,
+        signature: enrollment::hex(signature.to_der().as_bytes()),
+    };
+    let body = serde_json::to_vec(&request)?;
+    let (status, body) = enrollment::post_json(&config.config_url, &body)?;
+    match status {
+        204 | 304 => Ok(None),
+        200 => Ok(Some(serde_json::from_slice(&body)?)),
+        _ => bail!(
+            "Fleet server answered {}: {}",
+            status,
+            String::from_utf8_lossy(&body)
+        ),
+    }
+}
+
+/// `config` with the changed settings of `update` merged in
+fn apply_changes(config: &DeviceConfig, update: Update) -> Result<DeviceConfig> {
+    if update.version <= config.version {
+        bail!(
+            "Update to v{} is not newer than v{}",
+            update.version,
+            config.version
+        );
+    }
+
+    let mut fields = match serde_json::to_value(config)? {
+        Value::Object(fields) => fields,
+        _ => unreachable!("DeviceConfig serializes to an object"),
+    };
+    for (name, value) in update.changes {
+        if name == "version" || !fields.contains_key(&name) {
+            bail!("Update changes unknown setting '{}'", name);
+        }
+        fields.insert(name, value);
+    }
+
+    let updated: DeviceConfig = serde_json::from_value(Value::Object(fields))?;
+    Ok(DeviceConfig {
+        version: update.version,
+        aws_access_key: config.aws_access_key.clone(),
+        aws_secret_key: config.aws_secret_key.clone(),
+        s3_ca: config.s3_ca.clone(),
+        wifi_ca: config.wifi_ca.clone(),
+        ..updated
+    })
+}
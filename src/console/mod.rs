@@ -0,0 +1,240 @@
+//! Interactive SQL console over the UART0 serial port
+//!
+//! Lines typed into the serial monitor are queued by a reader thread and run
+//! by the ingestion loop between samples, so a query never races an upload.
+//! Queries go against the lake catalog (see `src/query.rs` for the
+//! supported SQL); results are printed as a table of at most `MAX_ROWS` rows.
+//! Dot-commands that act on the device rather than the catalog (`.promote`)
+//! are handed back to the loop as `Command`s; `.log` switches the log format
+//! right away.
+//!
+//! UART0 (TX GPIO43, RX GPIO44 on the ESP32-S3, TX GPIO16, RX GPIO17 on the
+//! ESP32-C6) is the DevKitC's USB-UART bridge, which also carries the log
+//! output.
+
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use anyhow::Result;
+use esp_idf_svc::hal::delay::BLOCK;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::uart::{config::Config as UartConfig, UartDriver, UART0};
+use esp_idf_svc::hal::units::Hertz;
+use log::{info, warn};
+
+use crate::board::{ConsoleRx, ConsoleTx};
+use crate::clock::parse_utc;
+use crate::lake::LakeBackend;
+use crate::logging::{self, LogFormat};
+use crate::query::{self, ResultSet, Value};
+
+const BAUDRATE: u32 = 115_200;
+const MAX_LINE_LEN: usize = 256;
+const MAX_ROWS: usize = 20;
+const READER_STACK_SIZE: usize = 4096;
+
+const HELP: &str = "\
+Queries run against the lake catalog, e.g.
+  SELECT COUNT(*) FROM esp32s3
+  FROM ducklake_snapshots('lake') ORDER BY snapshot_id DESC LIMIT 5
+  SELECT key, rows FROM data_files WHERE table = 'esp32s3'
+Views: data_files, snapshots, tables. Commands: .help, .tables
+  .log [text|json]      show or switch the log line format
+  .promote <from> <to>  upload raw SD card rows of a time range, e.g.
+                        .promote 2026-03-01T08:00 2026-03-01T12:00";
+
+pub struct ConsolePeripherals {
+    pub uart0: UART0,
+    pub tx: ConsoleTx,
+    pub rx: ConsoleRx,
+}
+
+/// Operator commands carried out by the ingestion loop
+pub enum Command {
+    /// Upload the raw rows between two Unix millisecond timestamps (inclusive)
+    Promote { from_ms: i64, to_ms: i64 },
+}
+
+pub struct Console {
+    lines: Receiver<String>,
+}
+
+impl Console {
+    /// Start reading lines from the serial port
+    pub fn start(peripherals: ConsolePeripherals) -> Result<Self> {
+        let config = UartConfig::new().baudrate(Hertz(BAUDRATE));
+        let uart = UartDriver::new(
+            peripherals.uart0,
+            peripherals.tx,
+            peripherals.rx,
+            Option::<AnyIOPin>::None,
+            Option::<AnyIOPin>::None,
+            &config,
+        )?;
+
+        let (sender, lines) = mpsc::channel();
+        thread::Builder::new()
+            .name("console".into())
+            .stack_size(READER_STACK_SIZE)
+            .spawn(move || {
+                let mut line = Vec::new();
+                let mut byte = [0u8; 1];
+                loop {
+                    if uart.read(&mut byte, BLOCK).unwrap_or(0) == 0 {
+                        continue;
+                    }
+                    match byte[0] {
+                        b'\r' | b'\n' => {
+                            let _ = uart.write(b"\r\n");
+                            let text = String::from_utf8_lossy(&line).trim().to_string();
+                            line.clear();
+                            if !text.is_empty() && sender.send(text).is_err() {
+                                return;
+                            }
+                        }
+                        // Backspace / delete
+                        0x08 | 0x7F => {
+                            if line.pop().is_some() {
+                                let _ = uart.write(b"\x08 \x08");
+                            }
+                        }
+                        b if line.len() < MAX_LINE_LEN => {
+                            line.push(b);
+                            let _ = uart.write(&byte);
+                        }
+                        _ => {}
+                    }
+                }
+            })?;
+
+        info!("SQL console on UART0, type .help");
+        Ok(Self { lines })
+    }
+
+    /// Run the queries typed since the last call, returning the commands among them
+    pub fn poll(&self, lake: &dyn LakeBackend) -> Vec<Command> {
+        let mut commands = Vec::new();
+        while let Ok(line) = self.lines.try_recv() {
+            commands.extend(run(&line, lake));
+        }
+        commands
+    }
+}
+
+fn run(line: &str, lake: &dyn LakeBackend) -> Option<Command> {
+    if line.eq_ignore_ascii_case(".help") {
+        println!("{}", HELP);
+        return None;
+    }
+    if let Some(args) = line.strip_prefix(".log") {
+        match args.trim() {
+            "" => println!("Log format: {}", logging::format().name()),
+            args => match LogFormat::parse(args) {
+                Ok(format) => {
+                    logging::set_format(format);
+                    println!("Log format: {} until reboot (log_fmt keeps it)", format.name());
+                }
+                Err(e) => println!("Error: {}", e),
+            },
+        }
+        return None;
+    }
+    if let Some(args) = line.strip_prefix(".promote") {
+        let mut args = args.split_whitespace().map(parse_time);
+        return match (args.next(), args.next(), args.next()) {
+            (Some(Some(from_ms)), Some(Some(to_ms)), None) if from_ms <= to_ms => {
+                Some(Command::Promote { from_ms, to_ms })
+            }
+            _ => {
+                println!("Usage: .promote <from> <to> (ISO 8601 UTC or Unix milliseconds)");
+                None
+            }
+        };
+    }
+
+    let Some(catalog) = lake.catalog() else {
+        println!("The '{}' lake backend has no local catalog to query", lake.name());
+        return None;
+    };
+
+    let sql = if line.eq_ignore_ascii_case(".tables") {
+        "FROM tables"
+    } else {
+        line
+    };
+    match query::execute(sql, catalog) {
+        Ok(result) => print_table(&result),
+        Err(e) => {
+            warn!("Console query failed: {}", e);
+            println!("Error: {}", e);
+        }
+    }
+    None
+}
+
+/// Unix milliseconds, or an ISO 8601 UTC date / date-time
+fn parse_time(value: &str) -> Option<i64> {
+    value.parse().ok().or_else(|| parse_utc(value))
+}
+
+/// Print `result` as an ASCII table, numbers right-aligned
+fn print_table(result: &ResultSet) {
+    let shown = &result.rows[..result.rows.len().min(MAX_ROWS)];
+    let cells: Vec<Vec<String>> = shown
+        .iter()
+        .map(|row| row.iter().map(Value::to_string).collect())
+        .collect();
+
+    let widths: Vec<usize> = result
+        .columns
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            cells
+                .iter()
+                .map(|row| row[i].len())
+                .chain([name.len()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let border: String = widths
+        .iter()
+        .map(|w| format!("+{}", "-".repeat(w + 2)))
+        .collect::<String>()
+        + "+";
+
+    println!("{}", border);
+    let header: String = result
+        .columns
+        .iter()
+        .zip(&widths)
+        .map(|(name, w)| format!("| {:<w$} ", name, w = w))
+        .collect();
+    println!("{}|", header);
+    println!("{}", border);
+    for (row, values) in cells.iter().zip(shown) {
+        let line: String = row
+            .iter()
+            .zip(values)
+            .zip(&widths)
+            .map(|((cell, value), w)| match value {
+                Value::Int(_) => format!("| {:>w$} ", cell, w = w),
+                _ => format!("| {:<w$} ", cell, w = w),
+            })
+            .collect();
+        println!("{}|", line);
+    }
+    println!("{}", border);
+
+    if result.rows.len() > shown.len() {
+        println!("{} rows ({} shown)", result.rows.len(), shown.len());
+    } else {
+        println!(
+            "{} row{}",
+            result.rows.len(),
+            if result.rows.len() == 1 { "" } else { "s" }
+        );
+    }
+}
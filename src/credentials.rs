@@ -0,0 +1,365 @@
+//! S3 credential storage and rotation
+//!
+//! Credentials live in two NVS slots (`a` and `b`) plus a one-byte pointer to
+//! the active slot. A rotation writes the new pair into the inactive slot, the
+//! pair is verified with a test request and only then is the pointer flipped.
+//! Flipping a single byte is atomic, so a power loss mid-rotation always
+//! leaves one complete set of credentials active.
+//!
+//! After a swap the store stays "on probation" until the first real upload
+//! succeeds. If uploads fail under the new credentials, `rollback()` flips the
+//! pointer back to the previous slot.
+//!
+//! The slots live in the encrypted secrets partition (see `secrets.rs`).
+//! With a temporary credential provider (see `sts.rs`), they hold the
+//! long-lived keys the temporary ones are requested with. New keys are
+//! staged by the fleet server through configuration sync (`config_sync.rs`).
+//! Host builds keep the slots in memory instead (`MemorySlots`).
+
+#[cfg(not(target_os = "espidf"))]
+use std::collections::HashMap;
+
+use anyhow::Result;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::nvs::{EspEncryptedNvsPartition, EspNvs, NvsEncrypted};
+use log::{info, warn};
+use rusty_s3::Credentials;
+
+#[cfg(target_os = "espidf")]
+const NAMESPACE: &str = "s3_creds";
+
+const KEY_ACTIVE: &str = "active";
+const KEY_STAGED: &str = "staged";
+const KEY_PROBATION: &str = "probation";
+
+// NVS keys are limited to 15 characters, access/secret keys fit in 128 bytes
+#[cfg(target_os = "espidf")]
+const MAX_VALUE_LEN: usize = 128;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct S3Credentials {
+    pub access_key: String,
+    pub secret_key: String,
+    /// Session token of temporary credentials, never stored
+    pub session_token: Option<String>,
+}
+
+impl S3Credentials {
+    pub fn new(access_key: &str, secret_key: &str) -> Self {
+        Self {
+            access_key: access_key.to_string(),
+            secret_key: secret_key.to_string(),
+            session_token: None,
+        }
+    }
+
+    /// Temporary credentials, e.g. from STS
+    pub fn temporary(access_key: &str, secret_key: &str, session_token: &str) -> Self {
+        Self {
+            session_token: Some(session_token.to_string()),
+            ..Self::new(access_key, secret_key)
+        }
+    }
+
+    /// Credentials in the form expected by rusty-s3 for URL signing
+    pub fn to_rusty_s3(&self) -> Credentials {
+        match &self.session_token {
+            Some(token) => Credentials::new_with_token(&self.access_key, &self.secret_key, token),
+            None => Credentials::new(&self.access_key, &self.secret_key),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RotationOutcome {
+    /// Nothing was staged
+    Unchanged,
+    /// Staged credentials passed verification and are now active
+    Rotated,
+    /// Staged credentials failed verification and were discarded
+    Rejected,
+}
+
+/// The NVS operations the store needs
+pub trait SlotStorage {
+    fn get_u8(&self, key: &str) -> Result<Option<u8>>;
+    fn set_u8(&mut self, key: &str, value: u8) -> Result<()>;
+    fn get_str(&self, key: &str) -> Result<Option<String>>;
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()>;
+    fn remove(&mut self, key: &str) -> Result<()>;
+}
+
+#[cfg(target_os = "espidf")]
+impl SlotStorage for EspNvs<NvsEncrypted> {
+    fn get_u8(&self, key: &str) -> Result<Option<u8>> {
+        Ok(EspNvs::get_u8(self, key)?)
+    }
+
+    fn set_u8(&mut self, key: &str, value: u8) -> Result<()> {
+        Ok(EspNvs::set_u8(self, key, value)?)
+    }
+
+    fn get_str(&self, key: &str) -> Result<Option<String>> {
+        let mut buf = [0u8; MAX_VALUE_LEN];
+        Ok(EspNvs::get_str(self, key, &mut buf)?.map(str::to_string))
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()> {
+        Ok(EspNvs::set_str(self, key, value)?)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        EspNvs::remove(self, key)?;
+        Ok(())
+    }
+}
+
+/// Slots in RAM, for host builds
+#[cfg(not(target_os = "espidf"))]
+#[derive(Default)]
+pub struct MemorySlots {
+    u8s: HashMap<String, u8>,
+    strs: HashMap<String, String>,
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl SlotStorage for MemorySlots {
+    fn get_u8(&self, key: &str) -> Result<Option<u8>> {
+        Ok(self.u8s.get(key).copied())
+    }
+
+    fn set_u8(&mut self, key: &str, value: u8) -> Result<()> {
+        self.u8s.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn get_str(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.strs.get(key).cloned())
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()> {
+        self.strs.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        self.u8s.remove(key);
+        self.strs.remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "espidf")]
+type DefaultSlots = EspNvs<NvsEncrypted>;
+#[cfg(not(target_os = "espidf"))]
+type DefaultSlots = MemorySlots;
+
+pub struct CredentialStore<S = DefaultSlots> {
+    nvs: S,
+}
+
+#[cfg(target_os = "espidf")]
+impl CredentialStore {
+    pub fn new(partition: EspEncryptedNvsPartition) -> Result<Self> {
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        Ok(Self { nvs })
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl CredentialStore {
+    /// A store that forgets everything when dropped
+    pub fn in_memory() -> Self {
+        Self {
+            nvs: MemorySlots::default(),
+        }
+    }
+}
+
+impl<S: SlotStorage> CredentialStore<S> {
+    /// Currently active credentials, or `fallback` if no slot has been written yet
+    pub fn active(&self, fallback: &S3Credentials) -> Result<S3Credentials> {
+        let slot = self.active_slot()?;
+        Ok(self.read_slot(slot)?.unwrap_or_else(|| fallback.clone()))
+    }
+
+    /// Stage new credentials for verification on the next rotation.
+    ///
+    /// The active slot is never touched, so staging bad credentials is harmless.
+    pub fn stage(&mut self, credentials: &S3Credentials) -> Result<()> {
+        let slot = other_slot(self.active_slot()?);
+        self.nvs.set_str(&slot_key(slot, "ak"), &credentials.access_key)?;
+        self.nvs.set_str(&slot_key(slot, "sk"), &credentials.secret_key)?;
+        self.nvs.set_u8(KEY_STAGED, 1)?;
+        info!("New S3 credentials staged in slot '{}'", slot);
+        Ok(())
+    }
+
+    /// Verify staged credentials with `verify` and swap them in if it succeeds
+    pub fn rotate_if_staged<F>(&mut self, verify: F) -> Result<RotationOutcome>
+    where
+        F: FnOnce(&S3Credentials) -> Result<()>,
+    {
+        if self.nvs.get_u8(KEY_STAGED)?.unwrap_or(0) == 0 {
+            return Ok(RotationOutcome::Unchanged);
+        }
+
+        let staged_slot = other_slot(self.active_slot()?);
+        let Some(staged) = self.read_slot(staged_slot)? else {
+            warn!("Staged flag set but slot '{}' is empty, ignoring", staged_slot);
+            self.nvs.set_u8(KEY_STAGED, 0)?;
+            return Ok(RotationOutcome::Unchanged);
+        };
+
+        info!("Verifying staged S3 credentials...");
+        if let Err(e) = verify(&staged) {
+            warn!("Staged credentials failed verification: {:?}", e);
+            self.nvs.set_u8(KEY_STAGED, 0)?;
+            return Ok(RotationOutcome::Rejected);
+        }
+
+        // Single-byte pointer flip is the commit point of the rotation. Probation
+        // follows it, or a power loss in between would put the old keys on it
+        self.nvs.set_u8(KEY_ACTIVE, staged_slot as u8)?;
+        self.nvs.set_u8(KEY_PROBATION, 1)?;
+        self.nvs.set_u8(KEY_STAGED, 0)?;
+        info!("S3 credentials rotated to slot '{}'", staged_slot);
+
+        Ok(RotationOutcome::Rotated)
+    }
+
+    /// True while freshly rotated credentials have not yet completed an upload
+    pub fn in_probation(&self) -> Result<bool> {
+        Ok(self.nvs.get_u8(KEY_PROBATION)?.unwrap_or(0) == 1)
+    }
+
+    /// Mark the active credentials as known-good
+    pub fn confirm(&mut self) -> Result<()> {
+        if self.in_probation()? {
+            self.nvs.set_u8(KEY_PROBATION, 0)?;
+            info!("Rotated S3 credentials confirmed");
+        }
+        Ok(())
+    }
+
+    /// Switch back to the previous slot if the active credentials are on probation
+    pub fn rollback(&mut self) -> Result<bool> {
+        if !self.in_probation()? {
+            return Ok(false);
+        }
+
+        let previous = other_slot(self.active_slot()?);
+        self.nvs.set_u8(KEY_ACTIVE, previous as u8)?;
+        self.nvs.set_u8(KEY_PROBATION, 0)?;
+        warn!("Rolled back S3 credentials to slot '{}'", previous);
+
+        Ok(true)
+    }
+
+    /// Forget both slots, so the next provisioned credentials are used as-is
+    pub fn clear(&mut self) -> Result<()> {
+        for slot in ['a', 'b'] {
+            self.nvs.remove(&slot_key(slot, "ak"))?;
+            self.nvs.remove(&slot_key(slot, "sk"))?;
+        }
+        for key in [KEY_ACTIVE, KEY_STAGED, KEY_PROBATION] {
+            self.nvs.remove(key)?;
+        }
+        warn!("Rotated S3 credentials cleared");
+        Ok(())
+    }
+
+    fn active_slot(&self) -> Result<char> {
+        Ok(match self.nvs.get_u8(KEY_ACTIVE)? {
+            Some(b'b') => 'b',
+            _ => 'a',
+        })
+    }
+
+    fn read_slot(&self, slot: char) -> Result<Option<S3Credentials>> {
+        let access_key = self.nvs.get_str(&slot_key(slot, "ak"))?;
+        let secret_key = self.nvs.get_str(&slot_key(slot, "sk"))?;
+
+        Ok(match (access_key, secret_key) {
+            (Some(ak), Some(sk)) if !ak.is_empty() && !sk.is_empty() => {
+                Some(S3Credentials::new(&ak, &sk))
+            }
+            _ => None,
+        })
+    }
+}
+
+fn other_slot(slot: char) -> char {
+    if slot == 'a' {
+        'b'
+    } else {
+        'a'
+    }
+}
+
+fn slot_key(slot: char, field: &str) -> String {
+    format!("{}_{}", slot, field)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::bail;
+
+    use super::*;
+
+    fn store() -> CredentialStore {
+        CredentialStore::in_memory()
+    }
+
+    #[test]
+    fn stage_rotate_confirm() -> Result<()> {
+        let provisioned = S3Credentials::new("AKOLD", "old-secret");
+        let rotated = S3Credentials::new("AKNEW", "new-secret");
+        let mut store = store();
+
+        store.stage(&rotated)?;
+        assert_eq!(store.active(&provisioned)?, provisioned);
+
+        let mut verified = None;
+        let outcome = store.rotate_if_staged(|staged| {
+            verified = Some(staged.clone());
+            Ok(())
+        })?;
+        assert_eq!(outcome, RotationOutcome::Rotated);
+        assert_eq!(verified, Some(rotated.clone()));
+        assert_eq!(store.active(&provisioned)?, rotated);
+        assert!(store.in_probation()?);
+
+        store.confirm()?;
+        assert!(!store.in_probation()?);
+        assert!(!store.rollback()?);
+        assert_eq!(store.active(&provisioned)?, rotated);
+        assert_eq!(store.rotate_if_staged(|_| Ok(()))?, RotationOutcome::Unchanged);
+        Ok(())
+    }
+
+    #[test]
+    fn rejected_and_rolled_back() -> Result<()> {
+        let first = S3Credentials::new("AKONE", "one-secret");
+        let second = S3Credentials::new("AKTWO", "two-secret");
+        let fallback = S3Credentials::new("AKFALLBACK", "fallback-secret");
+        let mut store = store();
+
+        store.stage(&first)?;
+        store.rotate_if_staged(|_| Ok(()))?;
+        store.confirm()?;
+
+        store.stage(&second)?;
+        let outcome = store.rotate_if_staged(|_| bail!("403 Forbidden"))?;
+        assert_eq!(outcome, RotationOutcome::Rejected);
+        assert_eq!(store.active(&fallback)?, first);
+
+        store.stage(&second)?;
+        store.rotate_if_staged(|_| Ok(()))?;
+        assert_eq!(store.active(&fallback)?, second);
+        assert!(store.rollback()?);
+        assert_eq!(store.active(&fallback)?, first);
+        assert!(!store.in_probation()?);
+        Ok(())
+    }
+}
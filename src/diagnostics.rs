@@ -0,0 +1,235 @@
+//! `device_health` rows: heap, WiFi signal, flush latency and retries per flush
+//!
+//! Every flush samples the heap and the WiFi signal into a row. The
+//! forwarding round that follows adds how long it took to encode and upload
+//! the buffered batches, how many went up and how many transient failures
+//! were retried meanwhile, then writes the row along with the other
+//! auxiliary tables. Rows of flushes taken offline wait for the round that
+//! finally uploads their batches, and are carried across deep sleep.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::clock;
+use crate::lake::{Cell, Column, ColumnType, LakeBackend, RecordBatch, S3Target};
+use crate::pipeline::free_heap;
+use crate::sensors::sampling_stats;
+use crate::wifi::WifiLink;
+use crate::{net, power};
+
+const HEALTH_TABLE: &str = "device_health";
+// Rows kept for the lake; oldest dropped beyond
+const MAX_QUEUED: usize = 64;
+
+const HEALTH_COLUMNS: [Column; 13] = [
+    Column {
+        name: "timestamp",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "uptime_s",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "reset_reason",
+        kind: ColumnType::Text,
+    },
+    Column {
+        name: "free_heap",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "min_free_heap",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "largest_free_block",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "wifi_rssi",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "flush_rows",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "buffered_batches",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "flush_ms",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "uploaded_batches",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "retries",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "sample_overruns",
+        kind: ColumnType::Long,
+    },
+];
+
+/// The forwarding round that uploaded a flush
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+struct Round {
+    flush_ms: u32,
+    uploaded_batches: u32,
+    retries: u32,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Sample {
+    timestamp: i64,
+    uptime_s: u64,
+    // Of the boot the flush was taken in, deep sleep wakes included
+    reset_reason: String,
+    free_heap: u32,
+    min_free_heap: u32,
+    largest_free_block: u32,
+    // dBm, 0 while disconnected
+    wifi_rssi: i8,
+    flush_rows: u32,
+    buffered_batches: u32,
+    // Ticks missed and samples dropped by the sampler task since boot
+    #[serde(default)]
+    sample_overruns: u32,
+    round: Option<Round>,
+}
+
+/// What survives deep sleep: the queued rows
+#[derive(Serialize, Deserialize)]
+pub struct HealthState {
+    samples: VecDeque<Sample>,
+}
+
+pub struct Diagnostics {
+    enabled: bool,
+    samples: VecDeque<Sample>,
+    // Monotonic start and retry count of the running forwarding round
+    round_started: Option<(Duration, u32)>,
+}
+
+impl Diagnostics {
+    /// Health rows per flush, unless `health_tbl` is off
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            samples: VecDeque::new(),
+            round_started: None,
+        }
+    }
+
+    /// Sample the device right after a flush of `flush_rows` readings
+    pub fn sample(&mut self, flush_rows: usize, buffered_batches: usize, wifi: &WifiLink) {
+        if !self.enabled {
+            return;
+        }
+        if self.samples.len() == MAX_QUEUED {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample {
+            timestamp: clock().now_millis(),
+            uptime_s: clock().monotonic().as_secs(),
+            reset_reason: power::reset_reason().to_string(),
+            free_heap: free_heap(),
+            min_free_heap: unsafe { esp_idf_svc::sys::esp_get_minimum_free_heap_size() },
+            largest_free_block: largest_free_block(),
+            wifi_rssi: wifi.rssi().unwrap_or(0),
+            flush_rows: flush_rows as u32,
+            buffered_batches: buffered_batches as u32,
+            sample_overruns: sampling_stats().overruns(),
+            round: None,
+        });
+    }
+
+    /// A forwarding round starts uploading the buffered batches
+    pub fn start_round(&mut self) {
+        self.round_started = Some((clock().monotonic(), net::retries()));
+    }
+
+    /// The round uploaded `uploaded_batches`; completes every row still waiting for one
+    pub fn end_round(&mut self, uploaded_batches: usize) {
+        let Some((started, retries)) = self.round_started.take() else {
+            return;
+        };
+        let round = Round {
+            flush_ms: clock().elapsed_since(started).as_millis() as u32,
+            uploaded_batches: uploaded_batches as u32,
+            retries: net::retries().wrapping_sub(retries),
+        };
+        for sample in self.samples.iter_mut().filter(|s| s.round.is_none()) {
+            sample.round = Some(round);
+        }
+    }
+
+    /// True if rows are waiting for the lake
+    pub fn has_unwritten(&self) -> bool {
+        !self.samples.is_empty()
+    }
+
+    /// Write queued rows to `device_health`; they stay queued on failure
+    pub fn write(&mut self, lake: &mut dyn LakeBackend, target: &S3Target) {
+        let batch = RecordBatch {
+            columns: &HEALTH_COLUMNS,
+            rows: self.samples.iter().map(row).collect(),
+        };
+        let written = lake
+            .append_records(target, HEALTH_TABLE, &batch)
+            .and_then(|_| lake.commit());
+        match written {
+            Ok(()) => {
+                info!("  Wrote {} device health rows", self.samples.len());
+                self.samples.clear();
+            }
+            Err(e) => warn!("  Failed to write device health rows: {:?}", e),
+        }
+    }
+
+    /// Take queued rows for the next boot, `None` if there are none
+    pub fn take_state(&mut self) -> Option<HealthState> {
+        let samples = std::mem::take(&mut self.samples);
+        (!samples.is_empty()).then_some(HealthState { samples })
+    }
+
+    /// Take over what `take_state` took before the last boot
+    pub fn restore_state(&mut self, saved: HealthState) {
+        self.samples = saved.samples;
+    }
+}
+
+/// Largest allocation the heap can still serve, i.e. how fragmented it is
+fn largest_free_block() -> u32 {
+    use esp_idf_svc::sys::{heap_caps_get_largest_free_block, MALLOC_CAP_8BIT};
+
+    unsafe { heap_caps_get_largest_free_block(MALLOC_CAP_8BIT) as u32 }
+}
+
+/// One `device_health` row, in `HEALTH_COLUMNS` order
+fn row(sample: &Sample) -> Vec<Cell> {
+    let round = sample.round.unwrap_or_default();
+    vec![
+        Cell::Long(sample.timestamp),
+        Cell::Long(sample.uptime_s as i64),
+        Cell::Text(sample.reset_reason.clone()),
+        Cell::Long(sample.free_heap.into()),
+        Cell::Long(sample.min_free_heap.into()),
+        Cell::Long(sample.largest_free_block.into()),
+        Cell::Long(sample.wifi_rssi.into()),
+        Cell::Long(sample.flush_rows.into()),
+        Cell::Long(sample.buffered_batches.into()),
+        Cell::Long(round.flush_ms.into()),
+        Cell::Long(round.uploaded_batches.into()),
+        Cell::Long(round.retries.into()),
+        Cell::Long(sample.sample_overruns.into()),
+    ]
+}
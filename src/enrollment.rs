@@ -0,0 +1,223 @@
+//! Zero-touch enrollment with a fleet provisioning server
+//!
+//! A device that knows its WiFi network and a fleet server URL (compiled-in
+//! default or entered in the SoftAP portal), but has no S3 credentials yet,
+//! enrolls on first boot:
+//!
+//! 1. A P-256 device key is generated and kept in encrypted storage.
+//! 2. The device POSTs a claim - device ID, claim code, public key and
+//!    timestamp, signed with the device key - to the fleet server.
+//! 3. The server answers with the configuration bundle (WiFi, S3, lake
+//!    layout), which is saved like a provisioned configuration before the
+//!    device reboots into normal operation.
+//!
+//! While the claim hasn't been approved (any non-200 answer) the device keeps
+//! retrying, so devices can be shipped before they are assigned to a tenant.
+
+use std::convert::Infallible;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use embedded_svc::http::client::Client as HttpClient;
+use embedded_svc::http::Method;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::http::client::EspHttpConnection;
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use log::{error, info, warn};
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::clock;
+use crate::config::{ConfigStore, DeviceConfig};
+use crate::net;
+use crate::secrets::SecretStore;
+use crate::timesync;
+use crate::wifi::WifiLink;
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_BUNDLE_LEN: usize = 4096;
+
+#[derive(Serialize)]
+struct Claim<'a> {
+    device_id: &'a str,
+    claim_code: &'a str,
+    /// SEC1 uncompressed public key, hex
+    public_key: String,
+    timestamp: u64,
+    /// DER ECDSA signature over `device_id|claim_code|timestamp`, hex
+    signature: String,
+}
+
+/// Configuration bundle returned by the fleet server
+#[derive(Deserialize)]
+struct Bundle {
+    wifi_ssid: Option<String>,
+    wifi_password: Option<String>,
+    s3_bucket: String,
+    s3_region: String,
+    aws_access_key: String,
+    aws_secret_key: String,
+    data_path: Option<String>,
+    table: Option<String>,
+    /// Assigned by the fleet server, read by the `nvs` ID provider
+    device_id: Option<String>,
+}
+
+/// Enroll with the fleet server until a bundle is received, then reboot
+pub fn run(
+    modem: esp_idf_svc::hal::modem::Modem,
+    sys_loop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    mut store: ConfigStore,
+    mut secrets: SecretStore,
+    config: DeviceConfig,
+) -> Result<Infallible> {
+    info!("No S3 credentials, enrolling with {}...", config.enroll_url);
+
+    let key = device_key(&mut secrets)?;
+    let wifi = WifiLink::new(modem, sys_loop, nvs, &config, &secrets)?;
+    timesync::start(&config)?;
+    let device_id = device_id(&wifi)?;
+
+    loop {
+        if !wifi.is_connected() {
+            if let Err(e) = wifi.connect() {
+                warn!("WiFi unavailable, retrying enrollment later: {:?}", e);
+                clock().sleep(RETRY_INTERVAL);
+                continue;
+            }
+            // The claim timestamp must be real for the server to accept it
+            if let Err(e) = clock().synchronize() {
+                warn!("Time sync failed: {:?}", e);
+            }
+        }
+
+        match request_bundle(&config, &key, &device_id) {
+            Ok(bundle) => {
+                let enrolled = apply_bundle(&config, bundle);
+                store.save(&enrolled)?;
+                info!(
+                    event = "enrolled", device_id = device_id.as_str();
+                    "Enrolled as {}, bucket '{}', rebooting...",
+                    device_id, enrolled.s3_bucket
+                );
+                std::thread::sleep(Duration::from_secs(1));
+                esp_idf_svc::hal::reset::restart();
+            }
+            Err(e) => {
+                error!("Enrollment failed, retrying in {:?}: {:?}", RETRY_INTERVAL, e);
+                clock().sleep(RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Device ID sent to the fleet server: the station MAC address, hex
+pub fn device_id(wifi: &WifiLink) -> Result<String> {
+    Ok(hex(&wifi.mac()?))
+}
+
+/// Load the device key, generating it on first use
+pub fn device_key(secrets: &mut SecretStore) -> Result<SigningKey> {
+    if let Some(bytes) = secrets.device_key()? {
+        return SigningKey::from_slice(&bytes).map_err(|_| anyhow!("stored device key is invalid"));
+    }
+
+    info!("Generating device key...");
+    let key = SigningKey::random(&mut OsRng);
+    secrets.set_device_key(&key.to_bytes())?;
+    Ok(key)
+}
+
+fn request_bundle(config: &DeviceConfig, key: &SigningKey, device_id: &str) -> Result<Bundle> {
+    let timestamp = (clock().now_millis() / 1000) as u64;
+    let message = format!("{}|{}|{}", device_id, config.claim_code, timestamp);
+    let signature: Signature = key.sign(message.as_bytes());
+
+    let claim = Claim {
+        device_id,
+        claim_code: &config.claim_code,
+        public_key: hex(key.verifying_key().to_encoded_point(false).as_bytes()),
+        timestamp,
+        signature: hex(signature.to_der().as_bytes()),
+    };
+    let (status, bundle) = post_json(&config.enroll_url, &serde_json::to_vec(&claim)?)?;
+    if status != 200 {
+        bail!(
+            "Fleet server answered {}: {}",
+            status,
+            String::from_utf8_lossy(&bundle)
+        );
+    }
+
+    Ok(serde_json::from_slice(&bundle)?)
+}
+
+/// POST `body` to the fleet server, returning the status and response body
+pub fn post_json(url: &str, body: &[u8]) -> Result<(u16, Vec<u8>)> {
+    let http_config = net::http_config(url, Duration::from_secs(30));
+    let mut client = HttpClient::wrap(EspHttpConnection::new(&http_config)?);
+
+    let headers = [
+        ("Content-Type", "application/json"),
+        ("Content-Length", &body.len().to_string()),
+    ];
+    let mut request = client.request(Method::Post, url, &headers)?;
+    request.write_all(body)?;
+    let mut response = request.submit()?;
+    let status = response.status();
+
+    let mut payload = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        match response.read(&mut buf)? {
+            0 => break,
+            n if payload.len() + n > MAX_BUNDLE_LEN => bail!("Fleet server response too large"),
+            n => payload.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    Ok((status, payload))
+}
+
+fn apply_bundle(config: &DeviceConfig, bundle: Bundle) -> DeviceConfig {
+    let mut enrolled = config.clone();
+
+    if let Some(ssid) = bundle.wifi_ssid {
+        enrolled.wifi_ssid = ssid;
+        enrolled.wifi_password = bundle.wifi_password.unwrap_or_default();
+    }
+    enrolled.s3_bucket = bundle.s3_bucket;
+    enrolled.s3_region = bundle.s3_region;
+    enrolled.aws_access_key = bundle.aws_access_key;
+    enrolled.aws_secret_key = bundle.aws_secret_key;
+    if let Some(data_path) = bundle.data_path {
+        enrolled.data_path = data_path;
+    }
+    if let Some(table) = bundle.table {
+        enrolled.table_name = table;
+    }
+    if let Some(device_id) = bundle.device_id {
+        enrolled.device_id = device_id;
+    }
+
+    enrolled
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Bytes of a hex string, the reverse of `hex`
+pub fn unhex(value: &str) -> Result<Vec<u8>> {
+    if !value.is_ascii() || value.len() % 2 != 0 {
+        bail!("not an even number of hex digits");
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).map_err(|_| anyhow!("invalid hex")))
+        .collect()
+}
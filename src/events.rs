@@ -0,0 +1,122 @@
+//! `device_events` rows for conditions a fleet operator should know about
+//!
+//! Anything can `report()` an event, e.g. the lake when it writes a table
+//! in schema compatibility mode. Events are queued in memory and written
+//! with the next forwarding round, like boot records; a failed write keeps
+//! them queued for the round after. Events are reported when the condition
+//! is detected, so each boot reports its own.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::clock::clock;
+use crate::lake::{Cell, Column, ColumnType, LakeBackend, RecordBatch, S3Target};
+
+const EVENTS_TABLE: &str = "device_events";
+
+// Oldest events are dropped beyond this, e.g. when the lake can't take them
+const MAX_QUEUED_EVENTS: usize = 32;
+
+/// A table newer than the firmware is written with the columns it knows
+pub const SCHEMA_COMPAT: &str = "schema_compat";
+/// No SNTP sync for `ntp_trust_m` minutes, S3 uploads paused
+pub const TIME_UNTRUSTED: &str = "time_untrusted";
+/// A forwarding round was lost to an S3 outage, uploads back off
+pub const UPLOAD_BACKOFF: &str = "upload_backoff";
+/// An OTA update attached the lake and was confirmed (`ota` feature)
+#[cfg_attr(not(feature = "ota"), allow(dead_code))]
+pub const FIRMWARE_UPDATE: &str = "firmware_update";
+/// An OTA update was rolled back before it attached the lake
+#[cfg_attr(not(feature = "ota"), allow(dead_code))]
+pub const FIRMWARE_ROLLBACK: &str = "firmware_rollback";
+/// An operator switched a subsystem on or off in the `feature_flags` table
+pub const FEATURE_FLAG: &str = "feature_flag";
+/// A signed maintenance SQL task ran or was rejected
+pub const MAINTENANCE_TASK: &str = "maintenance_task";
+/// The freshness self-check found no recent rows and took a remediation step
+pub const STALE_DATA: &str = "stale_data";
+
+const EVENT_COLUMNS: [Column; 4] = [
+    Column {
+        name: "timestamp",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "event",
+        kind: ColumnType::Text,
+    },
+    Column {
+        name: "table_name",
+        kind: ColumnType::Text,
+    },
+    Column {
+        name: "detail",
+        kind: ColumnType::Text,
+    },
+];
+
+struct Event {
+    // Monotonic, the wall clock may not be set yet
+    at: Duration,
+    event: &'static str,
+    table: String,
+    detail: String,
+}
+
+static QUEUE: Mutex<Vec<Event>> = Mutex::new(Vec::new());
+
+/// Queue an event about `table` (empty if it concerns no table)
+pub fn report(event: &'static str, table: &str, detail: String) {
+    let Ok(mut queue) = QUEUE.lock() else {
+        return;
+    };
+    if queue.len() >= MAX_QUEUED_EVENTS {
+        queue.remove(0);
+    }
+    queue.push(Event {
+        at: clock().monotonic(),
+        event,
+        table: table.to_string(),
+        detail,
+    });
+}
+
+pub fn has_pending() -> bool {
+    QUEUE.lock().is_ok_and(|queue| !queue.is_empty())
+}
+
+/// Write the queued events to the `device_events` table; kept queued on failure
+pub fn write(lake: &mut dyn LakeBackend, target: &S3Target) {
+    let Ok(mut queue) = QUEUE.lock() else {
+        return;
+    };
+    let now_ms = clock().now_millis();
+    let uptime = clock().monotonic();
+    let batch = RecordBatch {
+        columns: &EVENT_COLUMNS,
+        rows: queue
+            .iter()
+            .map(|e| {
+                let ago = uptime.saturating_sub(e.at).as_millis() as i64;
+                vec![
+                    Cell::Long(now_ms - ago),
+                    Cell::Text(e.event.to_string()),
+                    Cell::Text(e.table.clone()),
+                    Cell::Text(e.detail.clone()),
+                ]
+            })
+            .collect(),
+    };
+    let written = lake
+        .append_records(target, EVENTS_TABLE, &batch)
+        .and_then(|_| lake.commit());
+    match written {
+        Ok(()) => {
+            info!("  Wrote {} device events", queue.len());
+            queue.clear();
+        }
+        Err(e) => warn!("  Failed to write {} device events: {:?}", queue.len(), e),
+    }
+}
@@ -0,0 +1,160 @@
+//! Last-resort upload path: gzip'd NDJSON over a SigV4-signed PUT
+//!
+//! If a batch can't be written to the lake (Parquet encoding fails, the
+//! presigned upload or the lake's metadata keeps failing) but plain HTTPS to
+//! S3 works, the batch is uploaded as gzip'd newline-delimited JSON to
+//! `<data_path>/_fallback/<table>/` instead, so the data still leaves the
+//! device. A server-side job compacts these objects into the lake later.
+//! Enabled with the `ndjson_fb` NVS key.
+
+use std::io::Write as IoWrite;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use embedded_svc::http::client::Client as HttpClient;
+use embedded_svc::http::Method;
+use esp_idf_svc::http::client::EspHttpConnection;
+use esp_idf_svc::io::Write;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{error, info};
+use rusty_s3::Bucket;
+use serde_json::{Map, Value};
+
+use crate::clock::clock;
+use crate::credentials::S3Credentials;
+use crate::identity::DeviceIdentity;
+use crate::lake::{S3Target, UploadStats};
+use crate::net::{self, with_retry, HttpStatusError};
+use crate::sensors::{SensorReading, METRIC_NAMES};
+use crate::sigv4;
+use crate::UPLOAD_RETRY;
+
+const FALLBACK_DIR: &str = "_fallback";
+
+/// Upload `readings` as one gzip'd NDJSON object
+pub fn upload_batch(
+    target: &S3Target,
+    data_path: &str,
+    identity: &DeviceIdentity,
+    table: &str,
+    readings: &[SensorReading],
+) -> Result<UploadStats> {
+    let encode_start = Instant::now();
+    let body = encode_ndjson(readings, identity)?;
+    let encode_time = encode_start.elapsed();
+
+    let key = format!(
+        "{}/{}/{}/sensor_data_{}_{}.ndjson.gz",
+        data_path,
+        FALLBACK_DIR,
+        table,
+        identity.device_id,
+        readings.first().map_or(0, |r| r.timestamp)
+    );
+    let (bucket, credentials) = target.router.route(table, target.credentials);
+
+    let upload_start = Instant::now();
+    with_retry(&UPLOAD_RETRY, "NDJSON fallback upload", || {
+        put_signed(bucket, credentials, &key, &body)
+    })?;
+    let upload_time = upload_start.elapsed();
+    info!(
+        "  Fallback upload successful: s3://{}/{} ({} rows, {} bytes gzip'd)",
+        bucket.name(),
+        key,
+        readings.len(),
+        body.len()
+    );
+
+    Ok(UploadStats {
+        batches: 1,
+        rows: readings.len(),
+        bytes: body.len(),
+        encode_time,
+        upload_time,
+    })
+}
+
+/// One JSON object per reading, unset (NaN) fields as null
+fn encode_ndjson(readings: &[SensorReading], identity: &DeviceIdentity) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    for reading in readings {
+        let mut row = Map::new();
+        row.insert("timestamp".into(), reading.timestamp.into());
+        for (name, value) in METRIC_NAMES.iter().zip(reading.metrics()) {
+            row.insert(name.to_string(), value.into());
+        }
+        row.insert("sample_interval_ms".into(), reading.sample_interval_ms.into());
+        row.insert("warming_up".into(), reading.warming_up.into());
+        row.insert("device_id".into(), identity.device_id.as_str().into());
+        row.insert("firmware_version".into(), identity.firmware_version.as_str().into());
+        row.insert("location".into(), identity.location.as_str().into());
+        let ingested_at = (reading.ingested_at != 0).then_some(reading.ingested_at);
+        row.insert("ingested_at".into(), ingested_at.into());
+        row.insert("source".into(), reading.source.name().into());
+        let pipeline_version = (reading.pipeline_version != 0).then_some(reading.pipeline_version);
+        row.insert("pipeline_version".into(), pipeline_version.into());
+
+        serde_json::to_writer(&mut encoder, &Value::Object(row))?;
+        encoder.write_all(b"\n")?;
+    }
+    Ok(encoder.finish()?)
+}
+
+/// PUT `body` to `key`, signed in the Authorization header
+fn put_signed(bucket: &Bucket, credentials: &S3Credentials, key: &str, body: &[u8]) -> Result<()> {
+    let base = bucket.base_url();
+    let host = match (base.host_str(), base.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => return Err(anyhow!("bucket URL '{}' has no host", base)),
+    };
+    // Path-style base URLs already end in `/<bucket>/`
+    let path = format!("{}{}", base.path(), sigv4::encode_key(key));
+    let url = format!("{}://{}{}", base.scheme(), host, path);
+
+    let signed = sigv4::sign(
+        "s3",
+        "PUT",
+        &host,
+        &path,
+        bucket.region(),
+        credentials,
+        body,
+        clock().now_millis(),
+    );
+
+    let http_config = net::http_config(&url, Duration::from_secs(30));
+    let mut client = HttpClient::wrap(EspHttpConnection::new(&http_config)?);
+
+    let content_length = body.len().to_string();
+    let mut headers = vec![
+        ("Content-Type", "application/gzip"),
+        ("Content-Length", content_length.as_str()),
+        ("Authorization", signed.authorization.as_str()),
+        ("x-amz-date", signed.amz_date.as_str()),
+        ("x-amz-content-sha256", signed.content_sha256.as_str()),
+    ];
+    if let Some(token) = &signed.security_token {
+        headers.push(("x-amz-security-token", token.as_str()));
+    }
+    let mut request = client.request(Method::Put, &url, &headers)?;
+    request.write_all(body)?;
+    let response = request.submit()?;
+    let status = response.status();
+
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        let mut buf = [0u8; 512];
+        let mut reader = response;
+        let read = embedded_svc::io::Read::read(&mut reader, &mut buf).unwrap_or(0);
+        error!("  Fallback upload failed with status {}", status);
+        Err(HttpStatusError {
+            status,
+            body: String::from_utf8_lossy(&buf[..read]).into_owned(),
+        }
+        .into())
+    }
+}
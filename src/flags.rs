@@ -0,0 +1,234 @@
+//! Runtime feature flags, synced from a `feature_flags` table in the lake
+//!
+//! Operators switch optional subsystems off fleet-wide, without an OTA, by
+//! writing one Parquet file to `<data_path>/feature_flags/flags.parquet`,
+//! e.g. from DuckDB:
+//!
+//! ```sql
+//! COPY (SELECT * FROM (VALUES ('http', false, NULL), ('alerts', true, 'a0b1c2d3e4f5'))
+//!       AS t(flag, enabled, device_id))
+//! TO 's3://bucket/opensensor-test/feature_flags/flags.parquet';
+//! ```
+//!
+//! A row with this device's ID overrides a fleet-wide one (`device_id` NULL,
+//! empty or missing). Flags nobody mentions stay on. The uploader fetches
+//! the file every `flags_m` minutes with a forwarding round; a missing file
+//! turns every flag back on. The last flags seen are kept in NVS, so a
+//! subsystem switched off stays off across reboots, before the first fetch.
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use embedded_svc::http::Method;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::Field;
+use rusty_s3::S3Action;
+
+use crate::clock::clock;
+use crate::config::DeviceConfig;
+use crate::lake::S3Target;
+use crate::net::{send_capped, status_error, with_retry};
+use crate::{events, UPLOAD_RETRY};
+
+const NAMESPACE: &str = "flags";
+const FLAGS_TABLE: &str = "feature_flags";
+const FLAGS_FILE: &str = "flags.parquet";
+const MAX_FLAGS_LEN: usize = 16 * 1024;
+const PRESIGN_EXPIRY: Duration = Duration::from_secs(300);
+
+/// Subsystems that can be switched off at runtime
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Flags {
+    /// Scheduled and requested lake maintenance (`maint_m`, `POST /maintain`)
+    pub compaction: bool,
+    /// Alert rule evaluation and webhook notifications
+    pub alerts: bool,
+    /// `/query`, `/catalog` and `/maintain`; `/health` and `/export` stay up
+    pub http: bool,
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Self {
+            compaction: true,
+            alerts: true,
+            http: true,
+        }
+    }
+}
+
+impl Flags {
+    const NAMES: [&'static str; 3] = ["compaction", "alerts", "http"];
+
+    fn get(&self, name: &str) -> bool {
+        match name {
+            "compaction" => self.compaction,
+            "alerts" => self.alerts,
+            "http" => self.http,
+            _ => true,
+        }
+    }
+
+    fn set(&mut self, name: &str, enabled: bool) {
+        match name {
+            "compaction" => self.compaction = enabled,
+            "alerts" => self.alerts = enabled,
+            "http" => self.http = enabled,
+            _ => {}
+        }
+    }
+
+    /// Switched-off flags, for logging
+    pub fn describe(&self) -> String {
+        let off: Vec<&str> = Self::NAMES.into_iter().filter(|name| !self.get(name)).collect();
+        if off.is_empty() {
+            "all on".to_string()
+        } else {
+            format!("{} off", off.join(", "))
+        }
+    }
+}
+
+pub struct FeatureFlags {
+    nvs: EspNvs<NvsDefault>,
+    key: String,
+    device_id: String,
+    // `None` with `flags_m` = 0
+    interval: Option<Duration>,
+    // Monotonic time of the next fetch
+    next_fetch: Duration,
+    flags: Flags,
+}
+
+impl FeatureFlags {
+    /// The flags the last boot saw
+    pub fn load(
+        partition: EspDefaultNvsPartition,
+        config: &DeviceConfig,
+        device_id: &str,
+    ) -> Result<Self> {
+        let nvs: EspNvs<NvsDefault> = EspNvs::new(partition, NAMESPACE, true)?;
+        let interval = (config.flags_mins > 0)
+            .then(|| Duration::from_secs(u64::from(config.flags_mins) * 60));
+        let mut flags = Flags::default();
+        // Without syncing there is nothing that could switch them back on
+        if interval.is_some() {
+            for name in Flags::NAMES {
+                if let Some(enabled) = nvs.get_u8(name)? {
+                    flags.set(name, enabled != 0);
+                }
+            }
+        }
+        if flags != Flags::default() {
+            info!("Feature flags carried over: {}", flags.describe());
+        }
+        Ok(Self {
+            nvs,
+            key: format!("{}/{}", config.path_for(FLAGS_TABLE), FLAGS_FILE),
+            device_id: device_id.to_string(),
+            interval,
+            next_fetch: Duration::ZERO,
+            flags,
+        })
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    /// True if the flags table is due for a fetch
+    pub fn is_due(&self) -> bool {
+        self.interval.is_some() && clock().monotonic() >= self.next_fetch
+    }
+
+    /// Fetch the flags table and apply it; failures keep the current flags
+    pub fn refresh(&mut self, target: &S3Target) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        self.next_fetch = clock().monotonic() + interval;
+        let flags = match self.fetch(target) {
+            Ok(flags) => flags,
+            Err(e) => {
+                warn!("Feature flags fetch failed, keeping {}: {:?}", self.flags.describe(), e);
+                return;
+            }
+        };
+        if flags == self.flags {
+            return;
+        }
+
+        for name in Flags::NAMES {
+            let enabled = flags.get(name);
+            if enabled == self.flags.get(name) {
+                continue;
+            }
+            let detail = format!("{} {}", name, if enabled { "on" } else { "off" });
+            info!("Feature flag {}", detail);
+            events::report(events::FEATURE_FLAG, FLAGS_TABLE, detail);
+            if let Err(e) = self.nvs.set_u8(name, u8::from(enabled)) {
+                warn!("Failed to save feature flag '{}': {:?}", name, e);
+            }
+        }
+        self.flags = flags;
+    }
+
+    fn fetch(&self, target: &S3Target) -> Result<Flags> {
+        let (bucket, credentials) = target.router.route(FLAGS_TABLE, target.credentials);
+        let s3 = credentials.to_rusty_s3();
+        let url = bucket.get_object(Some(&s3), &self.key).sign(PRESIGN_EXPIRY);
+        let body = with_retry(&UPLOAD_RETRY, "Feature flags download", || {
+            match send_capped(Method::Get, url.as_str(), "", None, &[], MAX_FLAGS_LEN)? {
+                (200, body) => Ok(Some(body)),
+                // No table: nothing is switched off
+                (404, _) => Ok(None),
+                (status, body) => Err(status_error(status, &body)),
+            }
+        })?;
+        match body {
+            Some(body) => self.parse(body),
+            None => Ok(Flags::default()),
+        }
+    }
+
+    fn parse(&self, data: Vec<u8>) -> Result<Flags> {
+        let reader = SerializedFileReader::new(Bytes::from(data))?;
+        let mut fleet = Flags::default();
+        let mut device: Vec<(String, bool)> = Vec::new();
+
+        for row in reader.get_row_iter(None)? {
+            let row = row?;
+            let (mut flag, mut enabled, mut device_id) = (None, None, None);
+            for (name, field) in row.get_column_iter() {
+                match (name.as_str(), field) {
+                    ("flag", Field::Str(value)) => flag = Some(value.trim().to_ascii_lowercase()),
+                    ("enabled", Field::Bool(value)) => enabled = Some(*value),
+                    ("device_id", Field::Str(value)) if !value.is_empty() => {
+                        device_id = Some(value.as_str())
+                    }
+                    _ => {}
+                }
+            }
+            let (Some(flag), Some(enabled)) = (flag, enabled) else {
+                bail!("flags rows need a 'flag' string and an 'enabled' boolean");
+            };
+            if !Flags::NAMES.contains(&flag.as_str()) {
+                warn!("Ignoring unknown feature flag '{}'", flag);
+                continue;
+            }
+            match device_id {
+                None => fleet.set(&flag, enabled),
+                Some(id) if id == self.device_id => device.push((flag, enabled)),
+                Some(_) => {}
+            }
+        }
+
+        for (flag, enabled) in device {
+            fleet.set(&flag, enabled);
+        }
+        Ok(fleet)
+    }
+}
@@ -0,0 +1,189 @@
+//! Data freshness self-check with escalating remediation
+//!
+//! A device can look healthy while nothing it writes lands: a catalog handle
+//! gone bad, a WiFi association that passes no traffic, a wedged driver.
+//! With `fresh_m` set, every `fresh_m` minutes the forwarding round asks the
+//! lake itself for the newest data of the sensor table (`newest_data`): the
+//! catalog's newest file confirmed in S3 with a HEAD request, the newest
+//! file of this boot for plain Parquet, the current snapshot for Iceberg.
+//! If that is older than `fresh_m` minutes, or the round trip fails, the
+//! device takes the next remediation step:
+//!
+//! 1. re-attach the lake
+//! 2. reconnect WiFi
+//! 3. save its state like before deep sleep and reboot
+//!
+//! Each step waits a full period for rows to show up before the next one.
+//! Once rows are fresh again the escalation starts over; if they still
+//! aren't after the reboot, the device keeps reporting but takes no further
+//! steps, so a device that can't heal itself doesn't reboot in a loop. Every
+//! step and the recovery are recorded as `stale_data` rows of
+//! `device_events`. The step reached is kept in NVS, the reboot recorded by
+//! the next boot.
+//!
+//! Rounds lost to an S3 outage, which the upload backoff already reports,
+//! aren't checked.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{error, info, warn};
+
+use crate::clock::clock;
+use crate::config::DeviceConfig;
+use crate::events;
+use crate::lake::{LakeBackend, S3Target};
+
+const NAMESPACE: &str = "fresh";
+
+// Remediation steps taken since the rows went stale
+const KEY_STEP: &str = "step";
+// Why the device rebooted, for the next boot to record
+const KEY_REBOOTED: &str = "rebooted";
+const MAX_DETAIL_LEN: usize = 192;
+
+/// A remediation step, in escalation order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Remedy {
+    Reattach,
+    ReconnectWifi,
+    Reboot,
+}
+
+impl Remedy {
+    const ALL: [Remedy; 3] = [Remedy::Reattach, Remedy::ReconnectWifi, Remedy::Reboot];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Remedy::Reattach => "reattach",
+            Remedy::ReconnectWifi => "reconnect_wifi",
+            Remedy::Reboot => "reboot",
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Remedy::Reattach => "re-attaching the lake",
+            Remedy::ReconnectWifi => "reconnecting WiFi",
+            Remedy::Reboot => "rebooting",
+        }
+    }
+}
+
+pub struct FreshnessCheck {
+    nvs: EspNvs<NvsDefault>,
+    table: String,
+    max_age: Duration,
+    // Monotonic time of the last check, boot for the first one
+    checked: Duration,
+    // Remedies taken so far, 0 while rows are fresh
+    step: u8,
+    gave_up: bool,
+}
+
+impl FreshnessCheck {
+    /// `None` with `fresh_m` = 0; records a reboot the last boot took
+    pub fn load(partition: EspDefaultNvsPartition, config: &DeviceConfig) -> Result<Option<Self>> {
+        if config.fresh_mins == 0 {
+            return Ok(None);
+        }
+        let mut nvs: EspNvs<NvsDefault> = EspNvs::new(partition, NAMESPACE, true)?;
+        let step = nvs.get_u8(KEY_STEP)?.unwrap_or(0);
+        let mut buf = [0u8; MAX_DETAIL_LEN + 1];
+        if let Some(detail) = nvs.get_str(KEY_REBOOTED, &mut buf)?.map(str::to_string) {
+            nvs.remove(KEY_REBOOTED)?;
+            events::report(events::STALE_DATA, &config.table_name, detail);
+        }
+        if step > 0 {
+            warn!("Rows were stale at the last boot, {} remediation step(s) taken", step);
+        }
+        Ok(Some(Self {
+            nvs,
+            table: config.table_name.clone(),
+            max_age: Duration::from_secs(u64::from(config.fresh_mins) * 60),
+            checked: clock().monotonic(),
+            step,
+            gave_up: false,
+        }))
+    }
+
+    pub fn is_due(&self) -> bool {
+        clock().elapsed_since(self.checked) >= self.max_age
+    }
+
+    /// Ask the lake for the table's newest data, returning the remedy to
+    /// apply if it is stale
+    pub fn check(&mut self, lake: &mut dyn LakeBackend, target: &S3Target) -> Option<Remedy> {
+        self.checked = clock().monotonic();
+        let now_ms = clock().now_millis();
+        let max_age_ms = self.max_age.as_millis() as i64;
+        let problem = match lake.newest_data(target, &self.table) {
+            Ok(Some(newest_ms)) if now_ms - newest_ms <= max_age_ms => {
+                self.fresh();
+                return None;
+            }
+            Ok(Some(newest_ms)) => format!("newest row {} min old", (now_ms - newest_ms) / 60_000),
+            Ok(None) => "no rows".to_string(),
+            Err(e) => format!("query failed: {:#}", e),
+        };
+        let problem = format!(
+            "no rows newer than {} min in the {} lake ({})",
+            max_age_ms / 60_000,
+            lake.name(),
+            problem
+        );
+
+        let Some(&remedy) = Remedy::ALL.get(usize::from(self.step)) else {
+            if !self.gave_up {
+                self.gave_up = true;
+                error!(
+                    event = "stale_data", table = self.table.as_str();
+                    "Still {} after rebooting, no remedy left", problem
+                );
+                let detail = format!("{}, no remedy left", problem);
+                events::report(events::STALE_DATA, &self.table, detail);
+            }
+            return None;
+        };
+        warn!(
+            event = "stale_data", table = self.table.as_str(), remedy = remedy.name();
+            "{}, {}", problem, remedy.describe()
+        );
+        self.step += 1;
+        self.save();
+        let mut detail = format!("{}, {}", problem, remedy.describe());
+        if remedy == Remedy::Reboot {
+            // Events still queued die with the reboot
+            while detail.len() > MAX_DETAIL_LEN {
+                detail.pop();
+            }
+            if let Err(e) = self.nvs.set_str(KEY_REBOOTED, &detail) {
+                error!("Failed to save the freshness reboot: {:?}", e);
+            }
+        } else {
+            events::report(events::STALE_DATA, &self.table, detail);
+        }
+        Some(remedy)
+    }
+
+    /// Rows are fresh: the escalation starts over
+    fn fresh(&mut self) {
+        if self.step == 0 {
+            return;
+        }
+        let remedy = Remedy::ALL[usize::from(self.step).min(Remedy::ALL.len()) - 1];
+        info!("Rows are fresh again after {}", remedy.describe());
+        let detail = format!("rows fresh again after {}", remedy.describe());
+        events::report(events::STALE_DATA, &self.table, detail);
+        self.step = 0;
+        self.gave_up = false;
+        self.save();
+    }
+
+    fn save(&mut self) {
+        if let Err(e) = self.nvs.set_u8(KEY_STEP, self.step) {
+            error!("Failed to save the freshness remediation step: {:?}", e);
+        }
+    }
+}
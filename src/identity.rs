@@ -0,0 +1,132 @@
+//! Identity of the device writing the data
+//!
+//! Every row written to the lake carries the ID, firmware version and
+//! location of the node that wrote it, so many nodes can share one table.
+//! Where the ID comes from is up to an `IdProvider`, selected by the
+//! `id_src` NVS key:
+//!
+//! - `nvs` (default): the `device_id` NVS key, set by hand, config sync or
+//!   an enrollment bundle, and the station MAC address (hex) while it is empty
+//! - `mac`: always the station MAC address
+//! - `efuse`: the custom MAC address burnt into eFuse (`BLK3`), for fleets
+//!   that program their own serial numbers at the factory
+//!
+//! Integrators with IDs elsewhere, e.g. a secure element's serial number,
+//! implement the trait and `register` it under a name of their own before
+//! the identity is built. The location is the free-form `location` key.
+
+use std::sync::Mutex;
+
+use anyhow::{anyhow, bail, Result};
+use esp_idf_svc::sys::{
+    esp, esp_efuse_mac_get_custom, esp_mac_type_t_ESP_MAC_WIFI_STA, esp_read_mac,
+};
+
+use crate::config::DeviceConfig;
+use crate::enrollment::hex;
+
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Source of the device ID
+pub trait IdProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn device_id(&self, config: &DeviceConfig) -> Result<String>;
+}
+
+struct NvsId;
+struct MacId;
+struct EfuseId;
+
+impl IdProvider for NvsId {
+    fn name(&self) -> &'static str {
+        "nvs"
+    }
+
+    fn device_id(&self, config: &DeviceConfig) -> Result<String> {
+        if config.device_id.is_empty() {
+            mac_device_id()
+        } else {
+            Ok(config.device_id.clone())
+        }
+    }
+}
+
+impl IdProvider for MacId {
+    fn name(&self) -> &'static str {
+        "mac"
+    }
+
+    fn device_id(&self, _config: &DeviceConfig) -> Result<String> {
+        mac_device_id()
+    }
+}
+
+impl IdProvider for EfuseId {
+    fn name(&self) -> &'static str {
+        "efuse"
+    }
+
+    fn device_id(&self, _config: &DeviceConfig) -> Result<String> {
+        let mut mac = [0u8; 6];
+        esp!(unsafe { esp_efuse_mac_get_custom(mac.as_mut_ptr()) })
+            .map_err(|e| anyhow!("no custom MAC address in eFuse: {}", e))?;
+        Ok(hex(&mac))
+    }
+}
+
+// Providers registered by integrators
+static PROVIDERS: Mutex<Vec<&'static dyn IdProvider>> = Mutex::new(Vec::new());
+
+/// Make `provider` selectable as `id_src` = `provider.name()`
+#[allow(dead_code)] // Entry point for integrators
+pub fn register(provider: &'static dyn IdProvider) {
+    if let Ok(mut providers) = PROVIDERS.lock() {
+        providers.retain(|registered| registered.name() != provider.name());
+        providers.push(provider);
+    }
+}
+
+fn provider(name: &str) -> Result<&'static dyn IdProvider> {
+    Ok(match name {
+        "nvs" => &NvsId,
+        "mac" => &MacId,
+        "efuse" => &EfuseId,
+        other => {
+            let providers = PROVIDERS.lock().map_err(|_| anyhow!("ID providers poisoned"))?;
+            match providers.iter().find(|provider| provider.name() == other) {
+                Some(provider) => *provider,
+                None => bail!("unknown device ID provider '{}'", other),
+            }
+        }
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    pub device_id: String,
+    pub firmware_version: String,
+    pub location: String,
+}
+
+impl DeviceIdentity {
+    pub fn from_config(config: &DeviceConfig) -> Result<Self> {
+        let provider = provider(&config.id_source)?;
+        let device_id = provider.device_id(config)?;
+        if device_id.is_empty() {
+            bail!("the '{}' device ID provider returned an empty ID", provider.name());
+        }
+
+        Ok(Self {
+            device_id,
+            firmware_version: FIRMWARE_VERSION.to_string(),
+            location: config.location.clone(),
+        })
+    }
+}
+
+/// The station MAC address, hex; readable before WiFi starts
+fn mac_device_id() -> Result<String> {
+    let mut mac = [0u8; 6];
+    esp!(unsafe { esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_WIFI_STA) })?;
+    Ok(hex(&mac))
+}
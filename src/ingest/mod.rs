@@ -0,0 +1,14 @@
+//! Ingestion of data that doesn't come from the on-board sensors
+//!
+//! - `watch` - CSV files dropped on the SD card by other equipment (`sdcard`)
+//! - `mqtt` - JSON readings published by other sensors on the LAN (`mqtt`)
+
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(feature = "sdcard")]
+mod watch;
+
+#[cfg(feature = "mqtt")]
+pub use mqtt::MqttSource;
+#[cfg(feature = "sdcard")]
+pub use watch::{WatchFolder, SCAN_INTERVAL};
@@ -0,0 +1,325 @@
+//! MQTT ingestion of JSON readings published by other sensors on the LAN
+//!
+//! Subscribes to the `mqtt_topics` NVS key, a comma-separated list of topic
+//! filters with an optional target table (`+` and `#` wildcards allowed):
+//!
+//! ```text
+//! lan/+/air=lan_air,weather/station
+//! ```
+//!
+//! Topics without a table go to the on-board sensor table. A payload is one
+//! JSON object or an array of them, with sensor fields by name and an
+//! optional `timestamp` in Unix milliseconds (time of receipt otherwise).
+//! Gzip'd JSON and CBOR are decoded too (see `payload.rs`):
+//!
+//! ```json
+//! {"timestamp": 1700000000000, "temperature": 21.4, "humidity": 48}
+//! ```
+//!
+//! Unknown fields are ignored, missing ones are recorded as NaN. Readings are
+//! batched per table under the same `FlushPolicy` as the on-board sensors.
+//!
+//! With a `ctl_token`, the client also subscribes to
+//! `esp32s3-ducklake/<device_id>/pipeline`, where a `pause <ctl_token>` or
+//! `resume <ctl_token>` payload holds or drops the MQTT pause of the
+//! pipeline (see `pause.rs`). Commands without the token are ignored. While
+//! the pipeline is paused, readings received are dropped and nothing is
+//! batched.
+
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::mqtt::client::{Details, EspMqttClient, EventPayload, MqttClientConfiguration, QoS};
+use esp_idf_svc::sys::EspError;
+use log::{info, warn};
+use serde_json::Value;
+
+use crate::clock::clock;
+use crate::pause::{self, Holder, PauseState};
+use crate::payload::{self, PayloadEncoding};
+use crate::pipeline::{free_heap, FlushPolicy};
+use crate::sensors::{SensorReading, Source, METRIC_NAMES};
+
+// Readings waiting for the ingestion loop; further messages are dropped
+const QUEUE_LEN: usize = 256;
+const EVENTS_STACK_SIZE: usize = 6144;
+
+struct Subscription {
+    filter: String,
+    table: Option<String>,
+}
+
+/// Readings of one table that haven't been flushed yet
+struct Pending {
+    readings: Vec<SensorReading>,
+    started: Duration,
+}
+
+pub struct MqttSource {
+    client: EspMqttClient<'static>,
+    subscriptions: Vec<Subscription>,
+    /// Pause / resume commands for this device, `None` without a token
+    control: Option<String>,
+    /// Set by the event thread on (re)connect
+    connected: Arc<AtomicBool>,
+    received: Receiver<(String, SensorReading)>,
+    pending: HashMap<String, Pending>,
+    /// Of published messages
+    encoding: PayloadEncoding,
+}
+
+impl MqttSource {
+    /// Connect to `url` (e.g. `mqtt://192.168.1.10:1883`) and subscribe to
+    /// `topics`, whose readings default to `default_table`. Messages are
+    /// published in `encoding`; pause commands need `control_token`.
+    pub fn start(
+        url: &str,
+        topics: &str,
+        client_id: &str,
+        default_table: &str,
+        encoding: PayloadEncoding,
+        control_token: &str,
+    ) -> Result<Self> {
+        let subscriptions = parse_topics(topics)?;
+        let control = (!control_token.is_empty())
+            .then(|| format!("esp32s3-ducklake/{}/pipeline", client_id));
+        let client_id = format!("esp32s3-ducklake-{}", client_id);
+        let config = MqttClientConfiguration {
+            client_id: Some(&client_id),
+            ..Default::default()
+        };
+        let (client, mut connection) = EspMqttClient::new(url, &config)?;
+
+        let (sender, received) = mpsc::sync_channel(QUEUE_LEN);
+        let connected = Arc::new(AtomicBool::new(false));
+        let routes: Vec<(String, String)> = subscriptions
+            .iter()
+            .map(|s| {
+                let table = s.table.as_deref().unwrap_or(default_table);
+                (s.filter.clone(), table.to_string())
+            })
+            .collect();
+        let on_connect = connected.clone();
+        let control = control.map(|topic| (topic, control_token.to_string()));
+        let commands = control.clone();
+        thread::Builder::new()
+            .name("mqtt".into())
+            .stack_size(EVENTS_STACK_SIZE)
+            .spawn(move || {
+                while let Ok(event) = connection.next() {
+                    let payload = event.payload();
+                    on_event(payload, commands.as_ref(), &routes, &on_connect, &sender);
+                }
+                warn!("MQTT connection closed");
+            })?;
+
+        info!("MQTT ingestion from {}", url);
+        Ok(Self {
+            client,
+            subscriptions,
+            control: control.map(|(topic, _)| topic),
+            connected,
+            received,
+            pending: HashMap::new(),
+            encoding,
+        })
+    }
+
+    /// Batch the readings received since the last call, handing every batch
+    /// that hits `policy` to `sink`
+    pub fn poll<F>(&mut self, policy: &FlushPolicy, mut sink: F)
+    where
+        F: FnMut(&str, Vec<SensorReading>),
+    {
+        // Subscribing from the event thread would block the MQTT task
+        if self.connected.swap(false, Ordering::Relaxed) {
+            for subscription in &self.subscriptions {
+                if let Err(e) = self.client.subscribe(&subscription.filter, QoS::AtMostOnce) {
+                    warn!("MQTT subscribe to '{}' failed: {:?}", subscription.filter, e);
+                }
+            }
+            if let Some(control) = &self.control {
+                if let Err(e) = self.client.subscribe(control, QoS::AtLeastOnce) {
+                    warn!("MQTT subscribe to '{}' failed: {:?}", control, e);
+                }
+            }
+            info!("MQTT subscribed to {} topics", self.subscriptions.len());
+        }
+
+        while let Ok((table, reading)) = self.received.try_recv() {
+            self.pending
+                .entry(table)
+                .or_insert_with(|| Pending {
+                    readings: Vec::new(),
+                    started: clock().monotonic(),
+                })
+                .readings
+                .push(reading);
+        }
+
+        let heap = free_heap();
+        for (table, pending) in self.pending.iter_mut() {
+            let age = clock().elapsed_since(pending.started);
+            if let Some(reason) = policy.check(pending.readings.len(), age, heap) {
+                info!(
+                    "Flushing {} MQTT rows for '{}' ({})",
+                    pending.readings.len(),
+                    table,
+                    reason
+                );
+                sink(table, std::mem::take(&mut pending.readings));
+            }
+        }
+        self.pending.retain(|_, pending| !pending.readings.is_empty());
+    }
+
+    /// Publish `message` on `topic`, retained so a dashboard connecting
+    /// later still sees it
+    pub fn publish(&mut self, topic: &str, message: &Value) -> Result<()> {
+        let payload = payload::encode(message, self.encoding)?;
+        self.client.publish(topic, QoS::AtLeastOnce, true, &payload)?;
+        Ok(())
+    }
+}
+
+/// Queue the readings of a received message, or apply a pause / resume
+/// command on the `control` topic and token. `routes` maps topic filters to
+/// tables.
+fn on_event(
+    payload: EventPayload<'_, EspError>,
+    control: Option<&(String, String)>,
+    routes: &[(String, String)],
+    connected: &AtomicBool,
+    sender: &SyncSender<(String, SensorReading)>,
+) {
+    match payload {
+        // Sessions are clean, so every (re)connect subscribes again
+        EventPayload::Connected(_) => {
+            info!("MQTT connected");
+            connected.store(true, Ordering::Relaxed);
+        }
+        EventPayload::Disconnected => warn!("MQTT disconnected, reconnecting..."),
+        EventPayload::Received {
+            topic: Some(topic),
+            data,
+            details: Details::Complete,
+            ..
+        } if control.is_some_and(|(control, _)| topic == control.as_str()) => {
+            let token = control.map_or("", |(_, token)| token.as_str());
+            let command = String::from_utf8_lossy(data);
+            let command = command.trim();
+            let (command, presented) = command.split_once(' ').unwrap_or((command, ""));
+            if !pause::token_matches(presented.trim(), token) {
+                warn!("Ignoring a pipeline command without the control token on '{}'", topic);
+                return;
+            }
+            match command {
+                "pause" => pause::pause(Holder::Mqtt),
+                "resume" => pause::resume(Holder::Mqtt),
+                other => warn!("Unknown pipeline command '{}' on '{}'", other, topic),
+            }
+        }
+        // Ingestion stops with the pipeline
+        EventPayload::Received { .. } if pause::state() == PauseState::Paused => {}
+        EventPayload::Received {
+            topic: Some(topic),
+            data,
+            details: Details::Complete,
+            ..
+        } => {
+            let Some((_, table)) = routes.iter().find(|(filter, _)| topic_matches(filter, topic))
+            else {
+                return;
+            };
+            let readings = match parse_payload(data) {
+                Ok(readings) => readings,
+                Err(e) => {
+                    warn!("Invalid MQTT payload on '{}': {:?}", topic, e);
+                    return;
+                }
+            };
+            for reading in readings {
+                if let Err(TrySendError::Full(_)) = sender.try_send((table.clone(), reading)) {
+                    warn!("MQTT queue full, dropping reading from '{}'", topic);
+                }
+            }
+        }
+        // Chunked: larger than the client's receive buffer
+        EventPayload::Received { topic, .. } => {
+            warn!("Ignoring oversized MQTT message on {:?}", topic);
+        }
+        _ => {}
+    }
+}
+
+/// `filter[=table]`, comma-separated
+fn parse_topics(topics: &str) -> Result<Vec<Subscription>> {
+    let subscriptions: Vec<Subscription> = topics
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once('=') {
+            Some((filter, table)) => Subscription {
+                filter: filter.trim().to_string(),
+                table: Some(table.trim().to_string()),
+            },
+            None => Subscription {
+                filter: entry.to_string(),
+                table: None,
+            },
+        })
+        .collect();
+    if subscriptions.is_empty() {
+        return Err(anyhow!("no MQTT topics configured"));
+    }
+    Ok(subscriptions)
+}
+
+/// MQTT topic filter matching with `+` (one level) and `#` (the rest)
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (part, Some(level)) if part == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+/// One reading per JSON object
+fn parse_payload(data: &[u8]) -> Result<Vec<SensorReading>> {
+    let objects = match payload::decode(data, None)? {
+        Value::Array(items) => items,
+        object => vec![object],
+    };
+
+    objects
+        .iter()
+        .map(|object| {
+            let fields = object
+                .as_object()
+                .ok_or_else(|| anyhow!("expected a JSON object"))?;
+            let timestamp = fields
+                .get("timestamp")
+                .and_then(Value::as_i64)
+                .unwrap_or_else(|| clock().now_millis());
+
+            let mut reading = SensorReading::empty(timestamp);
+            for name in METRIC_NAMES {
+                if let Some(value) = fields.get(name).and_then(Value::as_f64) {
+                    reading.set_metric(name, value as f32);
+                }
+            }
+            reading.stamp(Source::Mqtt);
+            Ok(reading)
+        })
+        .collect()
+}
@@ -0,0 +1,254 @@
+//! Watch-folder ingestion of CSV files from other equipment (dataloggers)
+//!
+//! Files dropped into `<sdcard>/inbox` are matched by file-name prefix against
+//! the source mappings in `<sdcard>/ingest.json`, which name the timestamp
+//! column and map CSV columns onto sensor fields:
+//!
+//! ```json
+//! {"sources": [{
+//!     "name": "datalogger",
+//!     "prefix": "LOG",
+//!     "table": "datalogger",
+//!     "delimiter": ",",
+//!     "timestamp": {"column": "time", "format": "unix_s"},
+//!     "columns": {"temperature": "Temp_C", "humidity": "RH_%"}
+//! }]}
+//! ```
+//!
+//! A file is only picked up once its size is unchanged between two scans, so
+//! files still being written are left alone. Ingested files are moved to
+//! `archive/`, files that can't be parsed to `failed/`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::clock::parse_utc;
+use crate::sensors::{SensorReading, Source, METRIC_NAMES};
+
+const MAPPINGS_FILE: &str = "ingest.json";
+const INBOX_DIR: &str = "inbox";
+const ARCHIVE_DIR: &str = "archive";
+const FAILED_DIR: &str = "failed";
+
+pub const SCAN_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+struct Mappings {
+    sources: Vec<SourceMapping>,
+}
+
+#[derive(Deserialize)]
+struct SourceMapping {
+    name: String,
+    prefix: String,
+    /// Target table, defaults to the on-board sensor table
+    table: Option<String>,
+    #[serde(default = "default_delimiter")]
+    delimiter: char,
+    timestamp: TimestampMapping,
+    /// Sensor field -> CSV column header
+    columns: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct TimestampMapping {
+    column: String,
+    format: TimestampFormat,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TimestampFormat {
+    UnixS,
+    UnixMs,
+    /// `YYYY-MM-DD HH:MM:SS` or `YYYY-MM-DDTHH:MM:SS`, UTC
+    Iso8601,
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+pub struct WatchFolder {
+    root: PathBuf,
+    mappings: Vec<SourceMapping>,
+    /// Size of each inbox file at the previous scan
+    sizes: HashMap<PathBuf, u64>,
+}
+
+impl WatchFolder {
+    /// Load the source mappings from `root`; `None` if there aren't any
+    pub fn new(root: &str) -> Result<Option<Self>> {
+        let root = PathBuf::from(root);
+        let mappings_path = root.join(MAPPINGS_FILE);
+        if !mappings_path.exists() {
+            info!("No {} on the SD card, watch folder disabled", MAPPINGS_FILE);
+            return Ok(None);
+        }
+
+        let mappings: Mappings = serde_json::from_slice(&fs::read(&mappings_path)?)?;
+        for mapping in &mappings.sources {
+            for field in mapping.columns.keys() {
+                if !METRIC_NAMES.contains(&field.as_str()) {
+                    bail!("source '{}' maps unknown field '{}'", mapping.name, field);
+                }
+            }
+        }
+
+        for dir in [INBOX_DIR, ARCHIVE_DIR, FAILED_DIR] {
+            fs::create_dir_all(root.join(dir))?;
+        }
+        info!(
+            "Watching {} for {} sources",
+            root.join(INBOX_DIR).display(),
+            mappings.sources.len()
+        );
+
+        Ok(Some(Self {
+            root,
+            mappings: mappings.sources,
+            sizes: HashMap::new(),
+        }))
+    }
+
+    /// Ingest every settled inbox file, handing batches of at most `max_rows`
+    /// readings for their table to `sink`
+    pub fn scan<F>(&mut self, max_rows: usize, default_table: &str, mut sink: F)
+    where
+        F: FnMut(&str, Vec<SensorReading>),
+    {
+        let entries = match fs::read_dir(self.root.join(INBOX_DIR)) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Watch folder unreadable: {:?}", e);
+                return;
+            }
+        };
+
+        let mut sizes = HashMap::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let metadata = entry.metadata();
+            let Some(size) = metadata.ok().filter(|m| m.is_file()).map(|m| m.len()) else {
+                continue;
+            };
+            sizes.insert(path.clone(), size);
+
+            // Still being written: wait for the size to settle
+            if self.sizes.get(&path) != Some(&size) {
+                continue;
+            }
+
+            let outcome = self.ingest_file(&path, max_rows, default_table, &mut sink);
+            let target_dir = match outcome {
+                Ok(rows) => {
+                    info!("Ingested {} rows from {}", rows, path.display());
+                    ARCHIVE_DIR
+                }
+                Err(e) => {
+                    warn!("Failed to ingest {}: {:?}", path.display(), e);
+                    FAILED_DIR
+                }
+            };
+            if let Err(e) = self.move_to(&path, target_dir) {
+                warn!("Failed to move {}: {:?}", path.display(), e);
+            }
+            sizes.remove(&path);
+        }
+
+        self.sizes = sizes;
+    }
+
+    fn ingest_file<F>(
+        &self,
+        path: &Path,
+        max_rows: usize,
+        default_table: &str,
+        sink: &mut F,
+    ) -> Result<usize>
+    where
+        F: FnMut(&str, Vec<SensorReading>),
+    {
+        let file_name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow!("invalid file name"))?;
+        let mapping = self
+            .mappings
+            .iter()
+            .find(|m| file_name.starts_with(&m.prefix))
+            .ok_or_else(|| anyhow!("no source mapping matches"))?;
+        let table = mapping.table.as_deref().unwrap_or(default_table);
+
+        let readings = parse_csv(&fs::read_to_string(path)?, mapping)?;
+        let rows = readings.len();
+        for chunk in readings.chunks(max_rows.max(1)) {
+            sink(table, chunk.to_vec());
+        }
+
+        Ok(rows)
+    }
+
+    fn move_to(&self, path: &Path, dir: &str) -> Result<()> {
+        let file_name = path.file_name().ok_or_else(|| anyhow!("invalid file name"))?;
+        fs::rename(path, self.root.join(dir).join(file_name))?;
+        Ok(())
+    }
+}
+
+fn parse_csv(content: &str, mapping: &SourceMapping) -> Result<Vec<SensorReading>> {
+    let mut lines = content.lines().filter(|l| !l.trim().is_empty());
+    let header: Vec<&str> = lines
+        .next()
+        .ok_or_else(|| anyhow!("empty file"))?
+        .split(mapping.delimiter)
+        .map(str::trim)
+        .collect();
+    let position = |column: &str| {
+        header
+            .iter()
+            .position(|h| *h == column)
+            .ok_or_else(|| anyhow!("column '{}' not in header", column))
+    };
+
+    let timestamp_index = position(&mapping.timestamp.column)?;
+    let field_indices = mapping
+        .columns
+        .iter()
+        .map(|(field, column)| -> Result<(&str, usize)> { Ok((field, position(column)?)) })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut readings = Vec::new();
+    for (line_no, line) in lines.enumerate() {
+        let values: Vec<&str> = line.split(mapping.delimiter).map(str::trim).collect();
+        let value = |i: usize| values.get(i).copied().unwrap_or("");
+
+        let timestamp = parse_timestamp(value(timestamp_index), mapping.timestamp.format)
+            .ok_or_else(|| anyhow!("line {}: invalid timestamp", line_no + 2))?;
+        let mut reading = SensorReading::empty(timestamp);
+        for (field, index) in &field_indices {
+            // Missing or malformed values stay NaN, like a failed sensor read
+            let v = value(*index).parse().unwrap_or(f32::NAN);
+            reading.set_metric(field, v);
+        }
+        reading.stamp(Source::Watch);
+        readings.push(reading);
+    }
+
+    Ok(readings)
+}
+
+/// Timestamp in milliseconds since the Unix epoch
+fn parse_timestamp(value: &str, format: TimestampFormat) -> Option<i64> {
+    match format {
+        TimestampFormat::UnixS => value.parse::<f64>().ok().map(|s| (s * 1000.0) as i64),
+        TimestampFormat::UnixMs => value.parse().ok(),
+        TimestampFormat::Iso8601 => parse_utc(value),
+    }
+}
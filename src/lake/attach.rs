@@ -0,0 +1,190 @@
+//! Time-boxed attach with per-stage progress
+//!
+//! Attaching can block for a long time (a slow REST catalog, a huge catalog
+//! file on flash), so `lake::open` runs `LakeBackend::attach` on a thread of
+//! its own and gives up after `attach_s` seconds. Backends report the stage
+//! they are in with `enter`:
+//!
+//! 1. `backend` - set-up from the configuration
+//! 2. `secrets` - catalog credentials (Iceberg OAuth2 token)
+//! 3. `catalog` - opening the catalog (flash file, REST `/v1/config`)
+//! 4. `data_path` - checking the data path against the catalog
+//!
+//! Each stage is logged with its duration, and the outcome, the stage an
+//! attach got stuck in and the per-stage times stay available to `/health`
+//! through `status()`. A thread that timed out is left behind: it can't be
+//! cancelled, and it no longer touches anything the loop uses.
+
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+
+use super::LakeBackend;
+use crate::clock::clock;
+
+// Room for a TLS handshake with the REST catalog
+const ATTACH_STACK_SIZE: usize = 24 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttachStage {
+    Pending,
+    Backend,
+    Secrets,
+    Catalog,
+    DataPath,
+    Attached,
+    Failed,
+    TimedOut,
+}
+
+impl AttachStage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Backend => "backend",
+            Self::Secrets => "secrets",
+            Self::Catalog => "catalog",
+            Self::DataPath => "data_path",
+            Self::Attached => "attached",
+            Self::Failed => "failed",
+            Self::TimedOut => "timed_out",
+        }
+    }
+}
+
+/// Where the last attach is, or where it ended
+#[allow(dead_code)] // Only read by the HTTP server
+#[derive(Clone, Debug)]
+pub struct AttachStatus {
+    pub backend: &'static str,
+    pub stage: AttachStage,
+    /// Stage the attach failed or timed out in
+    pub stuck_in: Option<AttachStage>,
+    pub elapsed_ms: u64,
+    /// Milliseconds spent in each stage entered so far
+    pub stages: Vec<(AttachStage, u64)>,
+    pub error: Option<String>,
+}
+
+struct Progress {
+    status: AttachStatus,
+    started: Duration,
+    stage_started: Duration,
+}
+
+static PROGRESS: Mutex<Option<Progress>> = Mutex::new(None);
+
+/// Status of the last attach; `None` before the first one
+#[allow(dead_code)] // Only used by the HTTP server
+pub fn status() -> Option<AttachStatus> {
+    let progress = PROGRESS.lock().ok()?;
+    progress.as_ref().map(|p| {
+        let mut status = p.status.clone();
+        if !matches!(
+            status.stage,
+            AttachStage::Attached | AttachStage::Failed | AttachStage::TimedOut
+        ) {
+            status.elapsed_ms = clock().elapsed_since(p.started).as_millis() as u64;
+        }
+        status
+    })
+}
+
+/// Record that the running attach moved on to `stage`
+pub fn enter(stage: AttachStage) {
+    let Ok(mut progress) = PROGRESS.lock() else {
+        return;
+    };
+    let Some(progress) = progress.as_mut() else {
+        return;
+    };
+    // An attach that already timed out doesn't get to change the outcome
+    if progress.status.stage == AttachStage::TimedOut {
+        return;
+    }
+
+    let now = clock().monotonic();
+    let spent = now.saturating_sub(progress.stage_started).as_millis() as u64;
+    let previous = progress.status.stage;
+    if previous != AttachStage::Pending {
+        progress.status.stages.push((previous, spent));
+        info!("  Attach stage '{}' took {} ms", previous.name(), spent);
+    }
+    progress.status.stage = stage;
+    progress.status.elapsed_ms = now.saturating_sub(progress.started).as_millis() as u64;
+    progress.stage_started = now;
+}
+
+/// Run `backend.attach()` for at most `timeout`, handing the backend back if it
+/// finished
+pub fn attach(
+    mut backend: Box<dyn LakeBackend>,
+    timeout: Duration,
+) -> Result<Box<dyn LakeBackend>> {
+    let name = backend.name();
+    if let Ok(mut progress) = PROGRESS.lock() {
+        let now = clock().monotonic();
+        *progress = Some(Progress {
+            status: AttachStatus {
+                backend: name,
+                stage: AttachStage::Pending,
+                stuck_in: None,
+                elapsed_ms: 0,
+                stages: Vec::new(),
+                error: None,
+            },
+            started: now,
+            stage_started: now,
+        });
+    }
+    info!("Attaching the {} lake (timeout {:?})...", name, timeout);
+
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new()
+        .name("lake-attach".into())
+        .stack_size(ATTACH_STACK_SIZE)
+        .spawn(move || {
+            enter(AttachStage::Backend);
+            let result = backend.attach();
+            let _ = sender.send((backend, result));
+        })?;
+
+    let outcome = match receiver.recv_timeout(timeout) {
+        Ok((backend, Ok(()))) => Ok(backend),
+        Ok((_, Err(e))) => Err((AttachStage::Failed, e)),
+        Err(_) => Err((
+            AttachStage::TimedOut,
+            anyhow!("attaching the {} lake timed out after {:?}", name, timeout),
+        )),
+    };
+
+    let stuck_in = status().map(|s| s.stage);
+    match outcome {
+        Ok(backend) => {
+            enter(AttachStage::Attached);
+            let elapsed = status().map_or(0, |s| s.elapsed_ms);
+            info!("Attached the {} lake in {} ms", name, elapsed);
+            Ok(backend)
+        }
+        Err((stage, e)) => {
+            enter(stage);
+            if let Ok(mut progress) = PROGRESS.lock() {
+                if let Some(progress) = progress.as_mut() {
+                    progress.status.stuck_in = stuck_in;
+                    progress.status.error = Some(format!("{:#}", e));
+                }
+            }
+            warn!(
+                "Attaching the {} lake {} in stage '{}'",
+                name,
+                stage.name(),
+                stuck_in.map_or("pending", AttachStage::name)
+            );
+            Err(e)
+        }
+    }
+}
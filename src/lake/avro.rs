@@ -0,0 +1,356 @@
+//! Minimal Avro object container files, for Iceberg manifests
+//!
+//! Iceberg keeps manifests and manifest lists in Avro. This covers what the
+//! Iceberg backend needs: writing single-block files with the `null` codec,
+//! and decoding files written by other engines (`null` or `deflate` codec)
+//! into generic values whose record fields carry their Iceberg `field-id`.
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use anyhow::{anyhow, bail, Result};
+use flate2::read::DeflateDecoder;
+use rand_core::{OsRng, RngCore};
+use serde_json::Value as Json;
+
+const MAGIC: &[u8; 4] = b"Obj\x01";
+const SYNC_LEN: usize = 16;
+
+// ============================================================================
+// ENCODING
+// ============================================================================
+
+/// Binary encoder for Avro datums
+#[derive(Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    /// `int` and `long`: zig-zag varint
+    pub fn long(&mut self, value: i64) -> &mut Self {
+        let mut n = ((value << 1) ^ (value >> 63)) as u64;
+        while n >= 0x80 {
+            self.buf.push((n as u8) | 0x80);
+            n >>= 7;
+        }
+        self.buf.push(n as u8);
+        self
+    }
+
+    pub fn int(&mut self, value: i32) -> &mut Self {
+        self.long(value.into())
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.long(value.len() as i64);
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    pub fn string(&mut self, value: &str) -> &mut Self {
+        self.bytes(value.as_bytes())
+    }
+
+    /// `["null", "long"]` union
+    pub fn optional_long(&mut self, value: Option<i64>) -> &mut Self {
+        match value {
+            Some(value) => self.long(1).long(value),
+            None => self.long(0),
+        }
+    }
+
+    fn raw(&mut self, bytes: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Build a container file from already encoded records
+pub fn write_container(
+    schema: &Json,
+    metadata: &[(&str, String)],
+    records: &[Vec<u8>],
+) -> Vec<u8> {
+    let mut sync = [0u8; SYNC_LEN];
+    OsRng.fill_bytes(&mut sync);
+
+    let schema = schema.to_string();
+    let mut out = Encoder::default();
+    out.raw(MAGIC);
+
+    // File metadata: a map<bytes> in a single block
+    out.long(metadata.len() as i64 + 2);
+    out.string("avro.schema").string(&schema);
+    out.string("avro.codec").string("null");
+    for (key, value) in metadata {
+        out.string(key).string(value);
+    }
+    out.long(0);
+    out.raw(&sync);
+
+    if !records.is_empty() {
+        let block = records.concat();
+        out.long(records.len() as i64).long(block.len() as i64);
+        out.raw(&block).raw(&sync);
+    }
+
+    out.into_bytes()
+}
+
+// ============================================================================
+// DECODING
+// ============================================================================
+
+/// A decoded Avro value. `int`/`long` decode to `Long`, `float`/`double` to
+/// `Double`, `string`/`bytes`/`fixed` to `Bytes` and enums to their index.
+#[allow(dead_code)] // Manifest lists only need some of the decoded values
+pub enum Datum {
+    Null,
+    Boolean(bool),
+    Long(i64),
+    Double(f64),
+    Bytes(Vec<u8>),
+    Record(Vec<Field>),
+    Array(Vec<Datum>),
+    Map(Vec<(String, Datum)>),
+}
+
+pub struct Field {
+    pub field_id: Option<i64>,
+    pub value: Datum,
+}
+
+impl Datum {
+    /// Record field by Iceberg field ID
+    pub fn field(&self, field_id: i64) -> Option<&Datum> {
+        match self {
+            Datum::Record(fields) => fields
+                .iter()
+                .find(|f| f.field_id == Some(field_id))
+                .map(|f| &f.value),
+            _ => None,
+        }
+    }
+
+    pub fn as_long(&self) -> Option<i64> {
+        match self {
+            Datum::Long(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Datum::Bytes(v) => std::str::from_utf8(v).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Decode every record of a container file
+pub fn read_container(data: &[u8]) -> Result<Vec<Datum>> {
+    let mut decoder = Decoder { data, pos: 0 };
+    if decoder.take(MAGIC.len())? != MAGIC {
+        bail!("not an Avro container file");
+    }
+
+    let mut metadata = HashMap::new();
+    decoder.blocks(|d| {
+        let key = String::from_utf8(d.bytes()?.to_vec())?;
+        metadata.insert(key, d.bytes()?.to_vec());
+        Ok(())
+    })?;
+    let sync = decoder.take(SYNC_LEN)?;
+
+    let schema: Json = serde_json::from_slice(
+        metadata
+            .get("avro.schema")
+            .ok_or_else(|| anyhow!("missing avro.schema"))?,
+    )?;
+    let codec = metadata.get("avro.codec").map_or(&b"null"[..], Vec::as_slice);
+
+    let mut names = HashMap::new();
+    let mut records = Vec::new();
+    while decoder.pos < data.len() {
+        let count = decoder.long()?;
+        let size = usize::try_from(decoder.long()?)?;
+        let raw = decoder.take(size)?;
+        let block = match codec {
+            b"null" => raw.to_vec(),
+            b"deflate" => {
+                let mut inflated = Vec::new();
+                DeflateDecoder::new(raw).read_to_end(&mut inflated)?;
+                inflated
+            }
+            other => bail!("unsupported Avro codec '{}'", String::from_utf8_lossy(other)),
+        };
+
+        let mut block_decoder = Decoder {
+            data: &block,
+            pos: 0,
+        };
+        for _ in 0..count {
+            records.push(block_decoder.datum(&schema, &mut names)?);
+        }
+
+        if decoder.take(SYNC_LEN)? != sync {
+            bail!("Avro sync marker mismatch");
+        }
+    }
+
+    Ok(records)
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| anyhow!("truncated Avro data"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn long(&mut self) -> Result<i64> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            n |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok((n >> 1) as i64 ^ -((n & 1) as i64));
+            }
+        }
+        bail!("Avro varint too long")
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = usize::try_from(self.long()?)?;
+        self.take(len)
+    }
+
+    /// Array and map items come in blocks; a negative count is followed by the block size
+    fn blocks(&mut self, mut item: impl FnMut(&mut Self) -> Result<()>) -> Result<()> {
+        loop {
+            let count = match self.long()? {
+                0 => return Ok(()),
+                n if n < 0 => {
+                    self.long()?;
+                    -n
+                }
+                n => n,
+            };
+            for _ in 0..count {
+                item(self)?;
+            }
+        }
+    }
+
+    fn datum(&mut self, schema: &Json, names: &mut HashMap<String, Json>) -> Result<Datum> {
+        match schema {
+            Json::String(name) => self.named(name, names),
+            Json::Array(branches) => {
+                let index = usize::try_from(self.long()?)?;
+                let branch = branches
+                    .get(index)
+                    .ok_or_else(|| anyhow!("Avro union index {} out of range", index))?;
+                self.datum(branch, names)
+            }
+            Json::Object(object) => {
+                let kind = object
+                    .get("type")
+                    .ok_or_else(|| anyhow!("Avro schema without type"))?;
+                let kind = match kind {
+                    Json::String(kind) => kind.as_str(),
+                    // {"type": {...}} wrapping another schema
+                    nested => return self.datum(nested, names),
+                };
+
+                if let Some(Json::String(name)) = object.get("name") {
+                    if !names.contains_key(name) {
+                        names.insert(name.clone(), schema.clone());
+                    }
+                }
+
+                match kind {
+                    "record" => {
+                        let mut fields = Vec::new();
+                        let specs = object.get("fields").and_then(Json::as_array);
+                        for spec in specs.into_iter().flatten() {
+                            let field_schema = spec
+                                .get("type")
+                                .ok_or_else(|| anyhow!("Avro field without type"))?;
+                            fields.push(Field {
+                                field_id: spec.get("field-id").and_then(Json::as_i64),
+                                value: self.datum(field_schema, names)?,
+                            });
+                        }
+                        Ok(Datum::Record(fields))
+                    }
+                    "array" => {
+                        let items = object.get("items").cloned().unwrap_or(Json::Null);
+                        let mut values = Vec::new();
+                        self.blocks(|d| {
+                            values.push(d.datum(&items, names)?);
+                            Ok(())
+                        })?;
+                        Ok(Datum::Array(values))
+                    }
+                    "map" => {
+                        let value_schema = object.get("values").cloned().unwrap_or(Json::Null);
+                        let mut entries = Vec::new();
+                        self.blocks(|d| {
+                            let key = String::from_utf8(d.bytes()?.to_vec())?;
+                            entries.push((key, d.datum(&value_schema, names)?));
+                            Ok(())
+                        })?;
+                        Ok(Datum::Map(entries))
+                    }
+                    "enum" => Ok(Datum::Long(self.long()?)),
+                    "fixed" => {
+                        let size = object.get("size").and_then(Json::as_u64).unwrap_or(0);
+                        Ok(Datum::Bytes(self.take(usize::try_from(size)?)?.to_vec()))
+                    }
+                    // Primitive with attributes such as a logicalType
+                    primitive => self.named(primitive, names),
+                }
+            }
+            other => bail!("invalid Avro schema: {}", other),
+        }
+    }
+
+    fn named(&mut self, name: &str, names: &mut HashMap<String, Json>) -> Result<Datum> {
+        Ok(match name {
+            "null" => Datum::Null,
+            "boolean" => Datum::Boolean(self.take(1)?[0] != 0),
+            "int" | "long" => Datum::Long(self.long()?),
+            "float" => {
+                let bytes: [u8; 4] = self.take(4)?.try_into()?;
+                Datum::Double(f32::from_le_bytes(bytes).into())
+            }
+            "double" => {
+                let bytes: [u8; 8] = self.take(8)?.try_into()?;
+                Datum::Double(f64::from_le_bytes(bytes))
+            }
+            "bytes" | "string" => Datum::Bytes(self.bytes()?.to_vec()),
+            reference => {
+                let schema = names
+                    .get(reference)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("unknown Avro type '{}'", reference))?;
+                return self.datum(&schema, names);
+            }
+        })
+    }
+}
@@ -0,0 +1,206 @@
+//! DuckLake-style lake: Parquet data files on S3 plus the catalog on flash
+//!
+//! Data files are written exactly like `ParquetBackend`'s. Each commit
+//! records the files appended since the previous one in the lake catalog
+//! (see `catalog.rs`) as a new snapshot, so the catalog always lists what
+//! landed in S3. Small files are merged and old snapshots expired by
+//! scheduled maintenance (see `maintenance.rs`). Tables record their
+//! partition fields (see `partition.rs`) when they are created, and again
+//! whenever the configured fields change. Sensor tables of older firmware
+//! are migrated before their next batch (see `migrations.rs`).
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+
+use super::attach::{self, AttachStage};
+use super::maintenance::{self, MaintenancePolicy};
+use super::migrations::{self, SCHEMA_VERSION};
+use super::partition::Partitioning;
+use super::records::write_record_files;
+use super::s3_parquet::{confirm_landed, write_data_files};
+use super::{LakeBackend, RecordBatch, S3Target, UploadStats};
+use crate::catalog::{Catalog, DataFile};
+use crate::identity::DeviceIdentity;
+use crate::sensors::SensorReading;
+
+pub struct DuckLakeBackend {
+    data_path: String,
+    identity: DeviceIdentity,
+    partitioning: Partitioning,
+    storage_root: String,
+    catalog: Option<Catalog>,
+    // Uploaded since the last commit
+    staged: Vec<DataFile>,
+    maintenance: MaintenancePolicy,
+}
+
+impl DuckLakeBackend {
+    pub fn new(
+        data_path: &str,
+        identity: &DeviceIdentity,
+        partitioning: Partitioning,
+        storage_root: &str,
+        maintenance: MaintenancePolicy,
+    ) -> Self {
+        Self {
+            data_path: data_path.to_string(),
+            identity: identity.clone(),
+            partitioning,
+            storage_root: storage_root.to_string(),
+            catalog: None,
+            staged: Vec::new(),
+            maintenance,
+        }
+    }
+
+    fn attached(&mut self) -> Result<&mut Catalog> {
+        self.catalog
+            .as_mut()
+            .ok_or_else(|| anyhow!("lake catalog is not attached"))
+    }
+
+    /// Register `table` with the configured partition fields, true if it is new
+    fn register(&mut self, table: &str) -> Result<bool> {
+        let created = self.attached()?.create_table(table)?;
+        if created {
+            info!("Created lake table '{}'", table);
+        }
+        let fields = self.partitioning.describe();
+        if self.attached()?.set_partitioning(table, &fields)? {
+            match fields.as_str() {
+                "" => info!("Lake table '{}' is no longer partitioned", table),
+                _ => info!("Lake table '{}' partitioned by ({})", table, fields),
+            }
+        }
+        Ok(created)
+    }
+
+    /// Bring the sensor table `table` to `SCHEMA_VERSION`
+    fn migrate(&mut self, table: &str) -> Result<()> {
+        let catalog = self.attached()?;
+        // Tables registered before schema versions predate every migration
+        let version = catalog.schema_version(table).unwrap_or(1);
+        for migration in migrations::pending(table, version) {
+            catalog.set_schema_version(table, migration.version)?;
+            info!(
+                "Migrated lake table '{}' to schema v{} ({})",
+                table,
+                migration.version,
+                migrations::describe(migration)
+            );
+        }
+        Ok(())
+    }
+}
+
+impl LakeBackend for DuckLakeBackend {
+    fn name(&self) -> &'static str {
+        "ducklake"
+    }
+
+    // Re-attaches the existing catalog across reboots, creating it on a fresh device
+    fn attach(&mut self) -> Result<()> {
+        attach::enter(AttachStage::Catalog);
+        let catalog = Catalog::open(&self.storage_root)?;
+
+        attach::enter(AttachStage::DataPath);
+        let prefix = format!("{}/", self.data_path);
+        let elsewhere = catalog
+            .files()
+            .iter()
+            .filter(|f| !f.key.starts_with(&prefix))
+            .count();
+        if elsewhere > 0 {
+            // Still readable where they are; only new files go below the new path
+            warn!(
+                "{} catalogued data files are outside data_path '{}' (was it changed?)",
+                elsewhere, self.data_path
+            );
+        }
+
+        self.catalog = Some(catalog);
+        Ok(())
+    }
+
+    fn create_table(&mut self, _target: &S3Target, table: &str) -> Result<()> {
+        if self.register(table)? {
+            self.attached()?.set_schema_version(table, SCHEMA_VERSION)?;
+        }
+        self.migrate(table)
+    }
+
+    fn append_batch(
+        &mut self,
+        target: &S3Target,
+        table: &str,
+        readings: &[SensorReading],
+    ) -> Result<UploadStats> {
+        let (data_files, stats) = write_data_files(
+            &self.data_path,
+            &self.identity,
+            &self.partitioning,
+            target,
+            table,
+            readings,
+        )?;
+        self.staged.extend(data_files);
+        Ok(stats)
+    }
+
+    fn append_records(
+        &mut self,
+        target: &S3Target,
+        table: &str,
+        batch: &RecordBatch,
+    ) -> Result<UploadStats> {
+        // Auxiliary tables have no schema version, their files describe themselves
+        self.register(table)?;
+        let (data_files, stats) = write_record_files(
+            &self.data_path,
+            &self.identity,
+            &self.partitioning,
+            target,
+            table,
+            batch,
+        )?;
+        self.staged.extend(data_files);
+        Ok(stats)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if self.staged.is_empty() {
+            return Ok(());
+        }
+
+        let files = std::mem::take(&mut self.staged);
+        let count = files.len();
+        let snapshot_id = self.attached()?.commit(files)?;
+        info!("  Committed {} data file(s) as snapshot {}", count, snapshot_id);
+        Ok(())
+    }
+
+    // The newest file the catalog lists for the table, confirmed in S3
+    fn newest_data(&mut self, target: &S3Target, table: &str) -> Result<Option<i64>> {
+        let newest = self
+            .attached()?
+            .files()
+            .iter()
+            .filter(|f| f.table == table)
+            .max_by_key(|f| f.max_timestamp)
+            .cloned();
+        match newest {
+            Some(file) => confirm_landed(target, &file).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn maintain(&mut self, target: &S3Target, force: bool) -> Result<()> {
+        let policy = self.maintenance;
+        let (data_path, identity) = (self.data_path.clone(), self.identity.clone());
+        maintenance::run(self.attached()?, &data_path, &identity, target, &policy, force)
+    }
+
+    fn catalog(&self) -> Option<&Catalog> {
+        self.catalog.as_ref()
+    }
+}
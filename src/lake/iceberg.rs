@@ -0,0 +1,894 @@
+//! Iceberg tables behind a REST catalog (Nessie, Polaris, ...)
+//!
+//! Data files are the same Parquet objects `ParquetBackend` writes. Each
+//! commit adds one Iceberg snapshot per table:
+//!
+//! 1. The table is loaded from the catalog for its current snapshot.
+//! 2. A manifest listing the new data files is written next to the table
+//!    metadata, followed by a manifest list holding the current snapshot's
+//!    manifests plus the new one.
+//! 3. The snapshot is committed with an `assert-ref-snapshot-id`
+//!    requirement, so a concurrent writer makes the commit fail rather than
+//!    overwrite its snapshot; the files stay staged for the next commit.
+//!
+//! Tables are created unpartitioned, format version 2, at
+//! `s3://<bucket>/<data_path>/<table>`, with a name mapping because the
+//! Parquet files carry no field IDs. Tables created by older firmware are
+//! migrated to the current schema before their first batch (see
+//! `migrations.rs`): the missing columns are added as optional fields in a
+//! new schema, with an `assert-current-schema-id` requirement. Manifests are
+//! never merged, so run `rewrite_manifests` / `expire_snapshots` from a
+//! query engine now and then.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use embedded_svc::http::Method;
+use log::{info, warn};
+use rand_core::{OsRng, RngCore};
+use rusty_s3::{Bucket, S3Action};
+use serde::Deserialize;
+use serde_json::{json, Value as Json};
+
+use super::attach::{self, AttachStage};
+use super::avro::{self, Encoder};
+use super::migrations::{self, MIGRATIONS, SCHEMA_VERSION};
+use super::records::ColumnType;
+use super::s3_parquet::write_data_file;
+use super::{LakeBackend, S3Target, UploadStats};
+use crate::catalog::DataFile;
+use crate::clock::clock;
+use crate::config::DeviceConfig;
+use crate::credentials::S3Credentials;
+use crate::identity::DeviceIdentity;
+use crate::net::{send_capped, status_error, with_retry};
+use crate::sensors::{SensorReading, METRIC_NAMES};
+use crate::{upload_to_s3_chunked, UPLOAD_RETRY};
+
+// Catalog responses and manifest lists are held in RAM
+const MAX_RESPONSE_LEN: usize = 32 * 1024;
+const MAX_MANIFEST_LIST_LEN: usize = 64 * 1024;
+// OAuth2 client-credentials scope (Polaris: all roles of the principal)
+const OAUTH_SCOPE: &str = "PRINCIPAL_ROLE:ALL";
+// Refresh OAuth tokens this long before they expire
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+// Table property holding the schema version
+const VERSION_PROPERTY: &str = "opensensor.schema-version";
+
+// Manifest entry status and manifest content type
+const STATUS_ADDED: i32 = 1;
+const CONTENT_DATA: i32 = 0;
+
+pub struct IcebergRestBackend {
+    url: String,
+    warehouse: String,
+    namespace: String,
+    data_path: String,
+    identity: DeviceIdentity,
+    client: Option<S3Credentials>,
+    token: Option<(String, Duration)>,
+    prefix: String,
+    tables: Vec<String>,
+    staged: Vec<Staged>,
+}
+
+/// An uploaded data file waiting for its snapshot
+struct Staged {
+    table: String,
+    file: DataFile,
+    bucket: Bucket,
+    credentials: S3Credentials,
+}
+
+#[derive(Deserialize)]
+struct LoadTableResult {
+    metadata: TableMetadata,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct TableMetadata {
+    format_version: u32,
+    location: String,
+    #[serde(default)]
+    last_sequence_number: i64,
+    current_snapshot_id: Option<i64>,
+    current_schema_id: i64,
+    last_column_id: i64,
+    schemas: Vec<Json>,
+    #[serde(default)]
+    properties: HashMap<String, String>,
+    default_spec_id: i64,
+    partition_specs: Vec<PartitionSpec>,
+    #[serde(default)]
+    snapshots: Vec<Snapshot>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct PartitionSpec {
+    spec_id: i64,
+    fields: Vec<Json>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Snapshot {
+    snapshot_id: i64,
+    #[serde(default)]
+    timestamp_ms: i64,
+    manifest_list: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+/// One entry of a manifest list
+struct ManifestFile {
+    path: String,
+    length: i64,
+    partition_spec_id: i64,
+    content: i64,
+    sequence_number: i64,
+    min_sequence_number: i64,
+    added_snapshot_id: i64,
+    added_files_count: i64,
+    existing_files_count: i64,
+    deleted_files_count: i64,
+    added_rows_count: i64,
+    existing_rows_count: i64,
+    deleted_rows_count: i64,
+}
+
+impl IcebergRestBackend {
+    /// `client` holds the OAuth2 client ID / secret, if the catalog requires them
+    pub fn new(
+        config: &DeviceConfig,
+        identity: &DeviceIdentity,
+        client: Option<S3Credentials>,
+    ) -> Self {
+        Self {
+            url: config.iceberg_url.trim_end_matches('/').to_string(),
+            warehouse: config.iceberg_warehouse.clone(),
+            namespace: config.iceberg_namespace.clone(),
+            data_path: config.data_path.clone(),
+            identity: identity.clone(),
+            client,
+            token: None,
+            prefix: String::new(),
+            tables: Vec::new(),
+            staged: Vec::new(),
+        }
+    }
+
+    fn table_url(&self, table: &str) -> String {
+        format!("{}/namespaces/{}/tables/{}", self.api(), self.namespace, table)
+    }
+
+    fn api(&self) -> String {
+        if self.prefix.is_empty() {
+            format!("{}/v1", self.url)
+        } else {
+            format!("{}/v1/{}", self.url, self.prefix)
+        }
+    }
+
+    fn load_table(&mut self, table: &str) -> Result<Option<TableMetadata>> {
+        let url = format!("{}?snapshots=refs", self.table_url(table));
+        let (status, body) = self.request(Method::Get, &url, None)?;
+        match status {
+            200 => Ok(Some(serde_json::from_slice::<LoadTableResult>(&body)?.metadata)),
+            404 => Ok(None),
+            _ => Err(status_error(status, &body)),
+        }
+    }
+
+    fn commit_table(&mut self, table: &str, staged: &[&Staged]) -> Result<i64> {
+        let metadata = self
+            .load_table(table)?
+            .ok_or_else(|| anyhow!("table '{}' no longer exists", table))?;
+        if metadata.format_version != 2 {
+            bail!("only format version 2 tables are supported");
+        }
+        let unpartitioned = metadata
+            .partition_specs
+            .iter()
+            .any(|spec| spec.spec_id == metadata.default_spec_id && spec.fields.is_empty());
+        if !unpartitioned {
+            bail!("only unpartitioned tables are supported");
+        }
+        let schema = current_schema(&metadata)?;
+
+        // Metadata files go to the table's bucket, written with the data files' credentials
+        let (bucket, credentials) = (&staged[0].bucket, &staged[0].credentials);
+        let (location_bucket, location_key) = split_s3_uri(&metadata.location)?;
+        if location_bucket != bucket.name() {
+            bail!(
+                "table location '{}' is outside bucket '{}'",
+                metadata.location,
+                bucket.name()
+            );
+        }
+
+        let parent = metadata.current_snapshot_id.filter(|id| *id >= 0);
+        let mut manifests = match parent {
+            Some(parent) => {
+                let snapshot = metadata
+                    .snapshots
+                    .iter()
+                    .find(|s| s.snapshot_id == parent)
+                    .ok_or_else(|| anyhow!("current snapshot missing from table metadata"))?;
+                read_manifest_list(bucket, credentials, &snapshot.manifest_list)?
+            }
+            None => Vec::new(),
+        };
+
+        let snapshot_id = (OsRng.next_u64() >> 1) as i64;
+        let sequence_number = metadata.last_sequence_number + 1;
+        let files: Vec<&DataFile> = staged.iter().map(|s| &s.file).collect();
+        let rows: i64 = files.iter().map(|f| f.rows as i64).sum();
+        let s3 = credentials.to_rusty_s3();
+
+        // 1. Manifest of the new data files
+        let manifest = encode_manifest(&files, snapshot_id, schema, metadata.default_spec_id);
+        let manifest_key = format!("{}/metadata/{}-m0.avro", location_key, uuid());
+        with_retry(&UPLOAD_RETRY, "Iceberg manifest upload", || {
+            upload_to_s3_chunked(bucket, &s3, &manifest_key, &manifest)
+        })?;
+        manifests.push(ManifestFile {
+            path: format!("s3://{}/{}", bucket.name(), manifest_key),
+            length: manifest.len() as i64,
+            partition_spec_id: metadata.default_spec_id,
+            content: CONTENT_DATA.into(),
+            sequence_number,
+            min_sequence_number: sequence_number,
+            added_snapshot_id: snapshot_id,
+            added_files_count: files.len() as i64,
+            existing_files_count: 0,
+            deleted_files_count: 0,
+            added_rows_count: rows,
+            existing_rows_count: 0,
+            deleted_rows_count: 0,
+        });
+
+        // 2. Manifest list of the new snapshot
+        let manifest_list = encode_manifest_list(&manifests, snapshot_id, parent, sequence_number);
+        let list_key = format!(
+            "{}/metadata/snap-{}-1-{}.avro",
+            location_key,
+            snapshot_id,
+            uuid()
+        );
+        with_retry(&UPLOAD_RETRY, "Iceberg manifest list upload", || {
+            upload_to_s3_chunked(bucket, &s3, &list_key, &manifest_list)
+        })?;
+
+        // 3. Swap the main branch over to the new snapshot
+        let commit = json!({
+            "requirements": [
+                {"type": "assert-ref-snapshot-id", "ref": "main", "snapshot-id": parent}
+            ],
+            "updates": [
+                {
+                    "action": "add-snapshot",
+                    "snapshot": {
+                        "snapshot-id": snapshot_id,
+                        "parent-snapshot-id": parent,
+                        "sequence-number": sequence_number,
+                        "timestamp-ms": clock().now_millis(),
+                        "manifest-list": format!("s3://{}/{}", bucket.name(), list_key),
+                        "summary": {
+                            "operation": "append",
+                            "added-data-files": files.len().to_string(),
+                            "added-records": rows.to_string(),
+                        },
+                        "schema-id": metadata.current_schema_id,
+                    }
+                },
+                {
+                    "action": "set-snapshot-ref",
+                    "ref-name": "main",
+                    "type": "branch",
+                    "snapshot-id": snapshot_id,
+                }
+            ]
+        });
+        let url = self.table_url(table);
+        let (status, body) = self.request(Method::Post, &url, Some(&commit))?;
+        match status {
+            200 => Ok(snapshot_id),
+            409 => bail!("concurrent commit to '{}', will retry", table),
+            _ => Err(status_error(status, &body)),
+        }
+    }
+
+    /// Bring the existing `table` to `SCHEMA_VERSION` by adding the columns it lacks
+    fn migrate(&mut self, table: &str, metadata: &TableMetadata) -> Result<()> {
+        let mut fields = current_schema(metadata)?["fields"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let has = |fields: &[Json], name: &str| fields.iter().any(|f| f["name"] == name);
+
+        // Tables from before the property are as new as their columns show
+        let version = match metadata.properties.get(VERSION_PROPERTY) {
+            Some(version) => version
+                .parse()
+                .map_err(|_| anyhow!("invalid {} '{}'", VERSION_PROPERTY, version))?,
+            None => MIGRATIONS
+                .iter()
+                .take_while(|m| m.columns.iter().all(|c| has(&fields, c.name)))
+                .last()
+                .map_or(1, |m| m.version),
+        };
+        let pending = migrations::pending(table, version);
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut last_column_id = metadata.last_column_id;
+        for column in pending.iter().flat_map(|m| m.columns) {
+            if has(&fields, column.name) {
+                continue;
+            }
+            last_column_id += 1;
+            // Iceberg can't add required columns: older files don't have them
+            fields.push(json!({
+                "id": last_column_id,
+                "name": column.name,
+                "required": false,
+                "type": iceberg_type(column.kind),
+            }));
+        }
+        let schema_id = metadata
+            .schemas
+            .iter()
+            .filter_map(|s| s["schema-id"].as_i64())
+            .max()
+            .unwrap_or(0)
+            + 1;
+
+        let mapping = name_mapping(&fields);
+        let commit = json!({
+            "requirements": [
+                {
+                    "type": "assert-current-schema-id",
+                    "current-schema-id": metadata.current_schema_id,
+                },
+                {
+                    "type": "assert-last-assigned-field-id",
+                    "last-assigned-field-id": metadata.last_column_id,
+                }
+            ],
+            "updates": [
+                {
+                    "action": "add-schema",
+                    "schema": {"type": "struct", "schema-id": schema_id, "fields": fields},
+                    "last-column-id": last_column_id,
+                },
+                {"action": "set-current-schema", "schema-id": -1},
+                {
+                    "action": "set-properties",
+                    "updates": {
+                        VERSION_PROPERTY: SCHEMA_VERSION.to_string(),
+                        "schema.name-mapping.default": mapping.to_string(),
+                    }
+                }
+            ]
+        });
+        let url = self.table_url(table);
+        match self.request(Method::Post, &url, Some(&commit))? {
+            (200, _) => {}
+            (409, _) => bail!("concurrent schema change of '{}', will retry", table),
+            (status, body) => return Err(status_error(status, &body)),
+        }
+        for migration in pending {
+            info!(
+                "Migrated Iceberg table '{}.{}' to schema v{} ({})",
+                self.namespace,
+                table,
+                migration.version,
+                migrations::describe(migration)
+            );
+        }
+        Ok(())
+    }
+
+    /// Send a catalog request, returning the status and (size-capped) body
+    fn request(
+        &mut self,
+        method: Method,
+        url: &str,
+        body: Option<&Json>,
+    ) -> Result<(u16, Vec<u8>)> {
+        let authorization = self.token()?.map(|token| format!("Bearer {}", token));
+        let body = body.map(serde_json::to_vec).transpose()?.unwrap_or_default();
+        send(method, url, "application/json", authorization.as_deref(), &body)
+    }
+
+    /// Current OAuth2 access token, fetched with the client credentials when needed
+    fn token(&mut self) -> Result<Option<String>> {
+        let Some(client) = &self.client else {
+            return Ok(None);
+        };
+        if let Some((token, expires_at)) = &self.token {
+            if clock().monotonic() + TOKEN_EXPIRY_MARGIN < *expires_at {
+                return Ok(Some(token.clone()));
+            }
+        }
+
+        let form = format!(
+            "grant_type=client_credentials&client_id={}&client_secret={}&scope={}",
+            form_encode(&client.access_key),
+            form_encode(&client.secret_key),
+            form_encode(OAUTH_SCOPE)
+        );
+        let url = format!("{}/v1/oauth/tokens", self.url);
+        let (status, body) = send(
+            Method::Post,
+            &url,
+            "application/x-www-form-urlencoded",
+            None,
+            form.as_bytes(),
+        )?;
+        if status != 200 {
+            return Err(status_error(status, &body));
+        }
+
+        let response: TokenResponse = serde_json::from_slice(&body)?;
+        // Tokens without an expiry are refreshed hourly
+        let lifetime = Duration::from_secs(response.expires_in.unwrap_or(3600));
+        self.token = Some((response.access_token.clone(), clock().monotonic() + lifetime));
+        Ok(Some(response.access_token))
+    }
+}
+
+impl LakeBackend for IcebergRestBackend {
+    fn name(&self) -> &'static str {
+        "iceberg"
+    }
+
+    fn attach(&mut self) -> Result<()> {
+        if self.url.is_empty() {
+            bail!("the iceberg backend needs ice_url");
+        }
+
+        attach::enter(AttachStage::Secrets);
+        self.token()?;
+
+        // The catalog may scope all further paths under a prefix (Polaris: the catalog name)
+        attach::enter(AttachStage::Catalog);
+        let url = format!("{}/v1/config?warehouse={}", self.url, form_encode(&self.warehouse));
+        let (status, body) = self.request(Method::Get, &url, None)?;
+        if status != 200 {
+            return Err(status_error(status, &body));
+        }
+        let config: Json = serde_json::from_slice(&body)?;
+        self.prefix = ["overrides", "defaults"]
+            .iter()
+            .find_map(|section| config[*section]["prefix"].as_str())
+            .unwrap_or_default()
+            .to_string();
+
+        info!("Attached Iceberg REST catalog {} (namespace '{}')", self.url, self.namespace);
+        Ok(())
+    }
+
+    fn create_table(&mut self, target: &S3Target, table: &str) -> Result<()> {
+        if self.tables.iter().any(|t| t == table) {
+            return Ok(());
+        }
+
+        let url = format!("{}/namespaces", self.api());
+        let namespace = json!({"namespace": [self.namespace], "properties": {}});
+        match self.request(Method::Post, &url, Some(&namespace))? {
+            (200 | 409, _) => {}
+            (status, body) => return Err(status_error(status, &body)),
+        }
+
+        if let Some(metadata) = self.load_table(table)? {
+            self.migrate(table, &metadata)?;
+        } else {
+            let (bucket, _) = target.router.route(table, target.credentials);
+            let schema = table_schema();
+            let mapping = name_mapping(schema["fields"].as_array().map_or(&[][..], Vec::as_slice));
+            let request = json!({
+                "name": table,
+                "location": format!("s3://{}/{}/{}", bucket.name(), self.data_path, table),
+                "properties": {
+                    "format-version": "2",
+                    "write.parquet.compression-codec": "snappy",
+                    "schema.name-mapping.default": mapping.to_string(),
+                    VERSION_PROPERTY: SCHEMA_VERSION.to_string(),
+                },
+                "schema": schema,
+            });
+            let url = format!("{}/namespaces/{}/tables", self.api(), self.namespace);
+            match self.request(Method::Post, &url, Some(&request))? {
+                // 409: created concurrently by another device
+                (200 | 409, _) => info!("Created Iceberg table '{}.{}'", self.namespace, table),
+                (status, body) => return Err(status_error(status, &body)),
+            }
+        }
+
+        self.tables.push(table.to_string());
+        Ok(())
+    }
+
+    fn append_batch(
+        &mut self,
+        target: &S3Target,
+        table: &str,
+        readings: &[SensorReading],
+    ) -> Result<UploadStats> {
+        let (file, stats) =
+            write_data_file(&self.data_path, &self.identity, "", target, table, readings)?;
+        let (bucket, credentials) = target.router.route(table, target.credentials);
+        self.staged.push(Staged {
+            table: table.to_string(),
+            file,
+            bucket: bucket.clone(),
+            credentials: credentials.clone(),
+        });
+        Ok(stats)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        let staged = std::mem::take(&mut self.staged);
+        let mut tables: Vec<&str> = Vec::new();
+        for file in &staged {
+            if !tables.contains(&file.table.as_str()) {
+                tables.push(&file.table);
+            }
+        }
+
+        let mut failed_tables = Vec::new();
+        let mut first_error = None;
+        for table in tables {
+            let files: Vec<&Staged> = staged.iter().filter(|s| s.table == table).collect();
+            match self.commit_table(table, &files) {
+                Ok(snapshot_id) => info!(
+                    "  Committed {} data file(s) to '{}' as snapshot {}",
+                    files.len(),
+                    table,
+                    snapshot_id
+                ),
+                Err(e) => {
+                    warn!("  Iceberg commit to '{}' failed: {:?}", table, e);
+                    failed_tables.push(table.to_string());
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        // Files of failed commits stay staged for the next one
+        self.staged = staged
+            .into_iter()
+            .filter(|s| failed_tables.contains(&s.table))
+            .collect();
+        first_error.map_or(Ok(()), Err)
+    }
+
+    // Commit time of the table's current snapshot, as the catalog has it
+    fn newest_data(&mut self, _target: &S3Target, table: &str) -> Result<Option<i64>> {
+        let Some(metadata) = self.load_table(table)? else {
+            return Ok(None);
+        };
+        let current = metadata.current_snapshot_id.filter(|id| *id >= 0);
+        Ok(metadata
+            .snapshots
+            .iter()
+            .find(|s| Some(s.snapshot_id) == current)
+            .map(|s| s.timestamp_ms))
+    }
+
+    fn maintain(&mut self, _target: &S3Target, _force: bool) -> Result<()> {
+        // Files of a failed commit must not wait for the next batch
+        if self.staged.is_empty() {
+            return Ok(());
+        }
+        self.commit()
+    }
+}
+
+/// Iceberg schema of the sensor table, field IDs in Parquet column order
+fn table_schema() -> Json {
+    let mut fields = vec![json!({"id": 1, "name": "timestamp", "required": true, "type": "long"})];
+    for (i, name) in METRIC_NAMES.iter().enumerate() {
+        fields.push(json!({"id": i + 2, "name": name, "required": true, "type": "float"}));
+    }
+    fields.push(json!({
+        "id": METRIC_NAMES.len() + 2,
+        "name": "sample_interval_ms",
+        "required": true,
+        "type": "int",
+    }));
+    fields.push(json!({
+        "id": METRIC_NAMES.len() + 3,
+        "name": "warming_up",
+        "required": true,
+        "type": "boolean",
+    }));
+    for (i, name) in ["device_id", "firmware_version", "location"].iter().enumerate() {
+        fields.push(json!({
+            "id": METRIC_NAMES.len() + 4 + i,
+            "name": name,
+            "required": true,
+            "type": "string",
+        }));
+    }
+    // Provenance, NULL for rows from before it was recorded
+    let provenance = [("ingested_at", "long"), ("source", "string"), ("pipeline_version", "long")];
+    for (i, (name, kind)) in provenance.iter().enumerate() {
+        fields.push(json!({
+            "id": METRIC_NAMES.len() + 7 + i,
+            "name": name,
+            "required": false,
+            "type": kind,
+        }));
+    }
+    json!({"type": "struct", "schema-id": 0, "fields": fields})
+}
+
+fn current_schema(metadata: &TableMetadata) -> Result<&Json> {
+    metadata
+        .schemas
+        .iter()
+        .find(|s| s.get("schema-id").and_then(Json::as_i64) == Some(metadata.current_schema_id))
+        .ok_or_else(|| anyhow!("current schema missing from table metadata"))
+}
+
+fn iceberg_type(kind: ColumnType) -> &'static str {
+    match kind {
+        ColumnType::Long => "long",
+        ColumnType::Float => "float",
+        ColumnType::Boolean => "boolean",
+        ColumnType::Text => "string",
+    }
+}
+
+/// Maps Parquet column names to the IDs of schema `fields`, since the files carry none
+fn name_mapping(fields: &[Json]) -> Json {
+    fields
+        .iter()
+        .map(|field| json!({"field-id": field["id"], "names": [field["name"]]}))
+        .collect()
+}
+
+fn encode_manifest(
+    files: &[&DataFile],
+    snapshot_id: i64,
+    schema: &Json,
+    spec_id: i64,
+) -> Vec<u8> {
+    let avro_schema = json!({
+        "type": "record",
+        "name": "manifest_entry",
+        "fields": [
+            {"name": "status", "type": "int", "field-id": 0},
+            {"name": "snapshot_id", "type": ["null", "long"], "default": null, "field-id": 1},
+            {"name": "sequence_number", "type": ["null", "long"], "default": null, "field-id": 3},
+            {
+                "name": "file_sequence_number",
+                "type": ["null", "long"],
+                "default": null,
+                "field-id": 4
+            },
+            {
+                "name": "data_file",
+                "field-id": 2,
+                "type": {
+                    "type": "record",
+                    "name": "r2",
+                    "fields": [
+                        {"name": "content", "type": "int", "field-id": 134},
+                        {"name": "file_path", "type": "string", "field-id": 100},
+                        {"name": "file_format", "type": "string", "field-id": 101},
+                        {
+                            "name": "partition",
+                            "type": {"type": "record", "name": "r102", "fields": []},
+                            "field-id": 102
+                        },
+                        {"name": "record_count", "type": "long", "field-id": 103},
+                        {"name": "file_size_in_bytes", "type": "long", "field-id": 104}
+                    ]
+                }
+            }
+        ]
+    });
+
+    let records: Vec<Vec<u8>> = files
+        .iter()
+        .map(|file| {
+            let mut entry = Encoder::default();
+            entry
+                .int(STATUS_ADDED)
+                .optional_long(Some(snapshot_id))
+                // Sequence numbers of added files are inherited from the manifest list
+                .optional_long(None)
+                .optional_long(None)
+                .int(CONTENT_DATA)
+                .string(&format!("s3://{}/{}", file.bucket, file.key))
+                .string("PARQUET")
+                .long(file.rows as i64)
+                .long(file.bytes as i64);
+            entry.into_bytes()
+        })
+        .collect();
+
+    avro::write_container(
+        &avro_schema,
+        &[
+            ("schema", schema.to_string()),
+            ("schema-id", schema["schema-id"].to_string()),
+            ("partition-spec", "[]".to_string()),
+            ("partition-spec-id", spec_id.to_string()),
+            ("format-version", "2".to_string()),
+            ("content", "data".to_string()),
+        ],
+        &records,
+    )
+}
+
+fn encode_manifest_list(
+    manifests: &[ManifestFile],
+    snapshot_id: i64,
+    parent: Option<i64>,
+    sequence_number: i64,
+) -> Vec<u8> {
+    let field =
+        |name: &str, kind: &str, id: u32| json!({"name": name, "type": kind, "field-id": id});
+    let avro_schema = json!({
+        "type": "record",
+        "name": "manifest_file",
+        "fields": [
+            field("manifest_path", "string", 500),
+            field("manifest_length", "long", 501),
+            field("partition_spec_id", "int", 502),
+            field("content", "int", 517),
+            field("sequence_number", "long", 515),
+            field("min_sequence_number", "long", 516),
+            field("added_snapshot_id", "long", 503),
+            field("added_files_count", "int", 504),
+            field("existing_files_count", "int", 505),
+            field("deleted_files_count", "int", 506),
+            field("added_rows_count", "long", 512),
+            field("existing_rows_count", "long", 513),
+            field("deleted_rows_count", "long", 514),
+        ]
+    });
+
+    let records: Vec<Vec<u8>> = manifests
+        .iter()
+        .map(|m| {
+            let mut record = Encoder::default();
+            record
+                .string(&m.path)
+                .long(m.length)
+                .long(m.partition_spec_id)
+                .long(m.content)
+                .long(m.sequence_number)
+                .long(m.min_sequence_number)
+                .long(m.added_snapshot_id)
+                .long(m.added_files_count)
+                .long(m.existing_files_count)
+                .long(m.deleted_files_count)
+                .long(m.added_rows_count)
+                .long(m.existing_rows_count)
+                .long(m.deleted_rows_count);
+            record.into_bytes()
+        })
+        .collect();
+
+    avro::write_container(
+        &avro_schema,
+        &[
+            ("snapshot-id", snapshot_id.to_string()),
+            ("parent-snapshot-id", parent.map_or("null".to_string(), |p| p.to_string())),
+            ("sequence-number", sequence_number.to_string()),
+            ("format-version", "2".to_string()),
+        ],
+        &records,
+    )
+}
+
+/// Manifests of an existing snapshot, written by this device or any other engine
+fn read_manifest_list(
+    bucket: &Bucket,
+    credentials: &S3Credentials,
+    uri: &str,
+) -> Result<Vec<ManifestFile>> {
+    let (list_bucket, key) = split_s3_uri(uri)?;
+    if list_bucket != bucket.name() {
+        bail!("manifest list '{}' is outside bucket '{}'", uri, bucket.name());
+    }
+
+    let s3 = credentials.to_rusty_s3();
+    let url = bucket.get_object(Some(&s3), key).sign(Duration::from_secs(300));
+    let body = with_retry(&UPLOAD_RETRY, "Iceberg manifest list download", || {
+        match send_capped(Method::Get, url.as_str(), "", None, &[], MAX_MANIFEST_LIST_LEN)? {
+            (200, body) => Ok(body),
+            (status, body) => Err(status_error(status, &body)),
+        }
+    })?;
+
+    avro::read_container(&body)?
+        .iter()
+        .map(|record| {
+            let long = |id| record.field(id).and_then(avro::Datum::as_long);
+            Ok(ManifestFile {
+                path: record
+                    .field(500)
+                    .and_then(avro::Datum::as_str)
+                    .ok_or_else(|| anyhow!("manifest list entry without a path"))?
+                    .to_string(),
+                length: long(501).unwrap_or(0),
+                partition_spec_id: long(502).unwrap_or(0),
+                // Absent in format version 1 lists
+                content: long(517).unwrap_or(0),
+                sequence_number: long(515).unwrap_or(0),
+                min_sequence_number: long(516).unwrap_or(0),
+                added_snapshot_id: long(503).unwrap_or(0),
+                added_files_count: long(504).unwrap_or(0),
+                existing_files_count: long(505).unwrap_or(0),
+                deleted_files_count: long(506).unwrap_or(0),
+                added_rows_count: long(512).unwrap_or(0),
+                existing_rows_count: long(513).unwrap_or(0),
+                deleted_rows_count: long(514).unwrap_or(0),
+            })
+        })
+        .collect()
+}
+
+fn send(
+    method: Method,
+    url: &str,
+    content_type: &str,
+    authorization: Option<&str>,
+    body: &[u8],
+) -> Result<(u16, Vec<u8>)> {
+    send_capped(method, url, content_type, authorization, body, MAX_RESPONSE_LEN)
+}
+
+/// `s3://bucket/key` into bucket and key
+fn split_s3_uri(uri: &str) -> Result<(&str, &str)> {
+    uri.strip_prefix("s3://")
+        .or_else(|| uri.strip_prefix("s3a://"))
+        .and_then(|rest| rest.split_once('/'))
+        .ok_or_else(|| anyhow!("not an S3 location: '{}'", uri))
+}
+
+/// Random (version 4) UUID for metadata file names
+fn uuid() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0F) | 0x40;
+    bytes[8] = (bytes[8] & 0x3F) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Percent-encode for `application/x-www-form-urlencoded` bodies and query strings
+fn form_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,269 @@
+//! Scheduled maintenance of the DuckLake catalog and its data files
+//!
+//! Every flush adds one small Parquet object. Every `maint_m` minutes the
+//! DuckLake backend does the equivalent of DuckLake's
+//! `ducklake_merge_adjacent_files`, `ducklake_expire_snapshots` and
+//! `ducklake_cleanup_old_files`:
+//!
+//! 1. Runs of adjacent small files of a table are downloaded, merged into
+//!    one file and swapped in with a new snapshot. The originals are retired.
+//! 2. Snapshots older than `snap_keep_h` hours expire (the latest always stays).
+//! 3. Retired files no remaining snapshot references are deleted from S3.
+//!
+//! Merging decodes whole files in memory, so it only runs with heap headroom
+//! and stops at the first run that wouldn't fit. Files written by different
+//! firmware versions or at different locations, or of different partitions,
+//! are not merged.
+
+use std::mem::size_of;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use embedded_svc::http::Method;
+use log::{info, warn};
+use rusty_s3::S3Action;
+
+use super::s3_parquet::{read_sensor_parquet, upload_data_file};
+use super::S3Target;
+use crate::catalog::{Catalog, DataFile};
+use crate::clock::clock;
+use crate::config::DeviceConfig;
+use crate::identity::DeviceIdentity;
+use crate::net::{send_capped, status_error, with_retry};
+use crate::pipeline::free_heap;
+use crate::sensors::SensorReading;
+use crate::UPLOAD_RETRY;
+
+// Files at least this large are left alone
+const SMALL_FILE_BYTES: usize = 64 * 1024;
+// Bounds of one merged file
+const MERGE_MAX_BYTES: usize = 256 * 1024;
+const MERGE_MAX_ROWS: usize = 8192;
+// Floor below which maintenance doesn't start at all
+const MIN_MAINTENANCE_HEAP: u32 = 96 * 1024;
+const PRESIGN_EXPIRY: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Copy, Debug)]
+pub struct MaintenancePolicy {
+    /// Zero disables maintenance
+    pub interval: Duration,
+    pub retention: Duration,
+}
+
+impl MaintenancePolicy {
+    pub fn from_config(config: &DeviceConfig) -> Self {
+        Self {
+            interval: Duration::from_secs(u64::from(config.maintenance_mins) * 60),
+            retention: Duration::from_secs(u64::from(config.snapshot_retention_hours) * 3600),
+        }
+    }
+
+    /// True if maintenance last ran at `maintained_ms` and is due again
+    fn is_due(&self, maintained_ms: i64) -> bool {
+        !self.interval.is_zero()
+            && clock().now_millis() - maintained_ms >= self.interval.as_millis() as i64
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    merged_files: usize,
+    merged_into: usize,
+    expired_snapshots: usize,
+    deleted_files: usize,
+}
+
+/// Run maintenance on `catalog` if it is due (or `force`d) and the heap allows it
+pub fn run(
+    catalog: &mut Catalog,
+    data_path: &str,
+    identity: &DeviceIdentity,
+    target: &S3Target,
+    policy: &MaintenancePolicy,
+    force: bool,
+) -> Result<()> {
+    if !force && !policy.is_due(catalog.maintained_ms()) {
+        return Ok(());
+    }
+    let heap = free_heap();
+    if heap < MIN_MAINTENANCE_HEAP {
+        info!("Skipping lake maintenance, only {} bytes free heap", heap);
+        return Ok(());
+    }
+
+    info!("----------------------------------------");
+    info!("Lake maintenance ({} data files)...", catalog.files().len());
+    let mut report = Report::default();
+
+    for group in adjacent_small_files(catalog.files()) {
+        let needed = merge_heap(&group);
+        if free_heap() < MIN_MAINTENANCE_HEAP + needed {
+            info!(
+                "  Not enough heap to merge {} files of '{}' (~{} bytes), stopping",
+                group.len(),
+                group[0].table,
+                needed
+            );
+            break;
+        }
+        match merge(catalog, data_path, identity, target, &group) {
+            Ok(true) => {
+                report.merged_files += group.len();
+                report.merged_into += 1;
+            }
+            Ok(false) => {}
+            Err(e) => {
+                warn!("  Merging {} files of '{}' failed: {:?}", group.len(), group[0].table, e);
+                break;
+            }
+        }
+    }
+
+    let cutoff = clock().now_millis() - policy.retention.as_millis() as i64;
+    report.expired_snapshots = catalog.expire_snapshots(cutoff)?;
+
+    let mut deleted = Vec::new();
+    for file in catalog.unreferenced() {
+        match delete(target, &file) {
+            Ok(()) => deleted.push(file.key),
+            Err(e) => warn!("  Failed to delete s3://{}/{}: {:?}", file.bucket, file.key, e),
+        }
+    }
+    report.deleted_files = deleted.len();
+    catalog.forget_retired(&deleted)?;
+    catalog.set_maintained()?;
+
+    info!(
+        "  Merged {} files into {}, expired {} snapshots, deleted {} files ({} retired files left)",
+        report.merged_files,
+        report.merged_into,
+        report.expired_snapshots,
+        report.deleted_files,
+        catalog.retired().len()
+    );
+    Ok(())
+}
+
+/// Runs of at least two adjacent small files of the same table, bucket and partition,
+/// each bounded by `MERGE_MAX_BYTES` / `MERGE_MAX_ROWS`
+fn adjacent_small_files(files: &[DataFile]) -> Vec<Vec<DataFile>> {
+    let mut tables: Vec<&str> = files.iter().map(|f| f.table.as_str()).collect();
+    tables.sort_unstable();
+    tables.dedup();
+
+    let mut groups = Vec::new();
+    for table in tables {
+        let mut run: Vec<DataFile> = Vec::new();
+        // Auxiliary tables have their own schema, and are small anyway
+        for file in files.iter().filter(|f| f.table == table && !f.records) {
+            let fits = run.first().map_or(true, |first| {
+                first.bucket == file.bucket
+                    && first.partition == file.partition
+                    && run.iter().map(|f| f.bytes).sum::<usize>() + file.bytes <= MERGE_MAX_BYTES
+                    && run.iter().map(|f| f.rows).sum::<usize>() + file.rows <= MERGE_MAX_ROWS
+            });
+            if file.bytes >= SMALL_FILE_BYTES || !fits {
+                if run.len() >= 2 {
+                    groups.push(std::mem::take(&mut run));
+                }
+                run.clear();
+            }
+            if file.bytes < SMALL_FILE_BYTES {
+                run.push(file.clone());
+            }
+        }
+        if run.len() >= 2 {
+            groups.push(run);
+        }
+    }
+    groups
+}
+
+/// Rough peak heap of merging `group`: the downloaded files, the decoded
+/// readings (twice, while the merged file is encoded) and the encoded output
+fn merge_heap(group: &[DataFile]) -> u32 {
+    let bytes: usize = group.iter().map(|f| f.bytes).sum();
+    let rows: usize = group.iter().map(|f| f.rows).sum();
+    (bytes * 2 + rows * size_of::<SensorReading>() * 2) as u32
+}
+
+/// Merge `group` into one file; false if its files have different writers
+fn merge(
+    catalog: &mut Catalog,
+    data_path: &str,
+    identity: &DeviceIdentity,
+    target: &S3Target,
+    group: &[DataFile],
+) -> Result<bool> {
+    let table = &group[0].table;
+    let (bucket, credentials) = target.router.route(table, target.credentials);
+    if bucket.name() != group[0].bucket {
+        bail!("table is now routed to bucket '{}'", bucket.name());
+    }
+    let s3 = credentials.to_rusty_s3();
+
+    let mut readings = Vec::new();
+    let mut writer: Option<DeviceIdentity> = None;
+    for file in group {
+        let url = bucket.get_object(Some(&s3), &file.key).sign(PRESIGN_EXPIRY);
+        // The catalog knows the exact size, anything larger is not our file
+        let body = with_retry(&UPLOAD_RETRY, "Data file download", || {
+            match send_capped(Method::Get, url.as_str(), "", None, &[], file.bytes)? {
+                (200, body) => Ok(body),
+                (status, body) => Err(status_error(status, &body)),
+            }
+        })?;
+        // Files from before the identity columns were written by this device
+        let (file_identity, file_readings) = read_sensor_parquet(body)?;
+        let file_identity = file_identity.unwrap_or_else(|| identity.clone());
+        if writer.as_ref().is_some_and(|w| *w != file_identity) {
+            info!("  Not merging files of '{}' from different firmware / locations", table);
+            return Ok(false);
+        }
+        writer = Some(file_identity);
+        readings.extend(file_readings);
+    }
+    readings.sort_by_key(|r| r.timestamp);
+    let writer = writer.unwrap_or_else(|| identity.clone());
+
+    let partition = &group[0].partition;
+    let key = format!(
+        "{}/{}/{}merged_{}_{}_{}.parquet",
+        data_path,
+        table,
+        partition,
+        writer.device_id,
+        readings.first().map_or(0, |r| r.timestamp),
+        readings.last().map_or(0, |r| r.timestamp)
+    );
+    let (mut merged, _) = upload_data_file(&key, &writer, target, table, &readings)?;
+    merged.partition = partition.clone();
+    let replaced: Vec<String> = group.iter().map(|f| f.key.clone()).collect();
+    let snapshot_id = catalog.replace(&replaced, merged)?;
+    info!(
+        "  Merged {} files of '{}' ({} rows) as snapshot {}",
+        group.len(),
+        table,
+        readings.len(),
+        snapshot_id
+    );
+    Ok(true)
+}
+
+fn delete(target: &S3Target, file: &DataFile) -> Result<()> {
+    let (bucket, credentials) = target.router.route(&file.table, target.credentials);
+    if bucket.name() != file.bucket {
+        bail!("table is now routed to bucket '{}'", bucket.name());
+    }
+
+    let url = bucket
+        .delete_object(Some(&credentials.to_rusty_s3()), &file.key)
+        .sign(PRESIGN_EXPIRY);
+    with_retry(&UPLOAD_RETRY, "Data file delete", || {
+        match send_capped(Method::Delete, url.as_str(), "", None, &[], 1024)? {
+            // Already gone counts as deleted
+            (200 | 204 | 404, _) => Ok(()),
+            (status, body) => Err(status_error(status, &body)),
+        }
+    })
+}
@@ -0,0 +1,128 @@
+//! Versioned schema migrations of sensor tables
+//!
+//! Sensor tables carry a schema version in the lake: the `ducklake` catalog
+//! keeps one per table, Iceberg tables have it as the
+//! `opensensor.schema-version` property. When a batch goes to a table
+//! created by older firmware, the migrations after its version are applied
+//! in order before anything is written, the equivalent of
+//! `ALTER TABLE ... ADD COLUMN`. Files written before a migration simply
+//! lack its columns, which readers fill with NULL.
+//!
+//! A table whose version is newer than `SCHEMA_VERSION` was migrated by
+//! newer firmware. This firmware then runs it in compatibility mode: it
+//! leaves the schema alone and keeps writing the columns it knows, so the
+//! newer columns read as NULL in its files. The condition is logged and
+//! reported once per table and boot as a `schema_compat` device event.
+//!
+//! Adding a sensor field: append a `Migration` with the next version and its
+//! columns, and bump `SCHEMA_VERSION`. A signed `ALTER TABLE ... ADD COLUMN`
+//! maintenance task (see `sql_tasks.rs`) applies the migration of a column
+//! this firmware knows ahead of the table's next batch.
+
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+use log::warn;
+
+use super::records::{Column, ColumnType};
+use crate::events;
+
+/// Version of the schema this firmware writes
+pub const SCHEMA_VERSION: u32 = 4;
+
+/// Columns added to reach `version`
+pub struct Migration {
+    pub version: u32,
+    pub columns: &'static [Column],
+}
+
+/// Everything after version 1 (timestamp, the nine metrics and `sample_interval_ms`)
+pub const MIGRATIONS: [Migration; 3] = [
+    Migration {
+        version: 2,
+        columns: &[Column {
+            name: "warming_up",
+            kind: ColumnType::Boolean,
+        }],
+    },
+    Migration {
+        version: 3,
+        columns: &[
+            Column {
+                name: "device_id",
+                kind: ColumnType::Text,
+            },
+            Column {
+                name: "firmware_version",
+                kind: ColumnType::Text,
+            },
+            Column {
+                name: "location",
+                kind: ColumnType::Text,
+            },
+        ],
+    },
+    Migration {
+        version: 4,
+        columns: &[
+            Column {
+                name: "ingested_at",
+                kind: ColumnType::Long,
+            },
+            Column {
+                name: "source",
+                kind: ColumnType::Text,
+            },
+            Column {
+                name: "pipeline_version",
+                kind: ColumnType::Long,
+            },
+        ],
+    },
+];
+
+// Tables already reported in compatibility mode this boot
+static COMPATIBLE: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Migrations that bring `table` from schema `version` to `SCHEMA_VERSION`,
+/// none if it is already past it (compatibility mode)
+pub fn pending(table: &str, version: u32) -> &'static [Migration] {
+    if version > SCHEMA_VERSION {
+        compatibility_mode(table, version);
+        return &[];
+    }
+    let first = MIGRATIONS
+        .iter()
+        .position(|m| m.version > version)
+        .unwrap_or(MIGRATIONS.len());
+    &MIGRATIONS[first..]
+}
+
+fn compatibility_mode(table: &str, version: u32) {
+    let Ok(mut reported) = COMPATIBLE.lock() else {
+        return;
+    };
+    if !reported.insert(table.to_string()) {
+        return;
+    }
+    let detail = format!(
+        "table has schema v{}, firmware writes v{}: columns added after v{} are NULL",
+        version, SCHEMA_VERSION, SCHEMA_VERSION
+    );
+    warn!("Lake table '{}' in compatibility mode, {}", table, detail);
+    events::report(events::SCHEMA_COMPAT, table, detail);
+}
+
+/// Version and type of the migration that adds the column `name`
+pub fn adding(name: &str) -> Option<(u32, ColumnType)> {
+    MIGRATIONS.iter().find_map(|migration| {
+        let column = migration.columns.iter().find(|c| c.name.eq_ignore_ascii_case(name))?;
+        Some((migration.version, column.kind))
+    })
+}
+
+/// Names of the columns `migration` adds, for logs
+pub fn describe(migration: &Migration) -> String {
+    let columns: Vec<&str> = migration.columns.iter().map(|c| c.name).collect();
+    format!("ADD COLUMN {}", columns.join(", "))
+}
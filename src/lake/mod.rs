@@ -0,0 +1,215 @@
+//! Lake backends behind a common `LakeBackend` trait
+//!
+//! The ingestion loop only hands batches of readings to a backend; how they
+//! become tables is up to the backend. Selected with the `lake` NVS key:
+//!
+//! - `ducklake` (default) - Parquet data files on S3, recorded as snapshots
+//!   in the lake catalog on flash
+//! - `parquet` - plain Parquet files on S3, no catalog
+//! - `iceberg` - Iceberg tables behind a REST catalog (`iceberg` feature)
+//!
+//! With the `parquet-fallback` feature, a backend that fails to attach (a
+//! corrupt catalog, an unreachable REST catalog) is replaced by
+//! `ParquetBackend` for the rest of the boot, so data keeps flowing to the
+//! same `data_path` prefix, just without table metadata.
+
+mod attach;
+#[cfg(feature = "iceberg")]
+mod avro;
+mod ducklake;
+#[cfg(feature = "iceberg")]
+mod iceberg;
+mod maintenance;
+mod migrations;
+mod partition;
+mod records;
+mod s3_parquet;
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+
+use crate::catalog::Catalog;
+use crate::config::DeviceConfig;
+use crate::credentials::S3Credentials;
+use crate::identity::DeviceIdentity;
+use crate::profiles::ProfileRouter;
+use crate::secrets::SecretStore;
+use crate::sensors::SensorReading;
+
+#[cfg(feature = "http")]
+pub use attach::{status as attach_status, AttachStatus};
+pub use ducklake::DuckLakeBackend;
+#[cfg(feature = "iceberg")]
+pub use iceberg::IcebergRestBackend;
+use maintenance::MaintenancePolicy;
+pub use migrations::{adding as migration_adding, SCHEMA_VERSION};
+use partition::Partitioning;
+pub use records::{Cell, Column, ColumnType, RecordBatch};
+pub use s3_parquet::ParquetBackend;
+
+/// Where data files go: the routed bucket plus the currently active keys
+pub struct S3Target<'a> {
+    pub router: &'a ProfileRouter,
+    pub credentials: &'a S3Credentials,
+}
+
+// Send: attached on a thread of its own, see `attach.rs`
+pub trait LakeBackend: Send {
+    fn name(&self) -> &'static str;
+
+    /// Attach to the lake's metadata, once at startup, reporting progress
+    /// with `attach::enter`
+    fn attach(&mut self) -> Result<()>;
+
+    /// Make sure `table` exists before the first batch is appended to it
+    fn create_table(&mut self, target: &S3Target, table: &str) -> Result<()>;
+
+    /// Write one batch of readings as a data file of `table`
+    fn append_batch(
+        &mut self,
+        target: &S3Target,
+        table: &str,
+        readings: &[SensorReading],
+    ) -> Result<UploadStats>;
+
+    /// Write rows of the auxiliary table `table` as one data file, creating
+    /// the table if necessary. Committed like batches.
+    fn append_records(
+        &mut self,
+        _target: &S3Target,
+        table: &str,
+        _batch: &RecordBatch,
+    ) -> Result<UploadStats> {
+        bail!("the {} backend can't write the '{}' table", self.name(), table)
+    }
+
+    /// Make the batches appended since the last commit visible to readers
+    fn commit(&mut self) -> Result<()>;
+
+    /// Unix epoch ms of the newest data of `table` as the lake itself answers
+    /// it, a round trip for the freshness self-check; `None` without any
+    fn newest_data(&mut self, target: &S3Target, table: &str) -> Result<Option<i64>>;
+
+    /// Housekeeping after a forwarding round (compaction, snapshot expiry, ...),
+    /// `force` runs it even if it isn't due
+    fn maintain(&mut self, _target: &S3Target, _force: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// Catalog kept on the device, for inspection
+    #[allow(dead_code)] // Only used by the console
+    fn catalog(&self) -> Option<&Catalog> {
+        None
+    }
+}
+
+/// Build the backend selected in the configuration
+#[allow(unused_variables)] // `secrets` is only needed by the iceberg backend
+pub fn open(
+    config: &DeviceConfig,
+    identity: &DeviceIdentity,
+    secrets: &SecretStore,
+    storage_root: &str,
+) -> Result<Box<dyn LakeBackend>> {
+    let partitioning = Partitioning::parse(&config.partition_by)?;
+    let backend: Box<dyn LakeBackend> = match config.lake_backend.as_str() {
+        "ducklake" => Box::new(DuckLakeBackend::new(
+            &config.data_path,
+            identity,
+            partitioning.clone(),
+            storage_root,
+            MaintenancePolicy::from_config(config),
+        )),
+        "parquet" => Box::new(ParquetBackend::new(
+            &config.data_path,
+            identity,
+            partitioning.clone(),
+        )),
+        #[cfg(feature = "iceberg")]
+        "iceberg" => Box::new(IcebergRestBackend::new(
+            config,
+            identity,
+            secrets.iceberg_client()?,
+        )),
+        #[cfg(not(feature = "iceberg"))]
+        "iceberg" => bail!("the iceberg backend needs the `iceberg` feature"),
+        other => bail!("unknown lake backend '{}'", other),
+    };
+
+    let name = backend.name();
+    let timeout = Duration::from_secs(config.attach_secs.into());
+    let backend = match attach::attach(backend, timeout) {
+        Ok(backend) => backend,
+        #[cfg(feature = "parquet-fallback")]
+        Err(e) if name != "parquet" => {
+            warn!(
+                "Failed to attach the {} lake, writing plain Parquet files instead: {:?}",
+                name, e
+            );
+            // Attached directly: `/health` keeps reporting the attach that failed
+            let mut backend: Box<dyn LakeBackend> = Box::new(ParquetBackend::new(
+                &config.data_path,
+                identity,
+                partitioning.clone(),
+            ));
+            backend.attach()?;
+            backend
+        }
+        Err(e) => return Err(e),
+    };
+    info!(event = "lake_attached", backend = backend.name(); "Lake backend: {}", backend.name());
+    if partitioning.is_active() {
+        match backend.name() {
+            "iceberg" => warn!("The iceberg backend ignores the partition fields"),
+            _ => info!("Data files partitioned by ({})", partitioning.describe()),
+        }
+    }
+    Ok(backend)
+}
+
+/// Throughput of encoding and uploading batches
+#[derive(Default)]
+pub struct UploadStats {
+    pub batches: usize,
+    pub rows: usize,
+    pub bytes: usize,
+    pub encode_time: Duration,
+    pub upload_time: Duration,
+}
+
+impl UploadStats {
+    pub fn add(&mut self, other: &UploadStats) {
+        self.batches += other.batches;
+        self.rows += other.rows;
+        self.bytes += other.bytes;
+        self.encode_time += other.encode_time;
+        self.upload_time += other.upload_time;
+    }
+
+    pub fn log_summary(&self) {
+        let per_sec = |n: f64, t: Duration| n / t.as_secs_f64().max(0.001);
+        info!("----------------------------------------");
+        info!(
+            event = "upload_summary", batches = self.batches, rows = self.rows, bytes = self.bytes;
+            "Upload Summary:"
+        );
+        info!("  Batches uploaded: {} ({} rows)", self.batches, self.rows);
+        info!(
+            "  Total data: {} bytes ({:.2} KB)",
+            self.bytes,
+            self.bytes as f64 / 1024.0
+        );
+        info!(
+            "  Parquet encode: {} ms ({:.0} rows/s)",
+            self.encode_time.as_millis(),
+            per_sec(self.rows as f64, self.encode_time)
+        );
+        info!(
+            "  S3 upload: {} ms ({:.2} KB/s)",
+            self.upload_time.as_millis(),
+            per_sec(self.bytes as f64 / 1024.0, self.upload_time)
+        );
+    }
+}
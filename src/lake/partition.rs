@@ -0,0 +1,148 @@
+//! Hive-style partitioned layout of data files
+//!
+//! The equivalent of DuckLake's `ALTER TABLE ... SET PARTITIONED BY (...)`:
+//! with the `partition` NVS key set to e.g. `year,month,day,device_id`, data
+//! files are written below one directory per partition value,
+//!
+//! ```text
+//! <data_path>/<table>/year=2026/month=03/day=01/device_id=a1b2c3d4e5f6/sensor_data_...parquet
+//! ```
+//!
+//! so readers with hive partitioning (DuckDB's `read_parquet(...,
+//! hive_partitioning = true)`, Spark, Athena) skip whole directories instead
+//! of opening every file. Fields are `year`, `month`, `day` and `hour` of the
+//! row timestamp (UTC, also accepted as `year(timestamp)` etc.) and the
+//! identity columns `device_id` and `location`. A batch spanning several
+//! partitions is written as one file per partition. Changing the key only
+//! affects files written afterwards.
+
+use anyhow::{bail, Result};
+
+use crate::clock::civil_from_days;
+use crate::identity::DeviceIdentity;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PartitionField {
+    Year,
+    Month,
+    Day,
+    Hour,
+    DeviceId,
+    Location,
+}
+
+impl PartitionField {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Year => "year",
+            Self::Month => "month",
+            Self::Day => "day",
+            Self::Hour => "hour",
+            Self::DeviceId => "device_id",
+            Self::Location => "location",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Partitioning {
+    fields: Vec<PartitionField>,
+}
+
+impl Partitioning {
+    /// Parse a comma-separated list of partition fields (empty = unpartitioned)
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut fields = Vec::new();
+        for term in spec.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            let name = match term.strip_suffix("(timestamp)") {
+                Some(function) => function.trim(),
+                None => term,
+            };
+            let field = match name.to_ascii_lowercase().as_str() {
+                "year" => PartitionField::Year,
+                "month" => PartitionField::Month,
+                "day" => PartitionField::Day,
+                "hour" => PartitionField::Hour,
+                "device_id" => PartitionField::DeviceId,
+                "location" => PartitionField::Location,
+                _ => bail!(
+                    "unknown partition field '{}', expected year, month, day, hour, \
+                     device_id or location",
+                    term
+                ),
+            };
+            if fields.contains(&field) {
+                bail!("partition field '{}' is listed twice", term);
+            }
+            fields.push(field);
+        }
+        Ok(Self { fields })
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.fields.is_empty()
+    }
+
+    /// The fields as `SET PARTITIONED BY` would list them
+    pub fn describe(&self) -> String {
+        let names: Vec<&str> = self.fields.iter().map(|f| f.name()).collect();
+        names.join(", ")
+    }
+
+    /// Key prefix of the partition a row at `timestamp` belongs to, `""` or
+    /// ending in `/`
+    pub fn prefix(&self, timestamp: i64, identity: &DeviceIdentity) -> String {
+        let (year, month, day) = civil_from_days(timestamp.div_euclid(86_400_000));
+        let hour = timestamp.rem_euclid(86_400_000) / 3_600_000;
+
+        let mut prefix = String::new();
+        for field in &self.fields {
+            let value = match field {
+                PartitionField::Year => format!("{:04}", year),
+                PartitionField::Month => format!("{:02}", month),
+                PartitionField::Day => format!("{:02}", day),
+                PartitionField::Hour => format!("{:02}", hour),
+                PartitionField::DeviceId => path_value(&identity.device_id),
+                PartitionField::Location => path_value(&identity.location),
+            };
+            prefix += &format!("{}={}/", field.name(), value);
+        }
+        prefix
+    }
+
+    /// Split `rows` into runs of the same partition, in order
+    pub fn split<'a, T>(
+        &self,
+        rows: &'a [T],
+        timestamp: impl Fn(&T) -> i64,
+        identity: &DeviceIdentity,
+    ) -> Vec<(String, &'a [T])> {
+        if !self.is_active() {
+            return vec![(String::new(), rows)];
+        }
+
+        let mut runs = Vec::new();
+        let mut start = 0;
+        while start < rows.len() {
+            let prefix = self.prefix(timestamp(&rows[start]), identity);
+            let len = rows[start..]
+                .iter()
+                .take_while(|row| self.prefix(timestamp(row), identity) == prefix)
+                .count();
+            runs.push((prefix, &rows[start..start + len]));
+            start += len;
+        }
+        runs
+    }
+}
+
+/// `value` safe as one key path segment; empty values use Hive's null partition name
+fn path_value(value: &str) -> String {
+    if value.is_empty() {
+        return "__HIVE_DEFAULT_PARTITION__".to_string();
+    }
+    value
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+        .collect()
+}
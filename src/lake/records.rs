@@ -0,0 +1,215 @@
+//! Rows of auxiliary tables (alerts, diagnostics, ...)
+//!
+//! Sensor tables have the fixed `SensorReading` schema. Auxiliary tables
+//! declare theirs as a list of `Column`s and hand over rows of `Cell`s; the
+//! first column is the row's timestamp in Unix milliseconds. Files are named
+//! `<data_path>/<table>/<partition><table>_<device_id>_<first ts>.parquet`
+//! and carry the writer's identity columns, like the sensor data files.
+
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Result};
+use parquet::basic::{Compression, Encoding};
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, FloatType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+
+use super::partition::Partitioning;
+use super::s3_parquet::upload_parquet;
+use super::{S3Target, UploadStats};
+use crate::catalog::DataFile;
+use crate::identity::DeviceIdentity;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    Long,
+    Float,
+    Boolean,
+    Text,
+}
+
+pub struct Column {
+    pub name: &'static str,
+    pub kind: ColumnType,
+}
+
+#[derive(Clone, Debug)]
+pub enum Cell {
+    Long(i64),
+    Float(f32),
+    Boolean(bool),
+    Text(String),
+}
+
+/// Rows of one auxiliary table, every row in `columns` order
+pub struct RecordBatch {
+    pub columns: &'static [Column],
+    pub rows: Vec<Vec<Cell>>,
+}
+
+impl RecordBatch {
+    fn timestamp(row: &[Cell]) -> i64 {
+        match row.first() {
+            Some(Cell::Long(ts)) => *ts,
+            _ => 0,
+        }
+    }
+}
+
+/// Encode `batch` as Parquet and upload it as one file per partition,
+/// returning the written files and throughput
+pub(super) fn write_record_files(
+    data_path: &str,
+    identity: &DeviceIdentity,
+    partitioning: &Partitioning,
+    target: &S3Target,
+    table: &str,
+    batch: &RecordBatch,
+) -> Result<(Vec<DataFile>, UploadStats)> {
+    let mut files = Vec::new();
+    let mut stats = UploadStats::default();
+    let runs = partitioning.split(&batch.rows, |row| RecordBatch::timestamp(row), identity);
+    for (partition, rows) in runs {
+        let run = RecordBatch {
+            columns: batch.columns,
+            rows: rows.to_vec(),
+        };
+        let (file, file_stats) =
+            write_record_file(data_path, identity, &partition, target, table, &run)?;
+        files.push(file);
+        stats.add(&file_stats);
+    }
+    stats.batches = stats.batches.min(1);
+    Ok((files, stats))
+}
+
+fn write_record_file(
+    data_path: &str,
+    identity: &DeviceIdentity,
+    partition: &str,
+    target: &S3Target,
+    table: &str,
+    batch: &RecordBatch,
+) -> Result<(DataFile, UploadStats)> {
+    let first = batch.rows.first().map_or(0, |row| RecordBatch::timestamp(row));
+    let object_key = format!(
+        "{}/{}/{}{}_{}_{}.parquet",
+        data_path, table, partition, table, identity.device_id, first
+    );
+
+    let encode_start = Instant::now();
+    let parquet_data = encode_records(table, batch, identity)?;
+    let encode_time = encode_start.elapsed();
+
+    let timestamps = batch.rows.iter().map(|row| RecordBatch::timestamp(row));
+    let range = (timestamps.clone().min().unwrap_or(0), timestamps.max().unwrap_or(0));
+    let (mut data_file, stats) = upload_parquet(
+        &object_key,
+        target,
+        table,
+        &parquet_data,
+        batch.rows.len(),
+        range,
+        encode_time,
+    )?;
+    data_file.partition = partition.to_string();
+    data_file.records = true;
+    Ok((data_file, stats))
+}
+
+fn encode_records(table: &str, batch: &RecordBatch, identity: &DeviceIdentity) -> Result<Vec<u8>> {
+    if batch.columns.first().map(|c| c.kind) != Some(ColumnType::Long) {
+        bail!("table '{}' must start with a timestamp column", table);
+    }
+
+    let mut message_type = format!("message {} {{\n", table);
+    for column in batch.columns {
+        let physical = match column.kind {
+            ColumnType::Long => "int64",
+            ColumnType::Float => "float",
+            ColumnType::Boolean => "boolean",
+            ColumnType::Text => "binary",
+        };
+        let logical = if column.kind == ColumnType::Text { " (UTF8)" } else { "" };
+        message_type += &format!("    required {} {}{};\n", physical, column.name, logical);
+    }
+    for name in ["device_id", "firmware_version", "location"] {
+        message_type += &format!("    required binary {} (UTF8);\n", name);
+    }
+    message_type += "}";
+
+    let schema = Arc::new(parse_message_type(&message_type)?);
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_encoding(Encoding::PLAIN)
+        .build();
+
+    let mut buffer = Cursor::new(Vec::new());
+    let mut writer = SerializedFileWriter::new(&mut buffer, schema, Arc::new(props))?;
+    let mut row_group_writer = writer.next_row_group()?;
+
+    for (i, column) in batch.columns.iter().enumerate() {
+        let cells = batch.rows.iter().map(|row| row.get(i));
+        let mut col_writer = row_group_writer.next_column()?.unwrap();
+        let mismatch = || anyhow!("column '{}.{}' has a wrong value", table, column.name);
+        match column.kind {
+            ColumnType::Long => {
+                let values = cells
+                    .map(|cell| match cell {
+                        Some(Cell::Long(v)) => Ok(*v),
+                        _ => Err(mismatch()),
+                    })
+                    .collect::<Result<Vec<i64>>>()?;
+                col_writer.typed::<Int64Type>().write_batch(&values, None, None)?;
+            }
+            ColumnType::Float => {
+                let values = cells
+                    .map(|cell| match cell {
+                        Some(Cell::Float(v)) => Ok(*v),
+                        _ => Err(mismatch()),
+                    })
+                    .collect::<Result<Vec<f32>>>()?;
+                col_writer.typed::<FloatType>().write_batch(&values, None, None)?;
+            }
+            ColumnType::Boolean => {
+                let values = cells
+                    .map(|cell| match cell {
+                        Some(Cell::Boolean(v)) => Ok(*v),
+                        _ => Err(mismatch()),
+                    })
+                    .collect::<Result<Vec<bool>>>()?;
+                col_writer.typed::<BoolType>().write_batch(&values, None, None)?;
+            }
+            ColumnType::Text => {
+                let values = cells
+                    .map(|cell| match cell {
+                        Some(Cell::Text(v)) => Ok(ByteArray::from(v.as_str())),
+                        _ => Err(mismatch()),
+                    })
+                    .collect::<Result<Vec<ByteArray>>>()?;
+                col_writer.typed::<ByteArrayType>().write_batch(&values, None, None)?;
+            }
+        }
+        col_writer.close()?;
+    }
+
+    // Identity columns (UTF8), the same on every row
+    for value in [
+        &identity.device_id,
+        &identity.firmware_version,
+        &identity.location,
+    ] {
+        let column = vec![ByteArray::from(value.as_str()); batch.rows.len()];
+        let mut col_writer = row_group_writer.next_column()?.unwrap();
+        col_writer.typed::<ByteArrayType>().write_batch(&column, None, None)?;
+        col_writer.close()?;
+    }
+
+    row_group_writer.close()?;
+    writer.close()?;
+
+    Ok(buffer.into_inner())
+}
@@ -0,0 +1,440 @@
+//! Plain Parquet files on S3
+//!
+//! Every batch becomes one Snappy-compressed Parquet object under
+//! `<data_path>/<table>/`, named after the writing device and the first
+//! reading, so nodes sharing a table never overwrite each other's files.
+//! With partitioning (see `partition.rs`) they go one directory level per
+//! partition field deeper. There is no table metadata: readers glob the
+//! prefix. `DuckLakeBackend` writes its data files the same way.
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use embedded_svc::http::Method;
+use log::info;
+use parquet::basic::{Compression, Encoding};
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, FloatType, Int32Type, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::file::writer::SerializedFileWriter;
+use parquet::record::RowAccessor;
+use parquet::schema::parser::parse_message_type;
+use rusty_s3::S3Action;
+
+use super::partition::Partitioning;
+use super::records::write_record_files;
+use super::{LakeBackend, RecordBatch, S3Target, UploadStats};
+use crate::catalog::DataFile;
+use crate::identity::DeviceIdentity;
+use crate::net::{send_capped, status_error, with_retry};
+use crate::sensors::{SensorReading, Source};
+use crate::{upload_to_s3_chunked, UPLOAD_RETRY};
+
+const PRESIGN_EXPIRY: Duration = Duration::from_secs(300);
+
+pub struct ParquetBackend {
+    data_path: String,
+    identity: DeviceIdentity,
+    partitioning: Partitioning,
+    // Newest file written per table since boot, for `newest_data`
+    newest: HashMap<String, DataFile>,
+}
+
+impl ParquetBackend {
+    pub fn new(data_path: &str, identity: &DeviceIdentity, partitioning: Partitioning) -> Self {
+        Self {
+            data_path: data_path.to_string(),
+            identity: identity.clone(),
+            partitioning,
+            newest: HashMap::new(),
+        }
+    }
+}
+
+impl LakeBackend for ParquetBackend {
+    fn name(&self) -> &'static str {
+        "parquet"
+    }
+
+    fn attach(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // Tables are just key prefixes, created by their first object
+    fn create_table(&mut self, _target: &S3Target, _table: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn append_batch(
+        &mut self,
+        target: &S3Target,
+        table: &str,
+        readings: &[SensorReading],
+    ) -> Result<UploadStats> {
+        let (data_files, stats) = write_data_files(
+            &self.data_path,
+            &self.identity,
+            &self.partitioning,
+            target,
+            table,
+            readings,
+        )?;
+        let newest = data_files.into_iter().max_by_key(|f| f.max_timestamp);
+        if let Some(file) = newest {
+            self.newest.insert(table.to_string(), file);
+        }
+        Ok(stats)
+    }
+
+    fn append_records(
+        &mut self,
+        target: &S3Target,
+        table: &str,
+        batch: &RecordBatch,
+    ) -> Result<UploadStats> {
+        let (_, stats) = write_record_files(
+            &self.data_path,
+            &self.identity,
+            &self.partitioning,
+            target,
+            table,
+            batch,
+        )?;
+        Ok(stats)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // Without metadata to ask, the newest file this boot wrote must be in S3
+    fn newest_data(&mut self, target: &S3Target, table: &str) -> Result<Option<i64>> {
+        match self.newest.get(table) {
+            Some(file) => confirm_landed(target, file).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Encode a batch as Parquet and upload it as one file per partition
+pub(super) fn write_data_files(
+    data_path: &str,
+    identity: &DeviceIdentity,
+    partitioning: &Partitioning,
+    target: &S3Target,
+    table: &str,
+    readings: &[SensorReading],
+) -> Result<(Vec<DataFile>, UploadStats)> {
+    let mut files = Vec::new();
+    let mut stats = UploadStats::default();
+    for (partition, rows) in partitioning.split(readings, |r| r.timestamp, identity) {
+        let (file, file_stats) =
+            write_data_file(data_path, identity, &partition, target, table, rows)?;
+        files.push(file);
+        stats.add(&file_stats);
+    }
+    // Still one batch, however many files it took
+    stats.batches = stats.batches.min(1);
+    Ok((files, stats))
+}
+
+/// Encode a batch as Parquet and upload it below the `partition` prefix,
+/// returning the written file and throughput
+pub(super) fn write_data_file(
+    data_path: &str,
+    identity: &DeviceIdentity,
+    partition: &str,
+    target: &S3Target,
+    table: &str,
+    readings: &[SensorReading],
+) -> Result<(DataFile, UploadStats)> {
+    // Name objects after the device and their first reading so batches from
+    // different nodes and boots don't collide
+    let object_key = format!(
+        "{}/{}/{}sensor_data_{}_{}.parquet",
+        data_path,
+        table,
+        partition,
+        identity.device_id,
+        readings.first().map_or(0, |r| r.timestamp)
+    );
+    let (mut data_file, stats) = upload_data_file(&object_key, identity, target, table, readings)?;
+    data_file.partition = partition.to_string();
+    Ok((data_file, stats))
+}
+
+/// Encode `readings` as Parquet and upload them to `object_key`
+pub(super) fn upload_data_file(
+    object_key: &str,
+    identity: &DeviceIdentity,
+    target: &S3Target,
+    table: &str,
+    readings: &[SensorReading],
+) -> Result<(DataFile, UploadStats)> {
+    // Create Parquet file: one column write per field, not per row
+    let encode_start = Instant::now();
+    let parquet_data = create_sensor_parquet(readings, identity)?;
+    let encode_time = encode_start.elapsed();
+
+    let timestamps = readings.iter().map(|r| r.timestamp);
+    let range = (timestamps.clone().min().unwrap_or(0), timestamps.max().unwrap_or(0));
+    upload_parquet(object_key, target, table, &parquet_data, readings.len(), range, encode_time)
+}
+
+/// Upload an encoded Parquet file of `rows` rows spanning the timestamp `range`
+pub(super) fn upload_parquet(
+    object_key: &str,
+    target: &S3Target,
+    table: &str,
+    parquet_data: &[u8],
+    rows: usize,
+    range: (i64, i64),
+    encode_time: Duration,
+) -> Result<(DataFile, UploadStats)> {
+    info!(
+        "  Parquet file created: {} rows, {} bytes ({:.2} KB, Snappy compressed) in {} ms",
+        rows,
+        parquet_data.len(),
+        parquet_data.len() as f64 / 1024.0,
+        encode_time.as_millis()
+    );
+
+    // Upload to S3 using chunked transfer, to the bucket the table is routed to
+    let (bucket, credentials) = target.router.route(table, target.credentials);
+    let credentials = credentials.to_rusty_s3();
+    let upload_start = Instant::now();
+    with_retry(&UPLOAD_RETRY, "S3 upload", || {
+        upload_to_s3_chunked(bucket, &credentials, object_key, parquet_data)
+    })?;
+    let upload_time = upload_start.elapsed();
+    info!("  Upload successful: s3://{}/{}", bucket.name(), object_key);
+
+    let data_file = DataFile {
+        snapshot_id: 0,
+        table: table.to_string(),
+        bucket: bucket.name().to_string(),
+        key: object_key.to_string(),
+        rows,
+        bytes: parquet_data.len(),
+        min_timestamp: range.0,
+        max_timestamp: range.1,
+        partition: String::new(),
+        records: false,
+    };
+    let stats = UploadStats {
+        batches: 1,
+        rows,
+        bytes: parquet_data.len(),
+        encode_time,
+        upload_time,
+    };
+    Ok((data_file, stats))
+}
+
+/// Newest row of `file`, once a HEAD request has found the file in S3
+pub(super) fn confirm_landed(target: &S3Target, file: &DataFile) -> Result<i64> {
+    let (bucket, credentials) = target.router.route(&file.table, target.credentials);
+    if bucket.name() != file.bucket {
+        bail!("table is now routed to bucket '{}'", bucket.name());
+    }
+    let url = bucket
+        .head_object(Some(&credentials.to_rusty_s3()), &file.key)
+        .sign(PRESIGN_EXPIRY);
+    match send_capped(Method::Head, url.as_str(), "", None, &[], 1024)? {
+        (200, _) => Ok(file.max_timestamp),
+        (404, _) => bail!("data file {} is missing from S3", file.key),
+        (status, body) => Err(status_error(status, &body)),
+    }
+}
+
+/// Decode a file written by `create_sensor_parquet`, with the identity of
+/// its writer (`None` for files written before the identity columns)
+pub(super) fn read_sensor_parquet(
+    data: Vec<u8>,
+) -> Result<(Option<DeviceIdentity>, Vec<SensorReading>)> {
+    let reader = SerializedFileReader::new(Bytes::from(data))?;
+    let rows = reader.metadata().file_metadata().num_rows();
+    let mut readings = Vec::with_capacity(rows.max(0) as usize);
+    let mut identity = None;
+
+    for row in reader.get_row_iter(None)? {
+        let row = row?;
+        if identity.is_none() {
+            identity = match (row.get_string(12), row.get_string(13), row.get_string(14)) {
+                (Ok(device_id), Ok(firmware_version), Ok(location)) => Some(DeviceIdentity {
+                    device_id: device_id.clone(),
+                    firmware_version: firmware_version.clone(),
+                    location: location.clone(),
+                }),
+                _ => None,
+            };
+        }
+        let mut reading = SensorReading::empty(row.get_long(0)?);
+        let mut metrics = [f32::NAN; 9];
+        for (i, metric) in metrics.iter_mut().enumerate() {
+            *metric = row.get_float(i + 1)?;
+        }
+        reading.set_metrics(metrics);
+        // Files written before the columns existed
+        reading.sample_interval_ms = row.get_int(10).map_or(0, |v| v as u32);
+        reading.warming_up = row.get_bool(11).unwrap_or(false);
+        reading.ingested_at = row.get_long(15).unwrap_or(0);
+        reading.source = Source::from_name(row.get_string(16).map(String::as_str).ok());
+        reading.pipeline_version = row.get_long(17).map_or(0, |v| v as u32);
+        readings.push(reading);
+    }
+
+    Ok((identity, readings))
+}
+
+fn create_sensor_parquet(readings: &[SensorReading], identity: &DeviceIdentity) -> Result<Vec<u8>> {
+    // Schema matching opensensor.space structure (simplified for test)
+    let message_type = "
+        message sensor_data {
+            required int64 timestamp;
+            required float temperature;
+            required float humidity;
+            required float pressure;
+            required float pm1_0;
+            required float pm2_5;
+            required float pm10;
+            required float gas_resistance;
+            required float light;
+            required float noise;
+            required int32 sample_interval_ms;
+            required boolean warming_up;
+            required binary device_id (UTF8);
+            required binary firmware_version (UTF8);
+            required binary location (UTF8);
+            optional int64 ingested_at;
+            optional binary source (UTF8);
+            optional int64 pipeline_version;
+        }
+    ";
+
+    let schema = Arc::new(parse_message_type(message_type)?);
+
+    // Snappy compression - pure Rust, proven to work on ESP32
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_encoding(Encoding::PLAIN)
+        .build();
+
+    let mut buffer = Cursor::new(Vec::new());
+    let mut writer = SerializedFileWriter::new(&mut buffer, schema, Arc::new(props))?;
+    let mut row_group_writer = writer.next_row_group()?;
+
+    // Transpose rows into columns
+    let timestamps: Vec<i64> = readings.iter().map(|r| r.timestamp).collect();
+    let column = |f: fn(&SensorReading) -> f32| -> Vec<f32> { readings.iter().map(f).collect() };
+
+    let temperatures = column(|r| r.temperature);
+    let humidity = column(|r| r.humidity);
+    let pressure = column(|r| r.pressure);
+    let pm1_0 = column(|r| r.pm1_0);
+    let pm2_5 = column(|r| r.pm2_5);
+    let pm10 = column(|r| r.pm10);
+    let gas_resistance = column(|r| r.gas_resistance);
+    let light = column(|r| r.light);
+    let noise = column(|r| r.noise);
+    let sample_intervals: Vec<i32> = readings.iter().map(|r| r.sample_interval_ms as i32).collect();
+    let warming_up: Vec<bool> = readings.iter().map(|r| r.warming_up).collect();
+    // Provenance: nullable, only present values are written
+    let ingested_at: Vec<Option<i64>> =
+        readings.iter().map(|r| (r.ingested_at != 0).then_some(r.ingested_at)).collect();
+    let sources: Vec<Option<&str>> = readings.iter().map(|r| r.source.name()).collect();
+    let pipeline_versions: Vec<Option<i64>> = readings
+        .iter()
+        .map(|r| (r.pipeline_version != 0).then_some(r.pipeline_version.into()))
+        .collect();
+
+    // Write columns
+    // Timestamp column (INT64)
+    {
+        let mut col_writer = row_group_writer.next_column()?.unwrap();
+        col_writer.typed::<Int64Type>().write_batch(&timestamps, None, None)?;
+        col_writer.close()?;
+    }
+
+    // Float columns
+    let float_columns: [&[f32]; 9] = [
+        &temperatures,
+        &humidity,
+        &pressure,
+        &pm1_0,
+        &pm2_5,
+        &pm10,
+        &gas_resistance,
+        &light,
+        &noise,
+    ];
+
+    for col_data in float_columns {
+        let mut col_writer = row_group_writer.next_column()?.unwrap();
+        col_writer.typed::<FloatType>().write_batch(col_data, None, None)?;
+        col_writer.close()?;
+    }
+
+    // Sampling interval column (INT32)
+    {
+        let mut col_writer = row_group_writer.next_column()?.unwrap();
+        col_writer.typed::<Int32Type>().write_batch(&sample_intervals, None, None)?;
+        col_writer.close()?;
+    }
+
+    // Warm-up QC flag column (BOOLEAN)
+    {
+        let mut col_writer = row_group_writer.next_column()?.unwrap();
+        col_writer.typed::<BoolType>().write_batch(&warming_up, None, None)?;
+        col_writer.close()?;
+    }
+
+    // Identity columns (UTF8), the same on every row
+    for value in [
+        &identity.device_id,
+        &identity.firmware_version,
+        &identity.location,
+    ] {
+        let column = vec![ByteArray::from(value.as_str()); readings.len()];
+        let mut col_writer = row_group_writer.next_column()?.unwrap();
+        col_writer.typed::<ByteArrayType>().write_batch(&column, None, None)?;
+        col_writer.close()?;
+    }
+
+    // Provenance columns (optional INT64 / UTF8 / INT64)
+    {
+        let (values, levels) = definition_levels(&ingested_at);
+        let mut col_writer = row_group_writer.next_column()?.unwrap();
+        col_writer.typed::<Int64Type>().write_batch(&values, Some(&levels), None)?;
+        col_writer.close()?;
+    }
+    {
+        let (values, levels) = definition_levels(&sources);
+        let values: Vec<ByteArray> = values.into_iter().map(ByteArray::from).collect();
+        let mut col_writer = row_group_writer.next_column()?.unwrap();
+        col_writer.typed::<ByteArrayType>().write_batch(&values, Some(&levels), None)?;
+        col_writer.close()?;
+    }
+    {
+        let (values, levels) = definition_levels(&pipeline_versions);
+        let mut col_writer = row_group_writer.next_column()?.unwrap();
+        col_writer.typed::<Int64Type>().write_batch(&values, Some(&levels), None)?;
+        col_writer.close()?;
+    }
+
+    row_group_writer.close()?;
+    writer.close()?;
+
+    Ok(buffer.into_inner())
+}
+
+/// Present values of a nullable column, and its definition levels (1 = present)
+fn definition_levels<T: Copy>(column: &[Option<T>]) -> (Vec<T>, Vec<i16>) {
+    let values = column.iter().flatten().copied().collect();
+    let levels = column.iter().map(|v| i16::from(v.is_some())).collect();
+    (values, levels)
+}
@@ -0,0 +1,20 @@
+//! The parts of the firmware that build and test on the host
+//!
+//! These modules don't touch ESP-IDF, or only behind
+//! `cfg(target_os = "espidf")`, so their tests run on a laptop:
+//!
+//! ```sh
+//! cargo test --lib --target x86_64-unknown-linux-gnu
+//! ```
+//!
+//! The firmware (`main.rs`) uses them like its own modules, under the same
+//! names.
+
+pub mod buffer;
+pub mod catalog;
+pub mod clock;
+pub mod credentials;
+pub mod query;
+pub mod reading;
+#[cfg(any(feature = "fallback", feature = "sts"))]
+pub mod sigv4;
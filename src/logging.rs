@@ -0,0 +1,160 @@
+//! Log output: ESP-IDF text lines or JSON lines
+//!
+//! The logger wraps `EspLogger` and can switch to one JSON object per line
+//! at runtime (`log_fmt` in NVS, or `.log json` on the serial console), so
+//! bench-provisioning rigs and log collectors parse device output instead of
+//! scraping messages (wrapped here, one line on the wire):
+//!
+//! ```json
+//! {"ts":1767225600123,"up_ms":5120,"level":"INFO","module":"esp32s3_parquet_test::lake",
+//!  "event":"lake_attached","msg":"Lake attached","fields":{"backend":"ducklake"}}
+//! ```
+//!
+//! `ts` is the wall clock in Unix milliseconds, `null` before the first time
+//! sync. `event` and `fields` come from the record's key-values, e.g.
+//! `info!(event = "upload", rows = 178; "Uploaded ...")`: the `event` key
+//! names the milestone and every other key lands in `fields`. Text output
+//! ignores them, so the same log lines serve both formats. Logs of ESP-IDF's
+//! C components (WiFi driver, TLS) don't go through this logger and stay text.
+
+use std::io::Write as IoWrite;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use esp_idf_svc::log::EspLogger;
+use esp_idf_svc::sys::esp_timer_get_time;
+use log::kv::{self, Key, VisitSource};
+use log::{Log, Metadata, Record};
+use serde_json::{Map, Value};
+
+// Wall clock below this isn't synced yet (2024-01-01 in Unix ms)
+const MIN_SYNCED_MS: u128 = 1_704_067_200_000;
+
+static ESP_LOGGER: EspLogger = EspLogger::new();
+static LOGGER: DeviceLogger = DeviceLogger;
+static JSON: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => bail!("unknown log format '{}', expected text or json", other),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Json => "json",
+        }
+    }
+}
+
+/// Install the logger, in text format until `set_format`
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        ESP_LOGGER.initialize();
+    }
+}
+
+/// Switch the format of every line from now on
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+pub fn format() -> LogFormat {
+    if JSON.load(Ordering::Relaxed) {
+        LogFormat::Json
+    } else {
+        LogFormat::Text
+    }
+}
+
+struct DeviceLogger;
+
+impl Log for DeviceLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        ESP_LOGGER.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !JSON.load(Ordering::Relaxed) {
+            ESP_LOGGER.log(record);
+            return;
+        }
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = json_line(record);
+        // One write per line, so lines of concurrent threads don't interleave
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(line.as_bytes());
+        let _ = stdout.flush();
+    }
+
+    fn flush(&self) {
+        ESP_LOGGER.flush();
+    }
+}
+
+fn json_line(record: &Record) -> String {
+    let mut fields = Fields::default();
+    let _ = record.key_values().visit(&mut fields);
+
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis())
+        .ok()
+        .filter(|&ms| ms >= MIN_SYNCED_MS);
+    let message = record.args().to_string();
+
+    let mut line = Map::new();
+    line.insert("ts".into(), ts.map_or(Value::Null, |ms| Value::from(ms as u64)));
+    line.insert("up_ms".into(), Value::from(unsafe { esp_timer_get_time() } / 1000));
+    line.insert("level".into(), Value::from(record.level().as_str()));
+    line.insert("module".into(), Value::from(record.target()));
+    line.insert("event".into(), fields.event.map_or(Value::Null, Value::from));
+    line.insert("msg".into(), Value::from(message.trim()));
+    line.insert("fields".into(), Value::Object(fields.values));
+
+    // A map of strings and numbers always serializes
+    let mut text = serde_json::to_string(&line).unwrap_or_default();
+    text.push('\n');
+    text
+}
+
+#[derive(Default)]
+struct Fields {
+    event: Option<String>,
+    values: Map<String, Value>,
+}
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        if key.as_str() == "event" {
+            self.event = Some(value.to_string());
+            return Ok(());
+        }
+        let value = if let Some(b) = value.to_bool() {
+            Value::from(b)
+        } else if let Some(n) = value.to_i64() {
+            Value::from(n)
+        } else if let Some(n) = value.to_u64() {
+            Value::from(n)
+        } else if let Some(n) = value.to_f64() {
+            Value::from(n)
+        } else {
+            Value::from(value.to_string())
+        };
+        self.values.insert(key.as_str().to_string(), value);
+        Ok(())
+    }
+}
@@ -1,51 +1,150 @@
-//! ESP32-S3 Parquet + S3 Chunked Upload Experiment
+//! ESP32-S3 / ESP32-C6 Parquet + S3 Chunked Upload Experiment
 //!
 //! This experimental code for opensensor.space demonstrates:
-//! 1. Creating Snappy-compressed Parquet files on ESP32-S3
+//! 1. Creating Snappy-compressed Parquet files on ESP32-S3 or ESP32-C6
+//!    (target board selected by Cargo feature, see `board.rs`)
 //! 2. Uploading to AWS S3 using chunked transfer encoding
-//! 3. Testing with 3 sample sensor data files
+//! 3. Continuous ingestion: sensor readings are flushed to S3 by a
+//!    configurable row count / age / free-heap policy
 //!
-//! IMPORTANT: Replace AWS credentials and WiFi settings before flashing!
+//! IMPORTANT: Replace the default WiFi and bucket settings in `src/config.rs`
+//! (or provision them via the SoftAP portal). AWS credentials are only ever
+//! provisioned into encrypted NVS, never compiled in.
 
-use std::io::{Cursor, Write as IoWrite};
-use std::sync::Arc;
+use std::io::Write as IoWrite;
+use std::path::Path;
 use std::time::Duration;
 
-use anyhow::{bail, Result};
+use anyhow::Result;
 use embedded_svc::http::client::Client as HttpClient;
 use embedded_svc::http::Method;
-use embedded_svc::wifi::{AuthMethod, ClientConfiguration, Configuration};
 use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::hal::peripherals::Peripherals;
-use esp_idf_svc::http::client::{Configuration as HttpConfig, EspHttpConnection};
+use esp_idf_svc::http::client::EspHttpConnection;
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
-use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
 use log::{error, info, warn};
-use parquet::basic::{Compression, Encoding};
-use parquet::data_type::{FloatType, Int64Type};
-use parquet::file::properties::WriterProperties;
-use parquet::file::writer::SerializedFileWriter;
-use parquet::schema::parser::parse_message_type;
-use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+use rusty_s3::{Bucket, Credentials, S3Action};
+
+mod aggregation;
+mod alerts;
+mod backoff;
+mod board;
+mod boots;
+mod config;
+mod config_sync;
+#[cfg(feature = "console")]
+mod console;
+mod diagnostics;
+mod enrollment;
+mod events;
+#[cfg(feature = "fallback")]
+mod fallback;
+mod flags;
+mod freshness;
+mod identity;
+#[cfg(any(feature = "sdcard", feature = "mqtt"))]
+mod ingest;
+mod lake;
+mod logging;
+mod multipart;
+mod net;
+#[cfg(feature = "ota")]
+mod ota;
+mod pause;
+mod payload;
+mod pipeline;
+mod power;
+mod profiles;
+mod provisioning;
+mod report;
+mod reprovision;
+#[cfg(feature = "sdcard")]
+mod sdcard;
+mod secrets;
+mod sensors;
+#[cfg(feature = "http")]
+mod server;
+mod sketches;
+mod sla;
+mod sleep_state;
+mod sql_tasks;
+mod storage;
+mod sts;
+mod tasks;
+#[cfg(feature = "sdcard")]
+mod tiering;
+mod timesync;
+mod wifi;
+
+// The host-testable parts, see `lib.rs`
+#[cfg(any(feature = "fallback", feature = "sts"))]
+use esp32s3_parquet_test::sigv4;
+use esp32s3_parquet_test::{buffer, catalog, clock, credentials, query, reading};
+
+use aggregation::Aggregator;
+use alerts::Alerts;
+use backoff::UploadBackoff;
+use board::BoardPins;
+use boots::BootRecord;
+use buffer::OfflineBuffer;
+use clock::clock;
+use config::{ConfigStore, DeviceConfig};
+use config_sync::SyncOutcome;
+use credentials::{CredentialStore, RotationOutcome, S3Credentials};
+use diagnostics::Diagnostics;
+use identity::DeviceIdentity;
+use lake::{LakeBackend, S3Target, UploadStats};
+use net::{with_retry, HttpStatusError, RetryPolicy};
+use pipeline::FlushPolicy;
+use profiles::{ProfileRouter, S3Endpoint};
+use report::DailyReport;
+use secrets::SecretStore;
+use sensors::{
+    AdaptiveInterval, Deadband, Sampler, SamplerTask, SensorPeripherals, SensorReading,
+    WarmUpPolicy,
+};
+use sketches::BatchSketches;
+use sla::SlaTracker;
+use sleep_state::SleepState;
+use sts::TemporaryCredentials;
+use tasks::Message;
+use wifi::WifiLink;
 
 // ============================================================================
-// CONFIGURATION - REPLACE THESE VALUES!
+// CONFIGURATION
 // ============================================================================
-
-// WiFi Configuration
-const WIFI_SSID: &str = "YOUR_WIFI";
-const WIFI_PASSWORD: &str = "YOUR_PASSWORD";
-
-// AWS S3 Configuration
-const AWS_ACCESS_KEY: &str = "YOUR_ACCESS_KEY";
-const AWS_SECRET_KEY: &str = "YOUR_SECRET_KEY";
-const S3_BUCKET: &str = "YOUR_BUCKET";
-const S3_REGION: &str = "us-west-2";
+// WiFi, S3 and table settings are loaded from NVS, see `config.rs`
 
 // Upload settings
-const CHUNK_SIZE: usize = 8192; // 8KB chunks for chunked transfer
-const NUM_TEST_FILES: usize = 3;
 const ROWS_PER_FILE: usize = 178; // Similar to opensensor.space data
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Adaptive sampling: speed up on rapid changes, slow down when stable
+const ADAPTIVE_SAMPLING: bool = false;
+const MIN_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_SAMPLE_INTERVAL: Duration = Duration::from_secs(30);
+
+// How often the uploader takes the batcher's messages
+const UPLOADER_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Offline buffering
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(60);
+// Every buffered batch is spooled to a file here on the flash storage partition
+const SPOOL_DIR: &str = "spool";
+// Buffered batches the spool couldn't take, carried across deep sleep (and OTA reboots)
+const SLEEP_BUFFER_FILE: &str = "sleep_buffer.bin";
+// Alert states, open rollups and the rest of the pipeline state, see `sleep_state.rs`
+const SLEEP_STATE_FILE: &str = "sleep_state.json";
+
+// Object written with staged credentials before they are swapped in
+const ROTATION_PROBE_NAME: &str = "_rotation_probe";
+
+// Retry of transient S3 failures (exponential backoff with jitter)
+const UPLOAD_RETRY: RetryPolicy = RetryPolicy {
+    max_attempts: 4,
+    base_delay: Duration::from_millis(500),
+    max_delay: Duration::from_secs(10),
+};
 
 // ============================================================================
 // MAIN ENTRY POINT
@@ -53,10 +152,13 @@ const ROWS_PER_FILE: usize = 178; // Similar to opensensor.space data
 
 fn main() -> Result<()> {
     esp_idf_svc::sys::link_patches();
-    esp_idf_svc::log::EspLogger::initialize_default();
+    // Before anything reads the time, log lines included
+    clock::install(Box::new(timesync::SntpClock::new()));
+    logging::init();
+    board::configure_threads()?;
 
     info!("================================================");
-    info!("ESP32-S3 Parquet + S3 Chunked Upload Experiment");
+    info!("Parquet + S3 Chunked Upload Experiment ({})", board::CHIP);
     info!("For opensensor.space");
     info!("================================================");
 
@@ -64,309 +166,934 @@ fn main() -> Result<()> {
     let peripherals = Peripherals::take()?;
     let sys_loop = EspSystemEventLoop::take()?;
     let nvs = EspDefaultNvsPartition::take()?;
+    let secrets_nvs = secrets::take_partition()?;
+    let secrets_source = config::secrets_source(nvs.clone())?;
+    let secret_store = SecretStore::open(secrets_nvs.clone(), &secrets_source)?;
+    info!("Secrets provider: {}", secret_store.provider());
+    let mut config_store = ConfigStore::new(nvs.clone(), secret_store)?;
+    let config = match config_store.load()? {
+        // Never returns: reboots once the fleet server has sent the configuration bundle
+        Some(config) if config.needs_enrollment() => {
+            let secrets = SecretStore::open(secrets_nvs.clone(), &secrets_source)?;
+            let modem = peripherals.modem;
+            match enrollment::run(modem, sys_loop, nvs, config_store, secrets, config)? {}
+        }
+        Some(config) => config,
+        // Never returns: reboots once the user has submitted the portal form
+        None => match provisioning::run_portal(peripherals.modem, sys_loop, nvs, config_store)? {},
+    };
+    match logging::LogFormat::parse(&config.log_format) {
+        Ok(format) => logging::set_format(format),
+        Err(e) => warn!("{}, keeping text logs", e),
+    }
+    let mut secrets = SecretStore::open(secrets_nvs.clone(), &secrets_source)?;
+    let router = ProfileRouter::load(nvs.clone(), &secrets, &config)?;
+    let mut backoff = UploadBackoff::load(nvs.clone(), &config)?;
+    let mut auth_watch = reprovision::AuthWatch::load(nvs.clone(), &config)?;
+    let mut freshness = freshness::FreshnessCheck::load(nvs.clone(), &config)?;
+    #[cfg(feature = "ota")]
+    let mut updater = ota::Updater::load(nvs.clone(), &config)?;
+    let endpoint = S3Endpoint::from_config(&config)?;
+    match (endpoint.host(), config.s3_ca.is_empty()) {
+        (Some(host), false) => net::install_ca(host, &config.s3_ca)?,
+        (None, false) => warn!("s3_ca is set without s3_url, ignoring it"),
+        (_, true) => {}
+    }
+    multipart::install(multipart::UploadSizing::from_config(&config));
+    if let (Some(host), false) = (endpoint.host(), endpoint.is_tls()) {
+        warn!("S3 endpoint {} is plain HTTP: uploads are not encrypted", host);
+    }
+    let mut config_trial = config_store.update_trial(&config, !power::woke_from_sleep())?;
+    if let Some(trial) = &config_trial {
+        info!("Configuration v{} is on trial until a batch is committed", trial.version);
+    }
+    let mut credential_store = CredentialStore::new(secrets_nvs)?;
+
+    let pins = BoardPins::take(peripherals.pins);
+    let sources = sensors::build_sources(SensorPeripherals {
+        i2c0: peripherals.i2c0,
+        sda: pins.sda,
+        scl: pins.scl,
+        uart1: peripherals.uart1,
+        tx: pins.sensor_tx,
+        rx: pins.sensor_rx,
+    })?;
+    let sensor_names: Vec<&str> = sources.iter().map(|s| s.name()).collect();
+    let warm_up = WarmUpPolicy::parse(&config.warm_up)?;
+    let mut sampler = Sampler::new(sources, SAMPLE_INTERVAL, warm_up);
+    if ADAPTIVE_SAMPLING {
+        sampler = sampler.with_adaptive(AdaptiveInterval::new(
+            MIN_SAMPLE_INTERVAL,
+            MAX_SAMPLE_INTERVAL,
+        ));
+    }
+    let deadband = Deadband::parse(
+        &config.deadbands,
+        Duration::from_secs(config.heartbeat_secs.into()),
+    )?;
+    if deadband.is_active() {
+        info!(
+            "Deadband suppression: {} (heartbeat {}s)",
+            deadband.describe(),
+            config.heartbeat_secs
+        );
+        sampler = sampler.with_deadband(deadband);
+    }
+    let mut alerts = Alerts::parse(&config.alerts, &config.alert_url, &config.alert_encoding)?;
+    if alerts.is_active() {
+        info!("Alert rules: {}", alerts.describe());
+    }
+    let mut aggregator = Aggregator::from_config(&config)?;
+    if aggregator.is_active() {
+        info!("Rollups: {}", aggregator.describe());
+    }
+
+    // Other equipment drops CSV files into a watch folder on the SD card
+    #[cfg(feature = "sdcard")]
+    let (mut card, mut watch, mut tiering) = match sdcard::mount(sdcard::SdCardPeripherals {
+        spi2: peripherals.spi2,
+        sclk: pins.sd_sclk,
+        mosi: pins.sd_mosi,
+        miso: pins.sd_miso,
+        cs: pins.sd_cs,
+    }) {
+        Ok(card) => {
+            let watch = ingest::WatchFolder::new(sdcard::MOUNT_POINT).unwrap_or_else(|e| {
+                warn!("Invalid watch folder mappings: {:?}", e);
+                None
+            });
+            let tiering = tiering::Tiering::new(sdcard::MOUNT_POINT, &config)?;
+            (Some(card), watch, tiering)
+        }
+        Err(e) => {
+            warn!("SD card unavailable, watch folder disabled: {:?}", e);
+            if config.raw_days > 0 {
+                warn!("Raw data tiering needs the SD card, uploading raw readings");
+            }
+            (None, None, None)
+        }
+    };
+    #[cfg(feature = "sdcard")]
+    if let Some(tiering) = tiering.as_mut() {
+        info!("Retention tiering: {}", tiering.describe());
+        if let Err(e) = tiering.restore() {
+            warn!("Failed to restore rollup state: {:?}", e);
+        }
+    }
+
+    // Lake metadata on flash, re-attached across reboots
+    let mut flash = storage::mount()?;
+    let identity = DeviceIdentity::from_config(&config)?;
+    info!(
+        event = "device",
+        device_id = identity.device_id.as_str(),
+        firmware = identity.firmware_version.as_str();
+        "Device {} (firmware {}, location '{}')",
+        identity.device_id, identity.firmware_version, identity.location
+    );
+    let mut feature_flags = flags::FeatureFlags::load(nvs.clone(), &config, &identity.device_id)?;
+    let mut sql_tasks = sql_tasks::SqlTasks::load(nvs.clone(), &config, &identity.device_id)?;
+    let lake = lake::open(&config, &identity, &secrets, storage::MOUNT_POINT);
+    // A firmware update on probation stays only if it attached the configured lake
+    #[cfg(feature = "ota")]
+    updater.settle(&config.lake_backend, lake.as_ref().map(|lake| lake.name()));
+    let mut lake = lake?;
+    #[cfg(feature = "sdcard")]
+    let sd_card = card.is_some();
+    #[cfg(not(feature = "sdcard"))]
+    let sd_card = false;
+    let mut boot = BootRecord::new(&sensor_names, lake.as_ref(), &config, sd_card);
+    info!(event = "boot", backend = lake.name(); "Boot: {}", boot.describe());
+
+    #[cfg(feature = "console")]
+    let console = console::Console::start(console::ConsolePeripherals {
+        uart0: peripherals.uart0,
+        tx: pins.console_tx,
+        rx: pins.console_rx,
+    })?;
+
+    let mut buffer = OfflineBuffer::new(board::MAX_BUFFERED_ROWS);
+    let sleep_buffer_path = Path::new(storage::MOUNT_POINT).join(SLEEP_BUFFER_FILE);
+    if power::woke_from_sleep() {
+        info!("Woke from deep sleep");
+    }
+    if let Err(e) = buffer.open_spool(&Path::new(storage::MOUNT_POINT).join(SPOOL_DIR)) {
+        warn!("Failed to open the batch spool, buffered batches stay in RAM: {:?}", e);
+    }
+    if let Err(e) = buffer.restore(&sleep_buffer_path) {
+        warn!("Failed to restore buffered batches: {:?}", e);
+    }
+    let mut diagnostics = Diagnostics::new(config.health_table);
+    let mut sla = SlaTracker::new(&config);
+    let mut sketches = BatchSketches::new(config.sketches);
+    let mut report = DailyReport::new(&config, &identity);
+    let sleep_state_path = Path::new(storage::MOUNT_POINT).join(SLEEP_STATE_FILE);
+    let saved = SleepState::take(&sleep_state_path).unwrap_or_else(|e| {
+        warn!("Failed to restore the pipeline state: {:?}", e);
+        SleepState::default()
+    });
+    if let Some(state) = saved.alerts {
+        alerts.restore_state(state);
+    }
+    if let Some(state) = saved.rollups {
+        aggregator.restore_state(state);
+    }
+    if let Some(state) = saved.health {
+        diagnostics.restore_state(state);
+    }
+    if let (Some(sla), Some(state)) = (sla.as_mut(), saved.sla) {
+        sla.restore_state(state);
+    }
+    if let Some(state) = saved.sketches {
+        sketches.restore_state(state);
+    }
+    if let Some(state) = saved.report {
+        report.restore_state(state);
+    }
 
     // Connect to WiFi
     info!("Step 1: Connecting to WiFi...");
-    let _wifi = match connect_wifi(peripherals.modem, sys_loop, nvs) {
-        Ok(wifi) => {
-            info!("WiFi connected successfully!");
-            wifi
+    let wifi = WifiLink::new(peripherals.modem, sys_loop, nvs, &config, &secrets)?;
+    timesync::start(&config)?;
+    let device_id = enrollment::device_id(&wifi)?;
+    let mut temporary = TemporaryCredentials::from_config(&config, &mut secrets, &device_id)?;
+    // An update applied by the config sync at boot
+    let mut config_updated = false;
+    let mut credentials = match wifi.connect() {
+        Ok(()) => {
+            info!(event = "wifi_connected"; "WiFi connected successfully!");
+            let mut creds = go_online(&mut credential_store, &config, &router, temporary.as_mut())?;
+            // A config on trial has to prove itself before taking another update
+            let synced = match config_trial {
+                Some(_) => SyncOutcome::Current,
+                None => config_sync::run(
+                    &mut config_store,
+                    &mut secrets,
+                    &mut credential_store,
+                    &config,
+                    &device_id,
+                )?,
+            };
+            match synced {
+                SyncOutcome::CredentialsStaged if clock().is_trusted() => {
+                    let bucket = router.default_bucket();
+                    creds = rotate_credentials(
+                        &mut credential_store,
+                        &config,
+                        bucket,
+                        temporary.as_mut(),
+                    )?;
+                }
+                // Rebooted into from the ingest loop, which saves the state
+                SyncOutcome::Updated => config_updated = true,
+                _ => {}
+            }
+            Some(creds)
         }
         Err(e) => {
             error!("WiFi connection failed: {:?}", e);
-            error!("Running in offline mode - will create Parquet files only");
-            run_offline_test()?;
-            return Ok(());
+            warn!("Running in offline mode - batches will be buffered until WiFi returns");
+            None
         }
     };
+    // Reconnects after a drop, the forwarding below picks up from there
+    wifi.watch(RECONNECT_INTERVAL)?;
 
-    // Synchronize time (required for S3 presigned URLs)
-    if let Err(e) = initialize_sntp() {
-        error!("Failed to synchronize time: {:?}", e);
-        // Continue anyway, but upload might fail
-    }
-
+    pause::limit(Duration::from_secs(u64::from(config.pause_max_mins) * 60));
+    #[cfg(feature = "http")]
+    let server = server::Server::start(&config, &identity)?;
 
-    // Run the full experiment with S3 upload
-    run_full_experiment()?;
+    let policy = FlushPolicy::from_config(&config);
 
-    info!("================================================");
-    info!("Experiment complete!");
-    info!("================================================");
+    // Other sensors on the LAN publish JSON readings over MQTT
+    #[cfg(feature = "mqtt")]
+    let mut mqtt = if config.mqtt_url.is_empty() {
+        None
+    } else {
+        // Subscribers can't negotiate, `auto` publishes JSON
+        let encoding = payload::PeerEncoding::from_name(&config.alert_encoding)
+            .map_or(payload::PayloadEncoding::Json, |encoding| encoding.current());
+        ingest::MqttSource::start(
+            &config.mqtt_url,
+            &config.mqtt_topics,
+            &device_id,
+            &config.table_name,
+            encoding,
+            &config.control_token,
+        )
+        .map_err(|e| warn!("MQTT ingestion disabled: {:?}", e))
+        .ok()
+    };
+    info!(
+        "Step 2: Ingesting (flush at {} rows, every {:?} or below {} bytes free heap)...",
+        policy.max_rows, policy.max_age, policy.min_free_heap
+    );
 
-    // Keep running (don't exit)
-    loop {
-        std::thread::sleep(Duration::from_secs(60));
-    }
-}
+    // From here on the sensors are read and batched on their own threads,
+    // this loop is the uploader
+    let sampler = SamplerTask::start(
+        sampler,
+        #[cfg(feature = "timer-sampling")]
+        peripherals.timer00,
+    )?;
+    let messages = tasks::start(sampler, policy, config.maintenance_mins)?;
+
+    let mut batch_index = 0;
+    #[cfg(feature = "http")]
+    let mut last_flush_ms = None;
+    #[cfg(feature = "sdcard")]
+    let mut last_watch_scan = clock().monotonic();
+    let mut time_trusted = clock().is_trusted();
+    let mut maintenance_due = false;
+    // Batches flushed while the pipeline is paused, oldest first
+    let mut held = Vec::new();
+    // Whether the storage is unmounted for a pause
+    let mut released = false;
+
+    // Run until a reboot or deep sleep: take the batches into the buffer,
+    // forward whenever online
+    let shutdown = 'ingest: loop {
+        if config_updated {
+            break 'ingest Shutdown::Restart;
+        }
+        let mut flushed = false;
+        let mut uploaded = 0;
+        if let Some(sla) = sla.as_mut() {
+            sla.observe(wifi.is_connected());
+        }
+        let mut flushes = Vec::new();
+        for message in messages.try_iter() {
+            match message {
+                Message::Sample(reading) => {
+                    report.sample(&reading);
+                    if feature_flags.flags().alerts {
+                        for change in alerts.update(&reading) {
+                            report.alert(&reading, &change);
+                        }
+                    }
+                }
+                Message::Batch { readings, reason } => flushes.push((readings, reason)),
+                Message::Maintain => maintenance_due = true,
+            }
+        }
 
-// ============================================================================
-// WIFI CONNECTION
-// ============================================================================
+        // Between rounds: the last one has committed, nothing is in flight
+        let paused = pause::settle();
+        if paused {
+            held.append(&mut flushes);
+            let mut rows: usize = held.iter().map(|(readings, _)| readings.len()).sum();
+            while rows > board::MAX_BUFFERED_ROWS && held.len() > 1 {
+                let (dropped, _) = held.remove(0);
+                rows -= dropped.len();
+                warn!("Dropped a held batch of {} rows while paused", dropped.len());
+            }
+        } else if !held.is_empty() {
+            held.append(&mut flushes);
+            flushes = std::mem::take(&mut held);
+        }
+        // Quiesced: the holder, or whoever it hands the card to, has the storage
+        if paused != released {
+            released = paused;
+            if paused {
+                if let Err(e) = flash.release() {
+                    warn!("Failed to unmount the flash storage for the pause: {:?}", e);
+                }
+                #[cfg(feature = "sdcard")]
+                if let Some(card) = card.as_mut() {
+                    card.release();
+                }
+            } else {
+                flash.remount()?;
+                #[cfg(feature = "sdcard")]
+                if let Some(card) = card.as_mut() {
+                    card.resume();
+                }
+            }
+        }
 
-fn connect_wifi(
-    modem: esp_idf_svc::hal::modem::Modem,
-    sys_loop: EspSystemEventLoop,
-    nvs: EspDefaultNvsPartition,
-) -> Result<BlockingWifi<EspWifi<'static>>> {
-    let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(modem, sys_loop.clone(), Some(nvs))?,
-        sys_loop,
-    )?;
+        // The OTA holder installs once the pipeline has quiesced; offline, it
+        // keeps the pause until WiFi is back or the pause limit drops it
+        #[cfg(feature = "ota")]
+        if paused && pause::is_held(pause::Holder::Ota) && wifi.is_connected() {
+            if updater.update() {
+                // Batches held since the pause are saved with the rest, as raw rows
+                flash.remount()?;
+                #[cfg(feature = "sdcard")]
+                if let Some(card) = card.as_mut() {
+                    card.resume();
+                }
+                for (readings, _) in held.drain(..) {
+                    buffer.push(&config.table_name, batch_index, readings);
+                    batch_index += 1;
+                }
+                break 'ingest Shutdown::Restart;
+            }
+            pause::resume(pause::Holder::Ota);
+        }
 
-    let wifi_configuration = Configuration::Client(ClientConfiguration {
-        ssid: WIFI_SSID.try_into().unwrap(),
-        password: WIFI_PASSWORD.try_into().unwrap(),
-        auth_method: AuthMethod::WPA2Personal,
-        ..Default::default()
-    });
+        for (readings, reason) in flushes {
+            info!("----------------------------------------");
+            info!(
+                event = "flush", batch = batch_index + 1, rows = readings.len();
+                "Flushing batch {} ({} rows, {})", batch_index + 1, readings.len(), reason
+            );
+            let flush_rows = readings.len();
+            aggregator.record(&config.table_name, &readings);
+            sketches.record(&config.table_name, &readings);
+            // With tiering, raw rows stay on the SD card and the lake gets the aggregates
+            #[cfg(feature = "sdcard")]
+            let batches = match tiering.as_mut() {
+                Some(tiering) => tiering.record(&config.table_name, readings),
+                None => vec![(config.table_name.clone(), aggregator.raw(readings))],
+            };
+            #[cfg(not(feature = "sdcard"))]
+            let batches = vec![(config.table_name.clone(), aggregator.raw(readings))];
+            for (table, readings) in batches {
+                // Everything thinned out by `raw_upload`
+                if readings.is_empty() {
+                    continue;
+                }
+                if let Some(sla) = sla.as_mut() {
+                    sla.flushed(&readings);
+                }
+                buffer.push(&table, batch_index, readings);
+                batch_index += 1;
+            }
+            diagnostics.sample(flush_rows, buffer.len(), &wifi);
+            flushed = true;
+            #[cfg(feature = "http")]
+            {
+                last_flush_ms = Some(clock().now_millis());
+            }
+        }
 
-    wifi.set_configuration(&wifi_configuration)?;
-    wifi.start()?;
+        #[cfg(feature = "sdcard")]
+        if !paused && clock().elapsed_since(last_watch_scan) >= ingest::SCAN_INTERVAL {
+            last_watch_scan = clock().monotonic();
+            if let Some(watch) = watch.as_mut() {
+                watch.scan(policy.max_rows, &config.table_name, |table, readings| {
+                    sketches.record(table, &readings);
+                    buffer.push(table, batch_index, readings);
+                    batch_index += 1;
+                });
+            }
+        }
 
-    info!("WiFi started, connecting to '{}'...", WIFI_SSID);
-    wifi.connect()?;
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = mqtt.as_mut().filter(|_| !paused) {
+            mqtt.poll(&policy, |table, readings| {
+                sketches.record(table, &readings);
+                buffer.push(table, batch_index, readings);
+                batch_index += 1;
+            });
+        }
 
-    info!("Waiting for DHCP...");
-    wifi.wait_netif_up()?;
+        // Promoted raw rows trickle in behind live data
+        #[cfg(feature = "sdcard")]
+        if buffer.is_empty() && !paused {
+            if let Some((table, readings)) =
+                tiering.as_mut().and_then(|t| t.next_promoted(policy.max_rows))
+            {
+                info!("Queued {} promoted raw rows of '{}'", readings.len(), table);
+                buffer.push(&table, batch_index, readings);
+                batch_index += 1;
+            }
+        }
 
-    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
-    info!("WiFi connected! IP: {}", ip_info.ip);
+        // Untrusted time would fail SigV4 and mis-stamp the lake: keep buffering
+        if clock().is_trusted() != time_trusted {
+            time_trusted = !time_trusted;
+            if time_trusted {
+                info!("Time is trusted again, resuming S3 uploads");
+            } else {
+                warn!("Time is no longer trusted, pausing S3 uploads until SNTP syncs");
+                let detail = format!("no SNTP sync for {} min", config.time_trust_mins);
+                events::report(events::TIME_UNTRUSTED, "", detail);
+                timesync::resync();
+            }
+        }
 
-    Ok(wifi)
-}
+        let forward = !buffer.is_empty()
+            || alerts.has_unwritten()
+            || aggregator.has_unwritten()
+            || diagnostics.has_unwritten()
+            || sla.as_ref().is_some_and(SlaTracker::has_unwritten)
+            || sketches.has_unwritten()
+            || report.has_unwritten()
+            || freshness.as_ref().is_some_and(freshness::FreshnessCheck::is_due)
+            || sql_tasks.is_due()
+            || maintenance_due;
+        // An open breaker also spares STS and config sync during an outage
+        if forward && !paused && wifi.is_connected() && time_trusted && backoff.allows() {
+            let base = match &credentials {
+                Some(creds) => creds.clone(),
+                None => {
+                    let mut creds =
+                        go_online(&mut credential_store, &config, &router, temporary.as_mut())?;
+                    let synced = match config_trial {
+                        Some(_) => SyncOutcome::Current,
+                        None => config_sync::run(
+                            &mut config_store,
+                            &mut secrets,
+                            &mut credential_store,
+                            &config,
+                            &device_id,
+                        )?,
+                    };
+                    match synced {
+                        SyncOutcome::CredentialsStaged if clock().is_trusted() => {
+                            creds = rotate_credentials(
+                                &mut credential_store,
+                                &config,
+                                router.default_bucket(),
+                                temporary.as_mut(),
+                            )?;
+                        }
+                        SyncOutcome::Updated => break 'ingest Shutdown::Restart,
+                        _ => {}
+                    }
+                    credentials = Some(creds.clone());
+                    creds
+                }
+            };
+            let session = match temporary.as_mut() {
+                Some(temporary) => temporary
+                    .current(&base)
+                    .map_err(|e| warn!("No temporary S3 credentials, not forwarding: {:?}", e))
+                    .ok(),
+                None => Some(base.clone()),
+            };
+
+            if let Some(mut creds) = session {
+                diagnostics.start_round();
+                // Freshly rotated credentials must prove themselves on real uploads
+                let mut replay = replay_buffer(
+                    &config,
+                    &identity,
+                    &router,
+                    &creds,
+                    lake.as_mut(),
+                    &mut buffer,
+                    sla.as_mut(),
+                );
+                uploaded = replay.replayed;
+                if let (true, Some(temporary)) = (replay.expired, temporary.as_mut()) {
+                    warn!("Temporary S3 credentials expired early, requesting a new session");
+                    temporary.invalidate();
+                    match temporary.current(&base) {
+                        Ok(fresh) => {
+                            creds = fresh;
+                            replay = replay_buffer(
+                                &config,
+                                &identity,
+                                &router,
+                                &creds,
+                                lake.as_mut(),
+                                &mut buffer,
+                                sla.as_mut(),
+                            );
+                            uploaded += replay.replayed;
+                        }
+                        Err(e) => warn!("Failed to refresh temporary S3 credentials: {:?}", e),
+                    }
+                }
+                if uploaded == 0 && replay.outage {
+                    backoff.round_failed();
+                } else {
+                    backoff.round_succeeded();
+                }
+                if uploaded > 0 {
+                    auth_watch.round_succeeded();
+                } else if replay.auth_failed {
+                    auth_watch.round_failed();
+                }
+                diagnostics.end_round(uploaded);
+                let target = S3Target {
+                    router: &router,
+                    credentials: &creds,
+                };
+                if feature_flags.is_due() {
+                    feature_flags.refresh(&target);
+                }
+                if alerts.has_unwritten() {
+                    alerts.write(lake.as_mut(), &target);
+                }
+                if aggregator.has_unwritten() {
+                    aggregator.write(lake.as_mut(), &target);
+                }
+                if boot.is_pending() {
+                    boot.write(lake.as_mut(), &target);
+                }
+                if events::has_pending() {
+                    events::write(lake.as_mut(), &target);
+                }
+                if diagnostics.has_unwritten() {
+                    diagnostics.write(lake.as_mut(), &target);
+                }
+                if let Some(sla) = sla.as_mut().filter(|sla| sla.has_unwritten()) {
+                    sla.write(lake.as_mut(), &target);
+                }
+                if sketches.has_unwritten() {
+                    sketches.write(lake.as_mut(), &target);
+                }
+                if report.has_unwritten() {
+                    report.upload(&target);
+                }
+                // Outages are the backoff's business, not a stuck device
+                let check = freshness.as_mut().filter(|check| check.is_due() && !replay.outage);
+                match check.and_then(|check| check.check(lake.as_mut(), &target)) {
+                    Some(freshness::Remedy::Reattach) => {
+                        match lake::open(&config, &identity, &secrets, storage::MOUNT_POINT) {
+                            Ok(attached) => lake = attached,
+                            Err(e) => warn!("Re-attaching the lake failed: {:?}", e),
+                        }
+                    }
+                    Some(freshness::Remedy::ReconnectWifi) => {
+                        if let Err(e) = wifi.reconnect() {
+                            warn!("Reconnecting WiFi failed: {:?}", e);
+                        }
+                    }
+                    Some(freshness::Remedy::Reboot) => break 'ingest Shutdown::Restart,
+                    None => {}
+                }
+                // Compacting under a backlog would only delay the backlog
+                if maintenance_due && buffer.is_empty() {
+                    maintenance_due = false;
+                    if !feature_flags.flags().compaction {
+                        info!("Lake maintenance is switched off by the feature flags");
+                    } else if let Err(e) = lake.maintain(&target, false) {
+                        warn!("Lake maintenance failed: {:?}", e);
+                    }
+                }
+                if sql_tasks.is_due() {
+                    let compaction = feature_flags.flags().compaction;
+                    sql_tasks.run(lake.as_mut(), &target, compaction);
+                }
+                #[cfg(feature = "http")]
+                if server.take_maintenance_request() {
+                    if let Err(e) = lake.maintain(&target, true) {
+                        warn!("Requested lake maintenance failed: {:?}", e);
+                    }
+                }
+                if settle_rotation(&mut credential_store, uploaded, replay.auth_failed)? {
+                    credentials = None;
+                }
+                // Right after a flush went up, so a reboot loses no readings
+                #[cfg(feature = "ota")]
+                if flushed
+                    && buffer.is_empty()
+                    && !alerts.has_unwritten()
+                    && !aggregator.has_unwritten()
+                    && !diagnostics.has_unwritten()
+                    && !sla.as_ref().is_some_and(SlaTracker::has_unwritten)
+                    && !sketches.has_unwritten()
+                    && !report.has_unwritten()
+                    && updater.is_due()
+                {
+                    pause::pause(pause::Holder::Ota);
+                }
+            }
+        }
+        if wifi.is_connected() && feature_flags.flags().alerts {
+            alerts.notify(&identity);
+        }
 
-// ============================================================================
-// SNTP TIME SYNC
-// ============================================================================
+        // Revoked keys never come back: fetch fresh ones from the fleet server
+        let can_enroll = !config.enroll_url.is_empty() && config.has_credentials();
+        let due = (time_trusted && !paused).then(|| auth_watch.is_due(can_enroll));
+        if let Some(failing_mins) = due.flatten() {
+            error!(
+                event = "reprovisioning", failing_mins = failing_mins;
+                "S3 has rejected the credentials for {} min{}",
+                failing_mins,
+                if can_enroll { ", re-enrolling" } else { "; set enroll_url to re-enroll" }
+            );
+            let notice = reprovision::notice(&device_id, failing_mins, can_enroll);
+            auth_watch.notify(&config, &notice);
+            #[cfg(feature = "mqtt")]
+            if let Some(mqtt) = mqtt.as_mut() {
+                let topic = format!("esp32s3-ducklake/{}/status", device_id);
+                if let Err(e) = mqtt.publish(&topic, &notice) {
+                    warn!("Failed to publish the re-provisioning notice: {:?}", e);
+                }
+            }
+            if can_enroll {
+                secrets.clear_s3_credentials()?;
+                credential_store.clear()?;
+                auth_watch.reset();
+                break 'ingest Shutdown::Restart;
+            }
+        }
 
-fn initialize_sntp() -> Result<()> {
-    info!("Step 1.5: Synchronizing time via SNTP...");
+        if let Some(trial) = &config_trial {
+            if uploaded > 0 {
+                config_store.confirm_update()?;
+                info!("Configuration v{} committed a batch, update confirmed", trial.version);
+                config_trial = None;
+            } else if trial.expired() {
+                if config_store.rollback_update(&config)? {
+                    warn!(
+                        "No batch committed under configuration v{}, rolled back, rebooting...",
+                        trial.version
+                    );
+                    break 'ingest Shutdown::Restart;
+                }
+                warn!(
+                    "No batch committed under configuration v{}, nothing to roll back to",
+                    trial.version
+                );
+                config_trial = None;
+            }
+        }
 
-    let sntp = esp_idf_svc::sntp::EspSntp::new_default()?;
-    info!("SNTP initialized, waiting for status sync...");
+        #[cfg(feature = "console")]
+        for command in console.poll(lake.as_ref()) {
+            match command {
+                console::Command::Promote { .. } if paused => {
+                    println!("The pipeline is paused and the SD card unmounted, try after resume");
+                }
+                console::Command::Promote { from_ms, to_ms } => {
+                    #[cfg(feature = "sdcard")]
+                    match tiering.as_mut() {
+                        Some(tiering) => tiering.promote(&config.table_name, from_ms, to_ms),
+                        None => println!("Raw data tiering is off, nothing to promote"),
+                    }
+                    #[cfg(not(feature = "sdcard"))]
+                    {
+                        let _ = (from_ms, to_ms);
+                        println!("Promoting raw data needs the SD card (feature `sdcard`)");
+                    }
+                }
+            }
+        }
+        #[cfg(feature = "http")]
+        server.poll(
+            lake.as_ref(),
+            &server::Health {
+                wifi_connected: wifi.is_connected(),
+                wifi_network: wifi.network(),
+                wifi_rssi: wifi.rssi(),
+                last_flush_ms,
+                buffered_batches: buffer.len(),
+                backoff: backoff.status(),
+                feature_flags: feature_flags.flags(),
+            },
+        );
 
-    let mut wait_count = 0;
-    while sntp.get_sync_status() != esp_idf_svc::sntp::SyncStatus::Completed {
-        std::thread::sleep(Duration::from_millis(100));
-        wait_count += 1;
-        
-        // Print progress every second
-        if wait_count % 10 == 0 {
-            info!("  Waiting for time sync... ({}s)", wait_count / 10);
+        // Duty cycle: the upload window ends with the flush, unless a pause
+        // was asked for since `settle`, e.g. by the OTA check after the flush
+        let pausing = pause::state() != pause::PauseState::Running;
+        if flushed && !paused && !pausing && config.sleep_secs > 0 {
+            break 'ingest Shutdown::Sleep(Duration::from_secs(config.sleep_secs.into()));
         }
 
-        // Timeout after 15 seconds
-        if wait_count > 150 {
-             bail!("Timeout waiting for SNTP time sync");
+        clock().sleep(UPLOADER_POLL_INTERVAL);
+    };
+
+    // Everything the next boot picks up again, saved in one place
+    let root = Path::new(storage::MOUNT_POINT);
+    if buffer.has_unspooled() {
+        if let Err(e) = buffer.persist(&root.join(SLEEP_BUFFER_FILE)) {
+            error!("Failed to persist buffered batches, they will be lost: {:?}", e);
+        }
+    }
+    let state = SleepState {
+        alerts: alerts.take_state(),
+        rollups: aggregator.take_state(),
+        health: diagnostics.take_state(),
+        sla: sla.as_mut().and_then(SlaTracker::take_state),
+        sketches: sketches.take_state(),
+        report: report.take_state(),
+    };
+    if let Err(e) = state.save(&root.join(SLEEP_STATE_FILE)) {
+        error!("Failed to save the pipeline state, it will be lost: {:?}", e);
+    }
+    #[cfg(feature = "sdcard")]
+    if let Some(tiering) = tiering.as_ref() {
+        if let Err(e) = tiering.persist() {
+            error!("Failed to persist rollup state: {:?}", e);
         }
     }
+    match shutdown {
+        Shutdown::Restart => {
+            std::thread::sleep(Duration::from_secs(1));
+            esp_idf_svc::hal::reset::restart();
+        }
+        Shutdown::Sleep(duration) => power::deep_sleep(&wifi, duration),
+    }
+}
+
+/// How the ingest loop ends
+enum Shutdown {
+    // Into new firmware, new credentials or a fresh start
+    Restart,
+    Sleep(Duration),
+}
 
-    // Log current time
-    let now = std::time::SystemTime::now();
-    let since_epoch = now.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
-    info!("Time synchronized! Unix timestamp: {}", since_epoch);
+/// Time sync and credential rotation, run once connectivity is first available
+fn go_online(
+    store: &mut CredentialStore,
+    config: &DeviceConfig,
+    router: &ProfileRouter,
+    temporary: Option<&mut TemporaryCredentials>,
+) -> Result<S3Credentials> {
+    // Synchronize time (required for S3 presigned URLs)
+    info!("Step 1.5: Synchronizing time...");
+    if let Err(e) = clock().synchronize() {
+        error!("Failed to synchronize time: {:?}", e);
+    }
+    // A probe signed with the wrong time would reject good staged credentials
+    if !clock().is_trusted() {
+        warn!("Time not trusted, S3 uploads and credential rotation wait for SNTP");
+        return store.active(&config.credentials());
+    }
 
-    Ok(())
+    rotate_credentials(store, config, router.default_bucket(), temporary)
 }
 
 // ============================================================================
-// OFFLINE TEST (No WiFi)
+// CREDENTIAL ROTATION
 // ============================================================================
 
-fn run_offline_test() -> Result<()> {
-    info!("Running offline test - creating 3 Parquet files...");
+fn rotate_credentials(
+    store: &mut CredentialStore,
+    config: &DeviceConfig,
+    bucket: &Bucket,
+    temporary: Option<&mut TemporaryCredentials>,
+) -> Result<S3Credentials> {
+    let probe_key = format!("{}/{}", config.table_path(), ROTATION_PROBE_NAME);
+    let outcome = store.rotate_if_staged(|staged| {
+        // Test request: a tiny probe object must be writable with the new keys,
+        // or with a session they obtain
+        let session = match temporary {
+            Some(temporary) => temporary.fetch(staged)?,
+            None => staged.clone(),
+        };
+        let credentials = session.to_rusty_s3();
+        with_retry(&UPLOAD_RETRY, "Rotation probe", || {
+            upload_to_s3_chunked(bucket, &credentials, &probe_key, b"probe")
+        })
+    })?;
+
+    match outcome {
+        RotationOutcome::Rotated => info!("Using rotated S3 credentials"),
+        RotationOutcome::Rejected => warn!("Keeping current S3 credentials"),
+        RotationOutcome::Unchanged => {}
+    }
 
-    for i in 0..NUM_TEST_FILES {
-        let file_name = format!("sensor_data_{}.parquet", i + 1);
-        info!("Creating {}...", file_name);
+    store.active(&config.credentials())
+}
 
-        let parquet_data = create_sensor_parquet(i as u64)?;
-        info!(
-            "  File {} created: {} bytes ({:.2} KB)",
-            i + 1,
-            parquet_data.len(),
-            parquet_data.len() as f64 / 1024.0
-        );
+/// Confirm or roll back freshly rotated credentials, returning true on rollback.
+/// Only a rejected upload speaks against them: a round with nothing to upload,
+/// or one lost to an outage, proves nothing either way.
+fn settle_rotation(
+    store: &mut CredentialStore,
+    successful_uploads: usize,
+    auth_failed: bool,
+) -> Result<bool> {
+    if successful_uploads > 0 {
+        store.confirm()?;
+        return Ok(false);
+    }
+    if !auth_failed {
+        return Ok(false);
     }
 
-    info!("Offline test complete - {} Parquet files created in memory", NUM_TEST_FILES);
-    Ok(())
+    let rolled_back = store.rollback()?;
+    if rolled_back {
+        warn!("S3 rejected the rotated credentials, previous credentials restored");
+    }
+    Ok(rolled_back)
 }
 
 // ============================================================================
-// FULL EXPERIMENT WITH S3 UPLOAD
+// S3 UPLOAD OF BUFFERED BATCHES
 // ============================================================================
 
-fn run_full_experiment() -> Result<()> {
-    info!("Step 2: Creating and uploading {} Parquet files to S3...", NUM_TEST_FILES);
-
-    // Create AWS credentials
-    let credentials = Credentials::new(AWS_ACCESS_KEY, AWS_SECRET_KEY);
-
-    // Create S3 bucket reference
-    let endpoint = format!("https://s3.{}.amazonaws.com", S3_REGION);
-    let bucket = Bucket::new(
-        endpoint.parse()?,
-        UrlStyle::VirtualHost,
-        S3_BUCKET.to_string(),
-        S3_REGION.to_string(),
-    )?;
-
-    let mut total_bytes_uploaded = 0;
-    let mut successful_uploads = 0;
-
-    for i in 0..NUM_TEST_FILES {
-        info!("----------------------------------------");
-        info!("Processing file {}/{}...", i + 1, NUM_TEST_FILES);
-
-        // Create Parquet file
-        let parquet_data = create_sensor_parquet(i as u64)?;
-        info!(
-            "  Parquet file created: {} bytes ({:.2} KB, Snappy compressed)",
-            parquet_data.len(),
-            parquet_data.len() as f64 / 1024.0
-        );
-
-        // Generate object key with timestamp-like naming
-        let object_key = format!(
-            "opensensor-test/esp32s3/sensor_data_{:03}.parquet",
-            i + 1
-        );
+/// What replaying the offline buffer got through, and what stopped it
+struct Replay {
+    replayed: usize,
+    /// S3 rejected the credentials as expired
+    expired: bool,
+    /// A transient failure outlasted the retries, i.e. S3 looks unreachable
+    outage: bool,
+    /// S3 rejected the keys themselves, e.g. revoked ones
+    auth_failed: bool,
+}
 
-        // Upload to S3 using chunked transfer
-        match upload_to_s3_chunked(&bucket, &credentials, &object_key, &parquet_data) {
-            Ok(()) => {
-                info!("  Upload successful: s3://{}/{}", S3_BUCKET, object_key);
-                total_bytes_uploaded += parquet_data.len();
-                successful_uploads += 1;
+/// Upload buffered batches, oldest first, until one fails. Consecutive
+/// batches of a table are appended as one data file with one commit.
+#[allow(unused_variables)] // `config` / `identity` are only needed by the NDJSON fallback
+fn replay_buffer(
+    config: &DeviceConfig,
+    identity: &DeviceIdentity,
+    router: &ProfileRouter,
+    credentials: &S3Credentials,
+    lake: &mut dyn LakeBackend,
+    buffer: &mut OfflineBuffer,
+    mut sla: Option<&mut SlaTracker>,
+) -> Replay {
+    let target = S3Target {
+        router,
+        credentials,
+    };
+    let mut stats = UploadStats::default();
+    let mut expired = false;
+    let mut outage = false;
+    let mut auth_failed = false;
+    let replayed = buffer.replay(board::BULK_INSERT_ROWS, |batches| {
+        let table = &batches[0].table;
+        let combined: Vec<SensorReading>;
+        let readings = match batches {
+            [batch] => batch.readings.as_slice(),
+            _ => {
+                combined = batches.iter().flat_map(|b| b.readings.iter().cloned()).collect();
+                combined.as_slice()
+            }
+        };
+        let written = lake
+            .create_table(&target, table)
+            .and_then(|()| lake.append_batch(&target, table, readings));
+        let uploaded = match written {
+            Ok(batch_stats) => {
+                // The object is already in S3: a commit failure must not trigger a re-upload
+                if let Err(e) = lake.commit() {
+                    warn!("  Failed to commit upload to the lake: {:?}", e);
+                }
+                Ok(batch_stats)
+            }
+            #[cfg(feature = "fallback")]
+            // Expired credentials would be rejected by the fallback upload too
+            Err(e) if config.ndjson_fallback && !net::is_expired_credentials(&e) => {
+                warn!("  Lake upload failed, falling back to NDJSON: {:?}", e);
+                fallback::upload_batch(&target, &config.data_path, identity, table, readings)
             }
             Err(e) => {
-                error!("  Upload failed: {:?}", e);
+                expired = net::is_expired_credentials(&e);
+                outage = net::is_retryable(&e);
+                Err(e)
             }
+        };
+        if let Err(e) = &uploaded {
+            auth_failed = net::is_auth_failure(e);
         }
-    }
-
-    info!("----------------------------------------");
-    info!("Upload Summary:");
-    info!("  Files uploaded: {}/{}", successful_uploads, NUM_TEST_FILES);
-    info!(
-        "  Total data: {} bytes ({:.2} KB)",
-        total_bytes_uploaded,
-        total_bytes_uploaded as f64 / 1024.0
-    );
-
-    Ok(())
-}
-
-// ============================================================================
-// PARQUET FILE CREATION
-// ============================================================================
-
-fn create_sensor_parquet(file_index: u64) -> Result<Vec<u8>> {
-    // Schema matching opensensor.space structure (simplified for test)
-    let message_type = "
-        message sensor_data {
-            required int64 timestamp;
-            required float temperature;
-            required float humidity;
-            required float pressure;
-            required float pm1_0;
-            required float pm2_5;
-            required float pm10;
-            required float gas_resistance;
-            required float light;
-            required float noise;
+        if let Some(sla) = sla.as_deref_mut() {
+            sla.uploaded(uploaded.is_ok().then_some(readings));
         }
-    ";
-
-    let schema = Arc::new(parse_message_type(message_type)?);
-
-    // Snappy compression - pure Rust, proven to work on ESP32
-    let props = WriterProperties::builder()
-        .set_compression(Compression::SNAPPY)
-        .set_encoding(Encoding::PLAIN)
-        .build();
-
-    let mut buffer = Cursor::new(Vec::new());
-    let mut writer = SerializedFileWriter::new(&mut buffer, schema, Arc::new(props))?;
-    let mut row_group_writer = writer.next_row_group()?;
-
-    // Base timestamp (simulate different time windows per file)
-    let base_timestamp = 1733270400000i64 + (file_index as i64 * 900000); // 15 min apart
-
-    // Generate simulated sensor data (178 rows like opensensor.space)
-    let timestamps: Vec<i64> = (0..ROWS_PER_FILE)
-        .map(|i| base_timestamp + (i as i64 * 5000)) // 5 second intervals
-        .collect();
-
-    let temperatures: Vec<f32> = (0..ROWS_PER_FILE)
-        .map(|i| 20.0 + (i as f32 * 0.02) + (file_index as f32 * 0.5))
-        .collect();
-
-    let humidity: Vec<f32> = (0..ROWS_PER_FILE)
-        .map(|i| 45.0 + (i as f32 * 0.05) + (file_index as f32 * 2.0))
-        .collect();
-
-    let pressure: Vec<f32> = (0..ROWS_PER_FILE)
-        .map(|i| 1013.25 + (i as f32 * 0.01))
-        .collect();
-
-    let pm1_0: Vec<f32> = (0..ROWS_PER_FILE)
-        .map(|i| 5.0 + (i as f32 % 10.0) * 0.1)
-        .collect();
-
-    let pm2_5: Vec<f32> = (0..ROWS_PER_FILE)
-        .map(|i| 8.0 + (i as f32 % 15.0) * 0.2)
-        .collect();
-
-    let pm10: Vec<f32> = (0..ROWS_PER_FILE)
-        .map(|i| 12.0 + (i as f32 % 20.0) * 0.3)
-        .collect();
-
-    let gas_resistance: Vec<f32> = (0..ROWS_PER_FILE)
-        .map(|i| 50000.0 + (i as f32 * 100.0))
-        .collect();
-
-    let light: Vec<f32> = (0..ROWS_PER_FILE)
-        .map(|i| 100.0 + (i as f32 * 2.0))
-        .collect();
-
-    let noise: Vec<f32> = (0..ROWS_PER_FILE)
-        .map(|i| 35.0 + (i as f32 % 10.0) * 0.5)
-        .collect();
-
-    // Write columns
-    // Timestamp column (INT64)
-    {
-        let mut col_writer = row_group_writer.next_column()?.unwrap();
-        col_writer.typed::<Int64Type>().write_batch(&timestamps, None, None)?;
-        col_writer.close()?;
-    }
-
-    // Float columns
-    let float_columns: [&[f32]; 9] = [
-        &temperatures,
-        &humidity,
-        &pressure,
-        &pm1_0,
-        &pm2_5,
-        &pm10,
-        &gas_resistance,
-        &light,
-        &noise,
-    ];
+        stats.add(&uploaded?);
+        Ok(())
+    });
 
-    for col_data in float_columns {
-        let mut col_writer = row_group_writer.next_column()?.unwrap();
-        col_writer.typed::<FloatType>().write_batch(col_data, None, None)?;
-        col_writer.close()?;
+    if replayed > 0 {
+        stats.log_summary();
+    }
+    Replay {
+        replayed,
+        expired,
+        outage,
+        auth_failed,
     }
-
-    row_group_writer.close()?;
-    writer.close()?;
-
-    Ok(buffer.into_inner())
 }
 
 // ============================================================================
@@ -379,7 +1106,12 @@ fn upload_to_s3_chunked(
     object_key: &str,
     data: &[u8],
 ) -> Result<()> {
-    info!("  Uploading {} bytes in chunks of {} bytes...", data.len(), CHUNK_SIZE);
+    let sizing = multipart::sizing();
+    if data.len() >= sizing.threshold {
+        return multipart::upload(bucket, credentials, object_key, data, &sizing);
+    }
+    let chunk_size = sizing.chunk;
+    info!("  Uploading {} bytes in chunks of {} bytes...", data.len(), chunk_size);
 
     // Generate presigned PUT URL
     let mut put_action = bucket.put_object(Some(credentials), object_key);
@@ -389,16 +1121,11 @@ fn upload_to_s3_chunked(
     info!("  Presigned URL generated (valid for 5 min)");
 
     // Configure HTTP client for S3
-    let http_config = HttpConfig {
-        use_global_ca_store: true,
-        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
-        timeout: Some(Duration::from_secs(30)),
-        ..Default::default()
-    };
+    let http_config = net::http_config(&presigned_url, Duration::from_secs(30));
 
     let mut client = HttpClient::wrap(EspHttpConnection::new(&http_config)?);
 
-    // For small files (< 5MB), we use a simple PUT request
+    // Below the multipart threshold (`s3_mpu_kb`), a single PUT
     // This is simpler than multipart upload and works well for our ~10KB Parquet files
     let headers = [
         ("Content-Type", "application/octet-stream"),
@@ -409,14 +1136,14 @@ fn upload_to_s3_chunked(
 
     // Write data in chunks (simulating chunked transfer behavior)
     let mut bytes_sent = 0;
-    for chunk in data.chunks(CHUNK_SIZE) {
+    for chunk in data.chunks(chunk_size) {
         request.write(chunk)?;
         bytes_sent += chunk.len();
 
         // Log progress for larger files
-        if data.len() > CHUNK_SIZE * 2 {
+        if data.len() > chunk_size * 2 {
             let progress = (bytes_sent as f64 / data.len() as f64) * 100.0;
-            if bytes_sent % (CHUNK_SIZE * 4) == 0 || bytes_sent == data.len() {
+            if bytes_sent % (chunk_size * 4) == 0 || bytes_sent == data.len() {
                 info!("    Progress: {:.1}% ({} / {} bytes)", progress, bytes_sent, data.len());
             }
         }
@@ -429,36 +1156,23 @@ fn upload_to_s3_chunked(
     info!("  HTTP Response: {}", status);
 
     if status >= 200 && status < 300 {
-        info!("  Upload successful!");
+        info!(event = "upload", key = object_key, bytes = data.len(); "  Upload successful!");
         Ok(())
     } else {
         // Read error response body for debugging
         let mut body = [0u8; 512];
         let mut reader = response;
         let bytes_read = embedded_svc::io::Read::read(&mut reader, &mut body).unwrap_or(0);
-        let error_body = String::from_utf8_lossy(&body[..bytes_read]);
-        bail!("S3 upload failed with status {}: {}", status, error_body)
+        let error_body = String::from_utf8_lossy(&body[..bytes_read]).into_owned();
+        error!("  S3 upload failed with status {}", status);
+        Err(HttpStatusError {
+            status,
+            body: error_body,
+        }
+        .into())
     }
 }
 
-// ============================================================================
-// HELPER: For future multipart upload support (files > 5MB)
-// ============================================================================
-
-#[allow(dead_code)]
-fn calculate_part_size(total_size: usize) -> usize {
-    // S3 multipart upload constraints:
-    // - Minimum part size: 5MB (except last part)
-    // - Maximum parts: 10,000
-    // - Maximum object size: 5TB
-
-    const MIN_PART_SIZE: usize = 5 * 1024 * 1024; // 5MB
-    const MAX_PARTS: usize = 10000;
-
-    let part_size = (total_size / MAX_PARTS) + 1;
-    std::cmp::max(part_size, MIN_PART_SIZE)
-}
-
 // ============================================================================
 // NOTES FOR OPENSENSOR.SPACE INTEGRATION
 // ============================================================================
@@ -472,7 +1186,8 @@ fn calculate_part_size(total_size: usize) -> usize {
 // 2. Chunked upload pattern for S3
 //    - Uses presigned URLs (rusty-s3)
 //    - esp-idf-svc HTTP client for actual transfer
-//    - 8KB chunks balance memory vs. network efficiency
+//    - 8KB chunks balance memory vs. network efficiency (`s3_chunk_kb`)
+//    - Multipart upload above `s3_mpu_kb`, see `multipart.rs`
 //
 // 3. Hive-partitioned paths ready for integration:
 //    - s3://bucket/station=DEVICE_ID/year=YYYY/month=MM/day=DD/data_HHMM.parquet
@@ -481,6 +1196,5 @@ fn calculate_part_size(total_size: usize) -> usize {
 // - Add retry logic with exponential backoff
 // - Implement proper error handling and logging
 // - Use secure credential storage (NVS encrypted partition)
-// - Add multipart upload for files > 5MB (unlikely with sensor data)
 // - Consider compression ratio vs. CPU trade-off
 //
@@ -0,0 +1,193 @@
+//! S3 upload sizing and multipart uploads
+//!
+//! Three NVS settings decide how an object goes out:
+//!
+//! - `s3_chunk_kb`: size of each write to the HTTP connection. This is the
+//!   only one that costs RAM while sending (on top of the mbedTLS record
+//!   buffers), so it stays small.
+//! - `s3_mpu_kb`: objects of at least this size are sent as an S3 multipart
+//!   upload instead of a single PUT.
+//! - `s3_part_kb`: size of each part of a multipart upload.
+//!
+//! Parts are slices of the already encoded object and are streamed in
+//! chunks like a single PUT, so a large part size doesn't allocate a part
+//! buffer the way a desktop S3 client does. S3 rejects parts under 5 MB
+//! (except the last), which is why the part size is clamped to that. The
+//! default threshold is the size from which maintenance leaves files alone,
+//! so merged and other large files go up as single-part uploads and this
+//! path runs on every lake, not only once objects span several parts.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use embedded_svc::http::client::Client as HttpClient;
+use embedded_svc::http::{Headers, Method};
+use embedded_svc::io::Write;
+use esp_idf_svc::http::client::EspHttpConnection;
+use log::{info, warn};
+use rusty_s3::actions::CreateMultipartUpload;
+use rusty_s3::{Bucket, Credentials, S3Action};
+
+use crate::config::DeviceConfig;
+use crate::net;
+
+// S3 multipart upload limits: parts but the last are at least 5 MB, at most 10,000 parts
+const MIN_PART_KB: u32 = 5 * 1024;
+const MAX_PARTS: usize = 10_000;
+const MAX_CHUNK_KB: u32 = 32;
+const MAX_RESPONSE_LEN: usize = 4096;
+const SIGN_EXPIRY: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Copy, Debug)]
+pub struct UploadSizing {
+    /// Bytes per write to the connection
+    pub chunk: usize,
+    /// Bytes per multipart part
+    pub part: usize,
+    /// Objects of at least this many bytes are sent in parts
+    pub threshold: usize,
+}
+
+impl UploadSizing {
+    /// Sizing per `s3_chunk_kb`, `s3_part_kb` and `s3_mpu_kb`, clamped to what S3 accepts
+    pub fn from_config(config: &DeviceConfig) -> Self {
+        let chunk_kb = config.s3_chunk_kb.clamp(1, MAX_CHUNK_KB);
+        if chunk_kb != config.s3_chunk_kb {
+            warn!("s3_chunk_kb {} is out of range, using {}", config.s3_chunk_kb, chunk_kb);
+        }
+        let part_kb = config.s3_part_kb.max(MIN_PART_KB);
+        if part_kb != config.s3_part_kb {
+            warn!("s3_part_kb {} is below the S3 minimum, using {}", config.s3_part_kb, part_kb);
+        }
+        Self {
+            chunk: chunk_kb as usize * 1024,
+            part: part_kb as usize * 1024,
+            threshold: config.s3_mpu_kb.max(1) as usize * 1024,
+        }
+    }
+
+    /// Part size for an object of `len` bytes, grown to stay within the part limit
+    fn part_size(&self, len: usize) -> usize {
+        self.part.max(len.div_ceil(MAX_PARTS))
+    }
+}
+
+impl Default for UploadSizing {
+    fn default() -> Self {
+        Self::from_config(&DeviceConfig::default())
+    }
+}
+
+static SIZING: OnceLock<UploadSizing> = OnceLock::new();
+
+/// Size every upload from now on per `sizing`
+pub fn install(sizing: UploadSizing) {
+    info!(
+        "S3 uploads: {} KB writes, {} KB parts from {} KB",
+        sizing.chunk / 1024,
+        sizing.part / 1024,
+        sizing.threshold / 1024
+    );
+    if SIZING.set(sizing).is_err() {
+        warn!("S3 upload sizing is already installed");
+    }
+}
+
+/// The installed sizing, or the defaults before `install`
+pub fn sizing() -> UploadSizing {
+    SIZING.get().copied().unwrap_or_default()
+}
+
+/// Send `data` as a multipart upload; aborted on failure so S3 doesn't
+/// keep (and bill) the parts already sent
+pub fn upload(
+    bucket: &Bucket,
+    credentials: &Credentials,
+    object_key: &str,
+    data: &[u8],
+    sizing: &UploadSizing,
+) -> Result<()> {
+    let part_size = sizing.part_size(data.len());
+    info!(
+        "  Uploading {} bytes in {} parts of {} KB...",
+        data.len(),
+        data.len().div_ceil(part_size),
+        part_size / 1024
+    );
+
+    let create = bucket.create_multipart_upload(Some(credentials), object_key);
+    let url = create.sign(SIGN_EXPIRY).to_string();
+    let body = send(Method::Post, &url, &[])?;
+    let created = CreateMultipartUpload::parse_response(&body)
+        .context("parsing the CreateMultipartUpload response")?;
+    let upload_id = created.upload_id();
+
+    let sent = (|| -> Result<()> {
+        let mut etags = Vec::with_capacity(data.len().div_ceil(part_size));
+        for (index, part) in data.chunks(part_size).enumerate() {
+            let number = index as u16 + 1;
+            let action = bucket.upload_part(Some(credentials), object_key, number, upload_id);
+            let url = action.sign(SIGN_EXPIRY).to_string();
+            etags.push(put_part(&url, part, sizing.chunk)?);
+            info!("    Part {} done ({} bytes)", number, part.len());
+        }
+
+        let complete = bucket.complete_multipart_upload(
+            Some(credentials),
+            object_key,
+            upload_id,
+            etags.iter().map(String::as_str),
+        );
+        let url = complete.sign(SIGN_EXPIRY).to_string();
+        let body = send(Method::Post, &url, complete.body().as_bytes())?;
+        // S3 may fail a complete request with a 200 and an error document
+        if String::from_utf8_lossy(&body).contains("<Error>") {
+            return Err(net::status_error(200, &body));
+        }
+        Ok(())
+    })();
+
+    if let Err(e) = sent {
+        let abort = bucket.abort_multipart_upload(Some(credentials), object_key, upload_id);
+        if let Err(abort_error) = send(Method::Delete, &abort.sign(SIGN_EXPIRY).to_string(), &[])
+        {
+            warn!("  Failed to abort multipart upload {}: {:?}", upload_id, abort_error);
+        }
+        return Err(e);
+    }
+    info!("  Multipart upload successful!");
+    Ok(())
+}
+
+/// Stream one part in `chunk`-sized writes and return its ETag
+fn put_part(url: &str, part: &[u8], chunk: usize) -> Result<String> {
+    let http_config = net::http_config(url, Duration::from_secs(30));
+    let mut client = HttpClient::wrap(EspHttpConnection::new(&http_config)?);
+    let content_length = part.len().to_string();
+    let headers = [("Content-Length", content_length.as_str())];
+
+    let mut request = client.request(Method::Put, url, &headers)?;
+    for piece in part.chunks(chunk) {
+        request.write_all(piece)?;
+    }
+    let mut response = request.submit()?;
+    let status = response.status();
+    if !(200..300).contains(&status) {
+        let mut body = [0u8; 512];
+        let n = embedded_svc::io::Read::read(&mut response, &mut body).unwrap_or(0);
+        return Err(net::status_error(status, &body[..n]));
+    }
+    match response.header("ETag") {
+        Some(etag) => Ok(etag.to_string()),
+        None => bail!("part upload answered without an ETag"),
+    }
+}
+
+fn send(method: Method, url: &str, body: &[u8]) -> Result<Vec<u8>> {
+    let (status, body) = net::send_with_headers(method, url, &[], body, MAX_RESPONSE_LEN)?;
+    if !(200..300).contains(&status) {
+        return Err(net::status_error(status, &body));
+    }
+    Ok(body)
+}
@@ -0,0 +1,245 @@
+//! Retry with exponential backoff for network operations
+//!
+//! Transient failures (timeouts, dropped connections, S3 5xx / throttling)
+//! are retried with exponential backoff plus jitter, so a fleet recovering
+//! from an outage doesn't retry in lockstep. Fatal failures (rejected
+//! credentials, malformed requests, local encoding errors) are returned
+//! immediately.
+//!
+//! Also home to `send_capped`, a buffered HTTP request for the small JSON and
+//! metadata exchanges of the lake backends, and to `http_config`, which every
+//! HTTP client is configured with: servers are verified against the bundled
+//! root certificates, except the S3 endpoint a custom CA was installed for
+//! with `install_ca`.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use embedded_svc::http::client::Client as HttpClient;
+use embedded_svc::http::Method;
+use esp_idf_svc::http::client::{Configuration as HttpConfig, EspHttpConnection};
+use esp_idf_svc::io::{EspIOError, Read, Write};
+use esp_idf_svc::sys::{esp, EspError};
+use log::{info, warn};
+use rand_core::{OsRng, RngCore};
+
+use crate::clock::clock;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt` (1-based): full jitter over the backoff
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.max_delay);
+        let jitter = (OsRng.next_u32() as f64 / u32::MAX as f64) * 0.5 + 0.5;
+        backoff.mul_f64(jitter)
+    }
+}
+
+/// Non-2xx HTTP response, classified by status
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub status: u16,
+    pub body: String,
+}
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP status {}: {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// True if retrying `error` may succeed
+pub fn is_retryable(error: &anyhow::Error) -> bool {
+    if let Some(http) = error.downcast_ref::<HttpStatusError>() {
+        // Server errors, throttling and request timeouts are transient
+        return http.status >= 500 || http.status == 429 || http.status == 408;
+    }
+
+    // Connection, DNS and TLS failures surface as ESP-IDF or I/O errors
+    error.downcast_ref::<EspError>().is_some()
+        || error.downcast_ref::<EspIOError>().is_some()
+        || error.downcast_ref::<std::io::Error>().is_some()
+}
+
+/// True if `error` is S3 or STS rejecting expired temporary credentials,
+/// which a fresh session fixes
+pub fn is_expired_credentials(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<HttpStatusError>().is_some_and(|http| {
+        matches!(http.status, 400 | 403)
+            && ["ExpiredToken", "InvalidToken", "TokenRefreshRequired"]
+                .iter()
+                .any(|code| http.body.contains(code))
+    })
+}
+
+/// True if `error` is S3 rejecting the keys themselves, e.g. revoked ones,
+/// which no retry or fresh session fixes
+pub fn is_auth_failure(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<HttpStatusError>().is_some_and(|http| {
+        http.status == 401
+            || (http.status == 403
+                && ["InvalidAccessKeyId", "SignatureDoesNotMatch", "AccessDenied"]
+                    .iter()
+                    .any(|code| http.body.contains(code)))
+    })
+}
+
+// Transient failures retried since boot, see `diagnostics.rs`
+static RETRIES: AtomicU32 = AtomicU32::new(0);
+
+/// Transient failures retried since boot
+pub fn retries() -> u32 {
+    RETRIES.load(Ordering::Relaxed)
+}
+
+/// Run `op`, retrying transient failures according to `policy`
+pub fn with_retry<T, F>(policy: &RetryPolicy, what: &str, mut op: F) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    let mut attempt = 1;
+
+    loop {
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < policy.max_attempts && is_retryable(&e) => {
+                let delay = policy.delay(attempt);
+                warn!(
+                    "{} failed (attempt {}/{}), retrying in {:?}: {:?}",
+                    what, attempt, policy.max_attempts, delay, e
+                );
+                RETRIES.fetch_add(1, Ordering::Relaxed);
+                clock().sleep(delay);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Host (and port) of the S3 endpoint verified against the custom CA
+static CUSTOM_CA_HOST: OnceLock<String> = OnceLock::new();
+
+/// Verify `host`, and its virtual-host-style subdomains, against the PEM
+/// certificates `pem` instead of the bundled roots
+pub fn install_ca(host: &str, pem: &str) -> Result<()> {
+    // mbedTLS parses PEM including its terminating NUL, and keeps a copy
+    let mut buf = pem.trim().as_bytes().to_vec();
+    buf.push(0);
+    esp!(unsafe {
+        esp_idf_svc::sys::esp_tls_set_global_ca_store(buf.as_ptr(), buf.len() as u32)
+    })?;
+    if CUSTOM_CA_HOST.set(host.to_string()).is_err() {
+        bail!("a custom CA is already installed");
+    }
+    info!("Verifying {} against the custom CA", host);
+    Ok(())
+}
+
+/// HTTP client configuration for requests to `url`
+pub fn http_config(url: &str, timeout: Duration) -> HttpConfig {
+    let host = url_host(url);
+    let custom = CUSTOM_CA_HOST.get().is_some_and(|ca_host| {
+        host == ca_host
+            || host
+                .strip_suffix(ca_host.as_str())
+                .is_some_and(|bucket| bucket.ends_with('.'))
+    });
+    HttpConfig {
+        use_global_ca_store: custom,
+        crt_bundle_attach: (!custom).then_some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        timeout: Some(timeout),
+        ..Default::default()
+    }
+}
+
+/// `host[:port]` of `url`
+pub fn url_host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    rest.split(['/', '?']).next().unwrap_or(rest)
+}
+
+/// HTTP request returning the status and at most `max_len` bytes of the body
+pub fn send_capped(
+    method: Method,
+    url: &str,
+    content_type: &str,
+    authorization: Option<&str>,
+    body: &[u8],
+    max_len: usize,
+) -> Result<(u16, Vec<u8>)> {
+    let mut headers = Vec::new();
+    if !content_type.is_empty() {
+        headers.push(("Content-Type", content_type));
+    }
+    if let Some(authorization) = authorization {
+        headers.push(("Authorization", authorization));
+    }
+    send_with_headers(method, url, &headers, body, max_len)
+}
+
+/// `send_capped` with arbitrary request headers, e.g. SigV4 ones
+pub fn send_with_headers(
+    method: Method,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    max_len: usize,
+) -> Result<(u16, Vec<u8>)> {
+    let http_config = http_config(url, Duration::from_secs(30));
+    let mut client = HttpClient::wrap(EspHttpConnection::new(&http_config)?);
+
+    let content_length = body.len().to_string();
+    let mut headers = headers.to_vec();
+    headers.push(("Content-Length", content_length.as_str()));
+
+    let mut request = client.request(method, url, &headers)?;
+    request.write_all(body)?;
+    let mut response = request.submit()?;
+    let status = response.status();
+
+    let mut data = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        match response.read(&mut buf)? {
+            0 => break,
+            n if data.len() + n > max_len => bail!("response exceeds {} bytes", max_len),
+            n => data.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    Ok((status, data))
+}
+
+/// `HttpStatusError` for a non-2xx response
+pub fn status_error(status: u16, body: &[u8]) -> anyhow::Error {
+    HttpStatusError {
+        status,
+        body: String::from_utf8_lossy(body).into_owned(),
+    }
+    .into()
+}
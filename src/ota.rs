@@ -0,0 +1,275 @@
+//! Over-the-air firmware updates (`ota` feature)
+//!
+//! Every `ota_check_m` minutes the uploader fetches the HTTPS manifest at
+//! `ota_url`:
+//!
+//! ```json
+//! {"version": "0.3.0", "url": "https://.../esp32s3.bin", "sha256": "...", "size": 1021440}
+//! ```
+//!
+//! A newer version is only downloaded once the offline buffer has been
+//! uploaded, so a failed update can never take buffered readings with it.
+//! The image is streamed into the inactive OTA slot, checked against the
+//! manifest's SHA-256 and size, and the device reboots into it.
+//!
+//! The new image boots on probation (`CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE`):
+//! it is only marked valid once the configured lake backend has attached.
+//! A failed attach rolls back to the previous image right away, and so does
+//! the bootloader if the new image crashes or hangs before that milestone.
+//! The previous image then reports the rollback as a device event and never
+//! tries that version again.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use embedded_svc::http::client::Client as HttpClient;
+use esp_idf_svc::http::client::EspHttpConnection;
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::ota::{EspOta, SlotState};
+use log::{error, info, warn};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::clock::clock;
+use crate::config::DeviceConfig;
+use crate::enrollment::hex;
+use crate::identity::FIRMWARE_VERSION;
+use crate::{events, net};
+
+const NAMESPACE: &str = "ota";
+
+// Version rebooted into, until it proves itself
+const KEY_PENDING: &str = "pending";
+// Last version that had to be rolled back
+const KEY_BAD: &str = "bad_version";
+
+const MAX_MANIFEST_LEN: usize = 2048;
+const MAX_VERSION_LEN: usize = 32;
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+struct Manifest {
+    version: String,
+    url: String,
+    sha256: String,
+    size: usize,
+}
+
+pub struct Updater {
+    nvs: EspNvs<NvsDefault>,
+    url: String,
+    interval: Duration,
+    // Monotonic time of the next manifest check
+    next_check: Duration,
+    bad_version: String,
+}
+
+impl Updater {
+    /// Manifest checks per `ota_url`; also notices an update that was rolled back
+    pub fn load(partition: EspDefaultNvsPartition, config: &DeviceConfig) -> Result<Self> {
+        let nvs: EspNvs<NvsDefault> = EspNvs::new(partition, NAMESPACE, true)?;
+        let mut buf = [0u8; MAX_VERSION_LEN];
+        let pending = nvs.get_str(KEY_PENDING, &mut buf)?.unwrap_or("").to_string();
+        let mut bad_version = nvs.get_str(KEY_BAD, &mut buf)?.unwrap_or("").to_string();
+
+        // Rebooted into an update, but this is still the previous image
+        if !pending.is_empty() && pending != FIRMWARE_VERSION {
+            warn!("Firmware {} was rolled back, staying on {}", pending, FIRMWARE_VERSION);
+            let detail = format!("{} rolled back to {}", pending, FIRMWARE_VERSION);
+            events::report(events::FIRMWARE_ROLLBACK, "", detail);
+            nvs.set_str(KEY_BAD, &pending)?;
+            nvs.remove(KEY_PENDING)?;
+            bad_version = pending;
+        }
+
+        if !config.ota_url.is_empty() {
+            info!("OTA updates from {} every {} min", config.ota_url, config.ota_check_mins);
+        }
+        Ok(Self {
+            nvs,
+            url: config.ota_url.clone(),
+            interval: Duration::from_secs(u64::from(config.ota_check_mins.max(1)) * 60),
+            // The first forwarding round after boot checks right away
+            next_check: Duration::ZERO,
+            bad_version,
+        })
+    }
+
+    /// Keep the running image if it is an update on probation and the lake
+    /// attached with the configured backend, roll back (reboot) otherwise
+    pub fn settle(&mut self, configured: &str, attached: Result<&str, &anyhow::Error>) {
+        let unverified = EspOta::new()
+            .and_then(|ota| ota.get_running_slot())
+            .is_ok_and(|slot| slot.state == SlotState::Unverified);
+        if !unverified {
+            return;
+        }
+
+        let failure = match attached {
+            Ok(backend) if backend == configured => None,
+            Ok(backend) => Some(format!("fell back to the {} backend", backend)),
+            Err(e) => Some(format!("{:#}", e)),
+        };
+        let mut ota = match EspOta::new() {
+            Ok(ota) => ota,
+            Err(e) => {
+                error!("Can't settle firmware {}: {:?}", FIRMWARE_VERSION, e);
+                return;
+            }
+        };
+        match failure {
+            None => match ota.mark_running_slot_valid() {
+                Ok(()) => {
+                    info!(
+                        "Firmware {} attached the {} lake, update confirmed",
+                        FIRMWARE_VERSION, configured
+                    );
+                    let _ = self.nvs.remove(KEY_PENDING);
+                    let detail = format!("now running {}", FIRMWARE_VERSION);
+                    events::report(events::FIRMWARE_UPDATE, "", detail);
+                }
+                Err(e) => error!("Failed to confirm firmware {}: {:?}", FIRMWARE_VERSION, e),
+            },
+            Some(reason) => {
+                error!(
+                    "Firmware {} didn't attach the {} lake ({}), rolling back...",
+                    FIRMWARE_VERSION, configured, reason
+                );
+                // Only returns if there is no previous image to go back to
+                let e = ota.mark_running_slot_invalid_and_reboot();
+                error!("Rollback failed, keeping firmware {}: {:?}", FIRMWARE_VERSION, e);
+            }
+        }
+    }
+
+    /// True if the manifest is due for a check
+    pub fn is_due(&self) -> bool {
+        !self.url.is_empty() && clock().monotonic() >= self.next_check
+    }
+
+    /// Check the manifest and install a newer image into the inactive slot.
+    /// True if the device should reboot into it. Call only with the offline
+    /// buffer uploaded; failures are logged and retried with the next check.
+    pub fn update(&mut self) -> bool {
+        self.next_check = clock().monotonic() + self.interval;
+        let manifest = match fetch_manifest(&self.url) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                warn!("Firmware manifest check failed: {:?}", e);
+                return false;
+            }
+        };
+        if !is_newer(&manifest.version, FIRMWARE_VERSION) {
+            info!("Firmware {} is up to date", FIRMWARE_VERSION);
+            return false;
+        }
+        if manifest.version == self.bad_version {
+            info!("Not updating to firmware {}, it was rolled back", manifest.version);
+            return false;
+        }
+
+        info!("----------------------------------------");
+        info!(
+            "Updating firmware {} -> {} ({} bytes from {})",
+            FIRMWARE_VERSION, manifest.version, manifest.size, manifest.url
+        );
+        let started = clock().monotonic();
+        if let Err(e) = install(&manifest) {
+            error!("Firmware update to {} failed: {:?}", manifest.version, e);
+            return false;
+        }
+        if let Err(e) = self.nvs.set_str(KEY_PENDING, &manifest.version) {
+            // Still boots into it, but a rollback would go unreported
+            warn!("Failed to record the pending firmware version: {:?}", e);
+        }
+        info!(
+            "Firmware {} installed in {} s, rebooting into it",
+            manifest.version,
+            clock().elapsed_since(started).as_secs()
+        );
+        true
+    }
+}
+
+fn fetch_manifest(url: &str) -> Result<Manifest> {
+    if !url.starts_with("https://") {
+        bail!("ota_url must be HTTPS, got '{}'", url);
+    }
+    let (status, body) = net::send_capped(
+        embedded_svc::http::Method::Get,
+        url,
+        "",
+        None,
+        &[],
+        MAX_MANIFEST_LEN,
+    )?;
+    if status != 200 {
+        return Err(net::status_error(status, &body));
+    }
+    let manifest: Manifest = serde_json::from_slice(&body).context("parsing the manifest")?;
+    if !manifest.url.starts_with("https://") {
+        bail!("firmware image URL must be HTTPS, got '{}'", manifest.url);
+    }
+    if manifest.sha256.len() != 64 {
+        bail!("manifest sha256 must be 64 hex digits");
+    }
+    Ok(manifest)
+}
+
+/// Stream the image into the inactive slot and make it the boot image
+fn install(manifest: &Manifest) -> Result<()> {
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+
+    let written = (|| -> Result<()> {
+        let config = net::http_config(&manifest.url, DOWNLOAD_TIMEOUT);
+        let mut client = HttpClient::wrap(EspHttpConnection::new(&config)?);
+        let mut response = client.get(&manifest.url)?.submit()?;
+        if response.status() != 200 {
+            bail!("image download answered {}", response.status());
+        }
+
+        let mut hasher = Sha256::new();
+        let mut length = 0;
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = response.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            length += n;
+            if length > manifest.size {
+                bail!("image is larger than the {} bytes of the manifest", manifest.size);
+            }
+            hasher.update(&buf[..n]);
+            update.write_all(&buf[..n])?;
+        }
+        if length != manifest.size {
+            bail!("image is {} bytes, the manifest says {}", length, manifest.size);
+        }
+        let digest = hex(&hasher.finalize());
+        if !digest.eq_ignore_ascii_case(&manifest.sha256) {
+            bail!("image SHA-256 {} doesn't match the manifest", digest);
+        }
+        Ok(())
+    })();
+
+    match written {
+        // Validates the image and makes it the boot slot
+        Ok(()) => update.complete()?,
+        Err(e) => {
+            let _ = update.abort();
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// True if dotted version `candidate` is newer than `current`
+fn is_newer(candidate: &str, current: &str) -> bool {
+    let parse = |v: &str| -> Vec<u64> {
+        v.trim_start_matches('v').split(['.', '-']).map_while(|p| p.parse().ok()).collect()
+    };
+    parse(candidate) > parse(current)
+}
@@ -0,0 +1,170 @@
+//! Cooperative pause / resume of the pipeline
+//!
+//! Anything that needs the lake and the storage to itself for a while,
+//! such as the HTTP API, an MQTT command or an OTA install, holds a pause:
+//!
+//! 1. `pause(holder)` only records the request, from any thread.
+//! 2. The uploader takes it between forwarding rounds, so the round in
+//!    flight finishes its uploads and catalog commits first. From then on
+//!    no round runs and MQTT ingestion stops; lake maintenance, watch-folder
+//!    scans, promotions and deep sleep wait. `/storage` and the SD card are
+//!    unmounted until the resume, so nothing is left in a write cache.
+//! 3. Sampling goes on: flushed batches are held in memory, up to the
+//!    offline buffer's row budget, and taken in order on resume.
+//! 4. The pipeline resumes once every holder has called `resume(holder)`,
+//!    or a holder's pause outlives the `limit`, which drops it.
+//!
+//! `state()` tells a holder when it has the storage to itself. Holders
+//! are counted separately, so an HTTP resume can't cut an OTA short.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::clock::clock;
+
+/// Who wants the pipeline paused
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Holder {
+    Http,
+    Mqtt,
+    Ota,
+}
+
+impl Holder {
+    const ALL: [Holder; 3] = [Holder::Http, Holder::Mqtt, Holder::Ota];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Holder::Http => "http",
+            Holder::Mqtt => "mqtt",
+            Holder::Ota => "ota",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PauseState {
+    Running,
+    /// Requested, waiting for the forwarding round in flight
+    Pausing,
+    /// Quiesced: no lake or storage writes until resumed
+    Paused,
+}
+
+impl PauseState {
+    pub fn name(self) -> &'static str {
+        match self {
+            PauseState::Running => "running",
+            PauseState::Pausing => "pausing",
+            PauseState::Paused => "paused",
+        }
+    }
+}
+
+// Bit per `Holder` asking for the pause
+static HOLDERS: AtomicU8 = AtomicU8::new(0);
+// Whether the uploader has quiesced
+static QUIESCED: AtomicBool = AtomicBool::new(false);
+// Monotonic milliseconds each holder took its pause at, by `Holder` order
+static SINCE: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+// Longest a holder may keep its pause, 0 = no limit
+static LIMIT_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Drop pauses held longer than `max` (`Duration::ZERO` = no limit), so a
+/// holder that went away can't stop the pipeline for good
+pub fn limit(max: Duration) {
+    LIMIT_MS.store(max.as_millis() as u64, Ordering::Release);
+}
+
+/// Ask for the pipeline to pause; it does between forwarding rounds. Asking
+/// again renews the hold for another `limit`.
+pub fn pause(holder: Holder) {
+    let now = clock().monotonic().as_millis() as u64;
+    SINCE[holder as usize].store(now, Ordering::Release);
+    if HOLDERS.fetch_or(holder.bit(), Ordering::AcqRel) & holder.bit() == 0 {
+        info!("Pipeline pause requested by {}", holder.name());
+    }
+}
+
+/// Drop `holder`'s pause; the pipeline resumes once nobody holds one
+pub fn resume(holder: Holder) {
+    if HOLDERS.fetch_and(!holder.bit(), Ordering::AcqRel) & holder.bit() != 0 {
+        info!("Pipeline resume requested by {}", holder.name());
+    }
+}
+
+pub fn state() -> PauseState {
+    let requested = HOLDERS.load(Ordering::Acquire) != 0;
+    match (requested, QUIESCED.load(Ordering::Acquire)) {
+        (false, _) => PauseState::Running,
+        (true, false) => PauseState::Pausing,
+        (true, true) => PauseState::Paused,
+    }
+}
+
+/// True if `holder` asked for the pause
+pub fn is_held(holder: Holder) -> bool {
+    HOLDERS.load(Ordering::Acquire) & holder.bit() != 0
+}
+
+/// True if `presented` is the `ctl_token` pause / resume commands need;
+/// compared in constant time, so timing doesn't give the token away
+#[cfg_attr(not(any(feature = "http", feature = "mqtt")), allow(dead_code))]
+pub fn token_matches(presented: &str, token: &str) -> bool {
+    let (presented, token) = (presented.as_bytes(), token.as_bytes());
+    let differences = presented
+        .iter()
+        .zip(token)
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    !token.is_empty() && presented.len() == token.len() && differences == 0
+}
+
+/// Current holders, for `/health`
+pub fn holders() -> Vec<&'static str> {
+    let held = HOLDERS.load(Ordering::Acquire);
+    Holder::ALL
+        .into_iter()
+        .filter(|holder| held & holder.bit() != 0)
+        .map(Holder::name)
+        .collect()
+}
+
+/// Called by the uploader between forwarding rounds: true if it has to stay
+/// quiesced, i.e. a pause is held
+pub fn settle() -> bool {
+    expire();
+    let requested = HOLDERS.load(Ordering::Acquire) != 0;
+    let was_quiesced = QUIESCED.swap(requested, Ordering::AcqRel);
+    match (was_quiesced, requested) {
+        (false, true) => info!("Pipeline paused ({})", holders().join(", ")),
+        (true, false) => info!("Pipeline resumed"),
+        _ => {}
+    }
+    requested
+}
+
+/// Drop the pauses that outlived the limit
+fn expire() {
+    let limit_ms = LIMIT_MS.load(Ordering::Acquire);
+    if limit_ms == 0 {
+        return;
+    }
+    let now = clock().monotonic().as_millis() as u64;
+    for holder in Holder::ALL {
+        let since = SINCE[holder as usize].load(Ordering::Acquire);
+        if is_held(holder) && now.saturating_sub(since) > limit_ms {
+            warn!(
+                event = "pause_expired", holder = holder.name();
+                "Pipeline pause by {} held for over {} min, dropping it",
+                holder.name(), limit_ms / 60_000
+            );
+            resume(holder);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Payload encoding for the real-time side channel (MQTT and webhooks)
+//!
+//! S3 stays the durable store; side-channel messages are small JSON documents.
+//! On metered links they can be gzip-compressed (`gzip` feature) or re-encoded
+//! as CBOR (`cbor` feature). The encoding is picked per peer, either from
+//! configuration or, with `auto`, by negotiating against the `Accept` /
+//! `Accept-Encoding` values a webhook advertises in its responses. MQTT
+//! subscribers can't advertise anything, so `auto` publishes JSON there.
+//! Received payloads are decoded whatever their encoding.
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use embedded_svc::http::client::Client as HttpClient;
+use embedded_svc::http::{Headers, Method};
+use esp_idf_svc::http::client::EspHttpConnection;
+use esp_idf_svc::io::Write;
+use serde_json::Value;
+
+use crate::net;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PayloadEncoding {
+    Json,
+    #[cfg(feature = "gzip")]
+    GzipJson,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl PayloadEncoding {
+    /// Parse a configured encoding name (`json`, `gzip`, `cbor`)
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "json" | "identity" => Some(Self::Json),
+            #[cfg(feature = "gzip")]
+            "gzip" => Some(Self::GzipJson),
+            #[cfg(feature = "cbor")]
+            "cbor" => Some(Self::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Pick the most compact encoding the peer accepts.
+    ///
+    /// CBOR is preferred over gzip'd JSON, which is preferred over plain JSON.
+    /// Media types listed with `q=0` count as refused.
+    pub fn negotiate(accept: Option<&str>, accept_encoding: Option<&str>) -> Self {
+        #[cfg(feature = "cbor")]
+        if accepts(accept, "application/cbor") {
+            return Self::Cbor;
+        }
+
+        #[cfg(feature = "gzip")]
+        if accepts(accept_encoding, "gzip") {
+            return Self::GzipJson;
+        }
+
+        let _ = (accept, accept_encoding);
+        Self::Json
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "cbor")]
+            Self::Cbor => "application/cbor",
+            _ => "application/json",
+        }
+    }
+
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Self::GzipJson => Some("gzip"),
+            _ => None,
+        }
+    }
+}
+
+/// The encoding of one peer: configured, or negotiated with `auto`
+#[derive(Clone, Copy, Debug)]
+pub struct PeerEncoding {
+    encoding: PayloadEncoding,
+    negotiate: bool,
+}
+
+impl PeerEncoding {
+    /// A `PayloadEncoding` name, or `auto` to start with JSON and negotiate
+    pub fn from_name(name: &str) -> Option<Self> {
+        if name.trim().eq_ignore_ascii_case("auto") {
+            return Some(Self {
+                encoding: PayloadEncoding::Json,
+                negotiate: true,
+            });
+        }
+        PayloadEncoding::from_name(name).map(Self::from)
+    }
+
+    pub fn current(&self) -> PayloadEncoding {
+        self.encoding
+    }
+
+    /// Take the `Accept` / `Accept-Encoding` values the peer advertised, if
+    /// negotiating; a response advertising neither changes nothing
+    pub fn advertised(&mut self, accept: Option<&str>, accept_encoding: Option<&str>) {
+        if self.negotiate && (accept.is_some() || accept_encoding.is_some()) {
+            self.encoding = PayloadEncoding::negotiate(accept, accept_encoding);
+        }
+    }
+}
+
+impl From<PayloadEncoding> for PeerEncoding {
+    fn from(encoding: PayloadEncoding) -> Self {
+        Self {
+            encoding,
+            negotiate: false,
+        }
+    }
+}
+
+/// POST `value` to the webhook at `url` in the peer's encoding, learning
+/// the encodings its response advertises
+pub fn post(url: &str, value: &Value, peer: &mut PeerEncoding) -> Result<()> {
+    let encoding = peer.current();
+    let body = encode(value, encoding)?;
+
+    let http_config = net::http_config(url, Duration::from_secs(15));
+    let mut client = HttpClient::wrap(EspHttpConnection::new(&http_config)?);
+
+    let content_length = body.len().to_string();
+    let mut headers = vec![
+        ("Content-Type", encoding.content_type()),
+        ("Content-Length", content_length.as_str()),
+    ];
+    if let Some(content_encoding) = encoding.content_encoding() {
+        headers.push(("Content-Encoding", content_encoding));
+    }
+
+    let mut request = client.request(Method::Post, url, &headers)?;
+    request.write_all(&body)?;
+    let response = request.submit()?;
+    peer.advertised(response.header("Accept"), response.header("Accept-Encoding"));
+    match response.status() {
+        200..=299 => Ok(()),
+        status => bail!("webhook returned HTTP {}", status),
+    }
+}
+
+/// Serialize `value` with the given encoding
+pub fn encode(value: &Value, encoding: PayloadEncoding) -> Result<Vec<u8>> {
+    match encoding {
+        PayloadEncoding::Json => Ok(serde_json::to_vec(value)?),
+        #[cfg(feature = "gzip")]
+        PayloadEncoding::GzipJson => {
+            use std::io::Write;
+
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            serde_json::to_writer(&mut encoder, value)?;
+            encoder.flush()?;
+            Ok(encoder.finish()?)
+        }
+        #[cfg(feature = "cbor")]
+        PayloadEncoding::Cbor => {
+            let mut out = Vec::new();
+            ciborium::into_writer(value, &mut out)?;
+            Ok(out)
+        }
+    }
+}
+
+/// Deserialize a payload, using the content type when known and sniffing
+/// otherwise: gzip by its magic bytes, CBOR by not starting like JSON
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))] // MQTT ingestion is the receiver
+pub fn decode(bytes: &[u8], content_type: Option<&str>) -> Result<Value> {
+    if bytes.starts_with(&GZIP_MAGIC) {
+        #[cfg(feature = "gzip")]
+        {
+            let decoder = flate2::read::GzDecoder::new(bytes);
+            return Ok(serde_json::from_reader(decoder)?);
+        }
+        #[cfg(not(feature = "gzip"))]
+        bail!("gzip payload received but the `gzip` feature is disabled");
+    }
+
+    let is_cbor = match content_type {
+        Some(content_type) => content_type.starts_with("application/cbor"),
+        None => cfg!(feature = "cbor") && !looks_like_json(bytes),
+    };
+    if is_cbor {
+        #[cfg(feature = "cbor")]
+        return Ok(ciborium::from_reader(bytes)?);
+        #[cfg(not(feature = "cbor"))]
+        bail!("CBOR payload received but the `cbor` feature is disabled");
+    }
+
+    Ok(serde_json::from_slice(bytes)?)
+}
+
+/// True if the first byte could start a JSON value; CBOR items of the kind
+/// sent here (maps, arrays) start with a byte no JSON value starts with
+fn looks_like_json(bytes: &[u8]) -> bool {
+    let first = bytes.iter().find(|b| !b.is_ascii_whitespace());
+    first.is_none_or(|b| b"{[\"-0123456789tfn".contains(b))
+}
+
+#[cfg(any(feature = "gzip", feature = "cbor"))]
+/// True if `wanted` appears in a comma-separated header value without `q=0`
+fn accepts(header: Option<&str>, wanted: &str) -> bool {
+    let Some(header) = header else {
+        return false;
+    };
+
+    header.split(',').any(|entry| {
+        let mut parts = entry.split(';').map(str::trim);
+        let name = parts.next().unwrap_or("");
+        let refused = parts.any(|p| {
+            p.strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q <= 0.0)
+        });
+        name.eq_ignore_ascii_case(wanted) && !refused
+    })
+}
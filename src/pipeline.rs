@@ -0,0 +1,64 @@
+//! Flush policy of the continuous ingestion loop
+//!
+//! Readings accumulate in memory and are flushed to S3 as one Parquet file as
+//! soon as any threshold is hit: the row count, the age of the oldest pending
+//! reading, or free heap dropping below a floor (so a slow network can't
+//! starve the allocator).
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::config::DeviceConfig;
+
+#[derive(Clone, Copy, Debug)]
+pub struct FlushPolicy {
+    pub max_rows: usize,
+    pub max_age: Duration,
+    pub min_free_heap: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushReason {
+    Rows,
+    Age,
+    LowHeap,
+}
+
+impl fmt::Display for FlushReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FlushReason::Rows => "row count reached",
+            FlushReason::Age => "max age reached",
+            FlushReason::LowHeap => "free heap low",
+        })
+    }
+}
+
+impl FlushPolicy {
+    pub fn from_config(config: &DeviceConfig) -> Self {
+        Self {
+            max_rows: config.flush_rows.max(1) as usize,
+            max_age: Duration::from_secs(config.flush_secs.into()),
+            min_free_heap: config.min_free_heap,
+        }
+    }
+
+    /// Reason to flush `rows` pending readings, if any threshold is hit
+    pub fn check(&self, rows: usize, age: Duration, free_heap: u32) -> Option<FlushReason> {
+        if rows == 0 {
+            None
+        } else if rows >= self.max_rows {
+            Some(FlushReason::Rows)
+        } else if age >= self.max_age {
+            Some(FlushReason::Age)
+        } else if free_heap < self.min_free_heap {
+            Some(FlushReason::LowHeap)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn free_heap() -> u32 {
+    unsafe { esp_idf_svc::sys::esp_get_free_heap_size() }
+}
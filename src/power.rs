@@ -0,0 +1,53 @@
+//! Deep-sleep duty cycling for battery-powered nodes
+//!
+//! With `sleep_s` configured, the device wakes, samples until the flush
+//! policy triggers, forwards what it can and goes back to deep sleep. Deep
+//! sleep powers down everything except the RTC, so waking is a reboot:
+//! WiFi, SNTP and the lake catalog are restored by the normal boot path, and
+//! batches that couldn't be uploaded are carried across in flash (see
+//! `OfflineBuffer::persist`), the rest of the pipeline state in a
+//! `SleepState`.
+
+use std::time::Duration;
+
+use esp_idf_svc::sys::{
+    esp_deep_sleep, esp_reset_reason, esp_reset_reason_t_ESP_RST_BROWNOUT,
+    esp_reset_reason_t_ESP_RST_DEEPSLEEP, esp_reset_reason_t_ESP_RST_EXT,
+    esp_reset_reason_t_ESP_RST_INT_WDT, esp_reset_reason_t_ESP_RST_PANIC,
+    esp_reset_reason_t_ESP_RST_POWERON, esp_reset_reason_t_ESP_RST_SW,
+    esp_reset_reason_t_ESP_RST_TASK_WDT, esp_reset_reason_t_ESP_RST_WDT,
+    esp_sleep_get_wakeup_cause, esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER,
+};
+use log::info;
+
+use crate::wifi::WifiLink;
+
+/// True if this boot is a wake-up from a duty-cycle sleep
+pub fn woke_from_sleep() -> bool {
+    unsafe { esp_sleep_get_wakeup_cause() == esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER }
+}
+
+/// Why the chip last reset, e.g. `poweron`, `deepsleep` or `panic`
+pub fn reset_reason() -> &'static str {
+    #[allow(non_upper_case_globals)]
+    match unsafe { esp_reset_reason() } {
+        esp_reset_reason_t_ESP_RST_POWERON => "poweron",
+        esp_reset_reason_t_ESP_RST_EXT => "external",
+        esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_reset_reason_t_ESP_RST_INT_WDT => "int_wdt",
+        esp_reset_reason_t_ESP_RST_TASK_WDT => "task_wdt",
+        esp_reset_reason_t_ESP_RST_WDT => "wdt",
+        esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deepsleep",
+        esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        _ => "unknown",
+    }
+}
+
+/// Tear down WiFi and deep sleep for `duration`; the device reboots on wake
+pub fn deep_sleep(wifi: &WifiLink, duration: Duration) -> ! {
+    wifi.shutdown();
+
+    info!("Entering deep sleep for {:?}", duration);
+    unsafe { esp_deep_sleep(duration.as_micros() as u64) }
+}
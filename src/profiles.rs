@@ -0,0 +1,230 @@
+//! Named storage profiles with per-table routing
+//!
+//! Devices operated on behalf of customers may have to write different tables
+//! to different buckets or accounts, e.g. raw sensor data to the customer's
+//! bucket and `device_health` to the manufacturer's. The device configuration
+//! is the implicit `default` profile; additional profiles and routing rules
+//! are read from the `profiles` NVS namespace:
+//!
+//! | Key | Value |
+//! | --- | ----- |
+//! | `names` | Comma-separated profile names, e.g. `customer,mfr` |
+//! | `<name>_bkt` / `<name>_rgn` | Bucket and region of a profile |
+//! | `<name>_url` / `<name>_sty` | Optional S3-compatible endpoint and URL style of a profile |
+//! | `routes` | `table=profile` pairs, e.g. `esp32s3=customer,device_health=mfr` |
+//!
+//! Profile credentials are kept in the encrypted `SecretStore`. Tables without
+//! a route use the default profile.
+//!
+//! Buckets are on AWS unless an endpoint is set, e.g. an on-prem MinIO at
+//! `minio.lan:9000`, which usually also wants `path` style addressing
+//! (`https://minio.lan:9000/<bucket>/<key>`) instead of a bucket subdomain.
+
+use anyhow::{anyhow, bail, Result};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use rusty_s3::{Bucket, UrlStyle};
+
+use crate::config::DeviceConfig;
+use crate::credentials::S3Credentials;
+use crate::net;
+use crate::secrets::SecretStore;
+
+const NAMESPACE: &str = "profiles";
+
+const KEY_NAMES: &str = "names";
+const KEY_ROUTES: &str = "routes";
+
+pub const DEFAULT_PROFILE: &str = "default";
+const MAX_PROFILE_NAME_LEN: usize = 11;
+const MAX_VALUE_LEN: usize = 256;
+
+pub struct StorageProfile {
+    pub name: String,
+    pub bucket: Bucket,
+    /// `None` for the default profile, which uses the rotated device credentials
+    credentials: Option<S3Credentials>,
+}
+
+pub struct ProfileRouter {
+    default: StorageProfile,
+    profiles: Vec<StorageProfile>,
+    routes: Vec<(String, String)>,
+}
+
+impl ProfileRouter {
+    /// Build the router from the device configuration and the `profiles` namespace
+    pub fn load(
+        partition: EspDefaultNvsPartition,
+        secrets: &SecretStore,
+        config: &DeviceConfig,
+    ) -> Result<Self> {
+        let nvs: EspNvs<NvsDefault> = EspNvs::new(partition, NAMESPACE, true)?;
+        let default = StorageProfile {
+            name: DEFAULT_PROFILE.to_string(),
+            bucket: s3_bucket(
+                &config.s3_bucket,
+                &config.s3_region,
+                &S3Endpoint::from_config(config)?,
+            )?,
+            credentials: None,
+        };
+
+        let mut profiles = Vec::new();
+        for name in list(&get_str(&nvs, KEY_NAMES)?.unwrap_or_default()) {
+            match load_profile(&nvs, secrets, name) {
+                Ok(profile) => {
+                    info!("Storage profile '{}': s3://{}", name, profile.bucket.name());
+                    profiles.push(profile);
+                }
+                Err(e) => warn!("Skipping storage profile '{}': {:?}", name, e),
+            }
+        }
+
+        let mut routes = Vec::new();
+        for rule in list(&get_str(&nvs, KEY_ROUTES)?.unwrap_or_default()) {
+            let Some((table, profile)) = rule.split_once('=') else {
+                warn!("Ignoring malformed route '{}'", rule);
+                continue;
+            };
+            let (table, profile) = (table.trim(), profile.trim());
+            if profile != DEFAULT_PROFILE && !profiles.iter().any(|p| p.name == profile) {
+                warn!("Route '{}' targets unknown profile '{}', using default", table, profile);
+                continue;
+            }
+            info!("Routing table '{}' to profile '{}'", table, profile);
+            routes.push((table.to_string(), profile.to_string()));
+        }
+
+        Ok(Self {
+            default,
+            profiles,
+            routes,
+        })
+    }
+
+    /// Bucket of the default profile (credential rotation, probes)
+    pub fn default_bucket(&self) -> &Bucket {
+        &self.default.bucket
+    }
+
+    /// Profile a table is written to
+    pub fn for_table(&self, table: &str) -> &StorageProfile {
+        self.routes
+            .iter()
+            .find(|(t, _)| t == table)
+            .and_then(|(_, profile)| self.profiles.iter().find(|p| &p.name == profile))
+            .unwrap_or(&self.default)
+    }
+
+    /// Bucket and credentials for writing `table`
+    pub fn route<'a>(
+        &'a self,
+        table: &str,
+        default_credentials: &'a S3Credentials,
+    ) -> (&'a Bucket, &'a S3Credentials) {
+        let profile = self.for_table(table);
+        (
+            &profile.bucket,
+            profile.credentials.as_ref().unwrap_or(default_credentials),
+        )
+    }
+}
+
+/// Where buckets are served: AWS, or an S3-compatible object store
+#[derive(Clone, Debug)]
+pub struct S3Endpoint {
+    /// `http(s)://host[:port]`, empty for AWS
+    url: String,
+    style: UrlStyle,
+}
+
+impl S3Endpoint {
+    /// `endpoint` is `host[:port]`, or a URL whose scheme wins over `use_ssl`
+    pub fn parse(endpoint: &str, url_style: &str, use_ssl: bool) -> Result<Self> {
+        let style = match url_style.trim().to_ascii_lowercase().as_str() {
+            "" | "virtual" | "vhost" => UrlStyle::VirtualHost,
+            "path" => UrlStyle::Path,
+            other => bail!("unknown S3 URL style '{}', expected virtual or path", other),
+        };
+        let endpoint = endpoint.trim().trim_end_matches('/');
+        let url = match endpoint.split_once("://") {
+            _ if endpoint.is_empty() => String::new(),
+            Some(("http" | "https", _)) => endpoint.to_string(),
+            Some((scheme, _)) => bail!("unsupported S3 endpoint scheme '{}'", scheme),
+            None => format!("{}://{}", if use_ssl { "https" } else { "http" }, endpoint),
+        };
+        Ok(Self { url, style })
+    }
+
+    pub fn from_config(config: &DeviceConfig) -> Result<Self> {
+        Self::parse(&config.s3_endpoint, &config.s3_url_style, config.s3_use_ssl)
+    }
+
+    /// `host[:port]` of a custom endpoint
+    pub fn host(&self) -> Option<&str> {
+        (!self.url.is_empty()).then(|| net::url_host(&self.url))
+    }
+
+    pub fn is_tls(&self) -> bool {
+        !self.url.starts_with("http://")
+    }
+
+    /// STS API of the endpoint: regional AWS STS, or the object store's own
+    #[cfg(feature = "sts")]
+    pub fn sts_url(&self, region: &str) -> String {
+        match self.url.as_str() {
+            "" => format!("https://sts.{}.amazonaws.com", region),
+            url => url.to_string(),
+        }
+    }
+}
+
+pub fn s3_bucket(name: &str, region: &str, endpoint: &S3Endpoint) -> Result<Bucket> {
+    let url = match endpoint.url.as_str() {
+        "" => format!("https://s3.{}.amazonaws.com", region),
+        url => url.to_string(),
+    };
+    Ok(Bucket::new(
+        url.parse()?,
+        endpoint.style,
+        name.to_string(),
+        region.to_string(),
+    )?)
+}
+
+fn load_profile(
+    nvs: &EspNvs<NvsDefault>,
+    secrets: &SecretStore,
+    name: &str,
+) -> Result<StorageProfile> {
+    if name.len() > MAX_PROFILE_NAME_LEN || name == DEFAULT_PROFILE {
+        bail!("invalid profile name");
+    }
+
+    let bucket = get_str(nvs, &format!("{}_bkt", name))?.ok_or_else(|| anyhow!("no bucket"))?;
+    let region = get_str(nvs, &format!("{}_rgn", name))?.ok_or_else(|| anyhow!("no region"))?;
+    let endpoint = S3Endpoint::parse(
+        &get_str(nvs, &format!("{}_url", name))?.unwrap_or_default(),
+        &get_str(nvs, &format!("{}_sty", name))?.unwrap_or_default(),
+        true,
+    )?;
+    let credentials = secrets
+        .profile_credentials(name)?
+        .ok_or_else(|| anyhow!("no credentials in encrypted storage"))?;
+
+    Ok(StorageProfile {
+        name: name.to_string(),
+        bucket: s3_bucket(&bucket, &region, &endpoint)?,
+        credentials: Some(credentials),
+    })
+}
+
+fn get_str(nvs: &EspNvs<NvsDefault>, key: &str) -> Result<Option<String>> {
+    let mut buf = [0u8; MAX_VALUE_LEN];
+    Ok(nvs.get_str(key, &mut buf)?.map(str::to_string))
+}
+
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|v| !v.is_empty())
+}
@@ -0,0 +1,252 @@
+//! SoftAP captive portal for first-boot provisioning
+//!
+//! When NVS holds no device configuration, the device opens an open access
+//! point named `opensensor-XXXXXX` (last three MAC bytes). A tiny DNS server
+//! answers every query with the portal address, so phones and laptops pop up
+//! the form automatically; otherwise browse to http://192.168.71.1/.
+//!
+//! The submitted WiFi and S3 settings (or fleet server URL and claim code, see
+//! `enrollment.rs`) are saved through `ConfigStore` and the device reboots.
+
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use embedded_svc::wifi::{AccessPointConfiguration, AuthMethod, Configuration};
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::http::server::{Configuration as HttpServerConfig, EspHttpServer};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use log::{info, warn};
+
+use crate::config::{ConfigStore, DeviceConfig};
+
+// Default address of the ESP-IDF SoftAP interface
+const PORTAL_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 1);
+const MAX_FORM_LEN: usize = 1024;
+
+const FORM_HTML: &str = r#"<!DOCTYPE html>
+<html><head><meta name="viewport" content="width=device-width, initial-scale=1">
+<title>opensensor.space setup</title></head>
+<body><h2>opensensor.space setup</h2>
+<form method="post" action="/save">
+<h3>WiFi</h3>
+<p>SSID<br><input name="wifi_ssid" maxlength="32" required></p>
+<p>Password<br><input name="wifi_password" type="password" maxlength="64"></p>
+<p>Security<br><select name="wifi_auth"><option value="wpa2">WPA2 / WPA3</option>
+<option value="wpa3">WPA3 only</option><option value="enterprise">WPA2-Enterprise</option>
+<option value="open">Open</option></select></p>
+<p>Identity, for WPA2-Enterprise<br><input name="wifi_identity"></p>
+<h3>S3</h3>
+<p>Bucket<br><input name="s3_bucket"></p>
+<p>Region<br><input name="s3_region" value="us-west-2"></p>
+<p>Access key<br><input name="aws_access_key"></p>
+<p>Secret key<br><input name="aws_secret_key" type="password"></p>
+<p>Endpoint, empty for AWS<br><input name="s3_endpoint" placeholder="minio.lan:9000"></p>
+<p><label><input name="s3_url_style" type="checkbox" value="path"> Path-style addressing</label></p>
+<h3>Or enroll with a fleet server</h3>
+<p>Server URL<br><input name="enroll_url" type="url"></p>
+<p>Claim code<br><input name="claim_code"></p>
+<p><button type="submit">Save and reboot</button></p>
+</form></body></html>"#;
+
+const SAVED_HTML: &str = "<!DOCTYPE html><html><body><h2>Saved</h2>\
+    <p>The device is rebooting and will join your WiFi network.</p></body></html>";
+
+/// Run the provisioning portal until a configuration is saved, then reboot
+pub fn run_portal(
+    modem: esp_idf_svc::hal::modem::Modem,
+    sys_loop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    store: ConfigStore,
+) -> Result<Infallible> {
+    info!("Starting provisioning portal...");
+
+    let mut wifi = BlockingWifi::wrap(
+        EspWifi::new(modem, sys_loop.clone(), Some(nvs))?,
+        sys_loop,
+    )?;
+
+    let mac = wifi.wifi().ap_netif().get_mac()?;
+    let ssid = format!("opensensor-{:02X}{:02X}{:02X}", mac[3], mac[4], mac[5]);
+
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: ssid.as_str().try_into().map_err(|_| anyhow!("AP SSID too long"))?,
+        auth_method: AuthMethod::None,
+        channel: 1,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+    wifi.wait_netif_up()?;
+    info!(
+        event = "portal_up", ssid = ssid.as_str();
+        "Provisioning AP '{}' up, portal at http://{}/", ssid, PORTAL_IP
+    );
+
+    std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(run_dns_responder)?;
+
+    let (saved_tx, saved_rx) = mpsc::channel::<()>();
+    let store = Arc::new(Mutex::new(store));
+
+    let mut server = EspHttpServer::new(&HttpServerConfig {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    server.fn_handler("/", Method::Get, |req| {
+        req.into_ok_response()?.write_all(FORM_HTML.as_bytes())?;
+        Ok::<(), anyhow::Error>(())
+    })?;
+
+    server.fn_handler("/save", Method::Post, move |mut req| {
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len == 0 || len > MAX_FORM_LEN {
+            req.into_status_response(400)?.write_all(b"Invalid form")?;
+            return Ok(());
+        }
+
+        let mut body = vec![0u8; len];
+        let mut filled = 0;
+        while filled < len {
+            match req.read(&mut body[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        body.truncate(filled);
+
+        let config = parse_form(&String::from_utf8_lossy(&body));
+        let s3_configured = config.is_provisioned() || config.needs_enrollment();
+        if config.wifi_ssid.is_empty() || !s3_configured {
+            req.into_status_response(400)?
+                .write_all(b"WiFi and either S3 keys or a fleet server are required")?;
+            return Ok(());
+        }
+
+        store
+            .lock()
+            .map_err(|_| anyhow!("config store poisoned"))?
+            .save(&config)?;
+        info!(
+            event = "provisioned",
+            ssid = config.wifi_ssid.as_str(),
+            bucket = config.s3_bucket.as_str();
+            "Provisioned WiFi '{}', bucket '{}'", config.wifi_ssid, config.s3_bucket
+        );
+
+        req.into_ok_response()?.write_all(SAVED_HTML.as_bytes())?;
+        let _ = saved_tx.send(());
+        Ok::<(), anyhow::Error>(())
+    })?;
+
+    // Connectivity checks (generate_204, hotspot-detect.html, ...) land here
+    server.fn_handler("/*", Method::Get, |req| {
+        let location = format!("http://{}/", PORTAL_IP);
+        req.into_response(302, Some("Found"), &[("Location", location.as_str())])?;
+        Ok::<(), anyhow::Error>(())
+    })?;
+
+    saved_rx.recv()?;
+
+    // Give the HTTP response time to reach the browser
+    std::thread::sleep(Duration::from_secs(2));
+    info!("Provisioning complete, rebooting...");
+    esp_idf_svc::hal::reset::restart();
+}
+
+/// Build a configuration from the submitted form, keeping defaults for the rest
+fn parse_form(body: &str) -> DeviceConfig {
+    let mut config = DeviceConfig::default();
+
+    for pair in body.split('&') {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = url_decode(value);
+        match key {
+            "wifi_ssid" => config.wifi_ssid = value,
+            "wifi_password" => config.wifi_password = value,
+            "wifi_auth" => config.wifi_auth = value,
+            "wifi_identity" => config.wifi_identity = value,
+            "s3_bucket" => config.s3_bucket = value,
+            "s3_region" => config.s3_region = value,
+            "aws_access_key" => config.aws_access_key = value,
+            "aws_secret_key" => config.aws_secret_key = value,
+            "s3_endpoint" => config.s3_endpoint = value,
+            "s3_url_style" => config.s3_url_style = value,
+            "enroll_url" => config.enroll_url = value,
+            "claim_code" => config.claim_code = value,
+            _ => {}
+        }
+    }
+
+    config
+}
+
+/// Decode `application/x-www-form-urlencoded` values
+fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    Err(_) => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Answer every DNS A query with the portal address
+fn run_dns_responder() {
+    let socket = match UdpSocket::bind("0.0.0.0:53") {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Captive DNS unavailable: {:?}", e);
+            return;
+        }
+    };
+
+    let mut buf = [0u8; 512];
+    loop {
+        let Ok((len, peer)) = socket.recv_from(&mut buf) else {
+            continue;
+        };
+        // Header (12 bytes) plus at least a root name, type and class
+        if len < 17 {
+            continue;
+        }
+
+        let mut response = Vec::with_capacity(len + 16);
+        response.extend_from_slice(&buf[..2]); // Transaction ID
+        response.extend_from_slice(&[0x81, 0x80]); // Standard response, no error
+        response.extend_from_slice(&buf[4..6]); // Question count
+        response.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x00, 0x00]); // 1 answer
+        response.extend_from_slice(&buf[12..len]); // Question section
+        response.extend_from_slice(&[0xC0, 0x0C]); // Pointer to the queried name
+        response.extend_from_slice(&[0x00, 0x01, 0x00, 0x01]); // Type A, class IN
+        response.extend_from_slice(&60u32.to_be_bytes()); // TTL
+        response.extend_from_slice(&[0x00, 0x04]);
+        response.extend_from_slice(&PORTAL_IP.octets());
+
+        let _ = socket.send_to(&response, peer);
+    }
+}
@@ -0,0 +1,739 @@
+//! SQL subset over the lake catalog
+//!
+//! ```sql
+//! [SELECT <* | columns | aggregates>] FROM <source>
+//!     [WHERE <column> <op> <literal> [AND ...]]
+//!     [ORDER BY <column> [ASC | DESC]] [LIMIT <n>]
+//! ```
+//!
+//! Sources are the catalog views `data_files`, `snapshots` and `tables`
+//! (also as `ducklake_data_files(...)`, `ducklake_snapshots(...)` and
+//! `ducklake_table_info(...)`), or a lake table by name. Row data lives in
+//! S3, so a lake table only answers `COUNT(*)`, `MIN(timestamp)` and
+//! `MAX(timestamp)`, from the catalog's per-file row counts and ranges.
+//! Aggregates are `COUNT(*)`, `SUM`, `MIN` and `MAX`.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::catalog::Catalog;
+
+const DATA_FILE_COLUMNS: [&str; 9] = [
+    "snapshot_id",
+    "table",
+    "bucket",
+    "key",
+    "partition",
+    "rows",
+    "bytes",
+    "min_timestamp",
+    "max_timestamp",
+];
+const SNAPSHOT_COLUMNS: [&str; 4] = ["snapshot_id", "files", "rows", "bytes"];
+const TABLE_COLUMNS: [&str; 6] = [
+    "table",
+    "files",
+    "rows",
+    "bytes",
+    "partitioned_by",
+    "schema_version",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Text(String),
+    Null,
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(v) => write!(f, "{}", v),
+            Value::Text(v) => write!(f, "{}", v),
+            Value::Null => write!(f, "NULL"),
+        }
+    }
+}
+
+impl Value {
+    fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+            (Value::Text(a), Value::Text(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+}
+
+/// Result of a query: column names and rows
+pub struct ResultSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+enum Projection {
+    All,
+    Columns(Vec<String>),
+    Aggregates(Vec<(Aggregate, Option<String>)>),
+}
+
+struct Condition {
+    column: String,
+    op: String,
+    value: Value,
+}
+
+struct Query {
+    projection: Projection,
+    source: String,
+    conditions: Vec<Condition>,
+    order_by: Option<(String, bool)>,
+    limit: Option<usize>,
+}
+
+/// Run `sql` against `catalog`
+pub fn execute(sql: &str, catalog: &Catalog) -> Result<ResultSet> {
+    let query = parse(sql)?;
+    if let Some(result) = lake_table(&query, catalog)? {
+        return Ok(result);
+    }
+    let (columns, mut rows) = catalog_view(&query, catalog)?;
+
+    let index = |name: &str| -> Result<usize> {
+        columns
+            .iter()
+            .position(|c| c.eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("unknown column '{}'", name))
+    };
+
+    for condition in &query.conditions {
+        let column = index(&condition.column)?;
+        let mut kept = Vec::new();
+        for row in rows {
+            if matches(&row[column], &condition.op, &condition.value)? {
+                kept.push(row);
+            }
+        }
+        rows = kept;
+    }
+
+    if let Some((column, descending)) = &query.order_by {
+        let column = index(column)?;
+        rows.sort_by(|a, b| {
+            let ordering = a[column].compare(&b[column]).unwrap_or(Ordering::Equal);
+            if *descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+    }
+
+    let mut result = match &query.projection {
+        Projection::All => ResultSet {
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            rows,
+        },
+        Projection::Columns(names) => {
+            let indices = names.iter().map(|n| index(n)).collect::<Result<Vec<_>>>()?;
+            ResultSet {
+                columns: names.clone(),
+                rows: rows
+                    .iter()
+                    .map(|row| indices.iter().map(|i| row[*i].clone()).collect())
+                    .collect(),
+            }
+        }
+        Projection::Aggregates(aggregates) => {
+            let mut names = Vec::new();
+            let mut values = Vec::new();
+            for (aggregate, column) in aggregates {
+                let column_index = column.as_deref().map(index).transpose()?;
+                names.push(aggregate_name(*aggregate, column.as_deref()));
+                values.push(aggregate_value(*aggregate, column_index, &rows)?);
+            }
+            ResultSet {
+                columns: names,
+                rows: vec![values],
+            }
+        }
+    };
+
+    if let Some(limit) = query.limit {
+        result.rows.truncate(limit);
+    }
+    Ok(result)
+}
+
+type Rows = Vec<Vec<Value>>;
+
+/// Columns and rows of a catalog view
+fn catalog_view(query: &Query, catalog: &Catalog) -> Result<(Vec<&'static str>, Rows)> {
+    let files = catalog.files();
+    let text = |s: &str| Value::Text(s.to_string());
+    let int = |n: usize| Value::Int(n as i64);
+
+    match query.source.to_ascii_lowercase().as_str() {
+        "data_files" | "ducklake_data_files" => {
+            let rows = files
+                .iter()
+                .map(|f| {
+                    vec![
+                        Value::Int(f.snapshot_id as i64),
+                        text(&f.table),
+                        text(&f.bucket),
+                        text(&f.key),
+                        text(&f.partition),
+                        int(f.rows),
+                        int(f.bytes),
+                        Value::Int(f.min_timestamp),
+                        Value::Int(f.max_timestamp),
+                    ]
+                })
+                .collect();
+            Ok((DATA_FILE_COLUMNS.to_vec(), rows))
+        }
+        "snapshots" | "ducklake_snapshots" => {
+            let mut rows: Rows = Vec::new();
+            for file in files {
+                let id = Value::Int(file.snapshot_id as i64);
+                match rows.iter_mut().find(|row| row[0] == id) {
+                    Some(row) => add_file(row, file.rows, file.bytes),
+                    None => rows.push(vec![id, int(1), int(file.rows), int(file.bytes)]),
+                }
+            }
+            Ok((SNAPSHOT_COLUMNS.to_vec(), rows))
+        }
+        "tables" | "ducklake_table_info" => {
+            let mut rows: Rows = catalog
+                .tables()
+                .iter()
+                .map(|t| {
+                    let partitioned_by = text(catalog.partitioned_by(t));
+                    let version = catalog
+                        .schema_version(t)
+                        .map_or(Value::Null, |v| Value::Int(v.into()));
+                    vec![text(t), int(0), int(0), int(0), partitioned_by, version]
+                })
+                .collect();
+            for file in files {
+                let name = text(&file.table);
+                match rows.iter_mut().find(|row| row[0] == name) {
+                    Some(row) => add_file(row, file.rows, file.bytes),
+                    None => rows.push(vec![
+                        name,
+                        int(1),
+                        int(file.rows),
+                        int(file.bytes),
+                        text(catalog.partitioned_by(&file.table)),
+                        Value::Null,
+                    ]),
+                }
+            }
+            Ok((TABLE_COLUMNS.to_vec(), rows))
+        }
+        _ => bail!("unknown table '{}'", query.source),
+    }
+}
+
+/// Aggregates over a lake table, answered from the catalog; `None` if `query` isn't on one
+fn lake_table(query: &Query, catalog: &Catalog) -> Result<Option<ResultSet>> {
+    let table = &query.source;
+    let known = catalog.tables().iter().any(|t| t.eq_ignore_ascii_case(table))
+        || catalog.files().iter().any(|f| f.table.eq_ignore_ascii_case(table));
+    if !known {
+        return Ok(None);
+    }
+
+    let files: Vec<_> = catalog
+        .files()
+        .iter()
+        .filter(|f| f.table.eq_ignore_ascii_case(table))
+        .collect();
+    let unsupported = || {
+        anyhow!(
+            "row data lives in S3; lake tables only answer COUNT(*), MIN(timestamp) and \
+             MAX(timestamp), without WHERE"
+        )
+    };
+    let aggregates = match &query.projection {
+        Projection::Aggregates(aggregates) if query.conditions.is_empty() => aggregates,
+        _ => return Err(unsupported()),
+    };
+
+    let mut result = ResultSet {
+        columns: Vec::new(),
+        rows: vec![Vec::new()],
+    };
+    for (aggregate, column) in aggregates {
+        let is_timestamp = column
+            .as_deref()
+            .is_some_and(|c| c.eq_ignore_ascii_case("timestamp"));
+        let value = match (aggregate, column) {
+            (Aggregate::Count, None) => Value::Int(files.iter().map(|f| f.rows as i64).sum()),
+            (Aggregate::Min, Some(_)) if is_timestamp => {
+                files.iter().map(|f| f.min_timestamp).min().map_or(Value::Null, Value::Int)
+            }
+            (Aggregate::Max, Some(_)) if is_timestamp => {
+                files.iter().map(|f| f.max_timestamp).max().map_or(Value::Null, Value::Int)
+            }
+            _ => return Err(unsupported()),
+        };
+        result.columns.push(aggregate_name(*aggregate, column.as_deref()));
+        result.rows[0].push(value);
+    }
+    Ok(Some(result))
+}
+
+fn add_file(row: &mut [Value], rows: usize, bytes: usize) {
+    for (index, amount) in [(1, 1), (2, rows), (3, bytes)] {
+        if let Value::Int(total) = &mut row[index] {
+            *total += amount as i64;
+        }
+    }
+}
+
+fn matches(value: &Value, op: &str, literal: &Value) -> Result<bool> {
+    let ordering = match value.compare(literal) {
+        Some(ordering) => ordering,
+        None => bail!("can't compare {} with {}", value, literal),
+    };
+    Ok(match op {
+        "=" => ordering == Ordering::Equal,
+        "!=" | "<>" => ordering != Ordering::Equal,
+        "<" => ordering == Ordering::Less,
+        "<=" => ordering != Ordering::Greater,
+        ">" => ordering == Ordering::Greater,
+        ">=" => ordering != Ordering::Less,
+        other => bail!("unsupported operator '{}'", other),
+    })
+}
+
+fn aggregate_name(aggregate: Aggregate, column: Option<&str>) -> String {
+    let name = match aggregate {
+        Aggregate::Count => "count",
+        Aggregate::Sum => "sum",
+        Aggregate::Min => "min",
+        Aggregate::Max => "max",
+    };
+    format!("{}({})", name, column.unwrap_or("*"))
+}
+
+fn aggregate_value(
+    aggregate: Aggregate,
+    column: Option<usize>,
+    rows: &[Vec<Value>],
+) -> Result<Value> {
+    let Some(column) = column else {
+        return match aggregate {
+            Aggregate::Count => Ok(Value::Int(rows.len() as i64)),
+            _ => bail!("{} needs a column", aggregate_name(aggregate, None)),
+        };
+    };
+
+    let values = rows.iter().map(|row| &row[column]);
+    Ok(match aggregate {
+        Aggregate::Count => Value::Int(values.filter(|v| **v != Value::Null).count() as i64),
+        Aggregate::Sum => {
+            let mut total = 0;
+            for value in values {
+                match value {
+                    Value::Int(v) => total += v,
+                    Value::Null => {}
+                    Value::Text(_) => bail!("SUM needs a numeric column"),
+                }
+            }
+            Value::Int(total)
+        }
+        Aggregate::Min | Aggregate::Max => {
+            let wanted = if aggregate == Aggregate::Min {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            };
+            values
+                .filter(|v| **v != Value::Null)
+                .fold(None::<&Value>, |best, v| match best {
+                    Some(best) if v.compare(best) != Some(wanted) => Some(best),
+                    _ => Some(v),
+                })
+                .cloned()
+                .unwrap_or(Value::Null)
+        }
+    })
+}
+
+// ============================================================================
+// PARSER
+// ============================================================================
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Number(i64),
+    Text(String),
+    Symbol(String),
+}
+
+fn tokenize(sql: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = sql.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || c == ';' {
+            chars.next();
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric() || **c == '_') {
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        } else if c.is_ascii_digit() || c == '-' {
+            let mut number = String::from(c);
+            chars.next();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                number.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Number(number.parse()?));
+        } else if c == '\'' || c == '"' {
+            // 'literal', "quoted identifier"
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some(q) if q == c => break,
+                    Some(other) => text.push(other),
+                    None => bail!("unterminated string"),
+                }
+            }
+            tokens.push(if c == '"' {
+                Token::Word(text)
+            } else {
+                Token::Text(text)
+            });
+        } else {
+            chars.next();
+            let mut symbol = String::from(c);
+            if let Some(&next) = chars.peek() {
+                if matches!((c, next), ('<', '=') | ('>', '=') | ('!', '=') | ('<', '>')) {
+                    symbol.push(next);
+                    chars.next();
+                }
+            }
+            tokens.push(Token::Symbol(symbol));
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn advance(&mut self) -> Result<Token> {
+        let token = self.peek().cloned().ok_or_else(|| anyhow!("unexpected end of query"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        if !self.keyword(keyword) {
+            bail!("expected {}", keyword);
+        }
+        Ok(())
+    }
+
+    fn symbol(&mut self, symbol: &str) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol.to_string())) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<()> {
+        if !self.symbol(symbol) {
+            bail!("expected '{}'", symbol);
+        }
+        Ok(())
+    }
+
+    fn identifier(&mut self) -> Result<String> {
+        match self.advance()? {
+            Token::Word(word) => Ok(word),
+            other => bail!("expected a name, found {:?}", other),
+        }
+    }
+
+    fn literal(&mut self) -> Result<Value> {
+        match self.advance()? {
+            Token::Number(n) => Ok(Value::Int(n)),
+            Token::Text(t) => Ok(Value::Text(t)),
+            Token::Word(w) if w.eq_ignore_ascii_case("null") => Ok(Value::Null),
+            other => bail!("expected a literal, found {:?}", other),
+        }
+    }
+
+    fn projection(&mut self) -> Result<Projection> {
+        if self.symbol("*") {
+            return Ok(Projection::All);
+        }
+
+        let mut columns = Vec::new();
+        let mut aggregates = Vec::new();
+        loop {
+            let name = self.identifier()?;
+            let aggregate = match name.to_ascii_lowercase().as_str() {
+                "count" => Some(Aggregate::Count),
+                "sum" => Some(Aggregate::Sum),
+                "min" => Some(Aggregate::Min),
+                "max" => Some(Aggregate::Max),
+                _ => None,
+            };
+
+            match aggregate {
+                Some(aggregate) if self.symbol("(") => {
+                    let column = if self.symbol("*") {
+                        None
+                    } else {
+                        Some(self.identifier()?)
+                    };
+                    self.expect_symbol(")")?;
+                    aggregates.push((aggregate, column));
+                }
+                _ => columns.push(name),
+            }
+
+            if !self.symbol(",") {
+                break;
+            }
+        }
+
+        match (columns.is_empty(), aggregates.is_empty()) {
+            (true, false) => Ok(Projection::Aggregates(aggregates)),
+            (false, true) => Ok(Projection::Columns(columns)),
+            _ => bail!("mixing columns and aggregates needs GROUP BY, which isn't supported"),
+        }
+    }
+}
+
+fn parse(sql: &str) -> Result<Query> {
+    let mut parser = Parser {
+        tokens: tokenize(sql)?,
+        pos: 0,
+    };
+
+    // DuckDB-style `FROM x` is shorthand for `SELECT * FROM x`
+    let projection = if parser.keyword("select") {
+        parser.projection()?
+    } else {
+        Projection::All
+    };
+
+    parser.expect_keyword("from")?;
+    let source = parser.identifier()?;
+    // Table functions: the argument (the lake's name) is accepted and ignored
+    if parser.symbol("(") {
+        while !parser.symbol(")") {
+            parser.advance()?;
+        }
+    }
+
+    let mut conditions = Vec::new();
+    if parser.keyword("where") {
+        loop {
+            let column = parser.identifier()?;
+            let op = match parser.advance()? {
+                Token::Symbol(op) => op,
+                other => bail!("expected an operator, found {:?}", other),
+            };
+            let value = parser.literal()?;
+            conditions.push(Condition { column, op, value });
+            if !parser.keyword("and") {
+                break;
+            }
+        }
+    }
+
+    let mut order_by = None;
+    if parser.keyword("order") {
+        parser.expect_keyword("by")?;
+        let column = parser.identifier()?;
+        let descending = parser.keyword("desc");
+        if !descending {
+            parser.keyword("asc");
+        }
+        order_by = Some((column, descending));
+    }
+
+    let mut limit = None;
+    if parser.keyword("limit") {
+        match parser.advance()? {
+            Token::Number(n) if n >= 0 => limit = Some(n as usize),
+            other => bail!("expected a row count, found {:?}", other),
+        }
+    }
+
+    if let Some(token) = parser.peek() {
+        bail!("unexpected {:?}", token);
+    }
+
+    Ok(Query {
+        projection,
+        source,
+        conditions,
+        order_by,
+        limit,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::DataFile;
+
+    /// A catalog of two tables: `esp32s3` with three files over two
+    /// snapshots, and an empty `events`
+    fn lake(name: &str) -> Result<(std::path::PathBuf, Catalog)> {
+        let root = std::env::temp_dir().join(format!("query-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let mut catalog = Catalog::open(root.to_str().unwrap_or_default())?;
+        catalog.create_table("esp32s3")?;
+        catalog.create_table("events")?;
+        catalog.set_schema_version("esp32s3", 2)?;
+        let file = |key: &str, rows: usize, min_timestamp: i64| DataFile {
+            snapshot_id: 0,
+            table: "esp32s3".to_string(),
+            bucket: "lake".to_string(),
+            key: key.to_string(),
+            rows,
+            bytes: rows * 40,
+            min_timestamp,
+            max_timestamp: min_timestamp + 60_000,
+            partition: String::new(),
+            records: false,
+        };
+        catalog.commit(vec![file("a.parquet", 10, 1_000), file("b.parquet", 30, 61_000)])?;
+        catalog.commit(vec![file("c.parquet", 20, 121_000)])?;
+        Ok((root, catalog))
+    }
+
+    fn column(result: &ResultSet, index: usize) -> Vec<String> {
+        result.rows.iter().map(|row| row[index].to_string()).collect()
+    }
+
+    #[test]
+    fn catalog_views() -> Result<()> {
+        let (root, catalog) = lake("views")?;
+
+        let files = execute("FROM data_files", &catalog)?;
+        assert_eq!(files.columns, DATA_FILE_COLUMNS);
+        assert_eq!(column(&files, 3), ["a.parquet", "b.parquet", "c.parquet"]);
+
+        let snapshots = execute("SELECT * FROM ducklake_snapshots('lake')", &catalog)?;
+        assert_eq!(snapshots.columns, SNAPSHOT_COLUMNS);
+        assert_eq!(column(&snapshots, 0), ["1", "2"]);
+        assert_eq!(column(&snapshots, 1), ["2", "1"]);
+        assert_eq!(column(&snapshots, 2), ["40", "20"]);
+        assert_eq!(column(&snapshots, 3), ["1600", "800"]);
+
+        let tables = execute("SELECT table, rows, schema_version FROM tables", &catalog)?;
+        assert_eq!(
+            tables.rows,
+            [
+                vec![Value::Text("esp32s3".to_string()), Value::Int(60), Value::Int(2)],
+                vec![Value::Text("events".to_string()), Value::Int(0), Value::Null],
+            ]
+        );
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn filters_orders_and_limits() -> Result<()> {
+        let (root, catalog) = lake("filter")?;
+
+        let sql = "select key from data_files where rows >= 20 and snapshot_id = 1";
+        assert_eq!(column(&execute(sql, &catalog)?, 0), ["b.parquet"]);
+
+        let sql = "SELECT key FROM data_files ORDER BY rows DESC LIMIT 2";
+        assert_eq!(column(&execute(sql, &catalog)?, 0), ["b.parquet", "c.parquet"]);
+
+        let sql = "SELECT COUNT(*), SUM(rows), MAX(key) FROM data_files WHERE key != 'b.parquet'";
+        let result = execute(sql, &catalog)?;
+        assert_eq!(result.columns, ["count(*)", "sum(rows)", "max(key)"]);
+        assert_eq!(column(&result, 0), ["2"]);
+        assert_eq!(column(&result, 1), ["30"]);
+        assert_eq!(column(&result, 2), ["c.parquet"]);
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn lake_tables_answer_from_the_catalog() -> Result<()> {
+        let (root, catalog) = lake("lake")?;
+
+        let sql = "SELECT COUNT(*), MIN(timestamp), MAX(timestamp) FROM ESP32S3";
+        let result = execute(sql, &catalog)?;
+        assert_eq!(result.rows, [vec![Value::Int(60), Value::Int(1_000), Value::Int(181_000)]]);
+
+        let result = execute("SELECT COUNT(*), MIN(timestamp) FROM events", &catalog)?;
+        assert_eq!(result.rows, [vec![Value::Int(0), Value::Null]]);
+
+        assert!(execute("FROM esp32s3", &catalog).is_err());
+        assert!(execute("SELECT COUNT(*) FROM esp32s3 WHERE rows > 1", &catalog).is_err());
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_answer() -> Result<()> {
+        let (root, catalog) = lake("errors")?;
+
+        for sql in [
+            "SELECT * FROM nowhere",
+            "SELECT nothing FROM data_files",
+            "SELECT key, COUNT(*) FROM data_files",
+            "SELECT SUM(key) FROM data_files",
+            "SELECT * FROM data_files WHERE key = 1",
+            "SELECT * FROM data_files WHERE key = 'open",
+            "SELECT * FROM data_files LIMIT -1",
+            "SELECT * FROM data_files trailing",
+        ] {
+            assert!(execute(sql, &catalog).is_err(), "{}", sql);
+        }
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+//! Rows of sensor data and their provenance
+//!
+//! A `SensorReading` is one Parquet row, whichever source it came from: the
+//! on-board sensors (see `sensors/`), MQTT, the SD card watch folder or a
+//! replay. The rest of the pipeline only deals in readings.
+
+use crate::clock::clock;
+
+/// Version of the ingestion pipeline, stamped on every row as
+/// `pipeline_version`. Bump it when the way rows are produced changes
+/// (sampling, warm-up, deadband, rollups), so analysts can tell the rows apart.
+pub const PIPELINE_VERSION: u32 = 1;
+
+/// Names of the measured fields, in `SensorReading::metrics()` order
+pub const METRIC_NAMES: [&str; 9] = [
+    "temperature",
+    "humidity",
+    "pressure",
+    "pm1_0",
+    "pm2_5",
+    "pm10",
+    "gas_resistance",
+    "light",
+    "noise",
+];
+
+/// Where a row entered the pipeline, the `source` provenance column
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// Rows from before provenance was recorded (NULL)
+    Unknown,
+    /// The on-board sensors
+    Sensor,
+    /// Relayed by another sensor over MQTT
+    Mqtt,
+    /// Imported from the SD card watch folder
+    Watch,
+    /// Backfilled: restored after a reboot / sleep, or promoted raw rows
+    Replay,
+}
+
+impl Source {
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            Self::Unknown => None,
+            Self::Sensor => Some("sensor"),
+            Self::Mqtt => Some("mqtt"),
+            Self::Watch => Some("watch"),
+            Self::Replay => Some("replay"),
+        }
+    }
+
+    /// Inverse of `name`
+    pub fn from_name(name: Option<&str>) -> Self {
+        match name {
+            Some("sensor") => Self::Sensor,
+            Some("mqtt") => Self::Mqtt,
+            Some("watch") => Self::Watch,
+            Some("replay") => Self::Replay,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// One row of sensor data (one Parquet row)
+#[derive(Clone, Debug)]
+pub struct SensorReading {
+    pub timestamp: i64, // Unix epoch milliseconds
+    pub temperature: f32,
+    pub humidity: f32,
+    pub pressure: f32,
+    pub pm1_0: f32,
+    pub pm2_5: f32,
+    pub pm10: f32,
+    pub gas_resistance: f32,
+    pub light: f32,
+    pub noise: f32,
+    pub sample_interval_ms: u32, // Effective sampling interval for this row
+    pub warming_up: bool,        // Taken while a sensor was still warming up
+    // Provenance, stamped where the row enters the pipeline (0 / Unknown = NULL)
+    pub ingested_at: i64, // Unix epoch milliseconds
+    pub source: Source,
+    pub pipeline_version: u32,
+}
+
+impl SensorReading {
+    /// A reading with every measurement unset (NaN)
+    pub fn empty(timestamp: i64) -> Self {
+        Self {
+            timestamp,
+            temperature: f32::NAN,
+            humidity: f32::NAN,
+            pressure: f32::NAN,
+            pm1_0: f32::NAN,
+            pm2_5: f32::NAN,
+            pm10: f32::NAN,
+            gas_resistance: f32::NAN,
+            light: f32::NAN,
+            noise: f32::NAN,
+            sample_interval_ms: 0,
+            warming_up: false,
+            ingested_at: 0,
+            source: Source::Unknown,
+            pipeline_version: 0,
+        }
+    }
+
+    /// Record that the pipeline takes the row in from `source` now
+    pub fn stamp(&mut self, source: Source) {
+        self.ingested_at = clock().now_millis();
+        self.source = source;
+        self.pipeline_version = PIPELINE_VERSION;
+    }
+
+    /// Set the measured fields from `metrics()` order
+    pub fn set_metrics(&mut self, metrics: [f32; 9]) {
+        [
+            self.temperature,
+            self.humidity,
+            self.pressure,
+            self.pm1_0,
+            self.pm2_5,
+            self.pm10,
+            self.gas_resistance,
+            self.light,
+            self.noise,
+        ] = metrics;
+    }
+
+    /// Set one measured field by its `METRIC_NAMES` name, false if there is none
+    pub fn set_metric(&mut self, name: &str, value: f32) -> bool {
+        let Some(index) = METRIC_NAMES.iter().position(|n| *n == name) else {
+            return false;
+        };
+        let mut metrics = self.metrics();
+        metrics[index] = value;
+        self.set_metrics(metrics);
+        true
+    }
+
+    /// Measured fields, in `METRIC_NAMES` order
+    pub fn metrics(&self) -> [f32; 9] {
+        [
+            self.temperature,
+            self.humidity,
+            self.pressure,
+            self.pm1_0,
+            self.pm2_5,
+            self.pm10,
+            self.gas_resistance,
+            self.light,
+            self.noise,
+        ]
+    }
+}
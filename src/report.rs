@@ -0,0 +1,607 @@
+//! Daily report per device, rendered on the device and uploaded to S3
+//!
+//! With `report` set to `html` or `md`, every sample of the day (recorded
+//! or not) is folded into hourly min / avg / max per metric, along with the
+//! gaps in sampling and the alert raises and clears. When the first sample
+//! of the next UTC day arrives, the finished day is rendered, with a chart
+//! per metric as inline SVG, and uploaded with the next forwarding round to
+//!
+//! ```text
+//! s3://<bucket>/<data_path>/reports/<device_id>/<YYYY-MM-DD>.html
+//! ```
+//!
+//! so people who don't write SQL get one readable file per device and day.
+//! The day so far and reports not uploaded yet are kept across deep sleep.
+//! Samples under an untrusted clock are left out.
+
+use std::collections::VecDeque;
+use std::fmt::Write as FmtWrite;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use embedded_svc::http::Method;
+use log::{info, warn};
+use rusty_s3::S3Action;
+use serde::{Deserialize, Serialize};
+
+use crate::alerts::AlertChange;
+use crate::clock::{clock, utc_date};
+use crate::config::DeviceConfig;
+use crate::identity::DeviceIdentity;
+use crate::lake::S3Target;
+use crate::net::{send_capped, status_error, with_retry};
+use crate::sensors::{SensorReading, METRIC_NAMES};
+use crate::UPLOAD_RETRY;
+
+const REPORTS_DIR: &str = "reports";
+const DAY_MS: i64 = 86_400_000;
+const HOUR_MS: i64 = 3_600_000;
+// Sampling pauses shorter than this, or 3 sample intervals, aren't gaps
+const MIN_GAP_MS: i64 = 5 * 60 * 1000;
+// Kept per day; further ones are only counted
+const MAX_GAPS: usize = 48;
+const MAX_ALERTS: usize = 64;
+// Rendered days waiting for S3; oldest dropped beyond
+const MAX_PENDING: usize = 7;
+const PRESIGN_EXPIRY: Duration = Duration::from_secs(300);
+
+// Chart size in pixels
+const CHART_WIDTH: f32 = 480.0;
+const CHART_HEIGHT: f32 = 120.0;
+const CHART_PAD: f32 = 16.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ReportFormat {
+    Html,
+    Markdown,
+}
+
+impl ReportFormat {
+    /// `None` for `off` or empty
+    fn parse(value: &str) -> Result<Option<Self>> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "off" => Ok(None),
+            "html" => Ok(Some(Self::Html)),
+            "md" | "markdown" => Ok(Some(Self::Markdown)),
+            other => bail!("unknown report format '{}', expected html, md or off", other),
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Html => "html",
+            Self::Markdown => "md",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Html => "text/html; charset=utf-8",
+            Self::Markdown => "text/markdown; charset=utf-8",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+struct Stat {
+    count: u32,
+    min: f32,
+    max: f32,
+    sum: f64,
+}
+
+impl Stat {
+    fn add(&mut self, value: f32) {
+        if self.count == 0 {
+            (self.min, self.max) = (value, value);
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.count += 1;
+        self.sum += f64::from(value);
+    }
+
+    fn merge(&mut self, other: &Stat) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            *self = *other;
+            return;
+        }
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+
+    fn avg(&self) -> f32 {
+        (self.sum / f64::from(self.count.max(1))) as f32
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Alert {
+    timestamp: i64,
+    metric: String,
+    raised: bool,
+    value: f32,
+}
+
+/// The day being accumulated
+#[derive(Serialize, Deserialize)]
+struct Day {
+    // Days since 1970-01-01
+    day: i64,
+    // 24 hours, metrics in `METRIC_NAMES` order
+    hours: Vec<[Stat; 9]>,
+    samples: u32,
+    first_ms: i64,
+    last_ms: i64,
+    gaps: Vec<(i64, i64)>,
+    gaps_dropped: u32,
+    alerts: Vec<Alert>,
+    alerts_dropped: u32,
+}
+
+impl Day {
+    fn new(day: i64, timestamp: i64) -> Self {
+        Self {
+            day,
+            hours: vec![[Stat::default(); 9]; 24],
+            samples: 0,
+            first_ms: timestamp,
+            last_ms: timestamp,
+            gaps: Vec::new(),
+            gaps_dropped: 0,
+            alerts: Vec::new(),
+            alerts_dropped: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Rendered {
+    date: String,
+    body: String,
+}
+
+/// What survives deep sleep: the day so far and reports not yet uploaded
+#[derive(Default, Serialize, Deserialize)]
+pub struct ReportState {
+    day: Option<Day>,
+    pending: VecDeque<Rendered>,
+}
+
+pub struct DailyReport {
+    format: Option<ReportFormat>,
+    identity: DeviceIdentity,
+    prefix: String,
+    state: ReportState,
+}
+
+impl DailyReport {
+    pub fn new(config: &DeviceConfig, identity: &DeviceIdentity) -> Self {
+        let format = ReportFormat::parse(&config.report).unwrap_or_else(|e| {
+            warn!("{}, no daily report", e);
+            None
+        });
+        Self {
+            format,
+            identity: identity.clone(),
+            prefix: format!("{}/{}", config.path_for(REPORTS_DIR), identity.device_id),
+            state: ReportState::default(),
+        }
+    }
+
+    /// Fold in one sample, finishing the day before if it is a new one
+    pub fn sample(&mut self, reading: &SensorReading) {
+        if self.format.is_none() || !clock().is_trusted() {
+            return;
+        }
+        let timestamp = reading.timestamp;
+        let day = timestamp.div_euclid(DAY_MS);
+        match self.state.day.as_ref().map(|current| current.day) {
+            Some(current) if day < current => return,
+            Some(current) if day > current => self.finish(),
+            _ => {}
+        }
+        let current = self
+            .state
+            .day
+            .get_or_insert_with(|| Day::new(day, timestamp));
+
+        let gap_ms = MIN_GAP_MS.max(3 * i64::from(reading.sample_interval_ms));
+        if timestamp - current.last_ms > gap_ms {
+            if current.gaps.len() < MAX_GAPS {
+                current.gaps.push((current.last_ms, timestamp));
+            } else {
+                current.gaps_dropped += 1;
+            }
+        }
+        current.last_ms = current.last_ms.max(timestamp);
+        current.samples += 1;
+        if reading.warming_up {
+            return;
+        }
+        let hour = (timestamp.rem_euclid(DAY_MS) / HOUR_MS) as usize;
+        for (stat, value) in current.hours[hour].iter_mut().zip(reading.metrics()) {
+            if value.is_finite() {
+                stat.add(value);
+            }
+        }
+    }
+
+    /// `reading` raised or cleared an alert
+    pub fn alert(&mut self, reading: &SensorReading, change: &AlertChange) {
+        let Some(current) = self.state.day.as_mut() else {
+            return;
+        };
+        if current.alerts.len() == MAX_ALERTS {
+            current.alerts_dropped += 1;
+            return;
+        }
+        current.alerts.push(Alert {
+            timestamp: reading.timestamp,
+            metric: change.metric.clone(),
+            raised: change.raised,
+            value: change.value,
+        });
+    }
+
+    /// True if finished days are waiting for S3
+    pub fn has_unwritten(&self) -> bool {
+        !self.state.pending.is_empty()
+    }
+
+    /// Upload finished days, oldest first; they stay queued on failure
+    pub fn upload(&mut self, target: &S3Target) {
+        let Some(format) = self.format else {
+            return;
+        };
+        while let Some(report) = self.state.pending.front() {
+            let key = format!("{}/{}.{}", self.prefix, report.date, format.extension());
+            if let Err(e) = put(target, &key, report.body.as_bytes(), format.content_type()) {
+                warn!("  Failed to upload the report of {}: {:?}", report.date, e);
+                return;
+            }
+            info!("  Uploaded the report of {} ({} bytes)", report.date, report.body.len());
+            self.state.pending.pop_front();
+        }
+    }
+
+    /// Take the day so far and pending reports for the next boot, `None` if
+    /// there are none
+    pub fn take_state(&mut self) -> Option<ReportState> {
+        let state = std::mem::take(&mut self.state);
+        (state.day.is_some() || !state.pending.is_empty()).then_some(state)
+    }
+
+    /// Take over what `take_state` took before the last boot
+    pub fn restore_state(&mut self, saved: ReportState) {
+        self.state = saved;
+    }
+
+    /// Render the day so far and queue it
+    fn finish(&mut self) {
+        let (Some(format), Some(day)) = (self.format, self.state.day.take()) else {
+            return;
+        };
+        let date = utc_date(day.day * DAY_MS);
+        info!("Rendering the report of {}", date);
+        let body = render(format, &self.identity, &date, &day);
+        if self.state.pending.len() == MAX_PENDING {
+            self.state.pending.pop_front();
+        }
+        self.state.pending.push_back(Rendered { date, body });
+    }
+}
+
+fn put(target: &S3Target, key: &str, body: &[u8], content_type: &str) -> Result<()> {
+    let (bucket, credentials) = target.router.route(REPORTS_DIR, target.credentials);
+    let s3 = credentials.to_rusty_s3();
+    let mut action = bucket.put_object(Some(&s3), key);
+    action.headers_mut().insert("content-type", content_type);
+    let url = action.sign(PRESIGN_EXPIRY);
+    with_retry(&UPLOAD_RETRY, "Report upload", || {
+        match send_capped(Method::Put, url.as_str(), content_type, None, body, 1024)? {
+            (200..=299, _) => Ok(()),
+            (status, body) => Err(status_error(status, &body)),
+        }
+    })
+}
+
+// ============================================================================
+// RENDERING
+// ============================================================================
+
+fn render(format: ReportFormat, identity: &DeviceIdentity, date: &str, day: &Day) -> String {
+    let mut doc = Doc::new(format);
+    let title = format!("{} - {}", identity.device_id, date);
+    doc.open(&title);
+    doc.heading(1, &title);
+    let location = if identity.location.is_empty() {
+        "-"
+    } else {
+        identity.location.as_str()
+    };
+    doc.paragraph(&format!(
+        "Location {}, firmware {}. {} samples from {} to {} UTC.",
+        location,
+        identity.firmware_version,
+        day.samples,
+        time_of_day(day.first_ms),
+        time_of_day(day.last_ms)
+    ));
+
+    // Metrics the device never measured are left out
+    let totals: Vec<(usize, Stat)> = (0..METRIC_NAMES.len())
+        .map(|i| {
+            let mut total = Stat::default();
+            for hour in &day.hours {
+                total.merge(&hour[i]);
+            }
+            (i, total)
+        })
+        .filter(|(_, total)| total.count > 0)
+        .collect();
+
+    doc.heading(2, "Summary");
+    let rows: Vec<Vec<String>> = totals
+        .iter()
+        .map(|(i, total)| {
+            vec![
+                METRIC_NAMES[*i].to_string(),
+                format!("{:.1}", total.min),
+                format!("{:.1}", total.avg()),
+                format!("{:.1}", total.max),
+                total.count.to_string(),
+            ]
+        })
+        .collect();
+    doc.table(&["Metric", "Min", "Avg", "Max", "Values"], &rows);
+
+    doc.heading(2, "By hour (UTC)");
+    for (i, _) in &totals {
+        doc.heading(3, METRIC_NAMES[*i]);
+        doc.raw(&chart(&day.hours, *i));
+    }
+
+    doc.heading(2, "Gaps");
+    let mut gaps: Vec<String> = day
+        .gaps
+        .iter()
+        .map(|(from, to)| {
+            let mins = (to - from) / 60_000;
+            format!("{} - {} ({} min)", time_of_day(*from), time_of_day(*to), mins)
+        })
+        .collect();
+    if day.gaps_dropped > 0 {
+        gaps.push(format!("{} more", day.gaps_dropped));
+    }
+    doc.list(&gaps);
+
+    doc.heading(2, "Alerts");
+    let mut rows: Vec<Vec<String>> = day
+        .alerts
+        .iter()
+        .map(|alert| {
+            vec![
+                time_of_day(alert.timestamp),
+                alert.metric.clone(),
+                if alert.raised { "raised" } else { "cleared" }.to_string(),
+                format!("{:.1}", alert.value),
+            ]
+        })
+        .collect();
+    if day.alerts_dropped > 0 {
+        rows.push(vec![format!("{} more", day.alerts_dropped)]);
+    }
+    doc.table(&["Time", "Metric", "Change", "Value"], &rows);
+    doc.close()
+}
+
+/// `HH:MM` of a Unix epoch millisecond timestamp, in UTC
+fn time_of_day(epoch_millis: i64) -> String {
+    let minutes = epoch_millis.rem_euclid(DAY_MS) / 60_000;
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+/// Hourly avg of metric `index` as a line, with the min / max range as a band
+fn chart(hours: &[[Stat; 9]], index: usize) -> String {
+    let stats: Vec<Option<Stat>> = hours
+        .iter()
+        .map(|hour| Some(hour[index]).filter(|stat| stat.count > 0))
+        .collect();
+    let low = stats.iter().flatten().map(|s| s.min).fold(f32::INFINITY, f32::min);
+    let mut high = stats.iter().flatten().map(|s| s.max).fold(f32::NEG_INFINITY, f32::max);
+    if high - low < f32::EPSILON {
+        high = low + 1.0;
+    }
+    let x = |hour: usize| CHART_PAD + (hour as f32 + 0.5) * (CHART_WIDTH - 2.0 * CHART_PAD) / 24.0;
+    let y = |value: f32| {
+        CHART_HEIGHT - CHART_PAD - (value - low) / (high - low) * (CHART_HEIGHT - 2.0 * CHART_PAD)
+    };
+
+    let mut svg = String::new();
+    let _ = write!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+         viewBox=\"0 0 {w} {h}\" role=\"img\" aria-label=\"{label} by hour\">\
+         <rect width=\"{w}\" height=\"{h}\" fill=\"#f6f8fa\"/>",
+        w = CHART_WIDTH,
+        h = CHART_HEIGHT,
+        label = METRIC_NAMES[index]
+    );
+    // One band and line per run of hours with data, so gaps stay visible
+    let mut hour = 0;
+    while hour < stats.len() {
+        if stats[hour].is_none() {
+            hour += 1;
+            continue;
+        }
+        let start = hour;
+        while hour < stats.len() && stats[hour].is_some() {
+            hour += 1;
+        }
+        let run: Vec<(usize, Stat)> = (start..hour).filter_map(|h| Some((h, stats[h]?))).collect();
+        let upper: Vec<String> =
+            run.iter().map(|(h, s)| format!("{:.1},{:.1}", x(*h), y(s.max))).collect();
+        let lower: Vec<String> =
+            run.iter().rev().map(|(h, s)| format!("{:.1},{:.1}", x(*h), y(s.min))).collect();
+        let line: Vec<String> =
+            run.iter().map(|(h, s)| format!("{:.1},{:.1}", x(*h), y(s.avg()))).collect();
+        let _ = write!(
+            svg,
+            "<polygon points=\"{} {}\" fill=\"#c8dcf0\"/>",
+            upper.join(" "),
+            lower.join(" ")
+        );
+        if run.len() == 1 {
+            let (h, s) = run[0];
+            let _ = write!(
+                svg,
+                "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"2.5\" fill=\"#2f6fb3\"/>",
+                x(h),
+                y(s.avg())
+            );
+        } else {
+            let _ = write!(
+                svg,
+                "<polyline points=\"{}\" fill=\"none\" stroke=\"#2f6fb3\" stroke-width=\"2\"/>",
+                line.join(" ")
+            );
+        }
+    }
+    let _ = write!(
+        svg,
+        "<g font-family=\"sans-serif\" font-size=\"10\" fill=\"#555\">\
+         <text x=\"2\" y=\"11\">{:.1}</text><text x=\"2\" y=\"{:.0}\">{:.1}</text>\
+         <text x=\"{:.0}\" y=\"{:.0}\">00</text><text x=\"{:.0}\" y=\"{:.0}\">12</text>\
+         <text x=\"{:.0}\" y=\"{:.0}\">23</text></g></svg>",
+        high,
+        CHART_HEIGHT - 4.0,
+        low,
+        x(0) - 5.0,
+        CHART_HEIGHT - 4.0,
+        x(12) - 5.0,
+        CHART_HEIGHT - 4.0,
+        x(23) - 5.0,
+        CHART_HEIGHT - 4.0
+    );
+    svg
+}
+
+/// A document in either format
+struct Doc {
+    format: ReportFormat,
+    out: String,
+}
+
+impl Doc {
+    fn new(format: ReportFormat) -> Self {
+        Self {
+            format,
+            out: String::new(),
+        }
+    }
+
+    fn open(&mut self, title: &str) {
+        if self.format == ReportFormat::Html {
+            let _ = write!(
+                self.out,
+                "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title>\
+                 <style>body{{font-family:sans-serif;max-width:760px;margin:auto;padding:8px}}\
+                 table{{border-collapse:collapse}}th,td{{border:1px solid #ccc;\
+                 padding:2px 8px;text-align:right}}</style></head><body>\n",
+                escape(title)
+            );
+        }
+    }
+
+    fn close(mut self) -> String {
+        if self.format == ReportFormat::Html {
+            self.out.push_str("</body></html>\n");
+        }
+        self.out
+    }
+
+    fn heading(&mut self, level: usize, text: &str) {
+        let _ = match self.format {
+            ReportFormat::Html => writeln!(self.out, "<h{l}>{}</h{l}>", escape(text), l = level),
+            ReportFormat::Markdown => writeln!(self.out, "{} {}\n", "#".repeat(level), text),
+        };
+    }
+
+    fn paragraph(&mut self, text: &str) {
+        let _ = match self.format {
+            ReportFormat::Html => writeln!(self.out, "<p>{}</p>", escape(text)),
+            ReportFormat::Markdown => writeln!(self.out, "{}\n", text),
+        };
+    }
+
+    /// Inline SVG: HTML in both formats
+    fn raw(&mut self, html: &str) {
+        let _ = writeln!(self.out, "{}\n", html);
+    }
+
+    fn list(&mut self, items: &[String]) {
+        if items.is_empty() {
+            return self.paragraph("None.");
+        }
+        match self.format {
+            ReportFormat::Html => {
+                self.out.push_str("<ul>");
+                for item in items {
+                    let _ = write!(self.out, "<li>{}</li>", escape(item));
+                }
+                self.out.push_str("</ul>\n");
+            }
+            ReportFormat::Markdown => {
+                for item in items {
+                    let _ = writeln!(self.out, "- {}", item);
+                }
+                self.out.push('\n');
+            }
+        }
+    }
+
+    fn table(&mut self, headers: &[&str], rows: &[Vec<String>]) {
+        if rows.is_empty() {
+            return self.paragraph("None.");
+        }
+        match self.format {
+            ReportFormat::Html => {
+                self.out.push_str("<table><tr>");
+                for header in headers {
+                    let _ = write!(self.out, "<th>{}</th>", header);
+                }
+                self.out.push_str("</tr>");
+                for row in rows {
+                    self.out.push_str("<tr>");
+                    for cell in row {
+                        let _ = write!(self.out, "<td>{}</td>", escape(cell));
+                    }
+                    self.out.push_str("</tr>");
+                }
+                self.out.push_str("</table>\n");
+            }
+            ReportFormat::Markdown => {
+                let _ = writeln!(self.out, "| {} |", headers.join(" | "));
+                let _ = writeln!(self.out, "|{}", " --- |".repeat(headers.len()));
+                for row in rows {
+                    let _ = writeln!(self.out, "| {} |", row.join(" | "));
+                }
+                self.out.push('\n');
+            }
+        }
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
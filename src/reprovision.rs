@@ -0,0 +1,135 @@
+//! Re-provisioning after S3 has rejected the device's keys for too long
+//!
+//! Revoked or deleted keys never recover by retrying, so instead of buffering
+//! forever the uploader tracks how long every forwarding round has failed on
+//! authentication (`InvalidAccessKeyId`, `SignatureDoesNotMatch`,
+//! `AccessDenied`, 401). Once that has lasted `reprov_m` minutes, the device:
+//!
+//! 1. Notifies the operator: a POST to the `alert_url` webhook and, with the
+//!    MQTT gateway, a retained message on `esp32s3-ducklake/<device_id>/status`.
+//! 2. Saves the offline buffer and the other carried-over state like before
+//!    deep sleep.
+//! 3. Forgets its S3 keys (provisioned and rotated) and reboots into
+//!    enrollment (`enrollment.rs`), which claims a fresh bundle from the
+//!    fleet server. The next normal boot replays the buffer with the new keys.
+//!
+//! This needs `enroll_url`: without a fleet server the device only notifies,
+//! once, and keeps buffering, since falling back to the SoftAP portal would
+//! take an unattended device off the network. The start of the failures is
+//! kept in NVS (Unix epoch ms), so deep sleep and reboots don't restart the
+//! period. Any upload that gets through ends it.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{error, info, warn};
+use serde_json::json;
+
+use crate::clock::clock;
+use crate::config::DeviceConfig;
+use crate::payload::{self, PayloadEncoding, PeerEncoding};
+
+const NAMESPACE: &str = "reprov";
+
+const KEY_SINCE: &str = "since_ms";
+
+pub struct AuthWatch {
+    nvs: EspNvs<NvsDefault>,
+    // `None` with `reprov_m` = 0
+    period: Option<Duration>,
+    // Start of the auth failures, 0 while uploads authenticate
+    since_ms: i64,
+    notified: bool,
+}
+
+impl AuthWatch {
+    /// The failure period as the last boot left it
+    pub fn load(partition: EspDefaultNvsPartition, config: &DeviceConfig) -> Result<Self> {
+        let nvs: EspNvs<NvsDefault> = EspNvs::new(partition, NAMESPACE, true)?;
+        let since_ms = nvs.get_i64(KEY_SINCE)?.unwrap_or(0);
+        if since_ms > 0 {
+            warn!("S3 authentication has been failing since {}", since_ms);
+        }
+        Ok(Self {
+            nvs,
+            period: (config.reprov_mins > 0)
+                .then(|| Duration::from_secs(u64::from(config.reprov_mins) * 60)),
+            since_ms,
+            notified: false,
+        })
+    }
+
+    /// A forwarding round uploaded nothing because S3 rejected the keys
+    pub fn round_failed(&mut self) {
+        if self.since_ms > 0 || self.period.is_none() {
+            return;
+        }
+        self.since_ms = clock().now_millis();
+        warn!("S3 rejected the device's credentials, watching for revoked keys");
+        self.save();
+    }
+
+    /// A forwarding round uploaded something
+    pub fn round_succeeded(&mut self) {
+        if self.since_ms == 0 {
+            return;
+        }
+        info!("S3 accepts the device's credentials again");
+        self.since_ms = 0;
+        self.notified = false;
+        self.save();
+    }
+
+    /// Minutes of auth failures once they have outlasted `reprov_m`; only
+    /// once per boot if no enrollment can follow
+    pub fn is_due(&mut self, can_enroll: bool) -> Option<i64> {
+        let period = self.period?;
+        if self.since_ms == 0 || (self.notified && !can_enroll) {
+            return None;
+        }
+        let failing_ms = clock().now_millis() - self.since_ms;
+        if failing_ms < period.as_millis() as i64 {
+            return None;
+        }
+        self.notified = true;
+        Some(failing_ms / 60_000)
+    }
+
+    /// POST the re-provisioning notice to the `alert_url` webhook
+    pub fn notify(&self, config: &DeviceConfig, message: &serde_json::Value) {
+        if config.alert_url.is_empty() {
+            return;
+        }
+        // Alerts::parse has already rejected unknown encodings
+        let mut encoding = PeerEncoding::from_name(&config.alert_encoding)
+            .unwrap_or_else(|| PayloadEncoding::Json.into());
+        match payload::post(&config.alert_url, message, &mut encoding) {
+            Ok(()) => info!("Re-provisioning notice sent to the webhook"),
+            Err(e) => warn!("Re-provisioning webhook failed: {:?}", e),
+        }
+    }
+
+    /// The period starts over with the fresh keys
+    pub fn reset(&mut self) {
+        self.since_ms = 0;
+        self.save();
+    }
+
+    fn save(&mut self) {
+        if let Err(e) = self.nvs.set_i64(KEY_SINCE, self.since_ms) {
+            error!("Failed to save the auth failure period: {:?}", e);
+        }
+    }
+}
+
+/// Operator notice for the webhook and MQTT
+pub fn notice(device_id: &str, failing_mins: i64, enrolling: bool) -> serde_json::Value {
+    json!({
+        "device_id": device_id,
+        "event": "reprovisioning",
+        "failing_mins": failing_mins,
+        "action": if enrolling { "enrollment" } else { "none, no enroll_url" },
+        "timestamp": clock().now_millis(),
+    })
+}
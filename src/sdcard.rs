@@ -0,0 +1,100 @@
+//! SD card (SPI) mounted as FAT at `/sdcard`
+//!
+//! Wiring for the ESP32-S3 DevKitC: SCLK GPIO12, MOSI GPIO11, MISO GPIO13,
+//! CS GPIO10. On the ESP32-C6 DevKitC: SCLK GPIO21, MOSI GPIO19, MISO GPIO20,
+//! CS GPIO18 (see `board.rs`).
+//!
+//! While the pipeline is paused (see `pause.rs`) the card is unmounted, its
+//! FAT flushed and the SPI bus let go, so it can be pulled or handed over,
+//! and mounted again on resume.
+
+use anyhow::Result;
+use esp_idf_svc::fs::fatfs::Fatfs;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::hal::sd::spi::SdSpiHostDriver;
+use esp_idf_svc::hal::sd::{SdCardConfiguration, SdCardDriver};
+use esp_idf_svc::hal::spi::config::DriverConfig;
+use esp_idf_svc::hal::spi::{Dma, SpiDriver, SPI2};
+use esp_idf_svc::io::vfs::MountedFatfs;
+use log::{info, warn};
+
+use crate::board::{SdCs, SdMiso, SdMosi, SdSclk};
+
+pub const MOUNT_POINT: &str = "/sdcard";
+const MAX_OPEN_FILES: usize = 4;
+
+pub struct SdCardPeripherals {
+    pub spi2: SPI2,
+    pub sclk: SdSclk,
+    pub mosi: SdMosi,
+    pub miso: SdMiso,
+    pub cs: SdCs,
+}
+
+type Mounted = MountedFatfs<Fatfs<SdCardDriver<SdSpiHostDriver<'static, SpiDriver<'static>>>>>;
+
+/// The card and its bus; unmounted when dropped
+pub struct SdCard {
+    peripherals: SdCardPeripherals,
+    mounted: Option<Mounted>,
+}
+
+pub fn mount(peripherals: SdCardPeripherals) -> Result<SdCard> {
+    let mut card = SdCard {
+        peripherals,
+        mounted: None,
+    };
+    card.remount()?;
+    Ok(card)
+}
+
+impl SdCard {
+    /// Unmount the card and free the SPI bus
+    pub fn release(&mut self) {
+        if self.mounted.take().is_some() {
+            info!("SD card unmounted");
+        }
+    }
+
+    /// Mount the card again after `release`
+    pub fn remount(&mut self) -> Result<()> {
+        if self.mounted.is_some() {
+            return Ok(());
+        }
+        let pins = &mut self.peripherals;
+        // Safety: the drivers of the last mount were dropped with it, so
+        // these are the only ones on the bus and pins
+        let spi = SpiDriver::new(
+            unsafe { pins.spi2.clone_unchecked() },
+            unsafe { pins.sclk.clone_unchecked() },
+            unsafe { pins.mosi.clone_unchecked() },
+            Some(unsafe { pins.miso.clone_unchecked() }),
+            &DriverConfig::default().dma(Dma::Auto(4096)),
+        )?;
+
+        let host = SdSpiHostDriver::new(
+            spi,
+            Some(unsafe { pins.cs.clone_unchecked() }),
+            AnyIOPin::none(),
+            AnyIOPin::none(),
+            AnyIOPin::none(),
+            None,
+        )?;
+        let card = SdCardDriver::new_spi(host, &SdCardConfiguration::new())?;
+
+        let mounted =
+            MountedFatfs::mount(Fatfs::new_sdcard(0, card)?, MOUNT_POINT, MAX_OPEN_FILES)?;
+        info!("SD card mounted at {}", MOUNT_POINT);
+        self.mounted = Some(mounted);
+        Ok(())
+    }
+
+    /// `remount`, logging a failure: the watch folder and tiering then fail
+    /// until the next resume
+    pub fn resume(&mut self) {
+        if let Err(e) = self.remount() {
+            warn!("Failed to mount the SD card again: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Encrypted storage for secrets (S3 access / secret keys, device key, EAP-TLS client)
+//!
+//! Secrets live in their own NVS partition (`nvs_sec`) that is encrypted with
+//! keys from the `nvs_keys` partition, so they are neither in the firmware
+//! image nor readable from a plain flash dump. The keys partition is
+//! generated on first boot and is itself protected by flash encryption (see
+//! `partitions.csv` and `sdkconfig.defaults`).
+//!
+//! Where the secrets are kept is up to a `SecretsProvider`, selected by the
+//! `sec_src` NVS key. `nvs` is the encrypted partition above. Integrators
+//! keeping secrets elsewhere, e.g. in a secure element, implement the trait
+//! and `register` a factory under a name of their own before the first
+//! `SecretStore` is opened, without touching the modules that use secrets.
+//! Switching providers doesn't migrate the secrets already stored.
+
+use std::sync::Mutex;
+
+use anyhow::{bail, Result};
+use esp_idf_svc::nvs::{EspEncryptedNvsPartition, EspNvs, NvsEncrypted};
+use log::info;
+
+use crate::credentials::S3Credentials;
+
+const SECRETS_PARTITION: &str = "nvs_sec";
+const KEYS_PARTITION: &str = "nvs_keys";
+
+const NAMESPACE: &str = "secrets";
+
+const KEY_AWS_ACCESS_KEY: &str = "aws_ak";
+const KEY_AWS_SECRET_KEY: &str = "aws_sk";
+const KEY_DEVICE_KEY: &str = "device_key";
+const KEY_ICEBERG_CLIENT_ID: &str = "ice_id";
+const KEY_ICEBERG_CLIENT_SECRET: &str = "ice_secret";
+const KEY_EAP_CERT: &str = "eap_cert";
+const KEY_EAP_KEY: &str = "eap_key";
+
+const MAX_VALUE_LEN: usize = 128;
+// P-256 private scalar
+const DEVICE_KEY_LEN: usize = 32;
+// PEM client certificate / private key for EAP-TLS
+const MAX_EAP_PEM_LEN: usize = 4000;
+
+/// Take the encrypted NVS partition, initializing its keys on first use
+pub fn take_partition() -> Result<EspEncryptedNvsPartition> {
+    let partition = EspEncryptedNvsPartition::take(SECRETS_PARTITION, Some(KEYS_PARTITION))?;
+    info!("Encrypted NVS partition '{}' ready", SECRETS_PARTITION);
+    Ok(partition)
+}
+
+/// Storage of named secrets, strings or binary
+pub trait SecretsProvider: Send {
+    fn name(&self) -> &'static str;
+    /// The string stored under `key`, at most `max_len` bytes
+    fn get_str(&self, key: &str, max_len: usize) -> Result<Option<String>>;
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()>;
+    /// The bytes stored under `key`, at most `max_len` of them
+    fn get_blob(&self, key: &str, max_len: usize) -> Result<Option<Vec<u8>>>;
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> Result<()>;
+    fn remove(&mut self, key: &str) -> Result<()>;
+}
+
+/// Opens a provider; handed the encrypted partition, which it may ignore
+pub type SecretsFactory = fn(EspEncryptedNvsPartition) -> Result<Box<dyn SecretsProvider>>;
+
+// Providers registered by integrators, by `sec_src` name
+static FACTORIES: Mutex<Vec<(&'static str, SecretsFactory)>> = Mutex::new(Vec::new());
+
+/// Make `factory` selectable as `sec_src` = `name`
+#[allow(dead_code)] // Entry point for integrators
+pub fn register(name: &'static str, factory: SecretsFactory) {
+    if let Ok(mut factories) = FACTORIES.lock() {
+        factories.retain(|(registered, _)| *registered != name);
+        factories.push((name, factory));
+    }
+}
+
+/// Secrets in the encrypted NVS partition
+struct NvsSecrets {
+    nvs: EspNvs<NvsEncrypted>,
+}
+
+impl SecretsProvider for NvsSecrets {
+    fn name(&self) -> &'static str {
+        "nvs"
+    }
+
+    fn get_str(&self, key: &str, max_len: usize) -> Result<Option<String>> {
+        let mut buf = vec![0u8; max_len];
+        Ok(self.nvs.get_str(key, &mut buf)?.map(str::to_string))
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> Result<()> {
+        self.nvs.set_str(key, value)?;
+        Ok(())
+    }
+
+    fn get_blob(&self, key: &str, max_len: usize) -> Result<Option<Vec<u8>>> {
+        let mut buf = vec![0u8; max_len];
+        Ok(self.nvs.get_blob(key, &mut buf)?.map(<[u8]>::to_vec))
+    }
+
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        self.nvs.set_blob(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        self.nvs.remove(key)?;
+        Ok(())
+    }
+}
+
+pub struct SecretStore {
+    provider: Box<dyn SecretsProvider>,
+}
+
+impl SecretStore {
+    /// Open the store of the `sec_src` provider `source`
+    pub fn open(partition: EspEncryptedNvsPartition, source: &str) -> Result<Self> {
+        let provider: Box<dyn SecretsProvider> = match source {
+            "nvs" => Box::new(NvsSecrets {
+                nvs: EspNvs::new(partition, NAMESPACE, true)?,
+            }),
+            other => {
+                let factory = FACTORIES
+                    .lock()
+                    .ok()
+                    .and_then(|f| f.iter().find(|(name, _)| *name == other).map(|e| e.1));
+                match factory {
+                    Some(factory) => factory(partition)?,
+                    None => bail!("unknown secrets provider '{}'", other),
+                }
+            }
+        };
+        Ok(Self { provider })
+    }
+
+    /// Name of the provider in use, for logging
+    pub fn provider(&self) -> &'static str {
+        self.provider.name()
+    }
+
+    /// Provisioned S3 credentials, `None` until they have been written
+    pub fn s3_credentials(&self) -> Result<Option<S3Credentials>> {
+        self.read_pair(KEY_AWS_ACCESS_KEY, KEY_AWS_SECRET_KEY)
+    }
+
+    pub fn set_s3_credentials(&mut self, credentials: &S3Credentials) -> Result<()> {
+        self.write_pair(KEY_AWS_ACCESS_KEY, KEY_AWS_SECRET_KEY, credentials)
+    }
+
+    /// Forget the provisioned S3 credentials, see `reprovision.rs`
+    pub fn clear_s3_credentials(&mut self) -> Result<()> {
+        self.provider.remove(KEY_AWS_ACCESS_KEY)?;
+        self.provider.remove(KEY_AWS_SECRET_KEY)
+    }
+
+    /// Private key used to sign enrollment claims (see `enrollment.rs`)
+    pub fn device_key(&self) -> Result<Option<Vec<u8>>> {
+        self.provider.get_blob(KEY_DEVICE_KEY, DEVICE_KEY_LEN)
+    }
+
+    pub fn set_device_key(&mut self, key: &[u8]) -> Result<()> {
+        self.provider.set_blob(KEY_DEVICE_KEY, key)
+    }
+
+    /// Credentials of a named storage profile (see `profiles.rs`)
+    pub fn profile_credentials(&self, profile: &str) -> Result<Option<S3Credentials>> {
+        self.read_pair(&profile_key(profile, "ak"), &profile_key(profile, "sk"))
+    }
+
+    #[allow(dead_code)] // Entry point for the config channel
+    pub fn set_profile_credentials(
+        &mut self,
+        profile: &str,
+        credentials: &S3Credentials,
+    ) -> Result<()> {
+        self.write_pair(&profile_key(profile, "ak"), &profile_key(profile, "sk"), credentials)
+    }
+
+    /// OAuth2 client ID / secret for the Iceberg REST catalog (see `lake/iceberg.rs`)
+    #[allow(dead_code)] // Only read with the `iceberg` feature
+    pub fn iceberg_client(&self) -> Result<Option<S3Credentials>> {
+        self.read_pair(KEY_ICEBERG_CLIENT_ID, KEY_ICEBERG_CLIENT_SECRET)
+    }
+
+    #[allow(dead_code)] // Entry point for the config channel
+    pub fn set_iceberg_client(&mut self, client: &S3Credentials) -> Result<()> {
+        self.write_pair(KEY_ICEBERG_CLIENT_ID, KEY_ICEBERG_CLIENT_SECRET, client)
+    }
+
+    /// PEM client certificate and key for WPA2-Enterprise EAP-TLS (see `wifi.rs`)
+    pub fn eap_client(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let cert = self.provider.get_blob(KEY_EAP_CERT, MAX_EAP_PEM_LEN)?;
+        let key = self.provider.get_blob(KEY_EAP_KEY, MAX_EAP_PEM_LEN)?;
+
+        Ok(match (cert, key) {
+            (Some(cert), Some(key)) if !cert.is_empty() && !key.is_empty() => Some((cert, key)),
+            _ => None,
+        })
+    }
+
+    #[allow(dead_code)] // Entry point for the config channel
+    pub fn set_eap_client(&mut self, cert: &[u8], key: &[u8]) -> Result<()> {
+        self.provider.set_blob(KEY_EAP_CERT, cert)?;
+        self.provider.set_blob(KEY_EAP_KEY, key)
+    }
+
+    fn read_pair(&self, ak_key: &str, sk_key: &str) -> Result<Option<S3Credentials>> {
+        let access_key = self.provider.get_str(ak_key, MAX_VALUE_LEN)?;
+        let secret_key = self.provider.get_str(sk_key, MAX_VALUE_LEN)?;
+
+        Ok(match (access_key, secret_key) {
+            (Some(ak), Some(sk)) if !ak.is_empty() && !sk.is_empty() => {
+                Some(S3Credentials::new(&ak, &sk))
+            }
+            _ => None,
+        })
+    }
+
+    fn write_pair(&mut self, ak_key: &str, sk_key: &str, credentials: &S3Credentials) -> Result<()> {
+        self.provider.set_str(ak_key, &credentials.access_key)?;
+        self.provider.set_str(sk_key, &credentials.secret_key)
+    }
+}
+
+// Profile names are limited to 11 characters by the 15-character NVS key limit
+fn profile_key(profile: &str, field: &str) -> String {
+    format!("p{}_{}", profile, field)
+}
@@ -0,0 +1,71 @@
+//! Adaptive sampling interval based on signal variability
+//!
+//! After every reading the controller compares it with the previous one. A
+//! large relative change on any tracked metric (e.g. a PM spike) drops the
+//! interval straight to the minimum; several stable readings in a row let it
+//! back off gradually towards the maximum.
+
+use std::time::Duration;
+
+use super::SensorReading;
+
+// Relative change that counts as "rapid" / "stable"
+const SPIKE_THRESHOLD: f32 = 0.10;
+const STABLE_THRESHOLD: f32 = 0.01;
+// Stable readings required before backing off
+const STABLE_READINGS: u32 = 6;
+const BACKOFF_FACTOR: f32 = 1.5;
+
+pub struct AdaptiveInterval {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+    stable_count: u32,
+    previous: Option<SensorReading>,
+}
+
+impl AdaptiveInterval {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            current: max,
+            stable_count: 0,
+            previous: None,
+        }
+    }
+
+    /// Feed the latest reading and get the interval to wait before the next one
+    pub fn update(&mut self, reading: &SensorReading) -> Duration {
+        if let Some(previous) = &self.previous {
+            let change = max_relative_change(previous, reading);
+
+            if change >= SPIKE_THRESHOLD {
+                self.current = self.min;
+                self.stable_count = 0;
+            } else if change <= STABLE_THRESHOLD {
+                self.stable_count += 1;
+                if self.stable_count >= STABLE_READINGS {
+                    self.current = self.current.mul_f32(BACKOFF_FACTOR).min(self.max);
+                    self.stable_count = 0;
+                }
+            } else {
+                self.stable_count = 0;
+            }
+        }
+
+        self.previous = Some(reading.clone());
+        self.current
+    }
+}
+
+/// Largest relative change across the measured (non-NaN) metrics
+fn max_relative_change(a: &SensorReading, b: &SensorReading) -> f32 {
+    a.metrics()
+        .iter()
+        .zip(b.metrics().iter())
+        .filter(|(x, y)| x.is_finite() && y.is_finite())
+        // Floor the denominator so near-zero values (clean air PM) don't look like spikes
+        .map(|(x, y)| (y - x).abs() / x.abs().max(1.0))
+        .fold(0.0, f32::max)
+}
@@ -0,0 +1,173 @@
+//! Bosch BME280 temperature / humidity / pressure sensor (I2C, forced mode)
+//!
+//! Compensation uses the floating-point formulas from the BME280 datasheet
+//! (section 8.1).
+
+use anyhow::{bail, Result};
+use esp_idf_svc::hal::delay::{FreeRtos, BLOCK};
+use esp_idf_svc::hal::i2c::I2cDriver;
+
+use super::{SensorReading, SensorSource};
+
+const ADDRESS: u8 = 0x76;
+const CHIP_ID: u8 = 0x60;
+
+const REG_CHIP_ID: u8 = 0xD0;
+const REG_RESET: u8 = 0xE0;
+const REG_CALIB_TP: u8 = 0x88;
+const REG_CALIB_H1: u8 = 0xA1;
+const REG_CALIB_H2: u8 = 0xE1;
+const REG_CTRL_HUM: u8 = 0xF2;
+const REG_STATUS: u8 = 0xF3;
+const REG_CTRL_MEAS: u8 = 0xF4;
+const REG_DATA: u8 = 0xF7;
+
+// Oversampling x1 for all channels, forced mode
+const CTRL_HUM_X1: u8 = 0x01;
+const CTRL_MEAS_FORCED_X1: u8 = 0b001_001_01;
+const STATUS_MEASURING: u8 = 0x08;
+
+struct Calibration {
+    t1: f64,
+    t2: f64,
+    t3: f64,
+    p: [f64; 9],
+    h1: f64,
+    h2: f64,
+    h3: f64,
+    h4: f64,
+    h5: f64,
+    h6: f64,
+}
+
+pub struct Bme280 {
+    i2c: I2cDriver<'static>,
+    calib: Calibration,
+}
+
+impl Bme280 {
+    pub fn new(mut i2c: I2cDriver<'static>) -> Result<Self> {
+        let mut id = [0u8; 1];
+        i2c.write_read(ADDRESS, &[REG_CHIP_ID], &mut id, BLOCK)?;
+        if id[0] != CHIP_ID {
+            bail!("BME280 not found at 0x{:02x} (chip id 0x{:02x})", ADDRESS, id[0]);
+        }
+
+        // Soft reset, then wait for the NVM calibration copy to finish
+        i2c.write(ADDRESS, &[REG_RESET, 0xB6], BLOCK)?;
+        FreeRtos::delay_ms(10);
+
+        let calib = read_calibration(&mut i2c)?;
+        Ok(Self { i2c, calib })
+    }
+}
+
+impl SensorSource for Bme280 {
+    fn name(&self) -> &'static str {
+        "bme280"
+    }
+
+    fn read(&mut self, reading: &mut SensorReading) -> Result<()> {
+        // ctrl_hum only takes effect after a write to ctrl_meas
+        self.i2c.write(ADDRESS, &[REG_CTRL_HUM, CTRL_HUM_X1], BLOCK)?;
+        self.i2c.write(ADDRESS, &[REG_CTRL_MEAS, CTRL_MEAS_FORCED_X1], BLOCK)?;
+
+        // Max conversion time at x1 oversampling is ~10ms
+        let mut status = [STATUS_MEASURING];
+        for _ in 0..10 {
+            FreeRtos::delay_ms(5);
+            self.i2c.write_read(ADDRESS, &[REG_STATUS], &mut status, BLOCK)?;
+            if status[0] & STATUS_MEASURING == 0 {
+                break;
+            }
+        }
+        if status[0] & STATUS_MEASURING != 0 {
+            bail!("BME280 measurement timed out");
+        }
+
+        let mut d = [0u8; 8];
+        self.i2c.write_read(ADDRESS, &[REG_DATA], &mut d, BLOCK)?;
+
+        let adc_p = ((d[0] as u32) << 12) | ((d[1] as u32) << 4) | ((d[2] as u32) >> 4);
+        let adc_t = ((d[3] as u32) << 12) | ((d[4] as u32) << 4) | ((d[5] as u32) >> 4);
+        let adc_h = ((d[6] as u32) << 8) | d[7] as u32;
+
+        let (temperature, t_fine) = self.calib.temperature(adc_t as f64);
+        reading.temperature = temperature as f32;
+        reading.pressure = (self.calib.pressure(adc_p as f64, t_fine) / 100.0) as f32; // hPa
+        reading.humidity = self.calib.humidity(adc_h as f64, t_fine) as f32;
+
+        Ok(())
+    }
+}
+
+fn read_calibration(i2c: &mut I2cDriver<'static>) -> Result<Calibration> {
+    let mut tp = [0u8; 24];
+    let mut h1 = [0u8; 1];
+    let mut h = [0u8; 7];
+    i2c.write_read(ADDRESS, &[REG_CALIB_TP], &mut tp, BLOCK)?;
+    i2c.write_read(ADDRESS, &[REG_CALIB_H1], &mut h1, BLOCK)?;
+    i2c.write_read(ADDRESS, &[REG_CALIB_H2], &mut h, BLOCK)?;
+
+    let u16_at = |i: usize| u16::from_le_bytes([tp[i], tp[i + 1]]) as f64;
+    let i16_at = |i: usize| i16::from_le_bytes([tp[i], tp[i + 1]]) as f64;
+
+    let mut p = [0f64; 9];
+    p[0] = u16_at(6);
+    for (n, value) in p.iter_mut().enumerate().skip(1) {
+        *value = i16_at(6 + n * 2);
+    }
+
+    Ok(Calibration {
+        t1: u16_at(0),
+        t2: i16_at(2),
+        t3: i16_at(4),
+        p,
+        h1: h1[0] as f64,
+        h2: i16::from_le_bytes([h[0], h[1]]) as f64,
+        h3: h[2] as f64,
+        h4: (((h[3] as i8 as i16) << 4) | (h[4] & 0x0F) as i16) as f64,
+        h5: (((h[5] as i8 as i16) << 4) | (h[4] >> 4) as i16) as f64,
+        h6: h[6] as i8 as f64,
+    })
+}
+
+impl Calibration {
+    /// Temperature in °C plus the `t_fine` carry-over used by the other channels
+    fn temperature(&self, adc_t: f64) -> (f64, f64) {
+        let var1 = (adc_t / 16384.0 - self.t1 / 1024.0) * self.t2;
+        let var2 = (adc_t / 131072.0 - self.t1 / 8192.0).powi(2) * self.t3;
+        let t_fine = var1 + var2;
+        (t_fine / 5120.0, t_fine)
+    }
+
+    /// Pressure in Pa
+    fn pressure(&self, adc_p: f64, t_fine: f64) -> f64 {
+        let p = &self.p;
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * p[5] / 32768.0;
+        var2 += var1 * p[4] * 2.0;
+        var2 = var2 / 4.0 + p[3] * 65536.0;
+        var1 = (p[2] * var1 * var1 / 524288.0 + p[1] * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * p[0];
+        if var1 == 0.0 {
+            return 0.0; // Avoid division by zero
+        }
+
+        let mut pressure = 1048576.0 - adc_p;
+        pressure = (pressure - var2 / 4096.0) * 6250.0 / var1;
+        var1 = p[8] * pressure * pressure / 2147483648.0;
+        var2 = pressure * p[7] / 32768.0;
+        pressure + (var1 + var2 + p[6]) / 16.0
+    }
+
+    /// Relative humidity in %
+    fn humidity(&self, adc_h: f64, t_fine: f64) -> f64 {
+        let mut h = t_fine - 76800.0;
+        h = (adc_h - (self.h4 * 64.0 + self.h5 / 16384.0 * h))
+            * (self.h2 / 65536.0
+                * (1.0 + self.h6 / 67108864.0 * h * (1.0 + self.h3 / 67108864.0 * h)));
+        h *= 1.0 - self.h1 * h / 524288.0;
+        h.clamp(0.0, 100.0)
+    }
+}
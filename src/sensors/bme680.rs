@@ -0,0 +1,266 @@
+//! Bosch BME680 temperature / humidity / pressure / gas sensor (I2C, forced mode)
+//!
+//! Compensation uses the floating-point formulas from the Bosch BME680 API.
+//! The gas heater is driven to 320°C for 150ms on every reading. Gas
+//! resistance drifts for several minutes after power-up until the sensor
+//! surface settles.
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use esp_idf_svc::hal::delay::{FreeRtos, BLOCK};
+use esp_idf_svc::hal::i2c::I2cDriver;
+
+use super::{SensorReading, SensorSource};
+
+const ADDRESS: u8 = 0x77;
+const CHIP_ID: u8 = 0x61;
+
+const REG_CHIP_ID: u8 = 0xD0;
+const REG_RESET: u8 = 0xE0;
+const REG_COEFF1: u8 = 0x8A; // 23 bytes
+const REG_COEFF2: u8 = 0xE1; // 14 bytes
+const REG_COEFF3: u8 = 0x00; // 5 bytes
+const REG_CTRL_GAS_1: u8 = 0x71;
+const REG_CTRL_HUM: u8 = 0x72;
+const REG_CTRL_MEAS: u8 = 0x74;
+const REG_RES_HEAT_0: u8 = 0x5A;
+const REG_GAS_WAIT_0: u8 = 0x64;
+const REG_FIELD_0: u8 = 0x1D; // status + 14 data bytes
+
+const CTRL_HUM_X1: u8 = 0x01;
+const CTRL_MEAS_FORCED_X1: u8 = 0b001_001_01;
+const RUN_GAS: u8 = 0x10;
+const NEW_DATA: u8 = 0x80;
+const GAS_VALID: u8 = 0x20;
+const HEAT_STAB: u8 = 0x10;
+
+const HEATER_TEMP_C: f64 = 320.0;
+const HEATER_DURATION_MS: u16 = 150;
+const WARM_UP: Duration = Duration::from_secs(5 * 60);
+
+const GAS_RANGE_K1: [f64; 16] = [
+    0.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, -0.8, 0.0, 0.0, -0.2, -0.5, 0.0, -1.0, 0.0, 0.0,
+];
+const GAS_RANGE_K2: [f64; 16] = [
+    0.0, 0.0, 0.0, 0.0, 0.1, 0.7, 0.0, -0.8, -0.1, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+];
+
+struct Calibration {
+    t: [f64; 3],
+    p: [f64; 10],
+    h: [f64; 7],
+    g: [f64; 3],
+    res_heat_range: f64,
+    res_heat_val: f64,
+    range_sw_err: f64,
+}
+
+pub struct Bme680 {
+    i2c: I2cDriver<'static>,
+    calib: Calibration,
+    // Last temperature, used as ambient for the heater set-point
+    ambient_c: f64,
+}
+
+impl Bme680 {
+    pub fn new(mut i2c: I2cDriver<'static>) -> Result<Self> {
+        let mut id = [0u8; 1];
+        i2c.write_read(ADDRESS, &[REG_CHIP_ID], &mut id, BLOCK)?;
+        if id[0] != CHIP_ID {
+            bail!("BME680 not found at 0x{:02x} (chip id 0x{:02x})", ADDRESS, id[0]);
+        }
+
+        i2c.write(ADDRESS, &[REG_RESET, 0xB6], BLOCK)?;
+        FreeRtos::delay_ms(10);
+
+        let calib = read_calibration(&mut i2c)?;
+        Ok(Self {
+            i2c,
+            calib,
+            ambient_c: 25.0,
+        })
+    }
+
+    fn write_reg(&mut self, reg: u8, value: u8) -> Result<()> {
+        self.i2c.write(ADDRESS, &[reg, value], BLOCK)?;
+        Ok(())
+    }
+}
+
+impl SensorSource for Bme680 {
+    fn name(&self) -> &'static str {
+        "bme680"
+    }
+
+    fn warm_up(&self) -> Duration {
+        WARM_UP
+    }
+
+    fn read(&mut self, reading: &mut SensorReading) -> Result<()> {
+        let res_heat = self.calib.heater_resistance(HEATER_TEMP_C, self.ambient_c);
+        self.write_reg(REG_RES_HEAT_0, res_heat)?;
+        self.write_reg(REG_GAS_WAIT_0, gas_wait_code(HEATER_DURATION_MS))?;
+        self.write_reg(REG_CTRL_GAS_1, RUN_GAS)?; // heater profile 0
+        self.write_reg(REG_CTRL_HUM, CTRL_HUM_X1)?;
+        self.write_reg(REG_CTRL_MEAS, CTRL_MEAS_FORCED_X1)?;
+
+        // TPH conversion plus heater duration
+        FreeRtos::delay_ms(HEATER_DURATION_MS as u32 + 20);
+
+        let mut d = [0u8; 15];
+        for attempt in 0..10 {
+            self.i2c.write_read(ADDRESS, &[REG_FIELD_0], &mut d, BLOCK)?;
+            if d[0] & NEW_DATA != 0 {
+                break;
+            }
+            if attempt == 9 {
+                bail!("BME680 measurement timed out");
+            }
+            FreeRtos::delay_ms(10);
+        }
+
+        let adc_p = ((d[2] as u32) << 12) | ((d[3] as u32) << 4) | ((d[4] as u32) >> 4);
+        let adc_t = ((d[5] as u32) << 12) | ((d[6] as u32) << 4) | ((d[7] as u32) >> 4);
+        let adc_h = ((d[8] as u32) << 8) | d[9] as u32;
+        let adc_g = ((d[13] as u32) << 2) | ((d[14] as u32) >> 6);
+        let gas_range = (d[14] & 0x0F) as usize;
+
+        let t_fine = self.calib.t_fine(adc_t as f64);
+        let temperature = t_fine / 5120.0;
+        self.ambient_c = temperature;
+
+        reading.temperature = temperature as f32;
+        reading.pressure = (self.calib.pressure(adc_p as f64, t_fine) / 100.0) as f32; // hPa
+        reading.humidity = self.calib.humidity(adc_h as f64, temperature) as f32;
+
+        // Gas readings are only meaningful once the heater reached its set-point
+        if d[14] & GAS_VALID != 0 && d[14] & HEAT_STAB != 0 {
+            reading.gas_resistance = self.calib.gas_resistance(adc_g as f64, gas_range) as f32;
+        }
+
+        Ok(())
+    }
+}
+
+fn read_calibration(i2c: &mut I2cDriver<'static>) -> Result<Calibration> {
+    // Read all three coefficient blocks into one image indexed by register
+    let mut regs = [0u8; 256];
+    i2c.write_read(ADDRESS, &[REG_COEFF1], &mut regs[0x8A..0x8A + 23], BLOCK)?;
+    i2c.write_read(ADDRESS, &[REG_COEFF2], &mut regs[0xE1..0xE1 + 14], BLOCK)?;
+    i2c.write_read(ADDRESS, &[REG_COEFF3], &mut regs[0x00..0x05], BLOCK)?;
+
+    let u16_at = |lsb: usize| u16::from_le_bytes([regs[lsb], regs[lsb + 1]]) as f64;
+    let i16_at = |lsb: usize| i16::from_le_bytes([regs[lsb], regs[lsb + 1]]) as f64;
+    let i8_at = |r: usize| regs[r] as i8 as f64;
+    let u8_at = |r: usize| regs[r] as f64;
+
+    Ok(Calibration {
+        t: [u16_at(0xE9), i16_at(0x8A), i8_at(0x8C)],
+        p: [
+            u16_at(0x8E),
+            i16_at(0x90),
+            i8_at(0x92),
+            i16_at(0x94),
+            i16_at(0x96),
+            i8_at(0x99),
+            i8_at(0x98),
+            i16_at(0x9C),
+            i16_at(0x9E),
+            u8_at(0xA0),
+        ],
+        h: [
+            (((regs[0xE3] as u16) << 4) | (regs[0xE2] & 0x0F) as u16) as f64,
+            (((regs[0xE1] as u16) << 4) | (regs[0xE2] >> 4) as u16) as f64,
+            i8_at(0xE4),
+            i8_at(0xE5),
+            i8_at(0xE6),
+            u8_at(0xE7),
+            i8_at(0xE8),
+        ],
+        g: [i8_at(0xED), i16_at(0xEB), i8_at(0xEE)],
+        res_heat_range: ((regs[0x02] & 0x30) >> 4) as f64,
+        res_heat_val: i8_at(0x00),
+        range_sw_err: ((regs[0x04] as i8) >> 4) as f64,
+    })
+}
+
+impl Calibration {
+    fn t_fine(&self, adc_t: f64) -> f64 {
+        let [t1, t2, t3] = self.t;
+        let var1 = (adc_t / 16384.0 - t1 / 1024.0) * t2;
+        let var2 = (adc_t / 131072.0 - t1 / 8192.0).powi(2) * (t3 * 16.0);
+        var1 + var2
+    }
+
+    /// Pressure in Pa
+    fn pressure(&self, adc_p: f64, t_fine: f64) -> f64 {
+        let p = &self.p;
+        let mut var1 = t_fine / 2.0 - 64000.0;
+        let mut var2 = var1 * var1 * (p[5] / 131072.0);
+        var2 += var1 * p[4] * 2.0;
+        var2 = var2 / 4.0 + p[3] * 65536.0;
+        var1 = ((p[2] * var1 * var1) / 16384.0 + p[1] * var1) / 524288.0;
+        var1 = (1.0 + var1 / 32768.0) * p[0];
+        if var1 == 0.0 {
+            return 0.0;
+        }
+
+        let mut pressure = 1048576.0 - adc_p;
+        pressure = ((pressure - var2 / 4096.0) * 6250.0) / var1;
+        var1 = (p[8] * pressure * pressure) / 2147483648.0;
+        var2 = pressure * (p[7] / 32768.0);
+        let var3 = (pressure / 256.0).powi(3) * (p[9] / 131072.0);
+        pressure + (var1 + var2 + var3 + p[6] * 128.0) / 16.0
+    }
+
+    /// Relative humidity in %
+    fn humidity(&self, adc_h: f64, temperature: f64) -> f64 {
+        let h = &self.h;
+        let var1 = adc_h - (h[0] * 16.0 + (h[2] / 2.0) * temperature);
+        let var2 = var1
+            * ((h[1] / 262144.0)
+                * (1.0 + (h[3] / 16384.0) * temperature + (h[4] / 1048576.0) * temperature.powi(2)));
+        let var3 = h[5] / 16384.0;
+        let var4 = h[6] / 2097152.0;
+        (var2 + (var3 + var4 * temperature) * var2 * var2).clamp(0.0, 100.0)
+    }
+
+    /// Gas resistance in Ohm
+    fn gas_resistance(&self, adc_g: f64, gas_range: usize) -> f64 {
+        let var1 = 1340.0 + 5.0 * self.range_sw_err;
+        let var2 = var1 * (1.0 + GAS_RANGE_K1[gas_range] / 100.0);
+        let var3 = 1.0 + GAS_RANGE_K2[gas_range] / 100.0;
+        1.0 / (var3 * 0.000000125 * (1u32 << gas_range) as f64 * ((adc_g - 512.0) / var2 + 1.0))
+    }
+
+    /// Heater resistance register value for `target_c` at `ambient_c`
+    fn heater_resistance(&self, target_c: f64, ambient_c: f64) -> u8 {
+        let [g1, g2, g3] = self.g;
+        let var1 = g1 / 16.0 + 49.0;
+        let var2 = (g2 / 32768.0) * 0.0005 + 0.00235;
+        let var3 = g3 / 1024.0;
+        let var4 = var1 * (1.0 + var2 * target_c);
+        let var5 = var4 + var3 * ambient_c;
+        let res_heat = 3.4
+            * (var5
+                * (4.0 / (4.0 + self.res_heat_range))
+                * (1.0 / (1.0 + self.res_heat_val * 0.002))
+                - 25.0);
+        res_heat.clamp(0.0, 255.0) as u8
+    }
+}
+
+/// Encode a heater duration as the 6-bit mantissa / 2-bit multiplier register format
+fn gas_wait_code(mut duration_ms: u16) -> u8 {
+    if duration_ms >= 0xFC0 {
+        return 0xFF;
+    }
+
+    let mut factor = 0u8;
+    while duration_ms > 0x3F {
+        duration_ms /= 4;
+        factor += 1;
+    }
+    duration_ms as u8 + factor * 64
+}
@@ -0,0 +1,97 @@
+//! Per-metric deadband (change-of-value) suppression
+//!
+//! A reading is recorded only if at least one metric moved by more than its
+//! deadband since the last *recorded* reading, or if the heartbeat interval
+//! has elapsed, so slowly varying signals still show up periodically.
+//! Metrics without a deadband never trigger a record on their own.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::info;
+
+use super::{SensorReading, METRIC_NAMES};
+
+pub struct Deadband {
+    thresholds: [Option<f32>; 9],
+    heartbeat_ms: i64,
+    last_recorded: Option<SensorReading>,
+    suppressed: u64,
+}
+
+impl Deadband {
+    /// Parse a spec like `temperature=0.2,humidity=1,pm2_5=0.5`
+    pub fn parse(spec: &str, heartbeat: Duration) -> Result<Self> {
+        let mut thresholds = [None; 9];
+
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, value) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("deadband entry '{}' is not metric=threshold", entry))?;
+            let index = METRIC_NAMES
+                .iter()
+                .position(|m| *m == name.trim())
+                .ok_or_else(|| anyhow!("unknown deadband metric '{}'", name))?;
+            let threshold: f32 = value.trim().parse()?;
+            thresholds[index] = Some(threshold.abs());
+        }
+
+        Ok(Self {
+            thresholds,
+            heartbeat_ms: heartbeat.as_millis() as i64,
+            last_recorded: None,
+            suppressed: 0,
+        })
+    }
+
+    /// True if any metric has a deadband, i.e. suppression is active
+    pub fn is_active(&self) -> bool {
+        self.thresholds.iter().any(Option::is_some)
+    }
+
+    /// Configured deadbands as `metric=threshold` pairs, for logging
+    pub fn describe(&self) -> String {
+        METRIC_NAMES
+            .iter()
+            .zip(self.thresholds.iter())
+            .filter_map(|(name, t)| t.map(|t| format!("{}={}", name, t)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn should_record(&mut self, reading: &SensorReading) -> bool {
+        let record = match &self.last_recorded {
+            None => true,
+            Some(last) => {
+                reading.timestamp - last.timestamp >= self.heartbeat_ms
+                    || self.exceeds(last, reading)
+            }
+        };
+
+        if record {
+            self.last_recorded = Some(reading.clone());
+        } else {
+            self.suppressed += 1;
+            if self.suppressed % 100 == 0 {
+                info!("Deadband suppressed {} readings so far", self.suppressed);
+            }
+        }
+
+        record
+    }
+
+    fn exceeds(&self, last: &SensorReading, reading: &SensorReading) -> bool {
+        let (previous, current) = (last.metrics(), reading.metrics());
+
+        self.thresholds.iter().enumerate().any(|(i, threshold)| {
+            let Some(threshold) = threshold else {
+                return false;
+            };
+            match (previous[i].is_finite(), current[i].is_finite()) {
+                (true, true) => (current[i] - previous[i]).abs() > *threshold,
+                // A metric appearing or disappearing (sensor fault/recovery) is a change
+                (a, b) => a != b,
+            }
+        })
+    }
+}
@@ -0,0 +1,246 @@
+//! Sensor drivers behind a common `SensorSource` trait
+//!
+//! Drivers are selected with Cargo features so the same pipeline can be
+//! flashed on bare dev boards (simulated data) or real opensensor.space
+//! hardware:
+//!
+//! - `bme280`  - temperature / humidity / pressure over I2C
+//! - `bme680`  - temperature / humidity / pressure / gas resistance over I2C
+//! - `pms5003` - PM1.0 / PM2.5 / PM10 over UART
+//!
+//! With no sensor feature enabled, a `SimulatedSource` generates the same
+//! synthetic data the experiment has always used. Fields that no enabled
+//! driver measures are recorded as NaN. Drivers that need time to stabilize
+//! after power-up declare a warm-up period, handled by the `Sampler` (see
+//! `warmup.rs`).
+
+mod adaptive;
+#[cfg(feature = "bme280")]
+mod bme280;
+#[cfg(feature = "bme680")]
+mod bme680;
+mod deadband;
+#[cfg(feature = "pms5003")]
+mod pms5003;
+mod simulated;
+mod task;
+#[cfg(feature = "timer-sampling")]
+mod timed;
+mod warmup;
+
+use std::time::Duration;
+
+use anyhow::Result;
+use esp_idf_svc::hal::i2c::I2C0;
+use esp_idf_svc::hal::uart::UART1;
+use log::{info, warn};
+
+use crate::board::{I2cScl, I2cSda, SensorRx, SensorTx};
+use crate::clock::clock;
+
+pub use crate::reading::{SensorReading, Source, METRIC_NAMES};
+pub use adaptive::AdaptiveInterval;
+pub use deadband::Deadband;
+use simulated::SimulatedSource;
+pub use task::{sampling_stats, SamplerTask};
+pub use warmup::WarmUpPolicy;
+use warmup::WarmUp;
+
+#[cfg(all(feature = "bme280", feature = "bme680"))]
+compile_error!("features `bme280` and `bme680` share the I2C bus pins, enable only one");
+
+#[cfg(any(feature = "bme280", feature = "bme680"))]
+const I2C_BAUDRATE_HZ: u32 = 100_000;
+#[cfg(feature = "pms5003")]
+const PMS5003_BAUDRATE: u32 = 9600;
+
+/// Peripherals reserved for sensor drivers (pin assignment per board, see `board.rs`)
+#[allow(dead_code)] // Only the fields of enabled drivers are used
+pub struct SensorPeripherals {
+    pub i2c0: I2C0,
+    pub sda: I2cSda,
+    pub scl: I2cScl,
+    pub uart1: UART1,
+    pub tx: SensorTx,
+    pub rx: SensorRx,
+}
+
+/// `Send`, so the sampler task can own the sources
+pub trait SensorSource: Send {
+    fn name(&self) -> &'static str;
+
+    /// Fill in the fields this source measures
+    fn read(&mut self, reading: &mut SensorReading) -> Result<()>;
+
+    /// Time after power-up before readings are stable
+    fn warm_up(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+/// Samples all enabled sources, one reading per sampling interval
+pub struct Sampler {
+    sources: Vec<Box<dyn SensorSource>>,
+    interval: Duration,
+    adaptive: Option<AdaptiveInterval>,
+    deadband: Option<Deadband>,
+    warm_up: WarmUp,
+    // Taken by the last `poll`, recorded or not
+    last: Option<SensorReading>,
+}
+
+impl Sampler {
+    pub fn new(
+        sources: Vec<Box<dyn SensorSource>>,
+        interval: Duration,
+        warm_up: WarmUpPolicy,
+    ) -> Self {
+        let names: Vec<&str> = sources.iter().map(|s| s.name()).collect();
+        info!("Sensor sources: {}", names.join(", "));
+        for source in sources.iter().filter(|s| !s.warm_up().is_zero()) {
+            info!(
+                "Sensor '{}' warms up for {:?} ({:?})",
+                source.name(),
+                source.warm_up(),
+                warm_up
+            );
+        }
+        let warm_up = WarmUp::new(warm_up, sources.iter().map(|s| s.warm_up()).collect());
+        Self {
+            sources,
+            interval,
+            adaptive: None,
+            deadband: None,
+            warm_up,
+            last: None,
+        }
+    }
+
+    /// Let `controller` adjust the interval between readings
+    pub fn with_adaptive(mut self, controller: AdaptiveInterval) -> Self {
+        self.adaptive = Some(controller);
+        self
+    }
+
+    /// Only record readings that leave `deadband` (or hit its heartbeat)
+    pub fn with_deadband(mut self, deadband: Deadband) -> Self {
+        self.deadband = Some(deadband);
+        self
+    }
+
+    /// Take one reading from every source.
+    ///
+    /// Returns `None` if every source is still warming up under `Suppress`.
+    fn sample(&mut self) -> Option<SensorReading> {
+        let mut reading = SensorReading::empty(clock().now_millis());
+        reading.sample_interval_ms = self.interval.as_millis() as u32;
+        let mut warming_up = false;
+        let mut all_suppressed = true;
+
+        for (index, source) in self.sources.iter_mut().enumerate() {
+            let warming = self.warm_up.is_warming_up(index, source.name());
+            let before = reading.metrics();
+            if let Err(e) = source.read(&mut reading) {
+                // A failing sensor leaves its fields as NaN rather than dropping the row
+                warn!("Sensor '{}' read failed: {:?}", source.name(), e);
+            }
+            // Warming-up sources are still read: some (the BME680 heater) only settle by running
+            if warming && self.warm_up.policy() == WarmUpPolicy::Suppress {
+                reading.set_metrics(before);
+            } else {
+                all_suppressed = false;
+            }
+            warming_up |= warming;
+        }
+
+        if all_suppressed {
+            return None;
+        }
+        reading.warming_up = warming_up && self.warm_up.policy() == WarmUpPolicy::Flag;
+        reading.stamp(Source::Sensor);
+        Some(reading)
+    }
+
+    /// Current interval between readings
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// The reading taken by the last `poll`, even if it wasn't recorded
+    pub fn last_sample(&self) -> Option<&SensorReading> {
+        self.last.as_ref()
+    }
+
+    /// Take a reading and feed the adaptive controller.
+    ///
+    /// Returns `None` if the deadband or the warm-up policy suppressed the
+    /// reading. Warming-up rows bypass both the controller and the deadband,
+    /// so settling values don't skew them.
+    pub fn poll(&mut self) -> Option<SensorReading> {
+        self.last = self.sample();
+        let reading = self.last.clone()?;
+        if reading.warming_up {
+            return Some(reading);
+        }
+
+        if let Some(controller) = self.adaptive.as_mut() {
+            let next = controller.update(&reading);
+            if next != self.interval {
+                info!("Sampling interval: {:?} -> {:?}", self.interval, next);
+                self.interval = next;
+            }
+        }
+
+        let record = self
+            .deadband
+            .as_mut()
+            .map_or(true, |deadband| deadband.should_record(&reading));
+        record.then_some(reading)
+    }
+}
+
+/// Build the sources selected by Cargo features
+#[allow(unused_variables)]
+pub fn build_sources(peripherals: SensorPeripherals) -> Result<Vec<Box<dyn SensorSource>>> {
+    #[allow(unused_mut)]
+    let mut sources: Vec<Box<dyn SensorSource>> = Vec::new();
+
+    #[cfg(any(feature = "bme280", feature = "bme680"))]
+    let i2c = {
+        use esp_idf_svc::hal::i2c::{I2cConfig, I2cDriver};
+        use esp_idf_svc::hal::units::Hertz;
+
+        let config = I2cConfig::new().baudrate(Hertz(I2C_BAUDRATE_HZ));
+        I2cDriver::new(peripherals.i2c0, peripherals.sda, peripherals.scl, &config)?
+    };
+
+    #[cfg(feature = "bme280")]
+    sources.push(Box::new(bme280::Bme280::new(i2c)?));
+
+    #[cfg(feature = "bme680")]
+    sources.push(Box::new(bme680::Bme680::new(i2c)?));
+
+    #[cfg(feature = "pms5003")]
+    {
+        use esp_idf_svc::hal::gpio::AnyIOPin;
+        use esp_idf_svc::hal::uart::{config::Config as UartConfig, UartDriver};
+        use esp_idf_svc::hal::units::Hertz;
+
+        let config = UartConfig::new().baudrate(Hertz(PMS5003_BAUDRATE));
+        let uart = UartDriver::new(
+            peripherals.uart1,
+            peripherals.tx,
+            peripherals.rx,
+            Option::<AnyIOPin>::None,
+            Option::<AnyIOPin>::None,
+            &config,
+        )?;
+        sources.push(Box::new(pms5003::Pms5003::new(uart)));
+    }
+
+    if sources.is_empty() {
+        sources.push(Box::new(SimulatedSource::new()));
+    }
+
+    Ok(sources)
+}
@@ -0,0 +1,87 @@
+//! Plantower PMS5003 particulate matter sensor (UART, active mode)
+//!
+//! In active mode the sensor streams a 32-byte frame roughly every second:
+//! `0x42 0x4D`, a 16-bit frame length (28), 13 big-endian data words and a
+//! 16-bit checksum over all preceding bytes. Readings are stable about 30 s
+//! after the fan starts.
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::uart::UartDriver;
+
+use super::{SensorReading, SensorSource};
+
+const FRAME_START: [u8; 2] = [0x42, 0x4D];
+const FRAME_LEN: usize = 32;
+const READ_TIMEOUT_MS: u64 = 2500;
+const WARM_UP: Duration = Duration::from_secs(30);
+
+pub struct Pms5003 {
+    uart: UartDriver<'static>,
+}
+
+impl Pms5003 {
+    pub fn new(uart: UartDriver<'static>) -> Self {
+        Self { uart }
+    }
+
+    /// Read bytes until a complete frame with a valid checksum is found
+    fn read_frame(&mut self) -> Result<[u8; FRAME_LEN]> {
+        // Drop stale frames so the reading is current
+        self.uart.clear_rx()?;
+
+        let timeout = TickType::new_millis(READ_TIMEOUT_MS).into();
+        let mut window = [0u8; FRAME_LEN * 2];
+        let mut filled = 0;
+
+        while filled < window.len() {
+            let n = self.uart.read(&mut window[filled..], timeout)?;
+            if n == 0 {
+                bail!("PMS5003 read timed out");
+            }
+            filled += n;
+
+            for start in 0..filled.saturating_sub(FRAME_LEN - 1) {
+                let candidate = &window[start..start + FRAME_LEN];
+                if candidate[..2] == FRAME_START && checksum_ok(candidate) {
+                    let mut frame = [0u8; FRAME_LEN];
+                    frame.copy_from_slice(candidate);
+                    return Ok(frame);
+                }
+            }
+        }
+
+        bail!("No valid PMS5003 frame found")
+    }
+}
+
+impl SensorSource for Pms5003 {
+    fn name(&self) -> &'static str {
+        "pms5003"
+    }
+
+    fn warm_up(&self) -> Duration {
+        WARM_UP
+    }
+
+    fn read(&mut self, reading: &mut SensorReading) -> Result<()> {
+        let frame = self.read_frame()?;
+        let word = |n: usize| u16::from_be_bytes([frame[4 + n * 2], frame[5 + n * 2]]) as f32;
+
+        // Words 3-5 are the atmospheric-environment concentrations (µg/m³)
+        reading.pm1_0 = word(3);
+        reading.pm2_5 = word(4);
+        reading.pm10 = word(5);
+
+        Ok(())
+    }
+}
+
+fn checksum_ok(frame: &[u8]) -> bool {
+    let sum: u16 = frame[..FRAME_LEN - 2]
+        .iter()
+        .fold(0u16, |acc, b| acc.wrapping_add(*b as u16));
+    sum == u16::from_be_bytes([frame[FRAME_LEN - 2], frame[FRAME_LEN - 1]])
+}
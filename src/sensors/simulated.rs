@@ -0,0 +1,58 @@
+//! Synthetic sensor data matching the original opensensor.space experiment
+
+use anyhow::Result;
+
+use super::{SensorReading, SensorSource};
+use crate::ROWS_PER_FILE;
+
+/// Generates 178-row windows 15 minutes apart, 5 seconds between rows
+pub struct SimulatedSource {
+    file_index: u64,
+    row: usize,
+}
+
+impl SimulatedSource {
+    pub fn new() -> Self {
+        Self {
+            file_index: 0,
+            row: 0,
+        }
+    }
+}
+
+impl SensorSource for SimulatedSource {
+    fn name(&self) -> &'static str {
+        "simulated"
+    }
+
+    fn read(&mut self, reading: &mut SensorReading) -> Result<()> {
+        let (i, file_index) = (self.row, self.file_index);
+
+        // Base timestamp (simulate different time windows per file)
+        let base_timestamp = 1733270400000i64 + (file_index as i64 * 900000); // 15 min apart
+
+        *reading = SensorReading {
+            timestamp: base_timestamp + (i as i64 * 5000), // 5 second intervals
+            temperature: 20.0 + (i as f32 * 0.02) + (file_index as f32 * 0.5),
+            humidity: 45.0 + (i as f32 * 0.05) + (file_index as f32 * 2.0),
+            pressure: 1013.25 + (i as f32 * 0.01),
+            pm1_0: 5.0 + (i as f32 % 10.0) * 0.1,
+            pm2_5: 8.0 + (i as f32 % 15.0) * 0.2,
+            pm10: 12.0 + (i as f32 % 20.0) * 0.3,
+            gas_resistance: 50000.0 + (i as f32 * 100.0),
+            light: 100.0 + (i as f32 * 2.0),
+            noise: 35.0 + (i as f32 % 10.0) * 0.5,
+            sample_interval_ms: 5000,
+            warming_up: false,
+            ..SensorReading::empty(0)
+        };
+
+        self.row += 1;
+        if self.row == ROWS_PER_FILE {
+            self.row = 0;
+            self.file_index += 1;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+//! The sampler task: sensor reads on a thread of their own
+//!
+//! The `Sampler` moves to a thread that runs at a priority above the rest of
+//! the pipeline (on the ingestion core of the S3), so batching, Parquet
+//! encoding and uploads can't delay a sample. Its cadence comes from
+//! absolute deadlines on the monotonic clock, or from a hardware timer
+//! interrupt with the `timer-sampling` feature (see `timed.rs`). Every
+//! reading is stamped with the time of its tick rather than the time its
+//! reads finished, and handed to the batcher through a lock-free
+//! single-producer / single-consumer ring.
+//!
+//! Two things can still go wrong, and both are counted:
+//!
+//! - a tick comes due while the previous one is still being sampled (sensor
+//!   reads slower than the interval): the tick is missed
+//! - the ring is full because the batcher was held up for longer than
+//!   `RING_CAPACITY` intervals: the sample is dropped
+//!
+//! The counters and the worst tick-to-sample latency are on `/health` and in
+//! `device_health`.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+#[cfg(feature = "timer-sampling")]
+use esp_idf_svc::hal::timer::TIMER00;
+use esp_idf_svc::hal::task::thread::ThreadSpawnConfiguration;
+use log::info;
+
+use super::{Sampler, SensorReading};
+use crate::board;
+use crate::clock::clock;
+
+// Samples the batcher may fall behind by
+const RING_CAPACITY: usize = 64;
+const SAMPLER_STACK_SIZE: usize = 8192;
+// Above the main task (1) and the other pipeline threads (5)
+const SAMPLER_PRIORITY: u8 = 10;
+
+pub(super) static TICKS: AtomicU32 = AtomicU32::new(0);
+pub(super) static MISSED: AtomicU32 = AtomicU32::new(0);
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+pub(super) static MAX_LATENCY_US: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy, Debug)]
+pub struct SamplingStats {
+    pub ticks: u32,
+    /// Ticks that came due while the previous one was still being sampled
+    pub missed: u32,
+    /// Samples dropped because the batcher didn't drain the ring
+    pub dropped: u32,
+    /// Worst delay between a tick and the start of its sample
+    pub max_latency_us: u32,
+}
+
+impl SamplingStats {
+    pub fn overruns(&self) -> u32 {
+        self.missed + self.dropped
+    }
+}
+
+/// Counters since boot
+pub fn sampling_stats() -> SamplingStats {
+    SamplingStats {
+        ticks: TICKS.load(Ordering::Relaxed),
+        missed: MISSED.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+        max_latency_us: MAX_LATENCY_US.load(Ordering::Relaxed),
+    }
+}
+
+/// A sample taken on a tick
+pub struct Tick {
+    pub reading: SensorReading,
+    /// False if the deadband didn't record it; alerts still see it
+    pub recorded: bool,
+}
+
+/// What paces the sampler task
+pub(super) trait Cadence {
+    /// Block until the next tick, returning its wall-clock time (Unix ms)
+    fn wait(&mut self) -> i64;
+
+    /// The sample of the last tick is done; `interval` may have changed
+    fn done(&mut self, interval: Duration);
+}
+
+/// Consumer end of the sampler task
+pub struct SamplerTask {
+    ring: Arc<Ring<Tick, RING_CAPACITY>>,
+}
+
+impl SamplerTask {
+    /// Move `sampler` to its own thread
+    pub fn start(
+        sampler: Sampler,
+        #[cfg(feature = "timer-sampling")] timer: TIMER00,
+    ) -> Result<Self> {
+        let ring = Arc::new(Ring::new());
+        let producer = ring.clone();
+        let (started_tx, started_rx) = mpsc::channel();
+
+        let previous = ThreadSpawnConfiguration::get().unwrap_or_default();
+        ThreadSpawnConfiguration {
+            name: Some(&b"sampler\0"[..]),
+            priority: SAMPLER_PRIORITY,
+            pin_to_core: board::SAMPLER_CORE,
+            ..ThreadSpawnConfiguration::get().unwrap_or_default()
+        }
+        .set()?;
+        let spawned = thread::Builder::new()
+            .stack_size(SAMPLER_STACK_SIZE)
+            .spawn(move || {
+                // Set up here: a timer notification belongs to the waiting thread
+                #[cfg(feature = "timer-sampling")]
+                let cadence = super::timed::TimerCadence::start(timer, sampler.interval());
+                #[cfg(not(feature = "timer-sampling"))]
+                let cadence = Ok::<_, anyhow::Error>(SleepCadence::new(sampler.interval()));
+                match cadence {
+                    Ok(cadence) => {
+                        let _ = started_tx.send(Ok(()));
+                        run(sampler, cadence, &producer);
+                    }
+                    Err(e) => {
+                        let _ = started_tx.send(Err(e));
+                    }
+                }
+            });
+        // Threads spawned later get the previous configuration again
+        previous.set()?;
+        spawned?;
+
+        started_rx.recv().map_err(|_| anyhow!("sampler task exited"))??;
+        Ok(Self { ring })
+    }
+
+    /// The oldest sample not taken yet
+    pub fn pop(&self) -> Option<Tick> {
+        self.ring.pop()
+    }
+}
+
+fn run(mut sampler: Sampler, mut cadence: impl Cadence, ring: &Ring<Tick, RING_CAPACITY>) {
+    info!(
+        "Sampler task: every {:?} (priority {}, {})",
+        sampler.interval(),
+        SAMPLER_PRIORITY,
+        if cfg!(feature = "timer-sampling") {
+            "hardware timer"
+        } else {
+            "monotonic deadlines"
+        }
+    );
+    loop {
+        let tick_ms = cadence.wait();
+        let recorded = sampler.poll().is_some();
+        if let Some(sample) = sampler.last_sample() {
+            let mut reading = sample.clone();
+            reading.timestamp = tick_ms;
+            if ring.push(Tick { reading, recorded }).is_err() {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        // The adaptive controller may have changed the interval
+        cadence.done(sampler.interval());
+    }
+}
+
+/// Ticks on absolute deadlines, so the time spent sampling doesn't add up
+#[cfg(not(feature = "timer-sampling"))]
+struct SleepCadence {
+    interval: Duration,
+    // Monotonic time of the next tick
+    next: Duration,
+}
+
+#[cfg(not(feature = "timer-sampling"))]
+impl SleepCadence {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next: clock().monotonic(),
+        }
+    }
+}
+
+#[cfg(not(feature = "timer-sampling"))]
+impl Cadence for SleepCadence {
+    fn wait(&mut self) -> i64 {
+        let now = clock().monotonic();
+        if now < self.next {
+            clock().sleep(self.next - now);
+        }
+        TICKS.fetch_add(1, Ordering::Relaxed);
+
+        let now = clock().monotonic();
+        let late = now.saturating_sub(self.next);
+        MAX_LATENCY_US.fetch_max(late.as_micros() as u32, Ordering::Relaxed);
+        // Ticks that came and went while the last sample was taken
+        let skipped = (late.as_micros() / self.interval.as_micros().max(1)) as u32;
+        if skipped > 0 {
+            MISSED.fetch_add(skipped, Ordering::Relaxed);
+            self.next += self.interval * skipped;
+        }
+        let tick = self.next;
+        self.next += self.interval;
+        clock().now_millis() - now.saturating_sub(tick).as_millis() as i64
+    }
+
+    fn done(&mut self, interval: Duration) {
+        if interval != self.interval {
+            // The next tick moves with the interval
+            self.next = self.next - self.interval + interval;
+            self.interval = interval;
+        }
+    }
+}
+
+/// Bounded lock-free ring for exactly one producer and one consumer
+///
+/// Only the sampler task pushes and only the batcher pops, so each index
+/// has a single writer and no compare-and-swap is needed. `N` is a power of
+/// two, so the indices stay in step when they wrap.
+struct Ring<T, const N: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    // Next slot to pop, written by the consumer only
+    head: AtomicUsize,
+    // Next slot to push, written by the producer only
+    tail: AtomicUsize,
+}
+
+// Slots are handed over through the acquire / release pairs on head and tail
+unsafe impl<T: Send, const N: usize> Sync for Ring<T, N> {}
+
+impl<T, const N: usize> Ring<T, N> {
+    fn new() -> Self {
+        const { assert!(N.is_power_of_two(), "ring capacity must be a power of two") };
+        Self {
+            slots: std::array::from_fn(|_| UnsafeCell::new(MaybeUninit::uninit())),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Producer side; gives `value` back if the ring is full
+    fn push(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == N {
+            return Err(value);
+        }
+        unsafe { (*self.slots[tail % N].get()).write(value) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Consumer side
+    fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = unsafe { (*self.slots[head % N].get()).assume_init_read() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<T, const N: usize> Drop for Ring<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
@@ -0,0 +1,92 @@
+//! Sampler task cadence off a hardware timer (`timer-sampling` feature)
+//!
+//! A general-purpose timer fires every sampling interval. Its ISR only
+//! stamps the tick and wakes the sampler task, so the cadence holds to the
+//! timer's resolution rather than the scheduler's tick. A tick that fires
+//! while the previous one is still being sampled is counted as missed
+//! right in the ISR.
+
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::Duration;
+
+use anyhow::Result;
+use esp_idf_svc::hal::delay::BLOCK;
+use esp_idf_svc::hal::task::notification::Notification;
+use esp_idf_svc::hal::timer::config::Config as TimerConfig;
+use esp_idf_svc::hal::timer::{TimerDriver, TIMER00};
+use esp_idf_svc::sys::esp_timer_get_time;
+use log::warn;
+
+use super::task::{Cadence, MAX_LATENCY_US, MISSED, TICKS};
+use crate::clock::clock;
+
+// Shared with the ISR: `esp_timer` time of the last tick, and whether it is
+// still being sampled
+static TICK_US: AtomicI64 = AtomicI64::new(0);
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+pub(super) struct TimerCadence {
+    // Dropped after the timer, which holds its notifier
+    timer: TimerDriver<'static>,
+    notification: Notification,
+    interval: Duration,
+}
+
+impl TimerCadence {
+    /// Arm `timer`; must run on the thread that waits for the ticks
+    pub(super) fn start(timer: TIMER00, interval: Duration) -> Result<Self> {
+        let notification = Notification::new();
+        let mut timer = TimerDriver::new(timer, &TimerConfig::new().auto_reload(true))?;
+        timer.set_alarm(alarm_ticks(&timer, interval))?;
+
+        let notifier = notification.notifier();
+        // Runs in the ISR: no allocation, no logging, no locks
+        unsafe {
+            timer.subscribe(move || {
+                TICKS.fetch_add(1, Ordering::Relaxed);
+                if BUSY.swap(true, Ordering::AcqRel) {
+                    MISSED.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                TICK_US.store(esp_timer_get_time(), Ordering::Release);
+                notifier.notify_and_yield(NonZeroU32::MIN);
+            })?;
+        }
+        timer.enable_interrupt()?;
+        timer.enable_alarm(true)?;
+        timer.enable(true)?;
+
+        Ok(Self {
+            timer,
+            notification,
+            interval,
+        })
+    }
+}
+
+impl Cadence for TimerCadence {
+    fn wait(&mut self) -> i64 {
+        self.notification.wait(BLOCK);
+        let tick_us = TICK_US.load(Ordering::Acquire);
+        let now_us = unsafe { esp_timer_get_time() };
+        let latency_us = (now_us - tick_us).max(0);
+        MAX_LATENCY_US.fetch_max(latency_us as u32, Ordering::Relaxed);
+        // Wall clock at the tick, however long the reads take
+        clock().now_millis() - latency_us / 1000
+    }
+
+    fn done(&mut self, interval: Duration) {
+        BUSY.store(false, Ordering::Release);
+        if interval != self.interval {
+            self.interval = interval;
+            if let Err(e) = self.timer.set_alarm(alarm_ticks(&self.timer, interval)) {
+                warn!("Failed to re-arm the sampling timer for {:?}: {:?}", interval, e);
+            }
+        }
+    }
+}
+
+fn alarm_ticks(timer: &TimerDriver, interval: Duration) -> u64 {
+    timer.tick_hz() * interval.as_micros() as u64 / 1_000_000
+}
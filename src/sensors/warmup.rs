@@ -0,0 +1,74 @@
+//! Sensor warm-up after power-up
+//!
+//! Some sensors report unstable values for a while after power-up: the
+//! BME680's gas heater needs minutes to settle, the PMS5003's fan ~30 s.
+//! Drivers declare their warm-up period with `SensorSource::warm_up` and the
+//! sampler applies one policy to all of them (the `warmup` NVS key):
+//!
+//! - `flag` - record readings as usual, with `warming_up` set on the row
+//! - `suppress` - leave the warming-up driver's fields unset (NaN); a row
+//!   where every driver is still warming up is dropped
+//!
+//! The warm-up period counts from boot, so it starts over on every wake from
+//! deep sleep.
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use log::info;
+
+use crate::clock::clock;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WarmUpPolicy {
+    Flag,
+    Suppress,
+}
+
+impl WarmUpPolicy {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim() {
+            "flag" | "" => Ok(Self::Flag),
+            "suppress" => Ok(Self::Suppress),
+            other => bail!("unknown warm-up policy '{}', expected flag or suppress", other),
+        }
+    }
+}
+
+/// Per-source warm-up state, in the sampler's source order
+pub struct WarmUp {
+    policy: WarmUpPolicy,
+    started: Duration,
+    periods: Vec<Duration>,
+    warm: Vec<bool>,
+}
+
+impl WarmUp {
+    pub fn new(policy: WarmUpPolicy, periods: Vec<Duration>) -> Self {
+        let warm = periods.iter().map(|p| p.is_zero()).collect();
+        Self {
+            policy,
+            started: clock().monotonic(),
+            periods,
+            warm,
+        }
+    }
+
+    pub fn policy(&self) -> WarmUpPolicy {
+        self.policy
+    }
+
+    /// True while source `index` is still warming up; logs the end of its warm-up once
+    pub fn is_warming_up(&mut self, index: usize, name: &str) -> bool {
+        if self.warm[index] {
+            return false;
+        }
+        if clock().elapsed_since(self.started) < self.periods[index] {
+            return true;
+        }
+
+        self.warm[index] = true;
+        info!("Sensor '{}' warmed up after {:?}", name, self.periods[index]);
+        false
+    }
+}
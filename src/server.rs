@@ -0,0 +1,512 @@
+//! HTTP endpoints for polling a node directly
+//!
+//! - `GET /query?sql=<urlencoded>` (or `POST /query` with the SQL as body) runs
+//!   a read-only query against the lake catalog (see `query.rs`) and returns
+//!   `{"columns": [...], "rows": [{...}, ...]}`
+//! - `GET /health` returns free heap, WiFi state, the last flush, the time
+//!   sync state and how the lake attach went
+//! - `GET /export` returns the lake settings without secrets, and
+//!   `GET /catalog` the DuckLake catalog file, for `lakectl` on a laptop
+//! - `POST /maintain` runs lake maintenance with the next forwarding round,
+//!   whether it is due or not
+//! - `POST /pause` and `POST /resume` hold and drop the HTTP pause of the
+//!   pipeline (see `pause.rs`) and return `{"state": ..., "holders": [...]}`
+//!
+//! `/catalog`, `/maintain`, `/pause` and `/resume` need
+//! `Authorization: Bearer <ctl_token>`; without a `ctl_token` they are
+//! refused.
+//!
+//! Like the serial console, requests are handed to the ingestion loop, which
+//! answers them between samples. The httpd task only waits for the reply.
+//! `/pause` and `/resume` are answered by the httpd task itself, so they
+//! work while the loop is busy. With the `http` feature flag off (see
+//! `flags.rs`), only `/health`, `/export`, `/pause` and `/resume` are
+//! answered; while the pipeline is paused, `/query` and `/catalog` aren't.
+
+use std::cell::Cell;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use embedded_svc::http::Method;
+use esp_idf_svc::http::server::{
+    Configuration as ServerConfig, EspHttpConnection, EspHttpServer, Request as HttpRequest,
+};
+use esp_idf_svc::io::{Read, Write};
+use log::{info, warn};
+use serde_json::{json, Map, Value as Json};
+
+use crate::config::DeviceConfig;
+use crate::flags::Flags;
+use crate::identity::DeviceIdentity;
+use crate::lake::{self, AttachStatus, LakeBackend};
+use crate::pause::{self, Holder, PauseState};
+use crate::pipeline::free_heap;
+use crate::query::{self, ResultSet, Value};
+use crate::timesync;
+
+const MAX_ROWS: usize = 500;
+const MAX_SQL_LEN: usize = 1024;
+// The ingestion loop answers between samples
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Request {
+    Query(String),
+    Health,
+    Catalog,
+    Maintain,
+}
+
+struct Reply {
+    status: u16,
+    body: String,
+    /// Sent instead of `body`, e.g. a copy of the catalog
+    file: Option<PathBuf>,
+}
+
+/// Device state reported by `/health`, gathered by the ingestion loop
+pub struct Health {
+    pub wifi_connected: bool,
+    /// SSID of the network in use, see `wifi.rs`
+    pub wifi_network: Option<String>,
+    /// dBm, `None` while disconnected
+    pub wifi_rssi: Option<i8>,
+    pub last_flush_ms: Option<i64>,
+    pub buffered_batches: usize,
+    /// Rounds lost to an S3 outage and the end of the cooldown, see `backoff.rs`
+    pub backoff: (u32, Option<i64>),
+    /// Subsystems switched off from the lake, see `flags.rs`
+    pub feature_flags: Flags,
+}
+
+pub struct Server {
+    // Keeps the handlers registered
+    _httpd: EspHttpServer<'static>,
+    requests: Receiver<(Request, Sender<Reply>)>,
+    maintenance_requested: Cell<bool>,
+}
+
+impl Server {
+    /// Start the HTTP server on port 80
+    pub fn start(config: &DeviceConfig, identity: &DeviceIdentity) -> Result<Self> {
+        let mut httpd = EspHttpServer::new(&ServerConfig::default())?;
+        let (sender, requests) = mpsc::channel();
+
+        let queries = sender.clone();
+        httpd.fn_handler("/query", Method::Get, move |request| {
+            let sql = request
+                .uri()
+                .split_once('?')
+                .and_then(|(_, params)| query_param(params, "sql"));
+            match sql {
+                Some(sql) => respond(request, &queries, Request::Query(sql)),
+                None => write_reply(request, error_reply(400, "missing 'sql' parameter")),
+            }
+        })?;
+
+        let queries = sender.clone();
+        httpd.fn_handler("/query", Method::Post, move |mut request| {
+            match read_body(&mut request) {
+                Ok(sql) => respond(request, &queries, Request::Query(sql)),
+                Err(e) => write_reply(request, error_reply(400, &e.to_string())),
+            }
+        })?;
+
+        let health = sender.clone();
+        httpd.fn_handler("/health", Method::Get, move |request| {
+            respond(request, &health, Request::Health)
+        })?;
+
+        // Config changes reboot the device, so the export never goes stale
+        let export = export_json(config, identity).to_string();
+        httpd.fn_handler("/export", Method::Get, move |request| {
+            write_reply(request, ok_reply(export.clone()))
+        })?;
+
+        let catalog = sender.clone();
+        let token = config.control_token.clone();
+        httpd.fn_handler("/catalog", Method::Get, move |request| {
+            if let Some(refused) = refuse_control(&request, &token) {
+                return write_reply(request, refused);
+            }
+            respond(request, &catalog, Request::Catalog)
+        })?;
+
+        let token = config.control_token.clone();
+        httpd.fn_handler("/maintain", Method::Post, move |request| {
+            if let Some(refused) = refuse_control(&request, &token) {
+                return write_reply(request, refused);
+            }
+            respond(request, &sender, Request::Maintain)
+        })?;
+
+        let token = config.control_token.clone();
+        httpd.fn_handler("/pause", Method::Post, move |request| {
+            if let Some(refused) = refuse_control(&request, &token) {
+                return write_reply(request, refused);
+            }
+            pause::pause(Holder::Http);
+            write_reply(request, pause_reply())
+        })?;
+
+        let token = config.control_token.clone();
+        httpd.fn_handler("/resume", Method::Post, move |request| {
+            if let Some(refused) = refuse_control(&request, &token) {
+                return write_reply(request, refused);
+            }
+            pause::resume(Holder::Http);
+            write_reply(request, pause_reply())
+        })?;
+
+        info!(
+            "HTTP server listening on port 80 \
+             (/query, /health, /export, /catalog, /maintain, /pause, /resume)"
+        );
+        Ok(Self {
+            _httpd: httpd,
+            requests,
+            maintenance_requested: Cell::new(false),
+        })
+    }
+
+    /// Answer the requests received since the last call
+    pub fn poll(&self, lake: &dyn LakeBackend, health: &Health) {
+        while let Ok((request, reply_to)) = self.requests.try_recv() {
+            let reply = match request {
+                Request::Health => health_reply(health),
+                _ if !health.feature_flags.http => {
+                    error_reply(503, "the HTTP API is switched off by the feature flags")
+                }
+                Request::Query(_) | Request::Catalog if pause::state() == PauseState::Paused => {
+                    error_reply(503, "the pipeline is paused")
+                }
+                Request::Query(sql) => run_query(&sql, lake),
+                Request::Catalog => catalog_reply(lake),
+                Request::Maintain if !health.feature_flags.compaction => {
+                    error_reply(409, "lake maintenance is switched off by the feature flags")
+                }
+                Request::Maintain => self.request_maintenance(lake),
+            };
+            // The handler may have timed out already
+            let _ = reply_to.send(reply);
+        }
+    }
+
+    /// True once after `POST /maintain`
+    pub fn take_maintenance_request(&self) -> bool {
+        self.maintenance_requested.replace(false)
+    }
+
+    fn request_maintenance(&self, lake: &dyn LakeBackend) -> Reply {
+        if lake.catalog().is_none() {
+            let message = format!("the '{}' lake backend has no maintenance", lake.name());
+            return error_reply(409, &message);
+        }
+        info!("Lake maintenance requested over HTTP, running with the next forwarding round");
+        self.maintenance_requested.set(true);
+        Reply {
+            status: 202,
+            body: json!({ "scheduled": true }).to_string(),
+            file: None,
+        }
+    }
+}
+
+// ============================================================================
+// HANDLERS (httpd task)
+// ============================================================================
+
+/// Hand `request` to the ingestion loop and write its reply
+fn respond(
+    http: HttpRequest<&mut EspHttpConnection>,
+    queue: &Sender<(Request, Sender<Reply>)>,
+    request: Request,
+) -> Result<()> {
+    let (reply_to, reply) = mpsc::channel();
+    let reply = queue
+        .send((request, reply_to))
+        .map_err(|_| anyhow!("ingestion loop stopped"))
+        .and_then(|()| {
+            reply
+                .recv_timeout(REPLY_TIMEOUT)
+                .map_err(|_| anyhow!("timed out waiting for the ingestion loop"))
+        })
+        .unwrap_or_else(|e| error_reply(503, &e.to_string()));
+    write_reply(http, reply)
+}
+
+fn write_reply(http: HttpRequest<&mut EspHttpConnection>, reply: Reply) -> Result<()> {
+    // Opened before the headers go out, so a failure still gets a status
+    let file = match reply.file.as_ref().map(File::open).transpose() {
+        Ok(file) => file,
+        Err(e) => return write_reply(http, error_reply(500, &e.to_string())),
+    };
+    let mut response = http.into_response(
+        reply.status,
+        None,
+        &[("Content-Type", "application/json")],
+    )?;
+    match file {
+        // Streamed, the catalog may not fit in the heap at once
+        Some(mut file) => {
+            let mut chunk = [0u8; 1024];
+            loop {
+                let read = std::io::Read::read(&mut file, &mut chunk)?;
+                if read == 0 {
+                    break;
+                }
+                response.write_all(&chunk[..read])?;
+            }
+        }
+        None => response.write_all(reply.body.as_bytes())?,
+    }
+    Ok(())
+}
+
+fn read_body(request: &mut HttpRequest<&mut EspHttpConnection>) -> Result<String> {
+    let mut body = Vec::new();
+    let mut chunk = [0u8; 256];
+    loop {
+        let read = request.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        if body.len() + read > MAX_SQL_LEN {
+            return Err(anyhow!("query longer than {} bytes", MAX_SQL_LEN));
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+    Ok(String::from_utf8(body)?.trim().to_string())
+}
+
+/// The error reply to a control request (catalog, maintenance, pause and
+/// resume) without the control token
+fn refuse_control(request: &HttpRequest<&mut EspHttpConnection>, token: &str) -> Option<Reply> {
+    if token.is_empty() {
+        return Some(error_reply(403, "control endpoints are off, set ctl_token"));
+    }
+    let presented = request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    match presented {
+        Some(presented) if pause::token_matches(presented, token) => None,
+        _ => {
+            warn!("Refused HTTP {} without the control token", request.uri());
+            Some(error_reply(401, "missing or wrong bearer token"))
+        }
+    }
+}
+
+/// URL-decoded value of `name` in a query string
+fn query_param(params: &str, name: &str) -> Option<String> {
+    params
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| url_decode(value))
+}
+
+fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => match value.get(i + 1..i + 3).map(|hex| u8::from_str_radix(hex, 16)) {
+                Some(Ok(byte)) => {
+                    decoded.push(byte);
+                    i += 2;
+                }
+                _ => decoded.push(b'%'),
+            },
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// ============================================================================
+// REPLIES (ingestion loop)
+// ============================================================================
+
+fn run_query(sql: &str, lake: &dyn LakeBackend) -> Reply {
+    let Some(catalog) = lake.catalog() else {
+        let message = format!("the '{}' lake backend has no local catalog", lake.name());
+        return error_reply(409, &message);
+    };
+    match query::execute(sql, catalog) {
+        Ok(result) => ok_reply(result_json(&result).to_string()),
+        Err(e) => {
+            warn!("HTTP query failed: {}", e);
+            error_reply(400, &e.to_string())
+        }
+    }
+}
+
+/// Rows as objects keyed by column, at most `MAX_ROWS` of them
+fn result_json(result: &ResultSet) -> Json {
+    let rows: Vec<Json> = result
+        .rows
+        .iter()
+        .take(MAX_ROWS)
+        .map(|row| {
+            let object: Map<String, Json> = result
+                .columns
+                .iter()
+                .cloned()
+                .zip(row.iter().map(value_json))
+                .collect();
+            Json::Object(object)
+        })
+        .collect();
+    json!({
+        "columns": result.columns,
+        "rows": rows,
+        "truncated": result.rows.len() > MAX_ROWS,
+    })
+}
+
+fn value_json(value: &Value) -> Json {
+    match value {
+        Value::Int(v) => Json::from(*v),
+        Value::Text(v) => Json::from(v.as_str()),
+        Value::Null => Json::Null,
+    }
+}
+
+fn health_reply(health: &Health) -> Reply {
+    let stats = crate::sensors::sampling_stats();
+    let pipeline = crate::tasks::pipeline_stats();
+    let body = json!({
+        "free_heap": free_heap(),
+        "wifi": {
+            "connected": health.wifi_connected,
+            "ssid": health.wifi_network,
+            "rssi": health.wifi_rssi,
+        },
+        "last_flush_ms": health.last_flush_ms,
+        "buffered_batches": health.buffered_batches,
+        "backoff": {
+            "failed_rounds": health.backoff.0,
+            "until_ms": health.backoff.1,
+        },
+        "time": timesync::status().map(|status| json!({
+            "trusted": status.trusted,
+            "syncs": status.syncs,
+            "last_sync_ms": status.last_sync_ms,
+            "last_drift_ms": status.last_drift_ms,
+            "drift_ppm": status.drift_ppm,
+        })),
+        "lake": lake::attach_status().map(|status| attach_json(&status)),
+        "sampling": {
+            "ticks": stats.ticks,
+            "missed": stats.missed,
+            "dropped": stats.dropped,
+            "max_latency_us": stats.max_latency_us,
+        },
+        "pipeline": {
+            "alert_skips": pipeline.alert_skips,
+            "stalls": pipeline.stalls,
+            "max_stall_ms": pipeline.max_stall_ms,
+        },
+        "feature_flags": {
+            "compaction": health.feature_flags.compaction,
+            "alerts": health.feature_flags.alerts,
+            "http": health.feature_flags.http,
+        },
+        "pause": pause_json(),
+    });
+    ok_reply(body.to_string())
+}
+
+fn pause_json() -> Json {
+    json!({
+        "state": pause::state().name(),
+        "holders": pause::holders(),
+    })
+}
+
+/// 202: the pause takes effect between forwarding rounds
+fn pause_reply() -> Reply {
+    Reply {
+        status: 202,
+        body: pause_json().to_string(),
+        file: None,
+    }
+}
+
+/// Lake settings a host needs to read the same lake; no secrets
+fn export_json(config: &DeviceConfig, identity: &DeviceIdentity) -> Json {
+    json!({
+        "device_id": identity.device_id,
+        "firmware_version": identity.firmware_version,
+        "lake_backend": config.lake_backend,
+        "table": config.table_name,
+        "data_path": config.data_path,
+        "s3": {
+            "bucket": config.s3_bucket,
+            "region": config.s3_region,
+            "endpoint": config.s3_endpoint,
+            "url_style": config.s3_url_style,
+            "use_ssl": config.s3_use_ssl,
+        },
+    })
+}
+
+/// A consistent copy of the catalog file, which the httpd task then streams
+fn catalog_reply(lake: &dyn LakeBackend) -> Reply {
+    let Some(catalog) = lake.catalog() else {
+        let message = format!("the '{}' lake backend has no local catalog", lake.name());
+        return error_reply(409, &message);
+    };
+    match catalog.export() {
+        Ok(path) => Reply {
+            status: 200,
+            body: String::new(),
+            file: Some(path),
+        },
+        Err(e) => {
+            warn!("Catalog export failed: {:?}", e);
+            error_reply(500, &e.to_string())
+        }
+    }
+}
+
+fn attach_json(status: &AttachStatus) -> Json {
+    let stages: Map<String, Json> = status
+        .stages
+        .iter()
+        .map(|(stage, ms)| (stage.name().to_string(), Json::from(*ms)))
+        .collect();
+    json!({
+        "backend": status.backend,
+        "attach": {
+            "stage": status.stage.name(),
+            "stuck_in": status.stuck_in.map(|stage| stage.name()),
+            "elapsed_ms": status.elapsed_ms,
+            "stages_ms": stages,
+            "error": status.error,
+        },
+    })
+}
+
+fn ok_reply(body: String) -> Reply {
+    Reply {
+        status: 200,
+        body,
+        file: None,
+    }
+}
+
+fn error_reply(status: u16, message: &str) -> Reply {
+    Reply {
+        status,
+        body: json!({ "error": message }).to_string(),
+        file: None,
+    }
+}
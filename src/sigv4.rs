@@ -0,0 +1,172 @@
+//! AWS Signature Version 4 for plain requests
+//!
+//! The Parquet path signs presigned URLs with rusty-s3. This signs requests
+//! in the `Authorization` header instead, with nothing but SHA-256 / HMAC,
+//! for the NDJSON fallback uploader (see `fallback.rs`) and STS AssumeRole
+//! (see `sts.rs`). The payload hash is always sent, so bodies are signed
+//! too, and so is the session token of temporary credentials.
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::clock::civil_from_days;
+use crate::credentials::S3Credentials;
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+
+/// Headers to send with a signed request
+pub struct SignedHeaders {
+    pub authorization: String,
+    pub amz_date: String,
+    pub content_sha256: String,
+    /// `x-amz-security-token`, for temporary credentials
+    pub security_token: Option<String>,
+}
+
+/// Sign a request to `service` (`s3`, `sts`) for `path` (already URI-encoded) on `host`
+#[allow(clippy::too_many_arguments)]
+pub fn sign(
+    service: &str,
+    method: &str,
+    host: &str,
+    path: &str,
+    region: &str,
+    credentials: &S3Credentials,
+    body: &[u8],
+    epoch_millis: i64,
+) -> SignedHeaders {
+    let amz_date = format_amz_date(epoch_millis);
+    let date = &amz_date[..8];
+    let content_sha256 = hex(&Sha256::digest(body));
+
+    let mut canonical_headers = format!(
+        "host:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n",
+        host, content_sha256, amz_date
+    );
+    let mut signed_headers = "host;x-amz-content-sha256;x-amz-date".to_string();
+    if let Some(token) = &credentials.session_token {
+        canonical_headers += &format!("x-amz-security-token:{}\n", token);
+        signed_headers += ";x-amz-security-token";
+    }
+    let canonical_request = format!(
+        "{}\n{}\n\n{}\n{}\n{}",
+        method, path, canonical_headers, signed_headers, content_sha256
+    );
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        ALGORITHM,
+        amz_date,
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = [date, region, service, "aws4_request"].iter().fold(
+        format!("AWS4{}", credentials.secret_key).into_bytes(),
+        |key, part| hmac(&key, part.as_bytes()),
+    );
+    let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+    SignedHeaders {
+        authorization: format!(
+            "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            ALGORITHM, credentials.access_key, scope, signed_headers, signature
+        ),
+        amz_date,
+        content_sha256,
+        security_token: credentials.session_token.clone(),
+    }
+}
+
+/// URI-encode an object key for the canonical request, keeping `/` separators
+pub fn encode_key(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC key");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// `YYYYMMDD'T'HHMMSS'Z'` in UTC
+fn format_amz_date(epoch_millis: i64) -> String {
+    let secs = epoch_millis.div_euclid(1000);
+    let (days, time) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amz_dates() {
+        assert_eq!(format_amz_date(0), "19700101T000000Z");
+        assert_eq!(format_amz_date(1_440_938_160_000), "20150830T123600Z");
+        // The last second of a leap day
+        assert_eq!(format_amz_date(951_868_799_999), "20000229T235959Z");
+    }
+
+    #[test]
+    fn keys_keep_their_separators() {
+        assert_eq!(
+            encode_key("esp32s3/year=2026/a b.parquet"),
+            "esp32s3/year%3D2026/a%20b.parquet"
+        );
+        assert_eq!(encode_key("-_.~"), "-_.~");
+    }
+
+    #[test]
+    fn signs_with_a_session_token() {
+        let credentials = S3Credentials {
+            access_key: "AKIDEXAMPLE".to_string(),
+            secret_key: "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: Some("session".to_string()),
+        };
+        let signed = sign(
+            "s3",
+            "PUT",
+            "examplebucket.s3.amazonaws.com",
+            "/logs/a%20b.json",
+            "us-east-1",
+            &credentials,
+            br#"{"ok":true}"#,
+            1_440_938_160_000,
+        );
+
+        assert_eq!(signed.amz_date, "20150830T123600Z");
+        assert_eq!(
+            signed.content_sha256,
+            "4062edaf750fb8074e7e83e0c9028c94e32468a8b6f1614774328ef045150f93"
+        );
+        assert_eq!(
+            signed.authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/s3/aws4_request, \
+             SignedHeaders=host;x-amz-content-sha256;x-amz-date;x-amz-security-token, \
+             Signature=c5c9e581e68b32aaa06cb78af604356d63a85092bf4d7b4b02fde0be14e5654a"
+        );
+        assert_eq!(signed.security_token.as_deref(), Some("session"));
+    }
+}
@@ -0,0 +1,281 @@
+//! `batch_sketches` rows: per-batch, per-metric summaries for fleet analytics
+//!
+//! With `sketches` on, every batch flushed for the lake (on-board sensors,
+//! MQTT and the watch folder) yields one row per metric it has values for:
+//!
+//! | Column | Content |
+//! | ------ | ------- |
+//! | `timestamp`, `end_ms` | First and last reading of the batch |
+//! | `table_name`, `metric` | Table the batch was flushed for, metric name |
+//! | `count` | Values of the metric, NaN and warming-up rows left out |
+//! | `min`, `max`, `mean`, `stddev` | Over those values (population stddev) |
+//! | `p50`, `p90`, `p99` | Exact percentiles of the batch (nearest rank) |
+//! | `centroids` | t-digest of the values, a JSON array of `[mean, weight]` |
+//!
+//! The centroids merge across batches and devices, so fleet-wide percentiles
+//! come from a scan of this table instead of every device's raw Parquet:
+//!
+//! ```sql
+//! WITH c AS (
+//!   SELECT (u->>0)::DOUBLE AS mean, (u->>1)::DOUBLE AS weight
+//!   FROM batch_sketches, unnest(json_extract(centroids, '$[*]')) AS t(u)
+//!   WHERE metric = 'pm2_5'
+//! )
+//! SELECT min(mean) FROM (
+//!   SELECT mean, SUM(weight) OVER (ORDER BY mean) / SUM(weight) OVER () AS q FROM c
+//! ) WHERE q >= 0.9
+//! ```
+//!
+//! Rows are written with the next forwarding round, like the other auxiliary
+//! tables, and queued rows are saved across deep sleep.
+
+use std::collections::VecDeque;
+use std::fmt::Write as FmtWrite;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::lake::{Cell, Column, ColumnType, LakeBackend, RecordBatch, S3Target};
+use crate::sensors::{SensorReading, METRIC_NAMES};
+
+const SKETCHES_TABLE: &str = "batch_sketches";
+// Rows kept for the lake, a few dozen batches; oldest dropped beyond
+const MAX_QUEUED: usize = 512;
+// t-digest compression: at most about twice as many centroids per row
+const COMPRESSION: f64 = 20.0;
+
+const SKETCH_COLUMNS: [Column; 13] = [
+    Column {
+        name: "timestamp",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "end_ms",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "table_name",
+        kind: ColumnType::Text,
+    },
+    Column {
+        name: "metric",
+        kind: ColumnType::Text,
+    },
+    Column {
+        name: "count",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "min",
+        kind: ColumnType::Float,
+    },
+    Column {
+        name: "max",
+        kind: ColumnType::Float,
+    },
+    Column {
+        name: "mean",
+        kind: ColumnType::Float,
+    },
+    Column {
+        name: "stddev",
+        kind: ColumnType::Float,
+    },
+    Column {
+        name: "p50",
+        kind: ColumnType::Float,
+    },
+    Column {
+        name: "p90",
+        kind: ColumnType::Float,
+    },
+    Column {
+        name: "p99",
+        kind: ColumnType::Float,
+    },
+    Column {
+        name: "centroids",
+        kind: ColumnType::Text,
+    },
+];
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Sketch {
+    start_ms: i64,
+    end_ms: i64,
+    table: String,
+    metric: String,
+    count: u32,
+    min: f32,
+    max: f32,
+    mean: f32,
+    stddev: f32,
+    p50: f32,
+    p90: f32,
+    p99: f32,
+    centroids: String,
+}
+
+/// What survives deep sleep: the queued sketches
+#[derive(Serialize, Deserialize)]
+pub struct SketchState {
+    unwritten: VecDeque<Sketch>,
+}
+
+pub struct BatchSketches {
+    enabled: bool,
+    unwritten: VecDeque<Sketch>,
+}
+
+impl BatchSketches {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            unwritten: VecDeque::new(),
+        }
+    }
+
+    /// Sketch every metric of a batch of `readings` flushed for `table`
+    pub fn record(&mut self, table: &str, readings: &[SensorReading]) {
+        if !self.enabled {
+            return;
+        }
+        let readings: Vec<&SensorReading> = readings.iter().filter(|r| !r.warming_up).collect();
+        let (Some(start_ms), Some(end_ms)) = (
+            readings.iter().map(|r| r.timestamp).min(),
+            readings.iter().map(|r| r.timestamp).max(),
+        ) else {
+            return;
+        };
+        let metrics: Vec<[f32; 9]> = readings.iter().map(|r| r.metrics()).collect();
+
+        for (i, metric) in METRIC_NAMES.iter().enumerate() {
+            let mut values: Vec<f32> =
+                metrics.iter().map(|m| m[i]).filter(|v| !v.is_nan()).collect();
+            if values.is_empty() {
+                continue;
+            }
+            values.sort_by(f32::total_cmp);
+            let n = values.len() as f64;
+            let mean = values.iter().map(|&v| f64::from(v)).sum::<f64>() / n;
+            let variance = values.iter().map(|&v| (f64::from(v) - mean).powi(2)).sum::<f64>() / n;
+
+            if self.unwritten.len() == MAX_QUEUED {
+                self.unwritten.pop_front();
+            }
+            self.unwritten.push_back(Sketch {
+                start_ms,
+                end_ms,
+                table: table.to_string(),
+                metric: metric.to_string(),
+                count: values.len() as u32,
+                min: values[0],
+                max: values[values.len() - 1],
+                mean: mean as f32,
+                stddev: variance.sqrt() as f32,
+                p50: percentile(&values, 0.5),
+                p90: percentile(&values, 0.9),
+                p99: percentile(&values, 0.99),
+                centroids: centroids_json(&centroids(&values)),
+            });
+        }
+    }
+
+    /// True if sketches are waiting for the lake
+    pub fn has_unwritten(&self) -> bool {
+        !self.unwritten.is_empty()
+    }
+
+    /// Write queued sketches to `batch_sketches`; they stay queued on failure
+    pub fn write(&mut self, lake: &mut dyn LakeBackend, target: &S3Target) {
+        let batch = RecordBatch {
+            columns: &SKETCH_COLUMNS,
+            rows: self.unwritten.iter().map(row).collect(),
+        };
+        let written = lake
+            .append_records(target, SKETCHES_TABLE, &batch)
+            .and_then(|_| lake.commit());
+        match written {
+            Ok(()) => {
+                info!("  Wrote {} batch sketch rows", self.unwritten.len());
+                self.unwritten.clear();
+            }
+            Err(e) => warn!("  Failed to write batch sketch rows: {:?}", e),
+        }
+    }
+
+    /// Take queued sketches for the next boot, `None` if there are none
+    pub fn take_state(&mut self) -> Option<SketchState> {
+        let unwritten = std::mem::take(&mut self.unwritten);
+        (!unwritten.is_empty()).then_some(SketchState { unwritten })
+    }
+
+    /// Take over what `take_state` took before the last boot
+    pub fn restore_state(&mut self, saved: SketchState) {
+        self.unwritten = saved.unwritten;
+    }
+}
+
+/// Nearest-rank percentile `q` of `sorted`, which isn't empty
+fn percentile(sorted: &[f32], q: f64) -> f32 {
+    let rank = (q * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Merge `sorted` values into t-digest centroids `(mean, weight)`: single
+/// values at the tails, where percentiles need the resolution, and larger
+/// centroids towards the median
+fn centroids(sorted: &[f32]) -> Vec<(f64, u32)> {
+    let n = sorted.len() as f64;
+    let mut centroids = Vec::new();
+    // Weight of the centroids already closed
+    let mut before = 0u32;
+    let (mut sum, mut weight) = (0.0f64, 0u32);
+    for &value in sorted {
+        let q = (f64::from(before) + f64::from(weight + 1) / 2.0) / n;
+        let limit = (4.0 * n * q * (1.0 - q) / COMPRESSION).max(1.0);
+        if weight > 0 && f64::from(weight + 1) > limit {
+            centroids.push((sum / f64::from(weight), weight));
+            before += weight;
+            (sum, weight) = (0.0, 0);
+        }
+        sum += f64::from(value);
+        weight += 1;
+    }
+    if weight > 0 {
+        centroids.push((sum / f64::from(weight), weight));
+    }
+    centroids
+}
+
+fn centroids_json(centroids: &[(f64, u32)]) -> String {
+    let mut json = String::from("[");
+    for (i, (mean, weight)) in centroids.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        // Readings are f32, more digits would only be noise
+        let _ = write!(json, "[{},{}]", *mean as f32, weight);
+    }
+    json.push(']');
+    json
+}
+
+/// One `batch_sketches` row, in `SKETCH_COLUMNS` order
+fn row(sketch: &Sketch) -> Vec<Cell> {
+    vec![
+        Cell::Long(sketch.start_ms),
+        Cell::Long(sketch.end_ms),
+        Cell::Text(sketch.table.clone()),
+        Cell::Text(sketch.metric.clone()),
+        Cell::Long(sketch.count.into()),
+        Cell::Float(sketch.min),
+        Cell::Float(sketch.max),
+        Cell::Float(sketch.mean),
+        Cell::Float(sketch.stddev),
+        Cell::Float(sketch.p50),
+        Cell::Float(sketch.p90),
+        Cell::Float(sketch.p99),
+        Cell::Text(sketch.centroids.clone()),
+    ]
+}
@@ -0,0 +1,307 @@
+//! `sla_metrics` rows: WiFi availability, upload success and on-time delivery
+//!
+//! Time is cut into windows of `sla_m` minutes. Each window accumulates:
+//!
+//! - how long WiFi was connected and disconnected during it
+//! - how many batch uploads were attempted and how many succeeded
+//! - the rows flushed for the lake with a timestamp in the window, plus the
+//!   samples the sampler missed or dropped meanwhile: the rows expected
+//! - how many of those rows were committed within `sla_target_m` minutes
+//!   of their timestamp: the rows on time
+//!
+//! A window is final once its end is `sla_target_m` minutes past, when its
+//! last row could still have been on time. It is then queued as one row and
+//! written with the next forwarding round, like the other auxiliary tables,
+//! so a device's delivery SLA is a query over the lake:
+//!
+//! ```sql
+//! SELECT device_id, SUM(rows_on_time) * 100.0 / SUM(rows_expected)
+//! FROM sla_metrics WHERE timestamp >= epoch_ms(now() - INTERVAL 30 DAY) GROUP BY 1
+//! ```
+//!
+//! Time isn't accounted while the clock isn't trusted. Open windows and
+//! queued rows are carried across deep sleep.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::clock;
+use crate::config::DeviceConfig;
+use crate::lake::{Cell, Column, ColumnType, LakeBackend, RecordBatch, S3Target};
+use crate::sensors::{sampling_stats, SensorReading};
+
+const SLA_TABLE: &str = "sla_metrics";
+// Final rows kept for the lake; oldest dropped beyond
+const MAX_QUEUED: usize = 48;
+
+const SLA_COLUMNS: [Column; 12] = [
+    Column {
+        name: "timestamp",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "window_s",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "connected_s",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "disconnected_s",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "availability_pct",
+        kind: ColumnType::Float,
+    },
+    Column {
+        name: "upload_attempts",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "upload_successes",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "upload_success_pct",
+        kind: ColumnType::Float,
+    },
+    Column {
+        name: "samples_lost",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "rows_expected",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "rows_on_time",
+        kind: ColumnType::Long,
+    },
+    Column {
+        name: "on_time_pct",
+        kind: ColumnType::Float,
+    },
+];
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Window {
+    start_ms: i64,
+    connected_ms: u64,
+    disconnected_ms: u64,
+    upload_attempts: u32,
+    upload_successes: u32,
+    samples_lost: u32,
+    rows_flushed: u32,
+    rows_on_time: u32,
+}
+
+/// What survives deep sleep: open windows, queued rows and the final cursor
+#[derive(Default, Serialize, Deserialize)]
+pub struct SlaState {
+    // Oldest first
+    open: VecDeque<Window>,
+    queued: VecDeque<Window>,
+    // Rows stamped before this belong to windows already final
+    final_until_ms: i64,
+}
+
+pub struct SlaTracker {
+    window: Duration,
+    target: Duration,
+    state: SlaState,
+    // Monotonic time of the last `observe`
+    observed: Option<Duration>,
+    // Sampler overruns at the last `observe`
+    overruns: u32,
+}
+
+impl SlaTracker {
+    /// Windows of `sla_m` minutes, `None` if that is 0
+    pub fn new(config: &DeviceConfig) -> Option<Self> {
+        if config.sla_mins == 0 {
+            return None;
+        }
+        Some(Self {
+            window: Duration::from_secs(u64::from(config.sla_mins) * 60),
+            target: Duration::from_secs(u64::from(config.sla_target_mins) * 60),
+            state: SlaState::default(),
+            observed: None,
+            overruns: sampling_stats().overruns(),
+        })
+    }
+
+    /// Account the time since the last call to the link state; call every loop
+    pub fn observe(&mut self, connected: bool) {
+        let now = clock().monotonic();
+        let elapsed = self.observed.map(|at| now.saturating_sub(at));
+        self.observed = Some(now);
+        let overruns = sampling_stats().overruns();
+        let lost = overruns.wrapping_sub(self.overruns);
+        self.overruns = overruns;
+        if !clock().is_trusted() {
+            return;
+        }
+
+        let now_ms = clock().now_millis();
+        if let Some(window) = self.window_at(now_ms) {
+            let elapsed = elapsed.unwrap_or_default().as_millis() as u64;
+            if connected {
+                window.connected_ms += elapsed;
+            } else {
+                window.disconnected_ms += elapsed;
+            }
+            window.samples_lost += lost;
+        }
+        self.finalize(now_ms);
+    }
+
+    /// `readings` were flushed for the lake
+    pub fn flushed(&mut self, readings: &[SensorReading]) {
+        if !clock().is_trusted() {
+            return;
+        }
+        for reading in readings {
+            if let Some(window) = self.window_at(reading.timestamp) {
+                window.rows_flushed += 1;
+            }
+        }
+    }
+
+    /// A batch upload was attempted; `committed` holds its readings if it went up
+    pub fn uploaded(&mut self, committed: Option<&[SensorReading]>) {
+        if !clock().is_trusted() {
+            return;
+        }
+        let now_ms = clock().now_millis();
+        if let Some(window) = self.window_at(now_ms) {
+            window.upload_attempts += 1;
+            window.upload_successes += u32::from(committed.is_some());
+        }
+        let target_ms = self.target.as_millis() as i64;
+        for reading in committed.unwrap_or_default() {
+            if now_ms - reading.timestamp > target_ms {
+                continue;
+            }
+            if let Some(window) = self.window_at(reading.timestamp) {
+                window.rows_on_time += 1;
+            }
+        }
+    }
+
+    /// True if final windows are waiting for the lake
+    pub fn has_unwritten(&self) -> bool {
+        !self.state.queued.is_empty()
+    }
+
+    /// Write final windows to `sla_metrics`; they stay queued on failure
+    pub fn write(&mut self, lake: &mut dyn LakeBackend, target: &S3Target) {
+        let window_s = self.window.as_secs() as i64;
+        let batch = RecordBatch {
+            columns: &SLA_COLUMNS,
+            rows: self.state.queued.iter().map(|w| row(w, window_s)).collect(),
+        };
+        let written = lake
+            .append_records(target, SLA_TABLE, &batch)
+            .and_then(|_| lake.commit());
+        match written {
+            Ok(()) => {
+                info!("  Wrote {} SLA metrics rows", self.state.queued.len());
+                self.state.queued.clear();
+            }
+            Err(e) => warn!("  Failed to write SLA metrics rows: {:?}", e),
+        }
+    }
+
+    /// Take open windows and queued rows for the next boot, `None` if there
+    /// are none
+    pub fn take_state(&mut self) -> Option<SlaState> {
+        let state = std::mem::take(&mut self.state);
+        (!state.open.is_empty() || !state.queued.is_empty()).then_some(state)
+    }
+
+    /// Take over what `take_state` took before the last boot
+    pub fn restore_state(&mut self, saved: SlaState) {
+        self.state = saved;
+    }
+
+    /// The open window `ms` falls in, opened if need be; `None` if it is final
+    fn window_at(&mut self, ms: i64) -> Option<&mut Window> {
+        let len = self.window.as_millis() as i64;
+        // e.g. a reading stamped before the clock was set
+        let stale = ms < clock().now_millis() - len - self.target.as_millis() as i64;
+        if stale || ms < self.state.final_until_ms {
+            return None;
+        }
+        let start_ms = ms - ms.rem_euclid(len);
+        let open = &mut self.state.open;
+        let index = match open.iter().position(|w| w.start_ms >= start_ms) {
+            Some(i) if open[i].start_ms == start_ms => i,
+            position => {
+                let i = position.unwrap_or(open.len());
+                let window = Window {
+                    start_ms,
+                    ..Window::default()
+                };
+                open.insert(i, window);
+                i
+            }
+        };
+        open.get_mut(index)
+    }
+
+    /// Queue the windows no row can be on time for anymore
+    fn finalize(&mut self, now_ms: i64) {
+        let len = self.window.as_millis() as i64;
+        let target_ms = self.target.as_millis() as i64;
+        while let Some(window) = self.state.open.front() {
+            if window.start_ms + len + target_ms > now_ms {
+                break;
+            }
+            let Some(window) = self.state.open.pop_front() else {
+                break;
+            };
+            self.state.final_until_ms = window.start_ms + len;
+            if self.state.queued.len() == MAX_QUEUED {
+                self.state.queued.pop_front();
+            }
+            self.state.queued.push_back(window);
+        }
+    }
+}
+
+fn percent(part: u64, whole: u64) -> f32 {
+    if whole == 0 {
+        100.0
+    } else {
+        // Rows flushed before the window opened can still be on time in it
+        (part.min(whole) as f64 * 100.0 / whole as f64) as f32
+    }
+}
+
+/// One `sla_metrics` row, in `SLA_COLUMNS` order
+fn row(window: &Window, window_s: i64) -> Vec<Cell> {
+    let linked_ms = window.connected_ms + window.disconnected_ms;
+    let expected = window.rows_flushed + window.samples_lost;
+    vec![
+        Cell::Long(window.start_ms),
+        Cell::Long(window_s),
+        Cell::Long((window.connected_ms / 1000) as i64),
+        Cell::Long((window.disconnected_ms / 1000) as i64),
+        Cell::Float(percent(window.connected_ms, linked_ms)),
+        Cell::Long(window.upload_attempts.into()),
+        Cell::Long(window.upload_successes.into()),
+        Cell::Float(percent(
+            window.upload_successes.into(),
+            window.upload_attempts.into(),
+        )),
+        Cell::Long(window.samples_lost.into()),
+        Cell::Long(expected.into()),
+        Cell::Long(window.rows_on_time.into()),
+        Cell::Float(percent(window.rows_on_time.into(), expected.into())),
+    ]
+}
@@ -0,0 +1,74 @@
+//! Pipeline state carried across deep sleep and reboots
+//!
+//! Deep sleep, a freshness reboot, re-provisioning and an OTA update all go
+//! down with state only RAM holds: alert states, open rollups, queued health
+//! rows, SLA windows, batch sketches and the daily report. It is taken from
+//! each component into one `SleepState`, written as a single JSON file on
+//! the flash storage partition, and handed back by the next boot, which
+//! deletes the file. Parts that have nothing to carry are left out, and a
+//! part that is missing from the file starts over.
+//!
+//! Buffered batches have their own binary format, see
+//! `OfflineBuffer::persist`, and the retention tiering keeps its state on the
+//! SD card.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::aggregation::RollupState;
+use crate::alerts::AlertState;
+use crate::diagnostics::HealthState;
+use crate::report::ReportState;
+use crate::sketches::SketchState;
+use crate::sla::SlaState;
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SleepState {
+    pub alerts: Option<AlertState>,
+    pub rollups: Option<RollupState>,
+    pub health: Option<HealthState>,
+    pub sla: Option<SlaState>,
+    pub sketches: Option<SketchState>,
+    pub report: Option<ReportState>,
+}
+
+impl SleepState {
+    /// Write the state to `path`, or remove a stale file if there is none
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let empty = self.alerts.is_none()
+            && self.rollups.is_none()
+            && self.health.is_none()
+            && self.sla.is_none()
+            && self.sketches.is_none()
+            && self.report.is_none();
+        if empty {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+        let data = serde_json::to_vec(self)?;
+        fs::write(path, &data)?;
+        info!("Saved the pipeline state ({} bytes) to {}", data.len(), path.display());
+        Ok(())
+    }
+
+    /// Load what `save` wrote at `path` and delete the file; empty if there
+    /// is none
+    pub fn take(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let data = fs::read(path);
+        // Delete first: a corrupt file must not be retried on every boot
+        fs::remove_file(path)?;
+        let state = serde_json::from_slice(&data?)?;
+        info!("Restored the pipeline state from {}", path.display());
+        Ok(state)
+    }
+}
@@ -0,0 +1,367 @@
+//! Signed maintenance SQL tasks, queued in a `maintenance_tasks` table
+//!
+//! Operators queue one-off maintenance for one device or the whole fleet
+//! without an OTA by writing `<data_path>/maintenance_tasks/tasks.parquet`:
+//!
+//! | Column | Content |
+//! | ------ | ------- |
+//! | `task_id` | BIGINT, increasing: each device runs a task once |
+//! | `sql` | The statement |
+//! | `device_id` | The device to run it, NULL or empty for every device |
+//! | `signature` | Hex DER ECDSA P-256 signature over `task_id\|device_id\|sql` |
+//!
+//! The signature must verify against `task_key`, the operator's public key
+//! (SEC1, hex), so write access to the bucket alone can't run anything.
+//! Only statements the device can carry out are on the allow-list:
+//!
+//! - `CHECKPOINT`, `CALL ducklake_merge_adjacent_files()` and
+//!   `CALL ducklake_expire_snapshots()`: a forced lake maintenance run,
+//!   i.e. merging small files and expiring old snapshots
+//! - `SELECT ...` in the subset of `query.rs`, against the lake catalog
+//! - `ALTER TABLE <table> ADD [COLUMN] <column> [<type>]` of a sensor table,
+//!   for a column of a schema migration this firmware knows (see
+//!   `lake/migrations.rs`): the table is migrated right away rather than
+//!   with its next batch. Columns the firmware doesn't write are rejected.
+//!
+//! Anything else (`DROP`, `DELETE`, ...) is rejected. The table is fetched
+//! with the first forwarding round after boot and then every `task_m`
+//! minutes, independent of lake maintenance, and new tasks run in `task_id`
+//! order. Every outcome, with a query's result, is a `maintenance_task` row
+//! of `device_events`. The highest `task_id` handled is kept in NVS.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use embedded_svc::http::Method;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use log::{info, warn};
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::Field;
+use rusty_s3::S3Action;
+
+use crate::clock::clock;
+use crate::config::DeviceConfig;
+use crate::enrollment::unhex;
+use crate::lake::{self, ColumnType, LakeBackend, S3Target, SCHEMA_VERSION};
+use crate::net::{send_capped, status_error, with_retry};
+use crate::query::{self, ResultSet};
+use crate::{events, UPLOAD_RETRY};
+
+const NAMESPACE: &str = "sql_tasks";
+const KEY_LAST_ID: &str = "last_id";
+const TASKS_TABLE: &str = "maintenance_tasks";
+const TASKS_FILE: &str = "tasks.parquet";
+const MAX_TASKS_LEN: usize = 32 * 1024;
+const PRESIGN_EXPIRY: Duration = Duration::from_secs(300);
+// Of a query's result in the event detail
+const MAX_RESULT_ROWS: usize = 5;
+
+const MAINTENANCE_STATEMENTS: [&str; 3] = [
+    "checkpoint",
+    "call ducklake_merge_adjacent_files()",
+    "call ducklake_expire_snapshots()",
+];
+
+struct Task {
+    id: i64,
+    sql: String,
+    device_id: String,
+    signature: String,
+}
+
+/// What a statement on the allow-list does
+enum Statement<'a> {
+    Maintain,
+    Select(&'a str),
+    AddColumn {
+        table: String,
+        column: String,
+        version: u32,
+    },
+}
+
+pub struct SqlTasks {
+    nvs: EspNvs<NvsDefault>,
+    key: String,
+    device_id: String,
+    // The sensor table, which takes `ALTER TABLE ... ADD COLUMN`
+    table: String,
+    // `None` without `task_key`
+    verifier: Option<VerifyingKey>,
+    // `None` with `task_m` = 0
+    interval: Option<Duration>,
+    // Monotonic time of the next fetch
+    next_fetch: Duration,
+    last_id: i64,
+}
+
+impl SqlTasks {
+    pub fn load(
+        partition: EspDefaultNvsPartition,
+        config: &DeviceConfig,
+        device_id: &str,
+    ) -> Result<Self> {
+        let nvs: EspNvs<NvsDefault> = EspNvs::new(partition, NAMESPACE, true)?;
+        let verifier = match config.task_key.trim() {
+            "" => None,
+            key => unhex(key)
+                .ok()
+                .and_then(|key| VerifyingKey::from_sec1_bytes(&key).ok())
+                .or_else(|| {
+                    warn!("task_key is not a P-256 public key, no maintenance tasks");
+                    None
+                }),
+        };
+        Ok(Self {
+            last_id: nvs.get_i64(KEY_LAST_ID)?.unwrap_or(0),
+            nvs,
+            key: format!("{}/{}", config.path_for(TASKS_TABLE), TASKS_FILE),
+            device_id: device_id.to_string(),
+            table: config.table_name.clone(),
+            verifier,
+            interval: (config.task_mins > 0)
+                .then(|| Duration::from_secs(u64::from(config.task_mins) * 60)),
+            next_fetch: Duration::ZERO,
+        })
+    }
+
+    /// True if the tasks table is due for a fetch
+    pub fn is_due(&self) -> bool {
+        self.verifier.is_some()
+            && self.interval.is_some()
+            && clock().monotonic() >= self.next_fetch
+    }
+
+    /// Fetch the tasks table and run the tasks not handled yet; `maintenance`
+    /// is false while the feature flags switch lake maintenance off
+    pub fn run(&mut self, lake: &mut dyn LakeBackend, target: &S3Target, maintenance: bool) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        self.next_fetch = clock().monotonic() + interval;
+        let mut tasks = match self.fetch(target) {
+            Ok(tasks) => tasks,
+            Err(e) => {
+                warn!("Maintenance tasks fetch failed: {:?}", e);
+                return;
+            }
+        };
+        tasks.retain(|task| task.id > self.last_id);
+        tasks.sort_by_key(|task| task.id);
+
+        for task in tasks {
+            let outcome = self.execute(&task, lake, target, maintenance);
+            let detail = match &outcome {
+                Ok(result) => format!("task {} ok: {}", task.id, result),
+                Err(e) => format!("task {} rejected or failed: {:#}", task.id, e),
+            };
+            match outcome {
+                Ok(_) => info!("Maintenance {}", detail),
+                Err(_) => warn!("Maintenance {}", detail),
+            }
+            events::report(events::MAINTENANCE_TASK, TASKS_TABLE, detail);
+            // Failed tasks aren't retried, the operator queues a new one
+            self.last_id = task.id;
+            if let Err(e) = self.nvs.set_i64(KEY_LAST_ID, task.id) {
+                warn!("Failed to save the last maintenance task: {:?}", e);
+            }
+        }
+    }
+
+    fn execute(
+        &self,
+        task: &Task,
+        lake: &mut dyn LakeBackend,
+        target: &S3Target,
+        maintenance: bool,
+    ) -> Result<String> {
+        self.verify(task)?;
+        match statement(&task.sql)? {
+            Statement::Maintain if !maintenance => {
+                bail!("lake maintenance is switched off by the feature flags")
+            }
+            Statement::Maintain => {
+                lake.maintain(target, true)?;
+                Ok("lake maintenance ran".to_string())
+            }
+            Statement::Select(sql) => {
+                let Some(catalog) = lake.catalog() else {
+                    bail!("the '{}' lake backend has no local catalog", lake.name());
+                };
+                Ok(summarize(&query::execute(sql, catalog)?))
+            }
+            Statement::AddColumn {
+                table,
+                column,
+                version,
+            } => {
+                // The statement is lowercased, the sensor table may not be
+                let table = if table.eq_ignore_ascii_case(&self.table) {
+                    self.table.clone()
+                } else {
+                    table
+                };
+                let versioned = lake.catalog().and_then(|c| c.schema_version(&table));
+                if table != self.table && versioned.is_none() {
+                    bail!("'{}' is not a sensor table, only they have migrations", table);
+                }
+                // Idempotent, and brings the table to the firmware's schema
+                lake.create_table(target, &table)?;
+                Ok(format!(
+                    "'{}' migrated to schema v{}, '{}' added with v{}",
+                    table, SCHEMA_VERSION, column, version
+                ))
+            }
+        }
+    }
+
+    fn verify(&self, task: &Task) -> Result<()> {
+        let Some(verifier) = &self.verifier else {
+            bail!("no task_key");
+        };
+        let signature = Signature::from_der(&unhex(&task.signature)?)
+            .map_err(|_| anyhow!("malformed signature"))?;
+        let message = format!("{}|{}|{}", task.id, task.device_id, task.sql);
+        verifier
+            .verify(message.as_bytes(), &signature)
+            .map_err(|_| anyhow!("bad signature"))
+    }
+
+    fn fetch(&self, target: &S3Target) -> Result<Vec<Task>> {
+        let (bucket, credentials) = target.router.route(TASKS_TABLE, target.credentials);
+        let s3 = credentials.to_rusty_s3();
+        let url = bucket.get_object(Some(&s3), &self.key).sign(PRESIGN_EXPIRY);
+        let body = with_retry(&UPLOAD_RETRY, "Maintenance tasks download", || {
+            match send_capped(Method::Get, url.as_str(), "", None, &[], MAX_TASKS_LEN)? {
+                (200, body) => Ok(Some(body)),
+                // No table: nothing queued
+                (404, _) => Ok(None),
+                (status, body) => Err(status_error(status, &body)),
+            }
+        })?;
+        match body {
+            Some(body) => self.parse(body),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Tasks for this device or the fleet
+    fn parse(&self, data: Vec<u8>) -> Result<Vec<Task>> {
+        let reader = SerializedFileReader::new(Bytes::from(data))?;
+        let mut tasks = Vec::new();
+
+        for row in reader.get_row_iter(None)? {
+            let row = row?;
+            let (mut id, mut sql, mut device_id, mut signature) = (None, None, "", None);
+            for (name, field) in row.get_column_iter() {
+                match (name.as_str(), field) {
+                    ("task_id", Field::Long(value)) => id = Some(*value),
+                    ("task_id", Field::Int(value)) => id = Some(i64::from(*value)),
+                    ("sql", Field::Str(value)) => sql = Some(value.as_str()),
+                    ("device_id", Field::Str(value)) => device_id = value.as_str(),
+                    ("signature", Field::Str(value)) => signature = Some(value.as_str()),
+                    _ => {}
+                }
+            }
+            let (Some(id), Some(sql), Some(signature)) = (id, sql, signature) else {
+                bail!("task rows need 'task_id', 'sql' and 'signature'");
+            };
+            if !device_id.is_empty() && device_id != self.device_id {
+                continue;
+            }
+            tasks.push(Task {
+                id,
+                sql: sql.to_string(),
+                device_id: device_id.to_string(),
+                signature: signature.trim().to_string(),
+            });
+        }
+        Ok(tasks)
+    }
+}
+
+/// `sql` checked against the allow-list
+fn statement(sql: &str) -> Result<Statement<'_>> {
+    let sql = sql.trim().trim_end_matches(';').trim();
+    let normalized = sql.split_whitespace().collect::<Vec<_>>().join(" ");
+    let normalized = normalized.to_ascii_lowercase();
+    if MAINTENANCE_STATEMENTS.contains(&normalized.as_str()) {
+        return Ok(Statement::Maintain);
+    }
+    if normalized.starts_with("select ") || normalized.starts_with("from ") {
+        return Ok(Statement::Select(sql));
+    }
+    if let Some(alter) = normalized.strip_prefix("alter table ") {
+        return add_column(alter);
+    }
+    let verb = normalized.split(' ').next().unwrap_or_default();
+    bail!("'{}' statements are not on the allow-list", verb)
+}
+
+/// `<table> ADD [COLUMN] [IF NOT EXISTS] <column> [<type>]` of a lowercased
+/// `ALTER TABLE`, for a column a schema migration adds
+fn add_column(alter: &str) -> Result<Statement<'static>> {
+    let words: Vec<&str> = alter.split(' ').map(|word| word.trim_matches('"')).collect();
+    let (table, rest) = match words.as_slice() {
+        [table, "add", "column", rest @ ..] | [table, "add", rest @ ..] => (*table, rest),
+        _ => bail!("only ALTER TABLE ... ADD COLUMN is on the allow-list"),
+    };
+    let rest = match rest {
+        ["if", "not", "exists", rest @ ..] => rest,
+        rest => rest,
+    };
+    let (column, kind) = match rest {
+        [column] => (*column, None),
+        [column, kind] => (*column, Some(*kind)),
+        _ => bail!("ADD COLUMN takes a column name and an optional type"),
+    };
+    let Some((version, expected)) = lake::migration_adding(column) else {
+        bail!("no schema migration of this firmware adds a '{}' column", column);
+    };
+    if let Some(kind) = kind.filter(|kind| !type_matches(expected, kind)) {
+        bail!("'{}' is {:?} in its migration, not {}", column, expected, kind);
+    }
+    Ok(Statement::AddColumn {
+        table: table.to_string(),
+        column: column.to_string(),
+        version,
+    })
+}
+
+/// True if `name` is a DuckDB spelling of `kind`
+fn type_matches(kind: ColumnType, name: &str) -> bool {
+    let names: &[&str] = match kind {
+        ColumnType::Long => &["bigint", "int8", "long"],
+        ColumnType::Float => &["float", "float4", "real"],
+        ColumnType::Boolean => &["boolean", "bool", "logical"],
+        ColumnType::Text => &["varchar", "text", "string"],
+    };
+    names.contains(&name)
+}
+
+/// The first rows of a query result, for the event detail
+fn summarize(result: &ResultSet) -> String {
+    let rows: Vec<String> = result
+        .rows
+        .iter()
+        .take(MAX_RESULT_ROWS)
+        .map(|row| {
+            let cells: Vec<String> = result
+                .columns
+                .iter()
+                .zip(row)
+                .map(|(column, value)| format!("{}={}", column, value))
+                .collect();
+            cells.join(", ")
+        })
+        .collect();
+    let more = result.rows.len().saturating_sub(MAX_RESULT_ROWS);
+    match (rows.is_empty(), more) {
+        (true, _) => "no rows".to_string(),
+        (false, 0) => rows.join("; "),
+        (false, more) => format!("{}; {} more rows", rows.join("; "), more),
+    }
+}
@@ -0,0 +1,73 @@
+//! Wear-levelled FAT partition in internal flash, mounted at `/storage`
+//!
+//! Holds state that has to survive reboots, such as the lake catalog. The
+//! partition (`storage` in `partitions.csv`) is formatted on first mount.
+//! While the pipeline is paused (see `pause.rs`) it is unmounted, so every
+//! write has reached the flash, and mounted again on resume.
+
+use std::ffi::CString;
+
+use anyhow::Result;
+use esp_idf_svc::sys::{
+    esp, esp_vfs_fat_mount_config_t, esp_vfs_fat_spiflash_mount_rw_wl,
+    esp_vfs_fat_spiflash_unmount_rw_wl, wl_handle_t,
+};
+use log::info;
+
+pub const MOUNT_POINT: &str = "/storage";
+const PARTITION_LABEL: &str = "storage";
+const MAX_OPEN_FILES: i32 = 4;
+
+/// Mounted partition, released only while the pipeline is paused
+pub struct FlashStorage {
+    // `None` while released
+    handle: Option<wl_handle_t>,
+}
+
+pub fn mount() -> Result<FlashStorage> {
+    let mut storage = FlashStorage { handle: None };
+    storage.remount()?;
+    Ok(storage)
+}
+
+impl FlashStorage {
+    /// Unmount the partition, flushing the FAT and wear-levelling caches
+    pub fn release(&mut self) -> Result<()> {
+        let Some(handle) = self.handle else {
+            return Ok(());
+        };
+        let base_path = CString::new(MOUNT_POINT)?;
+        esp!(unsafe { esp_vfs_fat_spiflash_unmount_rw_wl(base_path.as_ptr(), handle) })?;
+        self.handle = None;
+        info!("Flash storage unmounted");
+        Ok(())
+    }
+
+    /// Mount the partition, the first time or again after `release`
+    pub fn remount(&mut self) -> Result<()> {
+        if self.handle.is_some() {
+            return Ok(());
+        }
+        let base_path = CString::new(MOUNT_POINT)?;
+        let label = CString::new(PARTITION_LABEL)?;
+        let config = esp_vfs_fat_mount_config_t {
+            format_if_mount_failed: true,
+            max_files: MAX_OPEN_FILES,
+            allocation_unit_size: 4096,
+            ..Default::default()
+        };
+
+        let mut handle: wl_handle_t = 0;
+        esp!(unsafe {
+            esp_vfs_fat_spiflash_mount_rw_wl(
+                base_path.as_ptr(),
+                label.as_ptr(),
+                &config,
+                &mut handle,
+            )
+        })?;
+        info!("Flash storage mounted at {}", MOUNT_POINT);
+        self.handle = Some(handle);
+        Ok(())
+    }
+}
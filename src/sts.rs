@@ -0,0 +1,308 @@
+//! Temporary S3 credentials from STS or a fleet token endpoint
+//!
+//! With `cred_src` set to something other than `static`, uploads are signed
+//! with short-lived credentials (access key, secret key, session token)
+//! instead of the provisioned keys themselves:
+//!
+//! - `sts`: AssumeRole on `sts_role` with the provisioned keys, against AWS
+//!   STS or the STS API of the S3-compatible endpoint (MinIO). Needs the
+//!   `sts` feature for the SigV4-signed request.
+//! - `token`: the device POSTs its ID and a timestamp, signed with the
+//!   enrollment device key, to `cred_url` and gets credentials back. No
+//!   long-lived keys are provisioned at all.
+//!
+//! Sessions are refreshed ahead of their expiration, and right away when S3
+//! rejects them as expired. Every request is signed with the current session,
+//! so a refresh applies to the next upload.
+
+use std::time::Duration;
+
+#[cfg(feature = "sts")]
+use anyhow::anyhow;
+use anyhow::{bail, Result};
+#[cfg(feature = "sts")]
+use embedded_svc::http::Method;
+use log::{info, warn};
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{self, clock};
+use crate::config::DeviceConfig;
+use crate::credentials::S3Credentials;
+use crate::enrollment;
+#[cfg(feature = "sts")]
+use crate::net;
+#[cfg(feature = "sts")]
+use crate::profiles::S3Endpoint;
+use crate::secrets::SecretStore;
+#[cfg(feature = "sts")]
+use crate::sigv4;
+
+// Refresh this long before the session expires, so no upload races it
+const REFRESH_MARGIN: Duration = Duration::from_secs(300);
+#[cfg(feature = "sts")]
+const MAX_STS_RESPONSE_LEN: usize = 8192;
+// STS limits for AssumeRole sessions
+#[cfg(feature = "sts")]
+const MIN_SESSION_SECS: u32 = 900;
+#[cfg(feature = "sts")]
+const MAX_SESSION_SECS: u32 = 43_200;
+
+/// Credentials valid until `expires_at_ms` (Unix epoch milliseconds)
+pub struct Session {
+    pub credentials: S3Credentials,
+    pub expires_at_ms: i64,
+}
+
+/// Source of temporary credentials
+pub trait CredentialProvider: Send {
+    fn name(&self) -> &'static str;
+
+    /// Request a new session; `base` are the long-lived keys, if any
+    fn fetch(&mut self, base: &S3Credentials) -> Result<Session>;
+}
+
+// ============================================================================
+// STS ASSUMEROLE
+// ============================================================================
+
+#[cfg(feature = "sts")]
+pub struct AssumeRole {
+    url: String,
+    region: String,
+    role_arn: String,
+    session_name: String,
+    duration_secs: u32,
+}
+
+#[cfg(feature = "sts")]
+impl CredentialProvider for AssumeRole {
+    fn name(&self) -> &'static str {
+        "STS AssumeRole"
+    }
+
+    fn fetch(&mut self, base: &S3Credentials) -> Result<Session> {
+        let body = format!(
+            "Action=AssumeRole&Version=2011-06-15&RoleArn={}&RoleSessionName={}\
+             &DurationSeconds={}",
+            form_encode(&self.role_arn),
+            form_encode(&self.session_name),
+            self.duration_secs
+        );
+        let signed = sigv4::sign(
+            "sts",
+            "POST",
+            net::url_host(&self.url),
+            "/",
+            &self.region,
+            base,
+            body.as_bytes(),
+            clock().now_millis(),
+        );
+        let mut headers = vec![
+            ("Content-Type", "application/x-www-form-urlencoded"),
+            ("Authorization", signed.authorization.as_str()),
+            ("x-amz-date", signed.amz_date.as_str()),
+            ("x-amz-content-sha256", signed.content_sha256.as_str()),
+        ];
+        if let Some(token) = &signed.security_token {
+            headers.push(("x-amz-security-token", token.as_str()));
+        }
+
+        let url = format!("{}/", self.url);
+        let (status, response) = net::send_with_headers(
+            Method::Post,
+            &url,
+            &headers,
+            body.as_bytes(),
+            MAX_STS_RESPONSE_LEN,
+        )?;
+        if !(200..300).contains(&status) {
+            return Err(net::status_error(status, &response));
+        }
+
+        let xml = String::from_utf8_lossy(&response);
+        let tag = |name| xml_tag(&xml, name).ok_or_else(|| anyhow!("no {} from STS", name));
+        let credentials = S3Credentials::temporary(
+            tag("AccessKeyId")?,
+            tag("SecretAccessKey")?,
+            tag("SessionToken")?,
+        );
+        let expiration = tag("Expiration")?;
+        let expires_at_ms = clock::parse_utc(expiration)
+            .ok_or_else(|| anyhow!("invalid STS expiration '{}'", expiration))?;
+        Ok(Session {
+            credentials,
+            expires_at_ms,
+        })
+    }
+}
+
+/// Text of the first `<name>` element of `xml`
+#[cfg(feature = "sts")]
+fn xml_tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}>", name);
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find("</")? + start;
+    Some(xml[start..end].trim())
+}
+
+#[cfg(feature = "sts")]
+fn form_encode(value: &str) -> String {
+    sigv4::encode_key(value).replace('/', "%2F")
+}
+
+// ============================================================================
+// FLEET TOKEN ENDPOINT
+// ============================================================================
+
+#[derive(Serialize)]
+struct TokenRequest<'a> {
+    device_id: &'a str,
+    timestamp: u64,
+    /// DER ECDSA signature over `device_id|timestamp`, hex
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_key: String,
+    secret_key: String,
+    session_token: String,
+    /// `YYYY-MM-DDTHH:MM:SSZ`
+    expiration: String,
+}
+
+pub struct TokenEndpoint {
+    url: String,
+    device_id: String,
+    key: SigningKey,
+}
+
+impl CredentialProvider for TokenEndpoint {
+    fn name(&self) -> &'static str {
+        "token endpoint"
+    }
+
+    fn fetch(&mut self, _base: &S3Credentials) -> Result<Session> {
+        let timestamp = (clock().now_millis() / 1000) as u64;
+        let message = format!("{}|{}", self.device_id, timestamp);
+        let signature: Signature = self.key.sign(message.as_bytes());
+        let request = TokenRequest {
+            device_id: &self.device_id,
+            timestamp,
+            signature: enrollment::hex(signature.to_der().as_bytes()),
+        };
+
+        let (status, body) = enrollment::post_json(&self.url, &serde_json::to_vec(&request)?)?;
+        if status != 200 {
+            bail!(
+                "Token endpoint answered {}: {}",
+                status,
+                String::from_utf8_lossy(&body)
+            );
+        }
+        let response: TokenResponse = serde_json::from_slice(&body)?;
+        let Some(expires_at_ms) = clock::parse_utc(&response.expiration) else {
+            bail!("invalid token expiration '{}'", response.expiration);
+        };
+        Ok(Session {
+            credentials: S3Credentials::temporary(
+                &response.access_key,
+                &response.secret_key,
+                &response.session_token,
+            ),
+            expires_at_ms,
+        })
+    }
+}
+
+// ============================================================================
+// SESSION CACHE
+// ============================================================================
+
+pub struct TemporaryCredentials {
+    provider: Box<dyn CredentialProvider>,
+    session: Option<Session>,
+}
+
+impl TemporaryCredentials {
+    /// Provider selected by `cred_src`, `None` for the static keys
+    pub fn from_config(
+        config: &DeviceConfig,
+        secrets: &mut SecretStore,
+        device_id: &str,
+    ) -> Result<Option<Self>> {
+        let provider: Box<dyn CredentialProvider> = match config.credential_source.as_str() {
+            "" | "static" => return Ok(None),
+            #[cfg(feature = "sts")]
+            "sts" => {
+                if config.sts_role_arn.is_empty() {
+                    bail!("cred_src 'sts' needs sts_role");
+                }
+                let endpoint = S3Endpoint::from_config(config)?;
+                Box::new(AssumeRole {
+                    url: endpoint.sts_url(&config.s3_region),
+                    region: config.s3_region.clone(),
+                    role_arn: config.sts_role_arn.clone(),
+                    session_name: device_id.to_string(),
+                    duration_secs: config
+                        .credential_ttl_secs
+                        .clamp(MIN_SESSION_SECS, MAX_SESSION_SECS),
+                })
+            }
+            #[cfg(not(feature = "sts"))]
+            "sts" => bail!("cred_src 'sts' needs the `sts` feature"),
+            "token" => {
+                if config.credential_url.is_empty() {
+                    bail!("cred_src 'token' needs cred_url");
+                }
+                Box::new(TokenEndpoint {
+                    url: config.credential_url.clone(),
+                    device_id: device_id.to_string(),
+                    key: enrollment::device_key(secrets)?,
+                })
+            }
+            other => bail!(
+                "unknown credential source '{}', expected static, sts or token",
+                other
+            ),
+        };
+        info!("Temporary S3 credentials from the {}", provider.name());
+        Ok(Some(Self {
+            provider,
+            session: None,
+        }))
+    }
+
+    /// Request a session with `base`, without caching it (rotation checks)
+    pub fn fetch(&mut self, base: &S3Credentials) -> Result<S3Credentials> {
+        Ok(self.provider.fetch(base)?.credentials)
+    }
+
+    /// Credentials of the current session, refreshed if it is about to expire
+    pub fn current(&mut self, base: &S3Credentials) -> Result<S3Credentials> {
+        let refresh_at = clock().now_millis() + REFRESH_MARGIN.as_millis() as i64;
+        if let Some(session) = self.session.as_ref().filter(|s| s.expires_at_ms > refresh_at) {
+            return Ok(session.credentials.clone());
+        }
+
+        info!("Requesting temporary S3 credentials from the {}...", self.provider.name());
+        let session = self.provider.fetch(base)?;
+        let valid_secs = (session.expires_at_ms - clock().now_millis()) / 1000;
+        if session.expires_at_ms <= refresh_at {
+            warn!("Temporary S3 credentials expire in {}s, refreshing each round", valid_secs);
+        } else {
+            info!("Temporary S3 credentials valid for {}s", valid_secs);
+        }
+        let credentials = session.credentials.clone();
+        self.session = Some(session);
+        Ok(credentials)
+    }
+
+    /// Drop the session, e.g. after S3 rejected it as expired
+    pub fn invalidate(&mut self) {
+        self.session = None;
+    }
+}
@@ -0,0 +1,164 @@
+//! Pipeline tasks: sampler, batcher, uploader and maintenance
+//!
+//! Ingestion runs as four tasks, so a slow upload never holds up sampling:
+//!
+//! | Task | Thread | Does |
+//! | ---- | ------ | ---- |
+//! | Sampler | `sampler` (priority 10) | Reads the sensors (`sensors/task.rs`) |
+//! | Batcher | `batcher` (priority 5) | Applies the flush policy, cuts batches |
+//! | Uploader | main task | Buffers batches, uploads them and the auxiliary tables |
+//! | Maintenance | `maint` (priority 5) | Schedules lake maintenance |
+//!
+//! They are connected by bounded queues: the sampler hands samples to the
+//! batcher through a ring of 64, and the batcher and the maintenance timer
+//! send `Message`s to the uploader over a channel of `CHANNEL_CAPACITY`.
+//! Backpressure is explicit and counted all the way back:
+//!
+//! 1. While the uploader is busy (a round of uploads can take tens of
+//!    seconds), messages queue up in the channel.
+//! 2. Once the channel is full, samples for the alert rules are skipped and
+//!    the batcher blocks on the next batch until the uploader drains it.
+//! 3. A blocked batcher lets the sampler's ring fill, which then drops and
+//!    counts the newest samples (`sampling.dropped` on `/health`).
+//!
+//! The catalog has a single writer, the uploader: maintenance is only
+//! scheduled here and runs on the uploader between rounds.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{info, warn};
+
+use crate::clock::clock;
+use crate::pipeline::{free_heap, FlushPolicy, FlushReason};
+use crate::sensors::{SamplerTask, SensorReading};
+
+// Messages the uploader may fall behind by
+const CHANNEL_CAPACITY: usize = 16;
+const BATCHER_STACK_SIZE: usize = 8192;
+const MAINTENANCE_STACK_SIZE: usize = 4096;
+// How often the batcher drains the sampler's ring
+const DRAIN_INTERVAL: Duration = Duration::from_millis(250);
+// How often maintenance is offered to the uploader; the catalog knows if it is due
+const MAINTENANCE_CHECK: Duration = Duration::from_secs(5 * 60);
+
+static ALERT_SKIPS: AtomicU32 = AtomicU32::new(0);
+static STALLS: AtomicU32 = AtomicU32::new(0);
+static STALLED_MS: AtomicU32 = AtomicU32::new(0);
+
+/// What the batcher and the maintenance timer send the uploader
+pub enum Message {
+    /// Every sample, recorded or not, for the alert rules
+    Sample(SensorReading),
+    /// Readings to flush
+    Batch {
+        readings: Vec<SensorReading>,
+        reason: FlushReason,
+    },
+    /// Run lake maintenance if it is due
+    Maintain,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PipelineStats {
+    /// Samples the alert rules didn't see because the uploader was behind
+    pub alert_skips: u32,
+    /// Times the batcher had to wait for the uploader to take a batch
+    pub stalls: u32,
+    /// Longest of those waits
+    pub max_stall_ms: u32,
+}
+
+/// Counters since boot
+pub fn pipeline_stats() -> PipelineStats {
+    PipelineStats {
+        alert_skips: ALERT_SKIPS.load(Ordering::Relaxed),
+        stalls: STALLS.load(Ordering::Relaxed),
+        max_stall_ms: STALLED_MS.load(Ordering::Relaxed),
+    }
+}
+
+/// Start the batcher (and the maintenance timer with `maint_m` > 0); the
+/// uploader reads their messages off the returned receiver
+pub fn start(
+    sampler: SamplerTask,
+    policy: FlushPolicy,
+    maintenance_mins: u32,
+) -> Result<Receiver<Message>> {
+    let (sender, messages) = mpsc::sync_channel(CHANNEL_CAPACITY);
+
+    if maintenance_mins > 0 {
+        let sender = sender.clone();
+        let interval = Duration::from_secs(u64::from(maintenance_mins) * 60);
+        let every = MAINTENANCE_CHECK.min(interval);
+        thread::Builder::new()
+            .name("maint".into())
+            .stack_size(MAINTENANCE_STACK_SIZE)
+            .spawn(move || loop {
+                clock().sleep(every);
+                // Already queued if the channel is full, and the uploader will get to it
+                if let Err(TrySendError::Disconnected(_)) = sender.try_send(Message::Maintain) {
+                    return;
+                }
+            })?;
+    }
+
+    thread::Builder::new()
+        .name("batcher".into())
+        .stack_size(BATCHER_STACK_SIZE)
+        .spawn(move || batch(sampler, policy, sender))?;
+    info!(
+        "Pipeline tasks started (uploader queue of {}, maintenance {})",
+        CHANNEL_CAPACITY,
+        if maintenance_mins > 0 { "scheduled" } else { "off" }
+    );
+    Ok(messages)
+}
+
+fn batch(sampler: SamplerTask, policy: FlushPolicy, sender: SyncSender<Message>) {
+    let mut pending: Vec<SensorReading> = Vec::with_capacity(policy.max_rows);
+    let mut batch_started = clock().monotonic();
+    loop {
+        while let Some(tick) = sampler.pop() {
+            // Alerts see every sample, including the ones the deadband didn't record
+            match sender.try_send(Message::Sample(tick.reading.clone())) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    ALERT_SKIPS.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(TrySendError::Disconnected(_)) => return,
+            }
+            if tick.recorded {
+                pending.push(tick.reading);
+            }
+        }
+
+        let batch_age = clock().elapsed_since(batch_started);
+        if let Some(reason) = policy.check(pending.len(), batch_age, free_heap()) {
+            let readings = std::mem::replace(&mut pending, Vec::with_capacity(policy.max_rows));
+            let message = Message::Batch { readings, reason };
+            let message = match sender.try_send(message) {
+                Ok(()) => None,
+                Err(TrySendError::Full(message)) => Some(message),
+                Err(TrySendError::Disconnected(_)) => return,
+            };
+            // The uploader is behind: wait for it, the sampler's ring absorbs the delay
+            if let Some(message) = message {
+                let stalled = clock().monotonic();
+                if sender.send(message).is_err() {
+                    return;
+                }
+                let stalled_ms = clock().elapsed_since(stalled).as_millis() as u32;
+                STALLS.fetch_add(1, Ordering::Relaxed);
+                STALLED_MS.fetch_max(stalled_ms, Ordering::Relaxed);
+                warn!("Batcher waited {} ms for the uploader", stalled_ms);
+            }
+            batch_started = clock().monotonic();
+        }
+
+        clock().sleep(DRAIN_INTERVAL);
+    }
+}
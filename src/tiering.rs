@@ -0,0 +1,358 @@
+//! Retention tiering: full-rate raw data on the SD card, aggregates in the lake
+//!
+//! With `raw_days` set, flushed on-board readings are not uploaded. They are
+//! appended to one CSV file per UTC day, `<sdcard>/raw/<table>/<YYYY-MM-DD>.csv`,
+//! and kept for `raw_days` days. What goes to the lake instead are averages
+//! over the `rollups` resolutions (e.g. `1m,1h`), one table per resolution:
+//! `<table>_1m`, `<table>_1h`. An aggregate row's timestamp is the start of
+//! its bucket and its `sample_interval_ms` the bucket length; rows taken
+//! while a sensor was warming up are left out of the averages.
+//!
+//! After an incident, the raw rows of a time range can be promoted to the
+//! lake with the console's `.promote <from> <to>` command. They are uploaded
+//! to `<table>` itself, a flush-sized batch at a time whenever the offline
+//! buffer has drained, so a long range never crowds out live data.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::aggregation::parse_resolution;
+use crate::clock::{clock, utc_date};
+use crate::config::DeviceConfig;
+use crate::sensors::{SensorReading, Source, METRIC_NAMES};
+
+const RAW_DIR: &str = "raw";
+// Open buckets and the promotion cursor, carried across deep sleep
+const STATE_FILE: &str = "tiering.json";
+const DAY_MS: i64 = 86_400_000;
+
+struct Rollup {
+    // Table suffix, the resolution as configured ("1m")
+    suffix: String,
+    resolution_ms: i64,
+    bucket: Option<Bucket>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Bucket {
+    start: i64,
+    sums: [f64; 9],
+    counts: [u32; 9],
+}
+
+impl Bucket {
+    fn new(start: i64) -> Self {
+        Self {
+            start,
+            sums: [0.0; 9],
+            counts: [0; 9],
+        }
+    }
+
+    fn add(&mut self, reading: &SensorReading) {
+        for (i, value) in reading.metrics().into_iter().enumerate() {
+            if value.is_finite() {
+                self.sums[i] += f64::from(value);
+                self.counts[i] += 1;
+            }
+        }
+    }
+
+    fn average(&self, resolution_ms: i64) -> SensorReading {
+        let mut metrics = [f32::NAN; 9];
+        for (i, metric) in metrics.iter_mut().enumerate() {
+            if self.counts[i] > 0 {
+                *metric = (self.sums[i] / f64::from(self.counts[i])) as f32;
+            }
+        }
+        let mut reading = SensorReading::empty(self.start);
+        reading.set_metrics(metrics);
+        reading.sample_interval_ms = resolution_ms as u32;
+        // Only on-board readings are tiered
+        reading.stamp(Source::Sensor);
+        reading
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Promotion {
+    table: String,
+    // First timestamp not promoted yet
+    next_ms: i64,
+    to_ms: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Persisted {
+    buckets: Vec<(String, Bucket)>,
+    promotion: Option<Promotion>,
+}
+
+pub struct Tiering {
+    root: PathBuf,
+    raw_days: u32,
+    rollups: Vec<Rollup>,
+    promotion: Option<Promotion>,
+    // Day the raw files were last pruned on
+    pruned_on: String,
+}
+
+impl Tiering {
+    /// Tiering below `<mount_point>/raw`, or None unless `raw_days` is set
+    pub fn new(mount_point: &str, config: &DeviceConfig) -> Result<Option<Self>> {
+        if config.raw_days == 0 {
+            return Ok(None);
+        }
+
+        let mut rollups = Vec::new();
+        for spec in config.rollups.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            rollups.push(Rollup {
+                suffix: spec.to_string(),
+                resolution_ms: parse_resolution(spec)?,
+                bucket: None,
+            });
+        }
+        if rollups.is_empty() {
+            bail!("raw data tiering needs at least one rollup resolution");
+        }
+
+        let root = Path::new(mount_point).join(RAW_DIR);
+        fs::create_dir_all(&root)?;
+        Ok(Some(Self {
+            root,
+            raw_days: config.raw_days,
+            rollups,
+            promotion: None,
+            pruned_on: String::new(),
+        }))
+    }
+
+    pub fn describe(&self) -> String {
+        let suffixes: Vec<&str> = self.rollups.iter().map(|r| r.suffix.as_str()).collect();
+        format!("raw kept {} day(s), rollups {}", self.raw_days, suffixes.join(", "))
+    }
+
+    /// Store `readings` of `table` on the card and return the completed aggregate batches
+    pub fn record(
+        &mut self,
+        table: &str,
+        readings: Vec<SensorReading>,
+    ) -> Vec<(String, Vec<SensorReading>)> {
+        if let Err(e) = self.append_raw(table, &readings) {
+            // Nothing else keeps them, so upload the raw rows rather than lose them
+            warn!("Failed to store raw readings on the SD card, uploading them: {:?}", e);
+            return vec![(table.to_string(), readings)];
+        }
+        self.prune();
+
+        let mut batches = Vec::new();
+        for rollup in &mut self.rollups {
+            let mut completed = Vec::new();
+            for reading in readings.iter().filter(|r| !r.warming_up) {
+                let start = reading.timestamp - reading.timestamp.rem_euclid(rollup.resolution_ms);
+                match &mut rollup.bucket {
+                    Some(bucket) if bucket.start == start => bucket.add(reading),
+                    bucket => {
+                        if let Some(done) = bucket.take() {
+                            completed.push(done.average(rollup.resolution_ms));
+                        }
+                        let mut next = Bucket::new(start);
+                        next.add(reading);
+                        *bucket = Some(next);
+                    }
+                }
+            }
+            if !completed.is_empty() {
+                batches.push((format!("{}_{}", table, rollup.suffix), completed));
+            }
+        }
+        batches
+    }
+
+    /// Queue the raw rows of `table` between `from_ms` and `to_ms` (inclusive) for upload
+    pub fn promote(&mut self, table: &str, from_ms: i64, to_ms: i64) {
+        if let Some(previous) = &self.promotion {
+            warn!(
+                "Replacing the unfinished promotion of '{}' (up to {})",
+                previous.table, previous.to_ms
+            );
+        }
+        info!(
+            "Promoting raw '{}' rows from {} to {}",
+            table,
+            utc_date(from_ms),
+            utc_date(to_ms)
+        );
+        self.promotion = Some(Promotion {
+            table: table.to_string(),
+            next_ms: from_ms,
+            to_ms,
+        });
+    }
+
+    /// The next batch of at most `max_rows` promoted raw rows, in time order
+    pub fn next_promoted(&mut self, max_rows: usize) -> Option<(String, Vec<SensorReading>)> {
+        let promotion = self.promotion.as_mut()?;
+        let mut rows = Vec::new();
+        let mut day = promotion.next_ms - promotion.next_ms.rem_euclid(DAY_MS);
+        while rows.is_empty() && day <= promotion.to_ms {
+            let path = self.root.join(&promotion.table).join(format!("{}.csv", utc_date(day)));
+            if path.exists() {
+                match read_raw(&path, promotion.next_ms, promotion.to_ms, max_rows) {
+                    Ok(found) => rows = found,
+                    Err(e) => warn!("Skipping unreadable raw file {:?}: {:?}", path, e),
+                }
+            }
+            if rows.is_empty() {
+                day += DAY_MS;
+                promotion.next_ms = promotion.next_ms.max(day);
+            }
+        }
+
+        match rows.last() {
+            Some(last) => {
+                promotion.next_ms = last.timestamp + 1;
+                Some((promotion.table.clone(), rows))
+            }
+            None => {
+                info!("Promotion of raw '{}' rows complete", promotion.table);
+                self.promotion = None;
+                None
+            }
+        }
+    }
+
+    /// Save open buckets and the promotion before deep sleep
+    pub fn persist(&self) -> Result<()> {
+        let persisted = Persisted {
+            buckets: self
+                .rollups
+                .iter()
+                .filter_map(|r| Some((r.suffix.clone(), r.bucket.clone()?)))
+                .collect(),
+            promotion: self.promotion.clone(),
+        };
+        fs::write(self.root.join(STATE_FILE), serde_json::to_vec(&persisted)?)?;
+        Ok(())
+    }
+
+    /// Load what `persist` saved and delete the file
+    pub fn restore(&mut self) -> Result<()> {
+        let path = self.root.join(STATE_FILE);
+        if !path.exists() {
+            return Ok(());
+        }
+        let data = fs::read(&path)?;
+        fs::remove_file(&path)?;
+
+        let persisted: Persisted = serde_json::from_slice(&data)?;
+        for (suffix, bucket) in persisted.buckets {
+            if let Some(rollup) = self.rollups.iter_mut().find(|r| r.suffix == suffix) {
+                rollup.bucket = Some(bucket);
+            }
+        }
+        self.promotion = persisted.promotion;
+        Ok(())
+    }
+
+    fn append_raw(&self, table: &str, readings: &[SensorReading]) -> Result<()> {
+        let dir = self.root.join(table);
+        fs::create_dir_all(&dir)?;
+
+        let mut open: Option<(String, File)> = None;
+        for reading in readings {
+            let date = utc_date(reading.timestamp);
+            let file = match &mut open {
+                Some((open_date, file)) if *open_date == date => file,
+                slot => {
+                    let path = dir.join(format!("{}.csv", date));
+                    let is_new = !path.exists();
+                    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+                    if is_new {
+                        let header = METRIC_NAMES.join(",");
+                        writeln!(file, "timestamp,{},sample_interval_ms,warming_up", header)?;
+                    }
+                    &mut slot.insert((date, file)).1
+                }
+            };
+
+            let mut line = reading.timestamp.to_string();
+            for value in reading.metrics() {
+                line.push(',');
+                if !value.is_nan() {
+                    line += &value.to_string();
+                }
+            }
+            line += &format!(",{},{}", reading.sample_interval_ms, u8::from(reading.warming_up));
+            writeln!(file, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    /// Delete raw day files older than `raw_days`, once a day
+    fn prune(&mut self) {
+        let today = utc_date(clock().now_millis());
+        if today == self.pruned_on {
+            return;
+        }
+        self.pruned_on = today;
+
+        let cutoff = utc_date(clock().now_millis() - i64::from(self.raw_days) * DAY_MS);
+        let Ok(tables) = fs::read_dir(&self.root) else {
+            return;
+        };
+        for table in tables.flatten().filter(|e| e.path().is_dir()) {
+            let Ok(days) = fs::read_dir(table.path()) else {
+                continue;
+            };
+            for day in days.flatten() {
+                let name = day.file_name().to_string_lossy().to_string();
+                // `YYYY-MM-DD.csv` sorts by date
+                if name.ends_with(".csv") && name.as_str() < cutoff.as_str() {
+                    match fs::remove_file(day.path()) {
+                        Ok(()) => info!("Pruned raw data {:?}", day.path()),
+                        Err(e) => warn!("Failed to prune raw data {:?}: {:?}", day.path(), e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Up to `max_rows` rows of the raw file at `path` with `from_ms <= timestamp <= to_ms`
+fn read_raw(path: &Path, from_ms: i64, to_ms: i64, max_rows: usize) -> Result<Vec<SensorReading>> {
+    let mut rows = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines().skip(1) {
+        let line = line?;
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields.len() != 12 {
+            continue;
+        }
+        let Ok(timestamp) = fields[0].parse::<i64>() else {
+            continue;
+        };
+        if timestamp < from_ms || timestamp > to_ms {
+            continue;
+        }
+
+        let mut reading = SensorReading::empty(timestamp);
+        let mut metrics = [f32::NAN; 9];
+        for (metric, field) in metrics.iter_mut().zip(&fields[1..10]) {
+            *metric = field.parse().unwrap_or(f32::NAN);
+        }
+        reading.set_metrics(metrics);
+        reading.sample_interval_ms = fields[10].parse().unwrap_or(0);
+        reading.warming_up = fields[11] == "1";
+        reading.stamp(Source::Replay);
+        rows.push(reading);
+        if rows.len() == max_rows {
+            break;
+        }
+    }
+    Ok(rows)
+}
@@ -0,0 +1,270 @@
+//! SNTP time sync with periodic resync and drift compensation
+//!
+//! SNTP keeps running after the first sync and resyncs every `ntp_sync_m`
+//! minutes against the `ntp_servers`. Each resync compares the new time with
+//! where the system clock should have been, logs the drift and feeds an
+//! estimate of the clock's rate error, which `SntpClock`, the clock the
+//! firmware installs (see `clock.rs`), then corrects for between resyncs.
+//!
+//! Time is trusted once synced, until no resync has succeeded for
+//! `ntp_trust_m` minutes. Untrusted time would break SigV4 signatures and
+//! timestamps in the lake, so S3 uploads pause (batches keep buffering)
+//! until a sync succeeds again. Every boot, deep sleep wakes included,
+//! starts untrusted: the RTC keeps poor time while the chip sleeps.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use esp_idf_svc::sntp::{EspSntp, SntpConf};
+use log::{info, warn};
+
+use crate::clock::{clock, Clock};
+use crate::config::DeviceConfig;
+
+// Rate estimates need the drift to stand out from network jitter
+const MIN_RATE_INTERVAL: Duration = Duration::from_secs(600);
+// Beyond this the clock was stepped (or set wrong), which isn't drift
+const MAX_DRIFT_PPM: f64 = 500.0;
+// Floor of `ntp_sync_m`, lwIP itself would go down to 15 s
+const MIN_SYNC_INTERVAL: Duration = Duration::from_secs(60);
+// Boot waits this long for the first SNTP sync
+const SYNC_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub struct SyncStatus {
+    pub trusted: bool,
+    pub syncs: u32,
+    /// Unix epoch milliseconds of the last sync
+    pub last_sync_ms: Option<i64>,
+    /// Drift found by the last resync
+    pub last_drift_ms: Option<i64>,
+    /// Estimated rate error compensated between resyncs
+    pub drift_ppm: f64,
+}
+
+struct State {
+    trust_window: Duration,
+    /// Monotonic time and the synced wall clock of the last sync
+    last_sync: Option<(Duration, i64)>,
+    syncs: u32,
+    last_drift_ms: Option<i64>,
+    rate_ppm: f64,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    trust_window: Duration::ZERO,
+    last_sync: None,
+    syncs: 0,
+    last_drift_ms: None,
+    rate_ppm: 0.0,
+});
+
+// Kept running for the periodic resync
+static SNTP: Mutex<Option<EspSntp<'static>>> = Mutex::new(None);
+
+/// The system time, corrected for the drift since the last SNTP sync and
+/// only trusted while syncs keep succeeding
+pub struct SntpClock {
+    start: Instant,
+}
+
+impl SntpClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SntpClock {
+    fn now_millis(&self) -> i64 {
+        let system = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or(0);
+        system + correction_ms(self.monotonic())
+    }
+
+    fn monotonic(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+
+    /// Wait for SNTP, which resyncs on its own afterwards
+    fn synchronize(&self) -> Result<()> {
+        wait_for_sync(SYNC_TIMEOUT)
+    }
+
+    fn is_trusted(&self) -> bool {
+        is_trusted()
+    }
+}
+
+/// Start SNTP with the configured servers; needs the network interface up
+pub fn start(config: &DeviceConfig) -> Result<()> {
+    let mut sntp = SNTP.lock().map_err(|_| anyhow!("SNTP state poisoned"))?;
+    if sntp.is_some() {
+        return Ok(());
+    }
+
+    let configured: Vec<&'static str> = config
+        .ntp_servers
+        .split(',')
+        .map(str::trim)
+        .filter(|server| !server.is_empty())
+        // Leaked once: SNTP holds on to the names for as long as it runs
+        .map(|server| &*Box::leak(server.to_string().into_boxed_str()))
+        .collect();
+    let mut conf = SntpConf::default();
+    if !configured.is_empty() {
+        // Slots beyond the configured servers repeat them
+        for (slot, server) in conf.servers.iter_mut().zip(configured.iter().cycle()) {
+            *slot = *server;
+        }
+    }
+
+    let interval =
+        Duration::from_secs(u64::from(config.ntp_sync_mins) * 60).max(MIN_SYNC_INTERVAL);
+    // Read by SNTP when it starts
+    unsafe { esp_idf_svc::sys::sntp_set_sync_interval(interval.as_millis() as u32) };
+    if let Ok(mut state) = STATE.lock() {
+        state.trust_window = Duration::from_secs(u64::from(config.time_trust_mins) * 60);
+    }
+
+    *sntp = Some(EspSntp::new_with_callback(&conf, on_sync)?);
+    info!(
+        "SNTP started ({}), resyncing every {} min",
+        conf.servers.join(", "),
+        interval.as_secs() / 60
+    );
+    Ok(())
+}
+
+/// Ask SNTP to sync now instead of at the next interval
+pub fn resync() {
+    if SNTP.lock().is_ok_and(|sntp| sntp.is_some()) {
+        unsafe { esp_idf_svc::sys::sntp_restart() };
+    }
+}
+
+/// Wait until time is trusted, kicking a resync if it isn't
+pub fn wait_for_sync(timeout: Duration) -> Result<()> {
+    if is_trusted() {
+        return Ok(());
+    }
+    if SNTP.lock().map_or(true, |sntp| sntp.is_none()) {
+        bail!("SNTP isn't started");
+    }
+
+    info!("Synchronizing time via SNTP...");
+    resync();
+    let started = clock().monotonic();
+    let mut last_report = 0;
+    while !is_trusted() {
+        let waited = clock().elapsed_since(started);
+        if waited >= timeout {
+            bail!("Timeout waiting for SNTP time sync");
+        }
+        // Progress every second
+        if waited.as_secs() > last_report {
+            last_report = waited.as_secs();
+            info!("  Waiting for time sync... ({}s)", last_report);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+/// True if time was synced recently enough to sign requests and stamp rows with
+pub fn is_trusted() -> bool {
+    let Ok(state) = STATE.lock() else {
+        return false;
+    };
+    match state.last_sync {
+        Some((at, _)) => {
+            state.trust_window.is_zero() || clock().elapsed_since(at) <= state.trust_window
+        }
+        None => false,
+    }
+}
+
+/// Milliseconds to add to the system clock for the drift since the last sync
+pub fn correction_ms(monotonic: Duration) -> i64 {
+    let Ok(state) = STATE.lock() else {
+        return 0;
+    };
+    match state.last_sync {
+        Some((at, _)) => {
+            let elapsed_ms = monotonic.saturating_sub(at).as_millis() as f64;
+            (elapsed_ms * state.rate_ppm / 1e6) as i64
+        }
+        None => 0,
+    }
+}
+
+pub fn status() -> Option<SyncStatus> {
+    let trusted = is_trusted();
+    let state = STATE.lock().ok()?;
+    Some(SyncStatus {
+        trusted,
+        syncs: state.syncs,
+        last_sync_ms: state.last_sync.map(|(_, wall)| wall),
+        last_drift_ms: state.last_drift_ms,
+        drift_ppm: state.rate_ppm,
+    })
+}
+
+/// SNTP callback (lwIP task), `now` is the time the clock was just set to
+fn on_sync(now: Duration) {
+    let now_ms = now.as_millis() as i64;
+    let monotonic = clock().monotonic();
+    let Ok(mut state) = STATE.lock() else {
+        return;
+    };
+    let previous = state.last_sync.replace((monotonic, now_ms));
+    state.syncs += 1;
+
+    let Some((at, wall_ms)) = previous else {
+        drop(state);
+        info!("Time synchronized! Unix timestamp: {}", now_ms / 1000);
+        return;
+    };
+    // The system clock ran at the monotonic rate since the last sync
+    let elapsed_ms = monotonic.saturating_sub(at).as_millis() as i64;
+    let drift_ms = now_ms - (wall_ms + elapsed_ms);
+    let compensated_ms = (elapsed_ms as f64 * state.rate_ppm / 1e6) as i64;
+    state.last_drift_ms = Some(drift_ms);
+
+    let ppm = drift_ms as f64 * 1e6 / elapsed_ms.max(1) as f64;
+    let stepped = ppm.abs() > MAX_DRIFT_PPM;
+    if !stepped && elapsed_ms >= MIN_RATE_INTERVAL.as_millis() as i64 {
+        state.rate_ppm = if state.rate_ppm == 0.0 {
+            ppm
+        } else {
+            (state.rate_ppm + ppm) / 2.0
+        };
+    }
+    let rate_ppm = state.rate_ppm;
+    drop(state);
+
+    if stepped {
+        warn!(
+            "Clock was off by {} ms after {} min, stepped without adjusting the drift rate",
+            drift_ms,
+            elapsed_ms / 60_000
+        );
+    } else {
+        info!(
+            "Time resynced: drift {:+} ms over {} min ({:+.1} ppm, {:+} ms after compensation), \
+             rate now {:+.1} ppm",
+            drift_ms,
+            elapsed_ms / 60_000,
+            ppm,
+            drift_ms - compensated_ms,
+            rate_ppm
+        );
+    }
+}
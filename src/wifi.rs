@@ -0,0 +1,405 @@
+//! WiFi station with network failover and a reconnection watcher
+//!
+//! The device knows an ordered list of networks: the primary one (`wifi_ssid`
+//! / `wifi_pass`, secured per `wifi_auth`) followed by the fallbacks in
+//! `wifi_nets`. `connect()` tries them in order, starting with the one that
+//! connected last, each with its own auth method:
+//!
+//! - `open`, `wpa2` (WPA2 or better) and `wpa3` (WPA3-SAE only)
+//! - `enterprise`: WPA2-Enterprise, PEAP / TTLS with the identity and
+//!   password, or EAP-TLS if a client certificate and key are in encrypted
+//!   storage. The RADIUS server is verified against `wifi_ca` if set.
+//!
+//! Once started, a watcher thread checks the link every few seconds and
+//! reconnects when it drops, so uploads resume without a reboot.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use embedded_svc::wifi::{AuthMethod, ClientConfiguration, Configuration};
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sys::{self, esp};
+use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::clock::clock;
+use crate::config::DeviceConfig;
+use crate::secrets::SecretStore;
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+const WATCHER_STACK_SIZE: usize = 6144;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WifiAuth {
+    Open,
+    Wpa2,
+    Wpa3,
+    Enterprise,
+}
+
+impl WifiAuth {
+    pub fn parse(value: &str) -> Result<Self> {
+        Ok(match value.trim().to_ascii_lowercase().as_str() {
+            "open" | "none" => Self::Open,
+            "" | "wpa2" => Self::Wpa2,
+            "wpa3" => Self::Wpa3,
+            "enterprise" | "wpa2-enterprise" => Self::Enterprise,
+            other => bail!(
+                "unknown WiFi auth '{}', expected open, wpa2, wpa3 or enterprise",
+                other
+            ),
+        })
+    }
+
+    /// Weakest auth mode accepted from an access point
+    fn method(self) -> AuthMethod {
+        match self {
+            Self::Open => AuthMethod::None,
+            Self::Wpa2 => AuthMethod::WPA2Personal,
+            Self::Wpa3 => AuthMethod::WPA3Personal,
+            Self::Enterprise => AuthMethod::WPA2Enterprise,
+        }
+    }
+}
+
+pub struct WifiNetwork {
+    pub ssid: String,
+    pub password: String,
+    pub auth: WifiAuth,
+    /// EAP identity (and PEAP / TTLS username) for `enterprise`
+    pub identity: String,
+}
+
+/// `wifi_nets` entry, e.g. `{"ssid": "lab", "pass": "...", "auth": "wpa3"}`
+#[derive(Deserialize)]
+struct NetworkEntry {
+    ssid: String,
+    #[serde(default)]
+    pass: String,
+    #[serde(default)]
+    auth: String,
+    #[serde(default)]
+    id: String,
+}
+
+/// The primary network followed by the `wifi_nets` fallbacks
+pub fn networks(config: &DeviceConfig) -> Result<Vec<WifiNetwork>> {
+    let mut networks = vec![WifiNetwork {
+        ssid: config.wifi_ssid.clone(),
+        password: config.wifi_password.clone(),
+        auth: WifiAuth::parse(&config.wifi_auth)?,
+        identity: config.wifi_identity.clone(),
+    }];
+    if !config.wifi_networks.trim().is_empty() {
+        let entries: Vec<NetworkEntry> = serde_json::from_str(&config.wifi_networks)
+            .map_err(|e| anyhow!("invalid wifi_nets: {}", e))?;
+        for entry in entries {
+            networks.push(WifiNetwork {
+                auth: WifiAuth::parse(&entry.auth)?,
+                ssid: entry.ssid,
+                password: entry.pass,
+                identity: entry.id,
+            });
+        }
+    }
+    Ok(networks)
+}
+
+// Certificates handed to the EAP client, which keeps pointers to them
+struct EapCertificates {
+    /// PEM with its terminating NUL, empty to skip server verification
+    ca: Vec<u8>,
+    /// PEM certificate and key for EAP-TLS, each NUL-terminated
+    client: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+struct Station {
+    wifi: BlockingWifi<EspWifi<'static>>,
+    networks: Vec<WifiNetwork>,
+    certificates: EapCertificates,
+    // Network that connected last, tried first
+    preferred: usize,
+    stopped: bool,
+}
+
+impl Station {
+    fn connect(&mut self) -> Result<usize> {
+        let count = self.networks.len();
+        let mut last_error = None;
+        for index in (0..count).map(|i| (self.preferred + i) % count) {
+            match self.connect_to(index) {
+                Ok(()) => {
+                    self.preferred = index;
+                    return Ok(index);
+                }
+                Err(e) => {
+                    warn!("WiFi '{}' failed: {:?}", self.networks[index].ssid, e);
+                    // Associated without an address is no use, start clean
+                    let _ = self.wifi.disconnect();
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("no WiFi networks configured")))
+    }
+
+    fn connect_to(&mut self, index: usize) -> Result<()> {
+        let network = &self.networks[index];
+        let enterprise = network.auth == WifiAuth::Enterprise;
+        // The enterprise password goes to the EAP client instead
+        let password = if enterprise { "" } else { network.password.as_str() };
+        let configuration = Configuration::Client(ClientConfiguration {
+            ssid: network
+                .ssid
+                .as_str()
+                .try_into()
+                .map_err(|_| anyhow!("WiFi SSID too long"))?,
+            password: password
+                .try_into()
+                .map_err(|_| anyhow!("WiFi password too long"))?,
+            auth_method: network.auth.method(),
+            ..Default::default()
+        });
+
+        if self.wifi.is_connected().unwrap_or(false) {
+            self.wifi.disconnect()?;
+        }
+        self.wifi.set_configuration(&configuration)?;
+        if enterprise {
+            configure_eap(network, &self.certificates)?;
+        } else {
+            esp!(unsafe { sys::esp_wifi_sta_enterprise_disable() })?;
+        }
+
+        info!("WiFi started, connecting to '{}' ({:?})...", network.ssid, network.auth);
+        self.wifi.connect()?;
+
+        info!("Waiting for DHCP...");
+        self.wifi.wait_netif_up()?;
+
+        let ip_info = self.wifi.wifi().sta_netif().get_ip_info()?;
+        info!("WiFi connected to '{}'! IP: {}", network.ssid, ip_info.ip);
+        Ok(())
+    }
+}
+
+fn configure_eap(network: &WifiNetwork, certificates: &EapCertificates) -> Result<()> {
+    let identity = network.identity.as_bytes();
+    let password = network.password.as_bytes();
+    unsafe {
+        esp!(sys::esp_eap_client_set_identity(
+            identity.as_ptr(),
+            identity.len() as i32
+        ))?;
+        match &certificates.client {
+            Some((cert, key)) => esp!(sys::esp_eap_client_set_certificate_and_key(
+                cert.as_ptr(),
+                cert.len() as i32,
+                key.as_ptr(),
+                key.len() as i32,
+                std::ptr::null(),
+                0
+            ))?,
+            None => {
+                esp!(sys::esp_eap_client_set_username(
+                    identity.as_ptr(),
+                    identity.len() as i32
+                ))?;
+                esp!(sys::esp_eap_client_set_password(
+                    password.as_ptr(),
+                    password.len() as i32
+                ))?;
+            }
+        }
+        if certificates.ca.is_empty() {
+            sys::esp_eap_client_clear_ca_cert();
+        } else {
+            esp!(sys::esp_eap_client_set_ca_cert(
+                certificates.ca.as_ptr(),
+                certificates.ca.len() as i32
+            ))?;
+        }
+        esp!(sys::esp_wifi_sta_enterprise_enable())?;
+    }
+    Ok(())
+}
+
+/// The WiFi station, shared with the reconnection watcher
+#[derive(Clone)]
+pub struct WifiLink {
+    station: Arc<Mutex<Station>>,
+    connected: Arc<AtomicBool>,
+    // SSID of the network the station is connected to
+    network: Arc<Mutex<Option<String>>>,
+}
+
+impl WifiLink {
+    pub fn new(
+        modem: esp_idf_svc::hal::modem::Modem,
+        sys_loop: EspSystemEventLoop,
+        nvs: EspDefaultNvsPartition,
+        config: &DeviceConfig,
+        secrets: &SecretStore,
+    ) -> Result<Self> {
+        let networks = networks(config)?;
+        let certificates = EapCertificates {
+            ca: pem_bytes(&config.wifi_ca),
+            client: secrets
+                .eap_client()?
+                .map(|(cert, key)| (nul_terminated(cert), nul_terminated(key))),
+        };
+        if networks.len() > 1 {
+            let names: Vec<&str> = networks.iter().map(|n| n.ssid.as_str()).collect();
+            info!("WiFi networks in order: {}", names.join(", "));
+        }
+
+        let mut wifi = BlockingWifi::wrap(
+            EspWifi::new(modem, sys_loop.clone(), Some(nvs))?,
+            sys_loop,
+        )?;
+        wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+        wifi.start()?;
+
+        Ok(Self {
+            station: Arc::new(Mutex::new(Station {
+                wifi,
+                networks,
+                certificates,
+                preferred: 0,
+                stopped: false,
+            })),
+            connected: Arc::new(AtomicBool::new(false)),
+            network: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Connect to the first network that accepts the device
+    pub fn connect(&self) -> Result<()> {
+        let mut station = lock(&self.station)?;
+        let result = station.connect();
+        self.update(&station, result.as_ref().ok().copied());
+        result.map(|_| ())
+    }
+
+    /// Drop the association and connect again, e.g. when the link looks up
+    /// but nothing gets through
+    pub fn reconnect(&self) -> Result<()> {
+        let mut station = lock(&self.station)?;
+        if let Err(e) = station.wifi.disconnect() {
+            warn!("WiFi disconnect failed: {:?}", e);
+        }
+        self.update(&station, None);
+        let result = station.connect();
+        self.update(&station, result.as_ref().ok().copied());
+        result.map(|_| ())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// SSID of the current network, `None` while disconnected
+    pub fn network(&self) -> Option<String> {
+        self.network.lock().ok().and_then(|network| network.clone())
+    }
+
+    /// Signal strength of the current network in dBm, `None` while disconnected
+    pub fn rssi(&self) -> Option<i8> {
+        if !self.is_connected() {
+            return None;
+        }
+        // Asks the driver directly, so the watcher's reconnect doesn't block it
+        let mut record = sys::wifi_ap_record_t::default();
+        esp!(unsafe { sys::esp_wifi_sta_get_ap_info(&mut record) }).ok()?;
+        Some(record.rssi)
+    }
+
+    /// Station MAC address
+    pub fn mac(&self) -> Result<[u8; 6]> {
+        Ok(lock(&self.station)?.wifi.wifi().sta_netif().get_mac()?)
+    }
+
+    /// Watch the link, reconnecting at most once per `retry_interval` while it is down
+    pub fn watch(&self, retry_interval: Duration) -> Result<()> {
+        let link = self.clone();
+        thread::Builder::new()
+            .name("wifi-watch".into())
+            .stack_size(WATCHER_STACK_SIZE)
+            .spawn(move || {
+                let mut last_attempt = clock().monotonic();
+                loop {
+                    clock().sleep(WATCH_INTERVAL);
+                    let Ok(mut station) = lock(&link.station) else {
+                        return;
+                    };
+                    if station.stopped {
+                        return;
+                    }
+                    if station.wifi.is_up().unwrap_or(false) {
+                        continue;
+                    }
+                    if link.is_connected() {
+                        warn!("WiFi connection lost");
+                        link.update(&station, None);
+                    }
+                    if clock().elapsed_since(last_attempt) < retry_interval {
+                        continue;
+                    }
+
+                    last_attempt = clock().monotonic();
+                    info!("Reconnecting WiFi...");
+                    match station.connect() {
+                        Ok(index) => link.update(&station, Some(index)),
+                        Err(e) => warn!("WiFi still unavailable: {:?}", e),
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Disconnect and stop the station for good, e.g. before deep sleep
+    pub fn shutdown(&self) {
+        let Ok(mut station) = lock(&self.station) else {
+            return;
+        };
+        station.stopped = true;
+        if let Err(e) = station.wifi.disconnect() {
+            warn!("WiFi disconnect failed: {:?}", e);
+        }
+        if let Err(e) = station.wifi.stop() {
+            warn!("WiFi stop failed: {:?}", e);
+        }
+        self.update(&station, None);
+    }
+
+    fn update(&self, station: &Station, connected_to: Option<usize>) {
+        self.connected.store(connected_to.is_some(), Ordering::Relaxed);
+        if let Ok(mut network) = self.network.lock() {
+            *network = connected_to.map(|index| station.networks[index].ssid.clone());
+        }
+    }
+}
+
+fn lock(station: &Mutex<Station>) -> Result<std::sync::MutexGuard<'_, Station>> {
+    station.lock().map_err(|_| anyhow!("WiFi station lock poisoned"))
+}
+
+fn pem_bytes(pem: &str) -> Vec<u8> {
+    match pem.trim() {
+        "" => Vec::new(),
+        pem => nul_terminated(pem.as_bytes().to_vec()),
+    }
+}
+
+// mbedTLS parses PEM including its terminating NUL
+fn nul_terminated(mut pem: Vec<u8>) -> Vec<u8> {
+    if pem.last() != Some(&0) {
+        pem.push(0);
+    }
+    pem
+}